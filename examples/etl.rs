@@ -1,9 +1,8 @@
 //! An example showing how to do an ETL-like operation loading events into
 //! Segment.
 
-use segment::message::{Track, User};
+use segment::message::{Properties, Track, User};
 use segment::{Batcher, Client, HttpClient};
-use serde_json::json;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
@@ -20,9 +19,7 @@ async fn main() {
                 user_id: format!("user-{}", i),
             },
             event: "Batched Event".to_owned(),
-            properties: json!({
-                "foo": format!("bar-{}", i),
-            }),
+            properties: Properties::new().with("foo", format!("bar-{}", i)),
             ..Default::default()
         };
 
@@ -1,9 +1,8 @@
 //! An example showing how to do an ETL-like operation loading events into
 //! Segment using the `AutoBatcher`.
 
-use segment::message::{Track, User};
-use segment::{AutoBatcher, Batcher, HttpClient};
-use serde_json::json;
+use segment::message::{Properties, Track, User};
+use segment::{AutoBatcher, Batcher, HttpClient, WriteKey};
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
@@ -11,7 +10,7 @@ async fn main() {
 
     let client = HttpClient::default();
     let batcher = Batcher::new(None);
-    let mut batcher = AutoBatcher::new(client, batcher, write_key.to_string());
+    let mut batcher = AutoBatcher::new(client, batcher, WriteKey::new(write_key).unwrap());
 
     // Pretend this is reading off of a queue, a file, or some other data
     // source.
@@ -21,9 +20,7 @@ async fn main() {
                 user_id: format!("user-{}", i),
             },
             event: "Auto batched Event".to_owned(),
-            properties: json!({
-                "foo": format!("bar-{}", i),
-            }),
+            properties: Properties::new().with("foo", format!("bar-{}", i)),
             ..Default::default()
         };
 
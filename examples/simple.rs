@@ -1,8 +1,7 @@
 //! An example showing how to send a single event to Segment.
 
-use segment::message::{Track, User};
+use segment::message::{Properties, Track, User};
 use segment::{Client, HttpClient};
-use serde_json::json;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
@@ -17,10 +16,9 @@ async fn main() {
                     user_id: "some_user_id".to_owned(),
                 },
                 event: "Example Event".to_owned(),
-                properties: json!({
-                    "some property": "some value",
-                    "some other property": "some other value",
-                }),
+                properties: Properties::new()
+                    .with("some property", "some value")
+                    .with("some other property", "some other value"),
                 ..Default::default()
             }
             .into(),
@@ -0,0 +1,1053 @@
+//! A long-lived handle sending messages to Segment in the background.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use futures_channel::{mpsc, oneshot};
+use futures_util::future::{self, Either};
+use futures_util::StreamExt;
+use time::OffsetDateTime;
+
+use crate::backlog::Backlog;
+use crate::message::{self, BatchMessage, Identify, Track, Traits, User};
+use crate::validation;
+use crate::{
+    opt_out, runtime, scope, AutoBatcher, Client, Config, Error, Metrics, Recorder, Result,
+    ValidationMode,
+};
+
+pub(crate) enum Command {
+    Enqueue(BatchMessage),
+    EnqueueAt(BatchMessage, Instant),
+    EnqueueAll(Vec<BatchMessage>),
+    Flush(oneshot::Sender<Result<()>>),
+    Close(oneshot::Sender<Result<()>>),
+    Pause,
+    Resume,
+    Reconfigure(Box<Config>, oneshot::Sender<Result<()>>),
+}
+
+/// A handle to a background task batching messages and sending them to
+/// Segment.
+///
+/// Unlike the [`AutoBatcher`], which sends a batch from within the call to
+/// `push` that fills it, `Analytics` hands messages over to a background task
+/// and returns immediately. That task sends a batch whenever it is full, and
+/// at least every `flush_interval` so messages produced infrequently are not
+/// delayed forever.
+///
+/// `Analytics` is cheap to clone, and all the clones share the same
+/// background task. It is usually built with a
+/// [`ClientBuilder`](crate::ClientBuilder):
+///
+/// ```no_run
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> segment::Result<()> {
+/// use segment::ClientBuilder;
+/// use segment::message::{Properties, Track, User};
+///
+/// let analytics = ClientBuilder::from_env()?.build()?;
+///
+/// analytics.enqueue(Track {
+///     user: User::UserId { user_id: "user".to_owned() },
+///     event: "Example".to_owned(),
+///     properties: Properties::new().with("foo", "bar"),
+///     ..Default::default()
+/// })?;
+///
+/// analytics.close().await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Messages for the same user are always sent in the order they were queued,
+/// so that an `identify` is never sent after the `track` events following it.
+/// To send batches concurrently while keeping this guarantee, the queue can be
+/// split into [partitions](Analytics::partitioned), each with its own
+/// background task, by a hash of the user ID.
+///
+/// Errors happening in the background task are logged through the [`log`]
+/// crate, or passed to the callback registered with
+/// [`on_error`](Analytics::on_error). Code paths which must not fail because
+/// of analytics can use [`try_enqueue`](Analytics::try_enqueue), which reports
+/// its errors the same way instead of returning them.
+///
+/// The queue is unbounded. When the background tasks can't keep up, producers
+/// can slow down cooperatively once it reaches a [high-water
+/// mark](Analytics::set_high_water_mark), by waiting for
+/// [`acquire_slot`](Analytics::acquire_slot) before queueing, or by queueing
+/// with [`enqueue_if_room`](Analytics::enqueue_if_room) and backing off when
+/// it fails.
+#[derive(Clone, Debug)]
+pub struct Analytics {
+    partitions: Arc<[mpsc::UnboundedSender<Command>]>,
+    errors: Errors,
+    metrics: Arc<Metrics>,
+    backlog: Arc<Backlog>,
+    recorder: Option<Recorder>,
+    /// Whether incomplete messages are rejected when queued, rather than by
+    /// the background task.
+    validate: Arc<AtomicBool>,
+    /// The prefix of the names of the `track` events queued on this handle.
+    namespace: Option<Arc<str>>,
+}
+
+type ErrorFn = dyn Fn(&Error) + Send + Sync;
+
+/// A background task, to be run to completion.
+pub(crate) type Task = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A callback notified of errors which can't be returned to the caller.
+#[derive(Clone)]
+pub(crate) struct ErrorHook(pub(crate) Arc<ErrorFn>);
+
+impl fmt::Debug for ErrorHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ErrorHook")
+    }
+}
+
+/// The error callback, shared between the handles and the background task.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Errors(pub(crate) Arc<RwLock<Option<ErrorHook>>>);
+
+impl Errors {
+    pub(crate) fn report(&self, error: &Error) {
+        match &*self.0.read().unwrap() {
+            Some(ErrorHook(hook)) => hook(error),
+            None => log::error!("could not send messages to Segment: {}", error),
+        }
+    }
+}
+
+impl Analytics {
+    /// Spawn a background task sending the messages given to this handle
+    /// through `batcher`, flushing it at least every `flush_interval`.
+    ///
+    /// With the `tokio` feature, this must be called from within a Tokio
+    /// runtime. With only the `smol` feature, the task is spawned on smol's
+    /// global executor. Without either feature, there is no runtime to spawn
+    /// the task on, and this is not available.
+    #[cfg(any(feature = "tokio", feature = "smol"))]
+    pub fn new<C>(batcher: AutoBatcher<C>, flush_interval: Duration) -> Self
+    where
+        C: Client + Send + Sync + 'static,
+    {
+        Self::spawn(vec![batcher], flush_interval)
+    }
+
+    /// Spawn `partitions` background tasks, each sending messages through a
+    /// clone of `batcher`, flushing it at least every `flush_interval`.
+    ///
+    /// Messages are assigned to a partition by a hash of their user ID, or of
+    /// their anonymous ID if they have no user ID, so the messages of a given
+    /// user are still sent in order while the partitions send their batches
+    /// concurrently.
+    ///
+    /// See [`new`](Analytics::new) for the runtime requirements. The clones
+    /// of `batcher` would share its [`Spool`](crate::Spool), so a batcher
+    /// with a spool can only have one partition.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `partitions` is zero, or if it is more than one and
+    /// `batcher` has a spool.
+    #[cfg(any(feature = "tokio", feature = "smol"))]
+    pub fn partitioned<C>(
+        batcher: AutoBatcher<C>,
+        flush_interval: Duration,
+        partitions: usize,
+    ) -> Self
+    where
+        C: Client + Clone + Send + Sync + 'static,
+    {
+        assert!(partitions > 0, "there must be at least one partition");
+        assert!(
+            partitions == 1 || batcher.spool.is_none(),
+            "a batcher with a spool can't be partitioned"
+        );
+        Self::spawn(vec![batcher; partitions], flush_interval)
+    }
+
+    #[cfg(any(feature = "tokio", feature = "smol"))]
+    fn spawn<C>(batchers: Vec<AutoBatcher<C>>, flush_interval: Duration) -> Self
+    where
+        C: Client + Send + Sync + 'static,
+    {
+        Self::spawn_with(batchers, flush_interval, runtime::spawn)
+    }
+
+    /// Run the background task of every batcher with `spawn`.
+    pub(crate) fn spawn_with<C>(
+        batchers: Vec<AutoBatcher<C>>,
+        flush_interval: Duration,
+        mut spawn: impl FnMut(Task),
+    ) -> Self
+    where
+        C: Client + Send + Sync + 'static,
+    {
+        let errors = Errors::default();
+        let metrics = batchers[0].batcher.metrics();
+        let backlog = Arc::new(Backlog::default());
+        let recorder = batchers[0].recorder.clone();
+        let validate = Arc::new(AtomicBool::new(batchers[0].batcher.validates_on_push()));
+        let partitions = batchers
+            .into_iter()
+            .map(|batcher| {
+                let (commands, receiver) = mpsc::unbounded();
+                spawn(Box::pin(run(
+                    batcher,
+                    receiver,
+                    flush_interval,
+                    errors.clone(),
+                    backlog.clone(),
+                )));
+                commands
+            })
+            .collect();
+        Self {
+            partitions,
+            errors,
+            metrics,
+            backlog,
+            recorder,
+            validate,
+            namespace: None,
+        }
+    }
+
+    /// A handle to the same background tasks, prefixing the names of the
+    /// `track` events queued on it with `prefix`, e.g. `"Backend: "`, to tell
+    /// apart the sources sharing a write key.
+    ///
+    /// Names which already start with `prefix` are left as they are. The
+    /// clones of the returned handle share its prefix, but not this handle.
+    pub fn with_namespace(&self, prefix: impl Into<String>) -> Self {
+        Self {
+            namespace: Some(prefix.into().into()),
+            ..self.clone()
+        }
+    }
+
+    /// The prefix of the names of the `track` events queued on this handle,
+    /// if any. See [`with_namespace`](Analytics::with_namespace).
+    pub fn namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    /// The [`Recorder`] keeping the last messages sent, if one was set on the
+    /// batcher of this pipeline, e.g. with
+    /// [`ClientBuilder::recent_messages`](crate::ClientBuilder::recent_messages).
+    pub fn recorder(&self) -> Option<&Recorder> {
+        self.recorder.as_ref()
+    }
+
+    /// Call `hook` with the errors happening in the background task or in
+    /// [`try_enqueue`](Analytics::try_enqueue), instead of logging them.
+    ///
+    /// The hook is shared by all the clones of this handle.
+    pub fn on_error(&self, hook: impl Fn(&Error) + Send + Sync + 'static) {
+        self.set_error_hook(ErrorHook(Arc::new(hook)));
+    }
+
+    pub(crate) fn set_error_hook(&self, hook: ErrorHook) {
+        *self.errors.0.write().unwrap() = Some(hook);
+    }
+
+    /// The counters of the messages the background tasks did not send.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Consider the queue full once `high_water` messages are waiting for the
+    /// background tasks, or never if `None`, which is the default.
+    ///
+    /// Only [`acquire_slot`](Analytics::acquire_slot) and
+    /// [`enqueue_if_room`](Analytics::enqueue_if_room) honor it; the other
+    /// methods queue messages regardless. It is shared by all the clones of
+    /// this handle.
+    pub fn set_high_water_mark(&self, high_water: Option<usize>) {
+        self.backlog.set_high_water_mark(high_water);
+    }
+
+    /// The number of messages queued which the background tasks did not take
+    /// yet.
+    pub fn queued(&self) -> usize {
+        self.backlog.len()
+    }
+
+    /// Wait until the queue is below the [high-water
+    /// mark](Analytics::set_high_water_mark).
+    ///
+    /// Producers which can afford to wait call this before
+    /// [`enqueue`](Analytics::enqueue), so they slow down to the pace of the
+    /// background tasks instead of growing the queue without bound. Several
+    /// producers may be let through at once, so the queue can still grow a
+    /// little past the mark.
+    pub async fn acquire_slot(&self) {
+        self.backlog.ready().await
+    }
+
+    /// Queue a message to be sent in the background, unless the queue has
+    /// reached the [high-water mark](Analytics::set_high_water_mark).
+    ///
+    /// Returns [`Error::QueueFull`] if it has, so the caller can back off,
+    /// retry later or drop the message, or an error if the handle was
+    /// [closed](Analytics::close).
+    pub fn enqueue_if_room(&self, msg: impl Into<BatchMessage>) -> Result<()> {
+        if self.backlog.is_full() {
+            return Err(Error::QueueFull);
+        }
+        self.enqueue(msg)
+    }
+
+    /// Queue a message to be sent in the background, or drop it if the
+    /// process [opted out](crate::set_opted_out).
+    ///
+    /// Returns an error if the handle was [closed](Analytics::close), or in
+    /// strict mode, if the message is incomplete and validated when queued;
+    /// see [`ValidationMode`].
+    pub fn enqueue(&self, msg: impl Into<BatchMessage>) -> Result<()> {
+        if opt_out::is_opted_out() {
+            return Ok(());
+        }
+        let mut msg = msg.into();
+        // The background task runs outside of the caller's scope.
+        scope::apply(&mut msg);
+        self.apply_namespace(&mut msg);
+        self.validate(&msg)?;
+        let partition = self.partition(msg.user());
+        self.enqueue_partition(partition, Command::Enqueue(msg), 1)
+    }
+
+    /// Queue a message to be batched once `when` has come, e.g. a `Trial
+    /// Ending` event at the end of a trial, without a scheduler. The message
+    /// is timestamped with `when` unless it already has a timestamp.
+    ///
+    /// The wait is measured on a monotonic clock from now, so changes of the
+    /// system time don't affect it. Messages still waiting aren't counted in
+    /// the [queue](Analytics::queued).
+    ///
+    /// When the handle is closed, the messages not due yet are written to the
+    /// [`Spool`](crate::Spool) of the batcher, if it has one, to be sent with
+    /// the spooled batches once the process restarts, before they are due.
+    /// Otherwise, they are dropped, and [`close`](Analytics::close) returns
+    /// [`Error::ScheduledDropped`] with their number.
+    pub fn enqueue_at(&self, msg: impl Into<BatchMessage>, when: OffsetDateTime) -> Result<()> {
+        if opt_out::is_opted_out() {
+            return Ok(());
+        }
+        let mut msg = msg.into();
+        scope::apply(&mut msg);
+        self.apply_namespace(&mut msg);
+        self.validate(&msg)?;
+        msg.timestamp_mut().get_or_insert(when);
+        let delay: Duration = (when - OffsetDateTime::now_utc())
+            .try_into()
+            .unwrap_or_default();
+        let partition = self.partition(msg.user());
+        self.enqueue_partition(
+            partition,
+            Command::EnqueueAt(msg, Instant::now() + delay),
+            1,
+        )
+    }
+
+    /// Queue messages which must be sent in the same batch, in order. See
+    /// [`AutoBatcher::push_all`].
+    ///
+    /// The messages are assigned to the partition of the first one.
+    pub fn enqueue_all(
+        &self,
+        msgs: impl IntoIterator<Item = impl Into<BatchMessage>>,
+    ) -> Result<()> {
+        if opt_out::is_opted_out() {
+            return Ok(());
+        }
+        let mut msgs: Vec<BatchMessage> = msgs.into_iter().map(Into::into).collect();
+        let partition = match msgs.first() {
+            Some(msg) => self.partition(msg.user()),
+            None => return Ok(()),
+        };
+        for msg in &mut msgs {
+            scope::apply(msg);
+            self.apply_namespace(msg);
+        }
+        msgs.iter().try_for_each(|msg| self.validate(msg))?;
+        let len = msgs.len();
+        self.enqueue_partition(partition, Command::EnqueueAll(msgs), len)
+    }
+
+    /// Queue messages which must be sent in the same batch, in order, unless
+    /// they don't all fit below the [high-water
+    /// mark](Analytics::set_high_water_mark).
+    ///
+    /// The group is queued as a whole or not at all: an `identify`, `track`
+    /// and `group` emitted together at signup are never half-dropped. Returns
+    /// [`Error::QueueFull`] if there is no room for all of them.
+    pub fn enqueue_all_if_room(
+        &self,
+        msgs: impl IntoIterator<Item = impl Into<BatchMessage>>,
+    ) -> Result<()> {
+        let msgs: Vec<BatchMessage> = msgs.into_iter().map(Into::into).collect();
+        if !self.backlog.has_room(msgs.len()) {
+            return Err(Error::QueueFull);
+        }
+        self.enqueue_all(msgs)
+    }
+
+    /// Link the anonymous ID a user had to their new user ID, queueing an
+    /// `alias` and an `identify` to be sent in the same batch. See
+    /// [`alias_and_identify`](crate::message::alias_and_identify).
+    ///
+    /// With several [partitions](Analytics::partitioned), the messages sent
+    /// before with the anonymous ID may be sent after these.
+    pub fn alias_and_identify(
+        &self,
+        anonymous_id: impl Into<String>,
+        user_id: impl Into<String>,
+        traits: Traits,
+    ) -> Result<()> {
+        self.enqueue_all(message::alias_and_identify(anonymous_id, user_id, traits))
+    }
+
+    /// Queue a message to be sent in the background, without ever failing.
+    ///
+    /// If the message can't be queued, the error is passed to the
+    /// [`on_error`](Analytics::on_error) hook, or logged.
+    pub fn try_enqueue(&self, msg: impl Into<BatchMessage>) {
+        if let Err(e) = self.enqueue(msg) {
+            self.errors.report(&e);
+        }
+    }
+
+    /// Queue a `track` event without ever failing. See
+    /// [`try_enqueue`](Analytics::try_enqueue).
+    pub fn try_track(&self, msg: Track) {
+        self.try_enqueue(msg)
+    }
+
+    /// Queue an `identify` message without ever failing. See
+    /// [`try_enqueue`](Analytics::try_enqueue).
+    pub fn try_identify(&self, msg: Identify) {
+        self.try_enqueue(msg)
+    }
+
+    /// Stop sending messages to Segment until
+    /// [`resume`](Analytics::resume) is called, holding them in memory. See
+    /// [`AutoBatcher::pause`].
+    ///
+    /// Messages still held when the handle is [closed](Analytics::close) are
+    /// lost.
+    pub fn pause(&self) -> Result<()> {
+        self.broadcast(|| Command::Pause)
+    }
+
+    /// Send messages to Segment again, starting with the ones held while
+    /// paused.
+    pub fn resume(&self) -> Result<()> {
+        self.broadcast(|| Command::Resume)
+    }
+
+    /// Apply `config` to the running pipeline, without losing queued
+    /// messages.
+    ///
+    /// The messages queued so far are sent with the previous settings first.
+    /// Then the write key, host, timeouts, batch settings, retry policy and
+    /// flush interval of `config` are applied; see
+    /// [`AutoBatcher::reconfigure`]. So is its high-water mark.
+    ///
+    /// The number of partitions can't be changed on a running pipeline.
+    ///
+    /// To follow a configuration as it changes, call this whenever a new one
+    /// is published, for instance from a task watching a file or a channel.
+    pub async fn reconfigure(&self, config: Config) -> Result<()> {
+        self.request(|done| Command::Reconfigure(Box::new(config.clone()), done))
+            .await?;
+        self.set_high_water_mark(config.high_water_mark);
+        self.validate.store(
+            config.strict && config.validation_mode == ValidationMode::Enqueue,
+            Ordering::Relaxed,
+        );
+        Ok(())
+    }
+
+    /// Send all the queued messages now, and wait until they are sent.
+    pub async fn flush(&self) -> Result<()> {
+        self.request(Command::Flush).await
+    }
+
+    /// Send all the queued messages and stop the background task.
+    ///
+    /// Returns [`Error::ScheduledDropped`] if messages
+    /// [scheduled](Analytics::enqueue_at) for later were dropped.
+    ///
+    /// Any later call on this handle or its clones returns an error.
+    pub async fn close(&self) -> Result<()> {
+        self.request(Command::Close).await
+    }
+
+    /// [Close](Analytics::close) the handle, giving up waiting after
+    /// `timeout`, e.g. to honor a hard shutdown deadline.
+    ///
+    /// Returns [`Error::Timeout`] if the background tasks did not finish
+    /// sending the queued messages in time. Combine it with a retry
+    /// [`deadline`](crate::RetryPolicy::deadline) shorter than `timeout` to
+    /// dead-letter the batches which could not be sent instead.
+    pub async fn close_within(&self, timeout: Duration) -> Result<()> {
+        let close = self.close();
+        futures_util::pin_mut!(close);
+        let sleep = runtime::sleep(timeout);
+        futures_util::pin_mut!(sleep);
+        match future::select(close, sleep).await {
+            Either::Left((result, _)) => result,
+            Either::Right(((), _)) => Err(Error::Timeout),
+        }
+    }
+
+    fn apply_namespace(&self, msg: &mut BatchMessage) {
+        if let (Some(prefix), BatchMessage::Track(track)) = (&self.namespace, msg) {
+            if !track.event.starts_with(&**prefix) {
+                track.event.insert_str(0, prefix);
+            }
+        }
+    }
+
+    fn validate(&self, msg: &BatchMessage) -> Result<()> {
+        if self.validate.load(Ordering::Relaxed) {
+            validation::check_complete(msg)?;
+        }
+        Ok(())
+    }
+
+    fn partition(&self, user: &User) -> usize {
+        let mut hasher = DefaultHasher::new();
+        user.key().hash(&mut hasher);
+        (hasher.finish() % self.partitions.len() as u64) as usize
+    }
+
+    fn send(&self, partition: usize, command: Command) -> Result<()> {
+        self.partitions[partition]
+            .unbounded_send(command)
+            .map_err(|_| Error::Closed)
+    }
+
+    /// Send a command queueing `len` messages, counting them in the backlog
+    /// before the background task can take them.
+    fn enqueue_partition(&self, partition: usize, command: Command, len: usize) -> Result<()> {
+        self.backlog.add(len);
+        let result = self.send(partition, command);
+        if result.is_err() {
+            self.backlog.remove(len);
+        }
+        result
+    }
+
+    fn broadcast(&self, command: impl Fn() -> Command) -> Result<()> {
+        (0..self.partitions.len()).try_for_each(|partition| self.send(partition, command()))
+    }
+
+    /// Send a command to every partition, and wait until they all answered.
+    async fn request(
+        &self,
+        mut command: impl FnMut(oneshot::Sender<Result<()>>) -> Command,
+    ) -> Result<()> {
+        let mut results = Vec::with_capacity(self.partitions.len());
+        for partition in 0..self.partitions.len() {
+            let (done, result) = oneshot::channel();
+            self.send(partition, command(done))?;
+            results.push(result);
+        }
+        let mut outcome = Ok(());
+        for result in results {
+            let result = result
+                .await
+                .map_err(|_| Error::Closed)
+                .and_then(|result| result);
+            if outcome.is_ok() {
+                outcome = result;
+            }
+        }
+        outcome
+    }
+}
+
+async fn run<C: Client>(
+    mut batcher: AutoBatcher<C>,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    mut flush_interval: Duration,
+    errors: Errors,
+    backlog: Arc<Backlog>,
+) {
+    let mut next_flush = Instant::now() + flush_interval;
+    // The messages waiting to be pushed, by the time they are due and the
+    // order they were received in.
+    let mut scheduled = BTreeMap::new();
+    let mut received = 0u64;
+
+    loop {
+        let wake = match scheduled.keys().next() {
+            Some(&(due, _)) => next_flush.min(due),
+            None => next_flush,
+        };
+        let tick = runtime::sleep(wake.saturating_duration_since(Instant::now()));
+        futures_util::pin_mut!(tick);
+        match future::select(commands.next(), tick).await {
+            Either::Left((command, _)) => match command {
+                Some(Command::Enqueue(msg)) => {
+                    backlog.remove(1);
+                    if let Err(e) = batcher.push(msg).await {
+                        errors.report(&e);
+                    }
+                }
+                Some(Command::EnqueueAt(msg, due)) => {
+                    backlog.remove(1);
+                    scheduled.insert((due, received), msg);
+                    received += 1;
+                }
+                Some(Command::EnqueueAll(msgs)) => {
+                    backlog.remove(msgs.len());
+                    if let Err(e) = batcher.push_all(msgs).await {
+                        errors.report(&e);
+                    }
+                }
+                Some(Command::Flush(done)) => {
+                    let _ = done.send(flush(&mut batcher).await);
+                }
+                Some(Command::Pause) => batcher.pause(),
+                Some(Command::Resume) => {
+                    batcher.resume();
+                    if let Err(e) = flush(&mut batcher).await {
+                        errors.report(&e);
+                    }
+                }
+                Some(Command::Reconfigure(config, done)) => {
+                    if let Err(e) = flush(&mut batcher).await {
+                        errors.report(&e);
+                    }
+                    let result = batcher.reconfigure(&config);
+                    if result.is_ok() {
+                        flush_interval = config.flush_interval;
+                        next_flush = Instant::now() + flush_interval;
+                    }
+                    let _ = done.send(result);
+                }
+                Some(Command::Close(done)) => {
+                    // Reject new messages before reporting the handle as
+                    // closed.
+                    commands.close();
+                    let result = flush(&mut batcher).await;
+                    let kept = keep_scheduled(&mut batcher, scheduled);
+                    let _ = done.send(result.and(kept));
+                    break;
+                }
+                // Every handle was dropped.
+                None => {
+                    if let Err(e) = flush(&mut batcher).await {
+                        errors.report(&e);
+                    }
+                    if let Err(e) = keep_scheduled(&mut batcher, scheduled) {
+                        errors.report(&e);
+                    }
+                    break;
+                }
+            },
+            Either::Right(((), _)) => {
+                let now = Instant::now();
+                while let Some(entry) = scheduled.first_entry() {
+                    if entry.key().0 > now {
+                        break;
+                    }
+                    if let Err(e) = batcher.push(entry.remove()).await {
+                        errors.report(&e);
+                    }
+                }
+                if now >= next_flush {
+                    next_flush = now + flush_interval;
+                    if let Err(e) = flush(&mut batcher).await {
+                        errors.report(&e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Write the messages not due yet to the spool of `batcher`, returning
+/// [`Error::ScheduledDropped`] if it has none.
+fn keep_scheduled<C: Client>(
+    batcher: &mut AutoBatcher<C>,
+    scheduled: BTreeMap<(Instant, u64), BatchMessage>,
+) -> Result<()> {
+    let count = scheduled.len();
+    if count == 0 {
+        return Ok(());
+    }
+    if batcher.spool_unsent(scheduled.into_values().collect())? {
+        log::info!("spooled {} scheduled messages not due yet", count);
+        Ok(())
+    } else {
+        Err(Error::ScheduledDropped(count))
+    }
+}
+
+async fn flush<C: Client>(batcher: &mut AutoBatcher<C>) -> Result<()> {
+    if batcher.is_empty() {
+        return Ok(());
+    }
+    batcher.flush().await
+}
+
+#[cfg(all(test, any(feature = "tokio", feature = "smol")))]
+mod tests {
+    use super::*;
+    use crate::message::{Track, User};
+    use crate::test_utils::{Fault, FaultyClient, MockClient};
+    use crate::{Batcher, Spool, WriteKey};
+    use std::sync::Mutex;
+
+    fn track(user_id: &str) -> Track {
+        Track {
+            user: User::UserId {
+                user_id: user_id.to_owned(),
+            },
+            event: "Example".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flush_and_close() {
+        let client = MockClient::new();
+        let batcher = AutoBatcher::new(
+            client.clone(),
+            Batcher::new(None),
+            WriteKey::new("key").unwrap(),
+        );
+        let analytics = Analytics::new(batcher, Duration::from_secs(3600));
+
+        analytics.enqueue(track("foo")).unwrap();
+        analytics.enqueue(track("bar")).unwrap();
+        analytics.flush().await.unwrap();
+        assert_eq!(client.tracks().len(), 2);
+
+        analytics.enqueue(track("baz")).unwrap();
+        analytics.clone().close().await.unwrap();
+        assert_eq!(client.tracks().len(), 3);
+        assert!(matches!(
+            analytics.enqueue(track("qux")),
+            Err(Error::Closed)
+        ));
+    }
+
+    // Relies on the background task running on the test's thread.
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_high_water_mark() {
+        let client = MockClient::new();
+        let batcher = AutoBatcher::new(
+            client.clone(),
+            Batcher::new(None),
+            WriteKey::new("key").unwrap(),
+        );
+        let analytics = Analytics::new(batcher, Duration::from_secs(3600));
+        analytics.set_high_water_mark(Some(2));
+
+        // The background task can't run until this task yields.
+        analytics.enqueue_if_room(track("foo")).unwrap();
+        analytics.enqueue_if_room(track("bar")).unwrap();
+        assert_eq!(analytics.queued(), 2);
+        assert!(matches!(
+            analytics.enqueue_if_room(track("baz")),
+            Err(Error::QueueFull)
+        ));
+
+        analytics.acquire_slot().await;
+        assert!(analytics.queued() < 2);
+        analytics.enqueue_if_room(track("baz")).unwrap();
+        analytics.close().await.unwrap();
+        assert_eq!(client.tracks().len(), 3);
+        assert_eq!(analytics.queued(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_all_if_room() {
+        let client = MockClient::new();
+        let batcher = AutoBatcher::new(
+            client.clone(),
+            Batcher::new(None),
+            WriteKey::new("key").unwrap(),
+        );
+        let analytics = Analytics::new(batcher, Duration::from_secs(3600));
+        analytics.set_high_water_mark(Some(3));
+
+        // The background task can't run until this task yields.
+        analytics.enqueue_if_room(track("foo")).unwrap();
+        assert!(matches!(
+            analytics.enqueue_all_if_room(vec![track("a"), track("b"), track("c")]),
+            Err(Error::QueueFull)
+        ));
+        assert_eq!(analytics.queued(), 1);
+        analytics
+            .enqueue_all_if_room(vec![track("a"), track("b")])
+            .unwrap();
+        assert_eq!(analytics.queued(), 3);
+
+        analytics.close().await.unwrap();
+        let tracks = client.tracks();
+        let users: Vec<_> = tracks.iter().map(|track| track.user.key()).collect();
+        assert_eq!(users, ["foo", "a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_namespace() {
+        let client = MockClient::new();
+        let batcher = AutoBatcher::new(
+            client.clone(),
+            Batcher::new(None),
+            WriteKey::new("key").unwrap(),
+        );
+        let analytics = Analytics::new(batcher, Duration::from_secs(3600));
+        let backend = analytics.with_namespace("Backend: ");
+
+        backend.enqueue(track("foo")).unwrap();
+        backend
+            .enqueue(Track {
+                event: "Backend: Signed Up".to_owned(),
+                ..track("foo")
+            })
+            .unwrap();
+        analytics.enqueue(track("foo")).unwrap();
+        analytics.close().await.unwrap();
+
+        let tracks = client.tracks();
+        let events: Vec<_> = tracks.iter().map(|track| track.event.as_str()).collect();
+        assert_eq!(
+            events,
+            ["Backend: Example", "Backend: Signed Up", "Example"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flush_interval() {
+        let client = MockClient::new();
+        let batcher = AutoBatcher::new(
+            client.clone(),
+            Batcher::new(None),
+            WriteKey::new("key").unwrap(),
+        );
+        let analytics = Analytics::new(batcher, Duration::from_millis(10));
+
+        analytics.enqueue(track("foo")).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(client.tracks().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_at() {
+        let client = MockClient::new();
+        let batcher = AutoBatcher::new(
+            client.clone(),
+            Batcher::new(None),
+            WriteKey::new("key").unwrap(),
+        );
+        let analytics = Analytics::new(batcher, Duration::from_secs(3600));
+
+        let when = OffsetDateTime::now_utc() + Duration::from_millis(100);
+        analytics.enqueue_at(track("later"), when).unwrap();
+        analytics
+            .enqueue_at(track("past"), OffsetDateTime::UNIX_EPOCH)
+            .unwrap();
+        analytics.flush().await.unwrap();
+        assert_eq!(client.tracks().len(), 0);
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        analytics.flush().await.unwrap();
+        assert_eq!(client.tracks().len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        analytics
+            .enqueue_at(track("tomorrow"), when + Duration::from_secs(86400))
+            .unwrap();
+        assert!(matches!(
+            analytics.close().await,
+            Err(Error::ScheduledDropped(1))
+        ));
+        let tracks = client.tracks();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[1].timestamp, Some(when));
+    }
+
+    #[tokio::test]
+    async fn test_spool_scheduled() {
+        let dir = std::env::temp_dir().join(format!("segment-spool-{}", uuid::Uuid::new_v4()));
+        let when = OffsetDateTime::now_utc() + Duration::from_secs(86400);
+        {
+            let mut batcher = AutoBatcher::new(
+                MockClient::new(),
+                Batcher::new(None),
+                WriteKey::new("key").unwrap(),
+            );
+            batcher.set_spool(Spool::open(&dir).unwrap());
+            let analytics = Analytics::new(batcher, Duration::from_secs(3600));
+            analytics.enqueue_at(track("tomorrow"), when).unwrap();
+            analytics.close().await.unwrap();
+        }
+
+        // Once restarted, the scheduled message is sent with the spool.
+        let client = MockClient::new();
+        let mut batcher = AutoBatcher::new(
+            client.clone(),
+            Batcher::new(None),
+            WriteKey::new("key").unwrap(),
+        );
+        let spool = Spool::open(&dir).unwrap();
+        assert_eq!(spool.len(), 1);
+        batcher.set_spool(spool);
+        batcher.flush().await.unwrap();
+        let tracks = client.tracks();
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].timestamp, Some(when));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_on_error() {
+        let client = FaultyClient::new(MockClient::new(), vec![Fault::Status(500)]);
+        let mut batcher = Batcher::new(None);
+        batcher.set_max_batch_messages(1);
+        let batcher = AutoBatcher::new(client.clone(), batcher, WriteKey::new("key").unwrap());
+        let analytics = Analytics::new(batcher, Duration::from_secs(3600));
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let recorded = errors.clone();
+        analytics.on_error(move |e| recorded.lock().unwrap().push(e.to_string()));
+
+        analytics.try_track(track("foo"));
+        // Sends the batch containing `foo`, which fails.
+        analytics.try_track(track("bar"));
+        analytics.close().await.unwrap();
+        analytics.try_track(track("baz"));
+
+        assert_eq!(client.inner().tracks().len(), 1);
+        assert_eq!(
+            *errors.lock().unwrap(),
+            vec![
+                "server responded with status 500".to_owned(),
+                "the analytics pipeline is closed".to_owned(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validation_mode() {
+        let client = MockClient::new();
+        let mut batcher = Batcher::new(None);
+        batcher.enable_strict_mode();
+        let batcher = AutoBatcher::new(client.clone(), batcher, WriteKey::new("key").unwrap());
+        let analytics = Analytics::new(batcher, Duration::from_secs(3600));
+        let anonymous = Track {
+            event: "Example".to_owned(),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            analytics.enqueue(anonymous.clone()),
+            Err(Error::InvalidMessage { .. })
+        ));
+
+        let config = Config {
+            strict: true,
+            validation_mode: ValidationMode::Flush,
+            ..Config::default()
+        };
+        analytics.reconfigure(config).await.unwrap();
+        analytics.enqueue(anonymous).unwrap();
+        analytics.enqueue(track("foo")).unwrap();
+        analytics.close().await.unwrap();
+        assert_eq!(client.tracks().len(), 1);
+    }
+
+    // `HttpClient` needs a Tokio runtime.
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_reconfigure() {
+        use crate::test_utils::FakeServer;
+        use crate::HttpClient;
+
+        let (before, after) = (FakeServer::start(), FakeServer::start());
+        let client = HttpClient::new(reqwest::Client::new(), before.url());
+        let batcher = AutoBatcher::new(client, Batcher::new(None), WriteKey::new("key").unwrap());
+        let analytics = Analytics::new(batcher, Duration::from_secs(3600));
+
+        analytics.enqueue(track("foo")).unwrap();
+        let config = Config {
+            host: after.url(),
+            ..Config::default()
+        };
+        analytics.reconfigure(config).await.unwrap();
+        analytics.enqueue(track("bar")).unwrap();
+        analytics.flush().await.unwrap();
+
+        assert_eq!(before.accepted().len(), 1);
+        assert_eq!(after.accepted().len(), 1);
+        assert_eq!(after.accepted()[0]["batch"][0]["userId"], "bar");
+    }
+
+    #[tokio::test]
+    async fn test_partitioned() {
+        let client = MockClient::new();
+        let mut batcher = Batcher::new(None);
+        batcher.set_max_batch_messages(3);
+        let batcher = AutoBatcher::new(client.clone(), batcher, WriteKey::new("key").unwrap());
+        let analytics = Analytics::partitioned(batcher, Duration::from_secs(3600), 4);
+
+        for i in 0..50 {
+            let mut msg = track(&format!("user-{}", i % 7));
+            msg.properties = msg.properties.with("seq", i);
+            analytics.enqueue(msg).unwrap();
+        }
+        analytics.close().await.unwrap();
+
+        let tracks = client.tracks();
+        assert_eq!(tracks.len(), 50);
+        for user in 0..7 {
+            let user = User::UserId {
+                user_id: format!("user-{}", user),
+            };
+            let seqs: Vec<_> = tracks
+                .iter()
+                .filter(|track| track.user == user)
+                .map(|track| track.properties["seq"].as_u64().unwrap())
+                .collect();
+            assert!(seqs.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", seqs);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_partitioned_spool() {
+        let dir = std::env::temp_dir().join(format!("segment-spool-{}", uuid::Uuid::new_v4()));
+        let mut batcher = AutoBatcher::new(
+            MockClient::new(),
+            Batcher::new(None),
+            WriteKey::new("key").unwrap(),
+        );
+        batcher.set_spool(Spool::open(&dir).unwrap());
+        let partitioned = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            Analytics::partitioned(batcher.clone(), Duration::from_secs(3600), 2)
+        }));
+        assert!(partitioned.is_err());
+        Analytics::partitioned(batcher, Duration::from_secs(3600), 1)
+            .close()
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
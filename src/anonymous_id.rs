@@ -0,0 +1,145 @@
+//! Persisting an anonymous ID across the runs of an application.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::message::User;
+use crate::{Error, Result};
+
+/// The name of the file the anonymous ID is stored in.
+const FILE_NAME: &str = "anonymous_id";
+
+/// A file storing an anonymous ID generated on the first run of an
+/// application, and reused by the next ones.
+///
+/// CLI and desktop applications have no cookie to keep track of their users
+/// with, so the telemetry they send with a new anonymous ID on each run counts
+/// each run as a new user. Storing the ID once keeps it stable:
+///
+/// ```no_run
+/// # fn main() -> segment::Result<()> {
+/// use segment::AnonymousIdStore;
+/// use segment::message::Track;
+///
+/// let store = AnonymousIdStore::in_config_dir("my-cli")?;
+/// let track = Track {
+///     user: store.user()?,
+///     event: "Started".to_owned(),
+///     ..Default::default()
+/// };
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnonymousIdStore {
+    path: PathBuf,
+}
+
+impl AnonymousIdStore {
+    /// A store keeping the anonymous ID in the file at `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// A store keeping the anonymous ID in the `app` directory of the user's
+    /// configuration directory:
+    ///
+    /// - `$XDG_CONFIG_HOME/<app>/anonymous_id`, or
+    ///   `~/.config/<app>/anonymous_id`, on Linux and other Unixes;
+    /// - `~/Library/Application Support/<app>/anonymous_id` on macOS;
+    /// - `%APPDATA%\<app>\anonymous_id` on Windows.
+    ///
+    /// Fails if the configuration directory can't be found from the
+    /// environment.
+    pub fn in_config_dir(app: &str) -> Result<Self> {
+        let dir = config_dir().ok_or_else(|| {
+            Error::InvalidConfig("could not find the configuration directory".to_owned())
+        })?;
+        Ok(Self::new(dir.join(app).join(FILE_NAME)))
+    }
+
+    /// The file the anonymous ID is stored in.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The stored anonymous ID, or a new one which is stored for the next
+    /// runs if there is none yet.
+    ///
+    /// The directories leading to the file are created if needed, and the ID
+    /// is written to a temporary file first, so that an interrupted run does
+    /// not leave an empty ID behind.
+    pub fn load_or_create(&self) -> Result<String> {
+        match fs::read_to_string(&self.path) {
+            Ok(id) if !id.trim().is_empty() => return Ok(id.trim().to_owned()),
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        let id = uuid::Uuid::new_v4().to_string();
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = self
+            .path
+            .with_extension(format!("{}.tmp", uuid::Uuid::new_v4()));
+        fs::write(&tmp, &id)?;
+        fs::rename(&tmp, &self.path).inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })?;
+        Ok(id)
+    }
+
+    /// The [`User`] identified by the stored anonymous ID. See
+    /// [`load_or_create`](Self::load_or_create).
+    pub fn user(&self) -> Result<User> {
+        Ok(User::AnonymousId {
+            anonymous_id: self.load_or_create()?,
+        })
+    }
+}
+
+/// The user's configuration directory, following the conventions of the
+/// platform.
+pub(crate) fn config_dir() -> Option<PathBuf> {
+    let from_env = |var: &str| {
+        env::var_os(var)
+            .map(PathBuf::from)
+            .filter(|path| path.is_absolute())
+    };
+    if cfg!(windows) {
+        from_env("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        from_env("HOME").map(|home| home.join("Library").join("Application Support"))
+    } else {
+        from_env("XDG_CONFIG_HOME").or_else(|| from_env("HOME").map(|home| home.join(".config")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_or_create() {
+        let dir = std::env::temp_dir().join(format!("segment-anonymous-{}", uuid::Uuid::new_v4()));
+        let store = AnonymousIdStore::new(dir.join("app").join(FILE_NAME));
+
+        let id = store.load_or_create().unwrap();
+        assert_eq!(store.load_or_create().unwrap(), id);
+        assert_eq!(
+            store.user().unwrap(),
+            User::AnonymousId { anonymous_id: id }
+        );
+        assert_eq!(fs::read_dir(dir.join("app")).unwrap().count(), 1);
+
+        fs::write(store.path(), "  \n").unwrap();
+        let id = store.load_or_create().unwrap();
+        assert!(uuid::Uuid::parse_str(&id).is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
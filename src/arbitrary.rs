@@ -0,0 +1,327 @@
+//! [`Arbitrary`] implementations for the message types, for property-based
+//! testing with [`proptest`].
+//!
+//! This module is only available with the `testing` feature enabled.
+
+use proptest::collection::{btree_map, vec};
+use proptest::option;
+use proptest::prelude::*;
+use serde_json::{Map, Value};
+use time::OffsetDateTime;
+
+use crate::message::{
+    Alias, Batch, BatchMessage, Group, Identify, Message, Page, Properties, Screen, StoredMessage,
+    Track, Traits, User,
+};
+
+fn id() -> impl Strategy<Value = String> {
+    "[a-zA-Z0-9-]{1,24}"
+}
+
+fn name() -> impl Strategy<Value = String> {
+    "[a-zA-Z][a-zA-Z0-9 ]{0,31}"
+}
+
+/// A timestamp with a second precision between 2000 and 2100.
+fn timestamp() -> impl Strategy<Value = Option<OffsetDateTime>> {
+    option::of(
+        (946_684_800i64..4_102_444_800)
+            .prop_map(|secs| OffsetDateTime::from_unix_timestamp(secs).unwrap()),
+    )
+}
+
+/// An arbitrary JSON value. Floats are left out because they don't always
+/// survive a round trip through their textual representation.
+fn json_value() -> impl Strategy<Value = Value> {
+    let leaf = prop_oneof![
+        Just(Value::Null),
+        any::<bool>().prop_map(Value::from),
+        any::<i64>().prop_map(Value::from),
+        any::<u64>().prop_map(Value::from),
+        "[^\u{0}]{0,16}".prop_map(Value::from),
+    ];
+    leaf.prop_recursive(3, 32, 4, |inner| {
+        prop_oneof![
+            vec(inner.clone(), 0..4).prop_map(Value::Array),
+            btree_map("[a-zA-Z_]{1,8}", inner, 0..4)
+                .prop_map(|map| Value::Object(map.into_iter().collect())),
+        ]
+    })
+}
+
+/// An arbitrary JSON object, as used for properties, traits and context.
+fn map() -> impl Strategy<Value = Map<String, Value>> {
+    btree_map("[a-zA-Z_ ]{1,12}", json_value(), 0..6).prop_map(|map| map.into_iter().collect())
+}
+
+fn object() -> impl Strategy<Value = Value> {
+    map().prop_map(Value::Object)
+}
+
+fn properties() -> impl Strategy<Value = Properties> {
+    map().prop_map(Properties)
+}
+
+fn traits() -> impl Strategy<Value = Traits> {
+    map().prop_map(Traits)
+}
+
+/// Extra top-level fields. Their keys are prefixed so they never collide with
+/// the fields of the message they are flattened into.
+fn extra() -> impl Strategy<Value = Map<String, Value>> {
+    btree_map("x_[a-z]{1,8}", json_value(), 0..2).prop_map(|map| map.into_iter().collect())
+}
+
+impl Arbitrary for User {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        prop_oneof![
+            id().prop_map(|user_id| User::UserId { user_id }),
+            id().prop_map(|anonymous_id| User::AnonymousId { anonymous_id }),
+            (id(), id()).prop_map(|(user_id, anonymous_id)| User::Both {
+                user_id,
+                anonymous_id
+            }),
+        ]
+        .boxed()
+    }
+}
+
+impl Arbitrary for Identify {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (
+            any::<User>(),
+            traits(),
+            timestamp(),
+            option::of(object()),
+            option::of(object()),
+            extra(),
+        )
+            .prop_map(
+                |(user, traits, timestamp, context, integrations, extra)| Identify {
+                    user,
+                    traits,
+                    timestamp,
+                    context,
+                    integrations,
+                    extra,
+                },
+            )
+            .boxed()
+    }
+}
+
+impl Arbitrary for Track {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (
+            any::<User>(),
+            name(),
+            properties(),
+            timestamp(),
+            option::of(object()),
+            option::of(object()),
+            extra(),
+        )
+            .prop_map(
+                |(user, event, properties, timestamp, context, integrations, extra)| Track {
+                    user,
+                    event,
+                    properties,
+                    timestamp,
+                    context,
+                    integrations,
+                    extra,
+                },
+            )
+            .boxed()
+    }
+}
+
+impl Arbitrary for Page {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (
+            any::<User>(),
+            option::of(name()),
+            properties(),
+            timestamp(),
+            option::of(object()),
+            option::of(object()),
+            extra(),
+        )
+            .prop_map(
+                |(user, name, properties, timestamp, context, integrations, extra)| Page {
+                    user,
+                    name,
+                    properties,
+                    timestamp,
+                    context,
+                    integrations,
+                    extra,
+                },
+            )
+            .boxed()
+    }
+}
+
+impl Arbitrary for Screen {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (
+            any::<User>(),
+            name(),
+            properties(),
+            timestamp(),
+            option::of(object()),
+            option::of(object()),
+            extra(),
+        )
+            .prop_map(
+                |(user, name, properties, timestamp, context, integrations, extra)| Screen {
+                    user,
+                    name,
+                    properties,
+                    timestamp,
+                    context,
+                    integrations,
+                    extra,
+                },
+            )
+            .boxed()
+    }
+}
+
+impl Arbitrary for Group {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (
+            any::<User>(),
+            id(),
+            traits(),
+            timestamp(),
+            option::of(object()),
+            option::of(object()),
+            extra(),
+        )
+            .prop_map(
+                |(user, group_id, traits, timestamp, context, integrations, extra)| Group {
+                    user,
+                    group_id,
+                    traits,
+                    timestamp,
+                    context,
+                    integrations,
+                    extra,
+                },
+            )
+            .boxed()
+    }
+}
+
+impl Arbitrary for Alias {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (
+            any::<User>(),
+            id(),
+            timestamp(),
+            option::of(object()),
+            option::of(object()),
+            extra(),
+        )
+            .prop_map(
+                |(user, previous_id, timestamp, context, integrations, extra)| Alias {
+                    user,
+                    previous_id,
+                    timestamp,
+                    context,
+                    integrations,
+                    extra,
+                },
+            )
+            .boxed()
+    }
+}
+
+impl Arbitrary for BatchMessage {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        prop_oneof![
+            any::<Identify>().prop_map(BatchMessage::Identify),
+            any::<Track>().prop_map(BatchMessage::Track),
+            any::<Page>().prop_map(BatchMessage::Page),
+            any::<Screen>().prop_map(BatchMessage::Screen),
+            any::<Group>().prop_map(BatchMessage::Group),
+            any::<Alias>().prop_map(BatchMessage::Alias),
+        ]
+        .boxed()
+    }
+}
+
+impl Arbitrary for Batch {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (
+            vec(any::<BatchMessage>(), 0..8),
+            option::of(object()),
+            option::of(object()),
+            timestamp(),
+            extra(),
+        )
+            .prop_map(|(batch, context, integrations, sent_at, extra)| Batch {
+                batch,
+                context,
+                integrations,
+                sent_at,
+                extra,
+            })
+            .boxed()
+    }
+}
+
+impl Arbitrary for Message {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        any::<StoredMessage>().prop_map(Message::from).boxed()
+    }
+}
+
+impl Arbitrary for StoredMessage {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        prop_oneof![
+            any::<Identify>().prop_map(StoredMessage::Identify),
+            any::<Track>().prop_map(StoredMessage::Track),
+            any::<Page>().prop_map(StoredMessage::Page),
+            any::<Screen>().prop_map(StoredMessage::Screen),
+            any::<Group>().prop_map(StoredMessage::Group),
+            any::<Alias>().prop_map(StoredMessage::Alias),
+            any::<Batch>().prop_map(StoredMessage::Batch),
+        ]
+        .boxed()
+    }
+}
@@ -0,0 +1,190 @@
+//! A record of the messages sent to Segment, for compliance.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::message::{BatchMessage, User};
+use crate::{Error, Result};
+
+/// What happened to a message sent to Segment, without its payload.
+///
+/// Records are appended to an [`AuditLog`] once Segment accepted the batch the
+/// message was in, or once sending it failed, retries included.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditRecord {
+    /// The `messageId` of the message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    /// The type of the message, such as `track` or `identify`.
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    /// The user ID of the message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    /// The anonymous ID of the message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub anonymous_id: Option<String>,
+    /// The event name of a `track` event, or the name of a `page` or `screen`
+    /// event.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<String>,
+    /// The timestamp of the message.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        with = "time::serde::rfc3339::option"
+    )]
+    pub timestamp: Option<OffsetDateTime>,
+    /// When the batch was sent, or gave up on.
+    #[serde(with = "time::serde::rfc3339")]
+    pub flushed_at: OffsetDateTime,
+    /// Whether Segment accepted the message.
+    pub outcome: AuditOutcome,
+    /// Why sending the message failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Whether Segment accepted a message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    /// Segment accepted the batch the message was in.
+    Sent,
+    /// Sending the batch the message was in failed. It may be sent again
+    /// later if the error was transient and the batch was held or spooled.
+    Failed,
+}
+
+impl AuditRecord {
+    pub(crate) fn new(msg: &BatchMessage, flushed_at: OffsetDateTime, result: &Result<()>) -> Self {
+        let (user_id, anonymous_id) = match msg.user() {
+            User::UserId { user_id } => (Some(user_id.clone()), None),
+            User::AnonymousId { anonymous_id } => (None, Some(anonymous_id.clone())),
+            User::Both {
+                user_id,
+                anonymous_id,
+            } => (Some(user_id.clone()), Some(anonymous_id.clone())),
+        };
+        Self {
+            message_id: msg
+                .extra()
+                .get("messageId")
+                .and_then(|id| id.as_str())
+                .map(str::to_owned),
+            kind: msg.message_type(),
+            user_id,
+            anonymous_id,
+            event: msg.event_name().map(str::to_owned),
+            timestamp: msg.timestamp(),
+            flushed_at,
+            outcome: match result {
+                Ok(()) => AuditOutcome::Sent,
+                Err(_) => AuditOutcome::Failed,
+            },
+            error: result.as_ref().err().map(Error::to_string),
+        }
+    }
+}
+
+/// An append-only destination for [`AuditRecord`]s, set on an
+/// [`AutoBatcher`](crate::AutoBatcher) with
+/// [`set_audit_log`](crate::AutoBatcher::set_audit_log).
+///
+/// Failing to append records is logged, but does not fail the flush.
+pub trait AuditLog: Send + Sync {
+    /// Append the records of the messages of a batch.
+    fn append(&self, records: &[AuditRecord]) -> Result<()>;
+}
+
+/// An [`AuditLog`] writing records to a file as JSON lines.
+///
+/// The file is opened in append mode, so records written by previous
+/// processes are kept.
+#[derive(Debug)]
+pub struct AuditFile {
+    file: Mutex<File>,
+}
+
+impl AuditFile {
+    /// Open the file at `path` for appending, creating it if needed.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl AuditLog for AuditFile {
+    fn append(&self, records: &[AuditRecord]) -> Result<()> {
+        let mut lines = Vec::new();
+        for record in records {
+            serde_json::to_writer(&mut lines, record)?;
+            lines.push(b'\n');
+        }
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&lines)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/// The audit log of an [`AutoBatcher`](crate::AutoBatcher).
+#[derive(Clone)]
+pub(crate) struct Audit(pub(crate) Arc<dyn AuditLog>);
+
+impl fmt::Debug for Audit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Audit")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{track, Fault, FaultyClient, MockClient};
+    use crate::{AutoBatcher, Batcher, WriteKey};
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn test_audit_file() {
+        let path =
+            std::env::temp_dir().join(format!("segment-audit-{}.jsonl", uuid::Uuid::new_v4()));
+        let client = FaultyClient::new(MockClient::new(), vec![Fault::Status(400)]);
+        let mut batcher =
+            AutoBatcher::new(client, Batcher::new(None), WriteKey::new("key").unwrap());
+        batcher.set_audit_log(AuditFile::open(&path).unwrap());
+        batcher.push(track("foo", "Bought")).await.unwrap();
+        assert!(batcher.flush().await.is_err());
+        batcher.push(track("bar", "Bought")).await.unwrap();
+        batcher.flush().await.unwrap();
+
+        let records: Vec<Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["userId"], "foo");
+        assert_eq!(records[0]["outcome"], "failed");
+        assert_eq!(
+            records[0]["error"],
+            "server rejected the request with status 400: "
+        );
+        assert_eq!(records[1]["userId"], "bar");
+        assert_eq!(records[1]["type"], "track");
+        assert_eq!(records[1]["event"], "Bought");
+        assert_eq!(records[1]["outcome"], "sent");
+        assert!(records[1]["messageId"].is_string());
+        assert!(records[1].get("properties").is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
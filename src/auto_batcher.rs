@@ -1,35 +1,59 @@
 //! Utilities for batching up messages.
 //! When a batch is full it is automatically sent over the network
 
-use serde_json::Map;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+
+use time::OffsetDateTime;
 
 use crate::{
+    audit::{Audit, AuditLog, AuditRecord},
     batcher::Batcher,
+    catch_up::CatchUp,
     client::Client,
-    errors::Result,
+    config::Config,
+    dead_letter::{DeadLetter, DeadLetterSink},
+    enrich::{Enricher, Enrichment},
+    error_policy::{ErrorAction, ErrorPolicy},
+    errors::{Error, Result},
     http::HttpClient,
-    message::{Batch, BatchMessage, Message},
+    memory::HeapSize,
+    message::{self, Batch, BatchMessage, Message, StoredMessage, Traits},
+    opt_out,
+    pipeline::{self, SharedStage, Stage},
+    provider::{self, PropertyProvider, ProvidedProperties},
+    recorder::Recorder,
+    retry::RetryPolicy,
+    retry_budget::{AlertHook, RetryBudget, RetryBudgetAlert, RetryBudgetMonitor},
+    runtime,
+    spool::{Backoff, Spool},
+    window::FlushWindow,
+    write_key::WriteKey,
 };
 
+/// The number of batches held in memory by default, past which the oldest are
+/// dropped. See [`AutoBatcher::set_max_held_batches`].
+const DEFAULT_MAX_HELD: usize = 100;
+
 /// A batcher can accept messages into an internal buffer, and report when
 /// messages must be flushed.
 ///
 /// The recommended usage pattern looks something like this:
 ///
 /// ```
-/// use segment::{AutoBatcher, Batcher, HttpClient};
-/// use segment::message::{BatchMessage, Track, User};
-/// use serde_json::json;
+/// use segment::{AutoBatcher, Batcher, HttpClient, WriteKey};
+/// use segment::message::{BatchMessage, Properties, Track, User};
 ///
 /// let client = HttpClient::default();
 /// let batcher= Batcher::new(None);
-/// let mut batcher = AutoBatcher::new(client, batcher, "your_write_key".to_string());
+/// let mut batcher = AutoBatcher::new(client, batcher, WriteKey::new("your_write_key").unwrap());
 ///
 /// for i in 0..100 {
 ///     let msg = Track {
 ///         user: User::UserId { user_id: format!("user-{}", i) },
 ///         event: "Example".to_owned(),
-///         properties: json!({ "foo": "bar" }),
+///         properties: Properties::new().with("foo", "bar"),
 ///         ..Default::default()
 ///     };
 ///
@@ -44,83 +68,536 @@ use crate::{
 ///
 /// If this delay is a concern, it is recommended that you periodically flush
 /// the batcher on your own by calling [Self::flush].
+///
+/// Any [`Client`] can be used as the transport; it defaults to [`HttpClient`].
+///
+/// Failed batches are not retried, unless a [`RetryPolicy`] is set with
+/// [`set_retry_policy`](AutoBatcher::set_retry_policy).
+///
+/// Sending can be suspended with [`pause`](AutoBatcher::pause): full batches
+/// are then held in memory until [`resume`](AutoBatcher::resume) is called,
+/// and sent in order on the next flush, up to a [maximum
+/// number](AutoBatcher::set_max_held_batches). After a long pause, the
+/// backlog can be sent at a [catch-up rate](AutoBatcher::set_catch_up_rate)
+/// rather than all at once.
+///
+/// With a [`Spool`], set with [`set_spool`](AutoBatcher::set_spool), batches
+/// are written to disk until Segment accepts them, instead of being held in
+/// memory or dropped when they fail.
+///
+/// Batches which fail for good, including the ones past the
+/// [deadline](RetryPolicy::deadline) of the retry policy, can be kept by a
+/// [`DeadLetter`] set with [`set_dead_letter`](AutoBatcher::set_dead_letter).
+///
+/// What happened to every message sent can be recorded in an [`AuditLog`]
+/// set with [`set_audit_log`](AutoBatcher::set_audit_log).
+///
+/// What to do with a batch depending on why sending it failed, e.g. splitting
+/// it or halting, can be set with an [`ErrorPolicy`] with
+/// [`set_error_policy`](AutoBatcher::set_error_policy).
+///
+/// Messages can be given extra data asynchronously by [`Enricher`]s added
+/// with [`add_enricher`](AutoBatcher::add_enricher) before they are batched,
+/// and go through the [`Stage`]s added with
+/// [`add_stage`](AutoBatcher::add_stage), or composed with a
+/// [`Pipeline`](crate::Pipeline). Properties current when a batch is sent,
+/// such as the feature flags enabled, can be added to its messages by
+/// [`PropertyProvider`]s added with
+/// [`add_property_provider`](AutoBatcher::add_property_provider).
 #[derive(Clone, Debug)]
-pub struct AutoBatcher {
-    client: HttpClient,
-    batcher: Batcher,
-    key: String,
+pub struct AutoBatcher<C = HttpClient> {
+    client: C,
+    pub(crate) batcher: Batcher,
+    key: WriteKey,
+    retry: RetryPolicy,
+    retry_budget: Option<RetryBudgetMonitor>,
+    budget_alert: Option<AlertHook>,
+    error_policy: ErrorPolicy,
+    paused: bool,
+    catch_up: Option<CatchUp>,
+    windows: Vec<FlushWindow>,
+    held: VecDeque<Batch>,
+    max_held: usize,
+    pub(crate) spool: Option<Spool>,
+    audit: Option<Audit>,
+    pub(crate) recorder: Option<Recorder>,
+    dead_letter: Option<DeadLetterSink>,
+    pub(crate) stages: Vec<SharedStage>,
+    pub(crate) providers: Vec<ProvidedProperties>,
+}
+
+#[cfg(any(feature = "tokio", feature = "smol"))]
+impl<C: Client + Clone> AutoBatcher<C> {
+    /// A clone of this batcher sending its batches with `key`.
+    pub(crate) fn with_write_key(&self, key: WriteKey) -> Self {
+        Self {
+            key,
+            ..self.clone()
+        }
+    }
 }
 
-impl AutoBatcher {
+impl<C: Client> AutoBatcher<C> {
     /// Construct a new, empty batcher.
     ///
     /// ```
-    /// use segment::{AutoBatcher, Batcher, HttpClient};
+    /// use segment::{AutoBatcher, Batcher, HttpClient, WriteKey};
     ///
     /// let client = HttpClient::default();
     /// let batcher = Batcher::new(None);
-    /// let mut batcher = AutoBatcher::new(client, batcher, "your_write_key".to_string());
+    /// let key = WriteKey::new("your_write_key").unwrap();
+    /// let mut batcher = AutoBatcher::new(client, batcher, key);
     /// ```
-    pub fn new(client: HttpClient, batcher: Batcher, key: String) -> Self {
+    pub fn new(client: C, batcher: Batcher, key: WriteKey) -> Self {
         Self {
             batcher,
             client,
             key,
+            retry: RetryPolicy::none(),
+            retry_budget: None,
+            budget_alert: None,
+            error_policy: ErrorPolicy::new(),
+            paused: false,
+            catch_up: None,
+            windows: Vec::new(),
+            held: VecDeque::new(),
+            max_held: DEFAULT_MAX_HELD,
+            spool: None,
+            audit: None,
+            recorder: None,
+            dead_letter: None,
+            stages: Vec::new(),
+            providers: Vec::new(),
         }
     }
 
+    /// Run `enricher` on every message pushed from now on, after the
+    /// enrichers added before, giving up on a message after `timeout`.
+    ///
+    /// The enrichers run within [`push`](AutoBatcher::push), so a slow one
+    /// delays the messages pushed after it, by at most `timeout` each. A
+    /// message whose enrichment fails or times out is sent anyway; the error
+    /// is logged.
+    pub fn add_enricher(&mut self, enricher: impl Enricher + 'static, timeout: Duration) {
+        self.stages.push(SharedStage::new(Enrichment {
+            enricher: Arc::new(enricher),
+            timeout,
+        }));
+    }
+
+    /// Run `stage` on every message pushed from now on, after the stages and
+    /// enrichers added before. See [`Stage`].
+    ///
+    /// Returns [`Error::InvalidConfig`] if `stage` is a filter, sampling,
+    /// pruning rules, context privacy or a large integer policy, and the
+    /// batcher has that feature set already.
+    pub fn add_stage(&mut self, stage: impl Stage + 'static) -> Result<()> {
+        let stage = SharedStage::new(stage);
+        pipeline::check_stages(std::slice::from_ref(&stage), &self.batcher)?;
+        self.stages.push(stage);
+        Ok(())
+    }
+
+    /// Add the properties of `provider` to the `track`, `page` and `screen`
+    /// messages of every batch flushed from now on, giving up on it after
+    /// `timeout`.
+    ///
+    /// The provider is queried once per flush, so a slow one delays the
+    /// flushes, by at most `timeout` each. A batch is sent without the
+    /// properties of a provider which fails or times out; the error is
+    /// logged. The properties already set on a message, or by the providers
+    /// added before, take precedence. They are not counted in the size of
+    /// the batch, so they should be small. A batch which can't be sent right
+    /// away keeps the properties it was flushed with.
+    pub fn add_property_provider(
+        &mut self,
+        provider: impl PropertyProvider + 'static,
+        timeout: Duration,
+    ) {
+        self.providers.push(ProvidedProperties {
+            provider: Arc::new(provider),
+            timeout,
+        });
+    }
+
+    /// Write batches to `spool` until Segment accepts them.
+    ///
+    /// Batches failing transiently stay in the spool, and are retried on a
+    /// later flush once their backoff, derived from the retry policy, has
+    /// elapsed. Batches failing permanently are removed from it.
+    pub fn set_spool(&mut self, spool: Spool) {
+        self.spool = Some(spool);
+    }
+
+    /// Append a record of every message sent, and of whether Segment accepted
+    /// it, to `log`.
+    pub fn set_audit_log(&mut self, log: impl AuditLog + 'static) {
+        self.audit = Some(Audit(Arc::new(log)));
+    }
+
+    /// Keep the last messages sent, with their outcomes, in `recorder`.
+    pub fn set_recorder(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    /// Hand the batches which can't be delivered to `dead_letter`, instead of
+    /// dropping them.
+    pub fn set_dead_letter(&mut self, dead_letter: impl DeadLetter + 'static) {
+        self.dead_letter = Some(DeadLetterSink(Arc::new(dead_letter)));
+    }
+
+    /// Retry sending batches according to the given policy.
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
     /// Push a message into the batcher.
     /// If the batcher is full, send it and create a new batcher with the message.
     ///
     /// Returns an error if the message is too large to be sent to Segment's
-    /// API.
+    /// API. The message is dropped if the process [opted
+    /// out](crate::set_opted_out).
     ///
     /// ```
-    /// use serde_json::json;
-    /// use segment::{AutoBatcher, Batcher, HttpClient};
-    /// use segment::message::{BatchMessage, Track, User};
+    /// use segment::{AutoBatcher, Batcher, HttpClient, WriteKey};
+    /// use segment::message::{BatchMessage, Properties, Track, User};
     ///
     /// let client = HttpClient::default();
     /// let batcher = Batcher::new(None);
-    /// let mut batcher = AutoBatcher::new(client, batcher, "your_write_key".to_string());
+    /// let key = WriteKey::new("your_write_key").unwrap();
+    /// let mut batcher = AutoBatcher::new(client, batcher, key);
     ///
     /// let msg = BatchMessage::Track(Track {
     ///     user: User::UserId { user_id: String::from("user") },
     ///     event: "Example".to_owned(),
-    ///     properties: json!({ "foo": "bar" }),
+    ///     properties: Properties::new().with("foo", "bar"),
     ///     ..Default::default()
     /// });
     ///
     /// batcher.push(msg); // .await
     /// ```
     pub async fn push(&mut self, msg: impl Into<BatchMessage>) -> Result<()> {
+        if opt_out::is_opted_out() {
+            return Ok(());
+        }
+        let msg = match pipeline::run(&self.stages, msg.into()).await? {
+            Some(msg) => msg,
+            None => return Ok(()),
+        };
         if let Some(msg) = self.batcher.push(msg)? {
-            self.flush().await?;
-            // this can't return None: the batcher is empty and if the message is
-            // larger than the max size of the batcher it's supposed to throw an error
+            let flushed = self.flush().await;
+            // this can't return None: the batcher is empty (even if sending the
+            // previous batch failed) and if the message is larger than the max
+            // size of the batcher it's supposed to throw an error
             self.batcher.push(msg)?;
+            flushed?;
+        }
+
+        Ok(())
+    }
+
+    /// Push messages which must be sent in the same batch, in order. If they
+    /// don't fit in the current batch, it is sent first.
+    ///
+    /// See [`Batcher::push_all`].
+    pub async fn push_all(
+        &mut self,
+        msgs: impl IntoIterator<Item = impl Into<BatchMessage>>,
+    ) -> Result<()> {
+        if opt_out::is_opted_out() {
+            return Ok(());
+        }
+        let mut kept = Vec::new();
+        for msg in msgs {
+            kept.extend(pipeline::run(&self.stages, msg.into()).await?);
+        }
+        let msgs = kept;
+        if let Some(msgs) = self.batcher.push_all(msgs)? {
+            let flushed = self.flush().await;
+            // An empty batcher accepts any messages push_all accepted before.
+            self.batcher.push_all(msgs)?;
+            flushed?;
+        }
+        Ok(())
+    }
+
+    /// Write `msgs` to the spool without sending them, e.g. messages not due
+    /// yet when closing, so they are sent with the spooled batches later.
+    /// Returns `false` if there is no spool.
+    ///
+    /// The batches queued so far must have been flushed first.
+    pub(crate) fn spool_unsent(&mut self, msgs: Vec<BatchMessage>) -> Result<bool> {
+        let spool = match &self.spool {
+            Some(spool) => spool.clone(),
+            None => return Ok(false),
+        };
+        for msg in msgs {
+            if let Some(msg) = self.batcher.push(msg)? {
+                spool.push(&self.batcher.take())?;
+                self.batcher.push(msg)?;
+            }
+        }
+        if !self.batcher.is_empty() {
+            spool.push(&self.batcher.take())?;
+        }
+        Ok(true)
+    }
+
+    /// Queue messages taken from the [snapshot](Batcher::snapshot) of another
+    /// batcher, sending batches as they fill up. See [`Batcher::restore`].
+    pub async fn restore(&mut self, msgs: Vec<StoredMessage>) -> Result<()> {
+        let mut rest = self.batcher.restore(msgs)?;
+        while !rest.is_empty() {
+            let flushed = self.flush().await;
+            rest = self
+                .batcher
+                .restore(rest.into_iter().map(StoredMessage::from).collect())?;
+            flushed?;
+        }
+        Ok(())
+    }
+
+    /// A copy of the queued messages. See [`Batcher::snapshot`].
+    pub fn snapshot(&self) -> Vec<StoredMessage> {
+        self.batcher.snapshot()
+    }
+
+    /// Link the anonymous ID a user had to their new user ID, pushing an
+    /// `alias` and an `identify` in the same batch. See
+    /// [`alias_and_identify`](crate::message::alias_and_identify).
+    pub async fn alias_and_identify(
+        &mut self,
+        anonymous_id: impl Into<String>,
+        user_id: impl Into<String>,
+        traits: Traits,
+    ) -> Result<()> {
+        self.push_all(message::alias_and_identify(anonymous_id, user_id, traits))
+            .await
+    }
+
+    /// Alert when batches are retried more than `budget` allows within its
+    /// window, once per window. The retries are counted across the clones of
+    /// this batcher, e.g. the partitions of an [`Analytics`](crate::Analytics)
+    /// pipeline, and in its [`metrics`](crate::Batcher::metrics).
+    ///
+    /// The alert is logged, unless a hook was set with
+    /// [`on_retry_budget_exceeded`](AutoBatcher::on_retry_budget_exceeded).
+    pub fn set_retry_budget(&mut self, budget: RetryBudget) {
+        self.retry_budget = Some(RetryBudgetMonitor::new(budget));
+    }
+
+    /// Call `hook` instead of logging when the [retry
+    /// budget](AutoBatcher::set_retry_budget) is exceeded, e.g. to page
+    /// someone before the queue overflows.
+    pub fn on_retry_budget_exceeded(
+        &mut self,
+        hook: impl Fn(&RetryBudgetAlert) + Send + Sync + 'static,
+    ) {
+        self.budget_alert = Some(AlertHook(Arc::new(hook)));
+    }
+
+    /// Count a retry after `backoff` in the metrics and the retry budget.
+    fn record_retry(&self, backoff: Duration) {
+        self.batcher.metrics.record_retry(backoff);
+        if let Some(monitor) = &self.retry_budget {
+            monitor.record(
+                self.batcher.clock.now(),
+                backoff,
+                self.budget_alert.as_ref(),
+            );
         }
+    }
+
+    /// Handle the batches which fail according to `policy`, instead of
+    /// retrying the transient errors and dead-lettering the batches.
+    pub fn set_error_policy(&mut self, policy: ErrorPolicy) {
+        self.error_policy = policy;
+    }
 
+    /// Apply the write key, retry policy and budget, catch-up rate, error
+    /// policy, batch settings and transport settings of `config`.
+    ///
+    /// The write key is kept if `config` doesn't have one. Messages already
+    /// queued are sent with the new settings.
+    ///
+    /// Returns [`Error::InvalidConfig`], leaving the batcher as it was, if
+    /// `config` sets a feature which is one of the [stages](Stage) too.
+    pub fn reconfigure(&mut self, config: &Config) -> Result<()> {
+        let mut configured = Batcher::new(None);
+        configured.apply_config(config);
+        pipeline::check_stages(&self.stages, &configured)?;
+        self.client.reconfigure(config)?;
+        self.batcher.apply_config(config);
+        if let Some(key) = &config.write_key {
+            self.key = key.clone();
+        }
+        self.retry = config.retry;
+        match (config.retry_budget, &mut self.retry_budget) {
+            (Some(budget), Some(monitor)) => monitor.budget = budget,
+            (Some(budget), None) => self.set_retry_budget(budget),
+            (None, _) => self.retry_budget = None,
+        }
+        match (config.catch_up_rate, &mut self.catch_up) {
+            (Some(rate), Some(catch_up)) => catch_up.rate = rate,
+            (Some(rate), None) => self.set_catch_up_rate(rate),
+            (None, _) => self.catch_up = None,
+        }
+        self.error_policy = config.error_policy.clone();
+        self.windows = config.flush_windows.clone();
         Ok(())
     }
 
+    /// Stop sending batches until [`resume`](AutoBatcher::resume) is called.
+    ///
+    /// Messages are still accepted while paused, and the batches they fill
+    /// are held in memory.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Send batches again. The batches held while paused are sent on the next
+    /// flush.
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    /// Send the batches held while paused or outside of the [flush
+    /// windows](AutoBatcher::set_flush_windows), and the spooled ones, at
+    /// most `messages_per_second` messages per second on average, so a device
+    /// coming back online after a long time doesn't send its whole backlog at
+    /// once.
+    ///
+    /// The backlog is still sent oldest first, with the timestamps its
+    /// messages were given when pushed, over as many flushes as needed. New
+    /// batches are queued behind it. Batches still held when the batcher is
+    /// dropped are lost, as when paused, unless they are spooled.
+    pub fn set_catch_up_rate(&mut self, messages_per_second: u32) {
+        self.catch_up = Some(CatchUp::new(messages_per_second));
+    }
+
+    /// Whether the [catch-up rate](AutoBatcher::set_catch_up_rate) lets a
+    /// batch of the backlog be sent now.
+    fn may_catch_up(&mut self, batch: &Batch) -> bool {
+        let now = self.batcher.clock.now();
+        match &mut self.catch_up {
+            Some(catch_up) => catch_up.allows(batch.batch.len(), now),
+            None => true,
+        }
+    }
+
+    /// Whether sending is [paused](AutoBatcher::pause).
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Only send batches during the given daily windows, e.g. for edge
+    /// deployments which may only use the network during maintenance
+    /// windows. Batches filled outside of them are held, or spooled if the
+    /// batcher has a [`Spool`], until a flush happens within a window.
+    ///
+    /// An empty list of windows, the default, lets batches be sent at any
+    /// time.
+    ///
+    /// There are no cron-like schedules, but sending at set times of the day
+    /// amounts to short windows starting at those times, e.g. `02:00-02:05`,
+    /// with the flush interval shorter than the windows so a flush happens
+    /// within each of them.
+    ///
+    /// Without a spool, the oldest batches are dropped if more than the
+    /// [maximum number of held batches](AutoBatcher::set_max_held_batches)
+    /// fill up between two windows.
+    pub fn set_flush_windows(&mut self, windows: Vec<FlushWindow>) {
+        self.windows = windows;
+    }
+
+    /// Hold at most `max` batches in memory while paused or outside of the
+    /// [flush windows](AutoBatcher::set_flush_windows), 100 by default.
+    ///
+    /// Past it, the oldest held batch is given to the [`DeadLetter`], if any,
+    /// with [`Error::QueueFull`], and its messages are counted in
+    /// [`Metrics::dropped_held`](crate::Metrics::dropped_held). Spooled
+    /// batches are not held, so they are not limited.
+    pub fn set_max_held_batches(&mut self, max: usize) {
+        self.max_held = max;
+    }
+
+    /// Hold `batch` to send it later, dropping the oldest held batches if too
+    /// many are.
+    fn hold(&mut self, batch: Batch) {
+        self.held.push_back(batch);
+        while self.held.len() > self.max_held {
+            let oldest = match self.held.pop_front() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            log::warn!(
+                "dropping a held batch of {} messages, more than {} batches are held",
+                oldest.batch.len(),
+                self.max_held
+            );
+            self.batcher.metrics.record_dropped_held(oldest.batch.len());
+            if let Some(dead_letter) = &self.dead_letter {
+                dead_letter.send(&oldest, &Error::QueueFull);
+            }
+        }
+    }
+
+    /// Whether batches can't be sent right now, because sending is paused or
+    /// the current time is outside of the flush windows.
+    fn is_holding(&self) -> bool {
+        let now = self.batcher.clock.now();
+        self.paused
+            || !(self.windows.is_empty() || self.windows.iter().any(|window| window.contains(now)))
+    }
+
+    /// The number of messages waiting to be sent, including the ones held
+    /// while paused and the spooled ones.
+    pub fn len(&self) -> usize {
+        self.batcher.len()
+            + self
+                .held
+                .iter()
+                .map(|batch| batch.batch.len())
+                .sum::<usize>()
+            + self.spool.as_ref().map_or(0, Spool::len)
+    }
+
+    /// An estimate of the memory used by the messages waiting to be sent, in
+    /// bytes, including the ones held while paused but not the spooled ones.
+    /// See [`Batcher::approx_memory_bytes`].
+    pub fn approx_memory_bytes(&self) -> usize {
+        self.batcher.approx_memory_bytes()
+            + self
+                .held
+                .iter()
+                .map(|batch| batch.batch.heap_size())
+                .sum::<usize>()
+    }
+
+    /// Whether no message is waiting to be sent.
+    pub fn is_empty(&self) -> bool {
+        self.batcher.is_empty()
+            && self.held.is_empty()
+            && self.spool.as_ref().is_none_or(Spool::is_empty)
+    }
+
     /// Send all the message currently contained in the batcher, full or empty.
     ///
     /// Returns an error if the message is too large to be sent to Segment's
     /// API.
     /// ```
-    /// use serde_json::json;
-    /// use segment::{AutoBatcher, Batcher, HttpClient};
-    /// use segment::message::{BatchMessage, Track, User};
+    /// use segment::{AutoBatcher, Batcher, HttpClient, WriteKey};
+    /// use segment::message::{BatchMessage, Properties, Track, User};
     ///
     /// let client = HttpClient::default();
     /// let batcher = Batcher::new(None);
-    /// let mut batcher = AutoBatcher::new(client, batcher, "your_write_key".to_string());
+    /// let key = WriteKey::new("your_write_key").unwrap();
+    /// let mut batcher = AutoBatcher::new(client, batcher, key);
     ///
     /// let msg = BatchMessage::Track(Track {
     ///     user: User::UserId { user_id: String::from("user") },
     ///     event: "Example".to_owned(),
-    ///     properties: json!({ "foo": "bar" }),
+    ///     properties: Properties::new().with("foo", "bar"),
     ///     ..Default::default()
     /// });
     ///
@@ -128,14 +605,977 @@ impl AutoBatcher {
     /// batcher.flush(); // .await
     /// ```
     pub async fn flush(&mut self) -> Result<()> {
-        let message = Message::Batch(Batch {
-            batch: std::mem::take(&mut self.batcher.buf),
-            context: self.batcher.context.clone(),
-            integrations: None,
-            extra: Map::default(),
-        });
+        for (msg, e) in self.batcher.take_invalid() {
+            self.reject(msg, &e);
+        }
+        let mut batch = self.batcher.take();
+        if !batch.batch.is_empty() {
+            for provider in &self.providers {
+                if let Some(properties) = provider.fetch().await {
+                    provider::inject(&mut batch, &properties);
+                }
+            }
+        }
+
+        if let Some(spool) = self.spool.clone() {
+            if !batch.batch.is_empty() {
+                if let Err(e) = spool.push(&batch) {
+                    // Hold the batch rather than losing it, it is sent with
+                    // the held ones on the next flush.
+                    self.hold(batch);
+                    return Err(e);
+                }
+            }
+            if self.is_holding() {
+                return Ok(());
+            }
+            // Batches may be held after a split, if the error policy halted
+            // the batcher while sending a half.
+            self.deliver_held().await?;
+            return self.drain(&spool).await;
+        }
+
+        if self.is_holding() {
+            if !batch.batch.is_empty() {
+                self.hold(batch);
+            }
+            return Ok(());
+        }
+        if self.held.is_empty() {
+            return self.deliver(batch).await;
+        }
+
+        if !batch.batch.is_empty() {
+            self.hold(batch);
+        }
+        self.deliver_held().await
+    }
+
+    /// Send the held batches in order, stopping at the first one which fails.
+    async fn deliver_held(&mut self) -> Result<()> {
+        while let Some(batch) = self.held.pop_front() {
+            if !self.may_catch_up(&batch) {
+                self.held.push_front(batch);
+                break;
+            }
+            self.deliver(batch).await?;
+        }
+        Ok(())
+    }
+
+    /// Send `batch`, handling its failure according to the error policy.
+    ///
+    /// Returns the first error, once the halves of a split batch were all
+    /// attempted.
+    async fn deliver(&mut self, batch: Batch) -> Result<()> {
+        let mut pending = VecDeque::from([batch]);
+        let mut outcome = Ok(());
+        while let Some(batch) = pending.pop_front() {
+            let error = match self.send(&batch, self.batcher.clock.now()).await {
+                Ok(()) => continue,
+                Err(e) => e,
+            };
+            match self.error_policy.action(&error) {
+                Some(ErrorAction::Split) if batch.batch.len() > 1 => {
+                    let (first, second) = split(batch);
+                    pending.push_front(second);
+                    pending.push_front(first);
+                    continue;
+                }
+                Some(ErrorAction::Halt) => {
+                    self.halt(&error);
+                    // Send the batch and the halves not attempted yet first,
+                    // in order, once resumed.
+                    for batch in pending.into_iter().rev() {
+                        self.held.push_front(batch);
+                    }
+                    self.held.push_front(batch);
+                    return Err(error);
+                }
+                _ => self.give_up(&batch, &error),
+            }
+            if outcome.is_ok() {
+                outcome = Err(error);
+            }
+        }
+        outcome
+    }
 
-        self.client.send(self.key.to_string(), message).await?;
+    /// Send the spooled batches in order, stopping at the first one which
+    /// fails or whose backoff has not elapsed.
+    async fn drain(&mut self, spool: &Spool) -> Result<()> {
+        while let Some((seq, batch)) = spool.head()? {
+            let backoff = spool.backoff()?;
+            let now = self.batcher.clock.now();
+            if backoff.is_some_and(|backoff| now < backoff.retry_at) || !self.may_catch_up(&batch) {
+                return Ok(());
+            }
+            let since = backoff.and_then(|backoff| backoff.since).unwrap_or(now);
+            match self.send(&batch, since).await {
+                Ok(()) => spool.ack(seq, &batch, self.batcher.clock.now())?,
+                Err(e) if self.error_policy.is_retryable(&e) => {
+                    let now = self.batcher.clock.now();
+                    let failures = backoff.map_or(0, |backoff| backoff.failures) + 1;
+                    let delay = self.retry_delay(failures - 1, &e);
+                    if self
+                        .retry
+                        .within_deadline((now - since).unsigned_abs(), delay)
+                    {
+                        spool.set_backoff(&Backoff {
+                            seq,
+                            failures,
+                            retry_at: now + delay,
+                            since: Some(since),
+                        })?;
+                        self.record_retry(delay);
+                    } else {
+                        self.give_up(&batch, &e);
+                        spool.remove(seq)?;
+                    }
+                    return Err(e);
+                }
+                Err(e) => match self.error_policy.action(&e) {
+                    Some(ErrorAction::Halt) => {
+                        self.halt(&e);
+                        return Err(e);
+                    }
+                    Some(ErrorAction::Split) if batch.batch.len() > 1 => {
+                        // The batch stays spooled until its halves were sent,
+                        // so a crash meanwhile doesn't lose them.
+                        let held = self.held.len();
+                        let mut outcome = Ok(());
+                        let mut unsent = Vec::new();
+                        let (first, second) = split(batch);
+                        for half in [first, second] {
+                            if self.is_holding() {
+                                unsent.push(half);
+                            } else {
+                                outcome = outcome.and(self.deliver(half).await);
+                            }
+                        }
+                        // When halted, the parts not sent yet were held first,
+                        // they replace the batch in the spool instead.
+                        let rest = self
+                            .held
+                            .drain(..self.held.len() - held)
+                            .chain(unsent)
+                            .reduce(|mut rest, batch| {
+                                rest.batch.extend(batch.batch);
+                                rest
+                            });
+                        match rest {
+                            Some(rest) => spool.replace(seq, &rest)?,
+                            None => spool.remove(seq)?,
+                        }
+                        outcome?;
+                    }
+                    _ => {
+                        self.give_up(&batch, &e);
+                        spool.remove(seq)?;
+                        return Err(e);
+                    }
+                },
+            }
+        }
         Ok(())
     }
+
+    /// Pause because the error policy says `error` must halt the batcher.
+    fn halt(&mut self, error: &Error) {
+        log::error!(
+            "pausing after failing to send a batch to Segment: {}",
+            error
+        );
+        self.paused = true;
+    }
+
+    /// Drop a message found invalid when flushed, or rejected on its own by
+    /// Segment, dead-lettering it if possible.
+    fn reject(&self, msg: BatchMessage, error: &Error) {
+        match &self.dead_letter {
+            Some(dead_letter) => {
+                let batch = Batch {
+                    batch: vec![msg],
+                    ..Default::default()
+                };
+                dead_letter.send(&batch, error);
+            }
+            None => log::warn!("dropping invalid message of {}: {}", msg.user(), error),
+        }
+    }
+
+    /// Drop a batch which could not be sent, dead-lettering it unless the
+    /// error policy says otherwise.
+    fn give_up(&self, batch: &Batch, error: &Error) {
+        match self.error_policy.action(error) {
+            Some(ErrorAction::Drop) => log::warn!(
+                "dropping a batch of {} messages: {}",
+                batch.batch.len(),
+                error
+            ),
+            #[cfg(any(test, feature = "test-utils"))]
+            Some(ErrorAction::Panic) => panic!("could not send a batch to Segment: {}", error),
+            _ => {
+                if let (Some(dead_letter), false) = (&self.dead_letter, batch.batch.is_empty()) {
+                    dead_letter.send(batch, error);
+                }
+            }
+        }
+    }
+
+    /// Send `batch`, retrying until the retry policy is exhausted or the
+    /// deadline of a batch first attempted at `since` passed.
+    ///
+    /// The messages rejected on their own, when the rest of the batch is
+    /// accepted, are dead-lettered one by one, and the batch counts as sent.
+    async fn send(&self, batch: &Batch, since: OffsetDateTime) -> Result<()> {
+        let mut result = self.send_with_retries(batch, since).await;
+        let mut rejected = Vec::new();
+        if let Err(Error::PartiallyRejected(messages)) = &result {
+            rejected = messages
+                .iter()
+                .filter(|rejected| rejected.index < batch.batch.len())
+                .map(|rejected| (rejected.index, Err(rejected.to_error())))
+                .collect();
+            result = Ok(());
+        }
+        for (index, error) in &rejected {
+            if let Err(error) = error {
+                self.reject(batch.batch[*index].clone(), error);
+            }
+        }
+        let result_of = |index: usize| {
+            rejected
+                .iter()
+                .find(|(rejected, _)| *rejected == index)
+                .map_or(&result, |(_, error)| error)
+        };
+        let flushed_at = self.batcher.clock.now();
+        if let Some(recorder) = &self.recorder {
+            for (index, msg) in batch.batch.iter().enumerate() {
+                recorder.record(msg, flushed_at, result_of(index));
+            }
+        }
+        if let Some(Audit(log)) = &self.audit {
+            let records: Vec<_> = batch
+                .batch
+                .iter()
+                .enumerate()
+                .map(|(index, msg)| AuditRecord::new(msg, flushed_at, result_of(index)))
+                .collect();
+            if !records.is_empty() {
+                if let Err(e) = log.append(&records) {
+                    log::warn!("could not write the audit log: {}", e);
+                }
+            }
+        }
+        result
+    }
+
+    /// How long to wait before retrying after `error`, the `retry`th retry:
+    /// the backoff of the retry policy, or longer if Segment asked to.
+    fn retry_delay(&self, retry: u32, error: &Error) -> Duration {
+        let backoff = self.retry.backoff(retry);
+        error
+            .retry_after()
+            .map_or(backoff, |retry_after| backoff.max(retry_after))
+    }
+
+    async fn send_with_retries(&self, batch: &Batch, since: OffsetDateTime) -> Result<()> {
+        let mut retry = 0;
+        loop {
+            match self
+                .client
+                .send(self.key.expose().to_owned(), Message::Batch(batch.clone()))
+                .await
+            {
+                Ok(()) => return Ok(()),
+                Err(e)
+                    if self.error_policy.is_retryable(&e)
+                        && retry < self.retry.max_retries
+                        && self.retry.within_deadline(
+                            (self.batcher.clock.now() - since).unsigned_abs(),
+                            self.retry_delay(retry, &e),
+                        ) =>
+                {
+                    let backoff = self.retry_delay(retry, &e);
+                    log::warn!(
+                        "could not send batch to Segment, retrying in {:?}: {}",
+                        backoff,
+                        e
+                    );
+                    self.record_retry(backoff);
+                    runtime::sleep(backoff).await;
+                    retry += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Split a batch in two halves, each with the context and integrations of the
+/// batch.
+fn split(mut batch: Batch) -> (Batch, Batch) {
+    let second = Batch {
+        batch: batch.batch.split_off(batch.batch.len() / 2),
+        context: batch.context.clone(),
+        integrations: batch.integrations.clone(),
+        sent_at: batch.sent_at,
+        extra: batch.extra.clone(),
+    };
+    (batch, second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{StoredMessage, Track, User};
+    use crate::test_utils::{FakeServer, Fault, FaultyClient, MockClient, MockClock};
+    use crate::{DeadLetterFile, ErrorClass, ValidationMode};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_push_and_flush() {
+        let client = MockClient::new();
+        let mut batcher = AutoBatcher::new(
+            client.clone(),
+            Batcher::new(None),
+            WriteKey::new("key").unwrap(),
+        );
+
+        for i in 0..3 {
+            let msg = Track {
+                user: User::UserId {
+                    user_id: format!("user-{}", i),
+                },
+                event: "Example".to_owned(),
+                ..Default::default()
+            };
+            batcher.push(msg).await.unwrap();
+        }
+        assert!(client.messages().is_empty());
+
+        batcher.flush().await.unwrap();
+        assert_eq!(client.messages().len(), 1);
+        assert_eq!(client.tracks().len(), 3);
+        assert_eq!(client.for_user("user-1").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_flush_when_full() {
+        let client = MockClient::new();
+        let mut batcher = AutoBatcher::new(
+            client.clone(),
+            Batcher::new(None),
+            WriteKey::new("key").unwrap(),
+        );
+
+        let msg = Track {
+            user: User::UserId {
+                user_id: String::from_utf8(vec![b'a'; 1024 * 30]).unwrap(),
+            },
+            ..Default::default()
+        };
+        // 16 messages of ~30kB fit in a 512kB batch, so 40 of them should be
+        // sent in two full batches, the rest waiting in the batcher.
+        for _ in 0..40 {
+            batcher.push(msg.clone()).await.unwrap();
+        }
+
+        assert_eq!(client.messages().len(), 2);
+        assert_eq!(client.tracks().len(), 32);
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let server = FakeServer::start();
+        let client = HttpClient::new(reqwest::Client::new(), server.url());
+        let mut batcher =
+            AutoBatcher::new(client, Batcher::new(None), WriteKey::new("key").unwrap());
+        batcher.set_retry_policy(RetryPolicy {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            deadline: None,
+        });
+        let msg = Track {
+            user: User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            event: "Example".to_owned(),
+            ..Default::default()
+        };
+
+        server.fail_next(503, 2);
+        batcher.push(msg.clone()).await.unwrap();
+        batcher.flush().await.unwrap();
+        assert_eq!(server.requests().len(), 3);
+        assert_eq!(server.accepted().len(), 1);
+
+        server.fail_next(500, 3);
+        batcher.push(msg.clone()).await.unwrap();
+        assert!(batcher.flush().await.is_err());
+        assert_eq!(server.requests().len(), 6);
+
+        server.fail_next(400, 1);
+        batcher.push(msg).await.unwrap();
+        assert!(batcher.flush().await.is_err());
+        assert_eq!(server.requests().len(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_retry_budget() {
+        let client = FaultyClient::new(MockClient::new(), vec![Fault::Status(503); 5]);
+        let clock = MockClock::default();
+        let mut batcher = Batcher::new(None);
+        batcher.set_clock(clock.clone());
+        let mut batcher = AutoBatcher::new(client, batcher, WriteKey::new("key").unwrap());
+        batcher.set_retry_policy(RetryPolicy {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            deadline: None,
+        });
+        batcher.set_retry_budget(RetryBudget {
+            window: Duration::from_secs(60),
+            max_retries: Some(2),
+            max_backoff: None,
+        });
+        let alerts = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = alerts.clone();
+        batcher.on_retry_budget_exceeded(move |alert| recorded.lock().unwrap().push(*alert));
+        let msg = Track {
+            user: User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            event: "Example".to_owned(),
+            ..Default::default()
+        };
+
+        batcher.push(msg.clone()).await.unwrap();
+        assert!(batcher.flush().await.is_err());
+        batcher.push(msg.clone()).await.unwrap();
+        batcher.flush().await.unwrap();
+        assert_eq!(batcher.batcher.metrics().retries(), 4);
+        assert_eq!(
+            batcher.batcher.metrics().time_in_backoff(),
+            Duration::from_millis(4)
+        );
+        assert_eq!(
+            *alerts.lock().unwrap(),
+            [RetryBudgetAlert {
+                window_start: OffsetDateTime::UNIX_EPOCH,
+                retries: 3,
+                backoff: Duration::from_millis(3),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_error_policy() {
+        let client = MockClient::new();
+        let faulty =
+            FaultyClient::new(client.clone(), vec![Fault::Status(413), Fault::Status(401)]);
+        let mut batcher =
+            AutoBatcher::new(faulty, Batcher::new(None), WriteKey::new("key").unwrap());
+        batcher.set_error_policy(
+            ErrorPolicy::new()
+                .on(ErrorClass::Status(413), ErrorAction::Split)
+                .on(ErrorClass::Status(401), ErrorAction::Halt),
+        );
+        for i in 0..4 {
+            let msg = Track {
+                user: User::UserId {
+                    user_id: format!("user-{}", i),
+                },
+                event: "Example".to_owned(),
+                ..Default::default()
+            };
+            batcher.push(msg).await.unwrap();
+        }
+
+        // The batch is split, then the first half halts the batcher.
+        assert!(matches!(
+            batcher.flush().await,
+            Err(Error::Rejected { status: 401, .. })
+        ));
+        assert!(batcher.is_paused());
+        assert_eq!(batcher.len(), 4);
+
+        batcher.resume();
+        batcher.flush().await.unwrap();
+        let batches = client.messages();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(client.tracks().len(), 4);
+        assert_eq!(client.for_user("user-0").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_spool_split() {
+        let dir = std::env::temp_dir().join(format!("segment-spool-{}", uuid::Uuid::new_v4()));
+        let client = MockClient::new();
+        let faulty = FaultyClient::new(
+            client.clone(),
+            vec![Fault::Status(413), Fault::None, Fault::Status(401)],
+        );
+        let new_batcher = || {
+            let mut batcher = AutoBatcher::new(
+                faulty.clone(),
+                Batcher::new(None),
+                WriteKey::new("key").unwrap(),
+            );
+            batcher.set_error_policy(
+                ErrorPolicy::new()
+                    .on(ErrorClass::Status(413), ErrorAction::Split)
+                    .on(ErrorClass::Status(401), ErrorAction::Halt),
+            );
+            batcher.set_spool(Spool::open(&dir).unwrap());
+            batcher
+        };
+
+        let mut batcher = new_batcher();
+        for _ in 0..4 {
+            batcher.push(Track::default()).await.unwrap();
+        }
+        assert!(batcher.flush().await.is_err());
+        assert_eq!(client.tracks().len(), 2);
+
+        // The half which halted the batcher is left in the spool.
+        let mut batcher = new_batcher();
+        assert_eq!(batcher.len(), 2);
+        batcher.flush().await.unwrap();
+        assert_eq!(client.tracks().len(), 4);
+        assert!(new_batcher().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "could not send a batch to Segment")]
+    async fn test_error_policy_panic() {
+        let client = FaultyClient::new(MockClient::new(), vec![Fault::Status(400)]);
+        let mut batcher =
+            AutoBatcher::new(client, Batcher::new(None), WriteKey::new("key").unwrap());
+        batcher
+            .set_error_policy(ErrorPolicy::new().on(ErrorClass::ClientError, ErrorAction::Panic));
+        batcher.push(Track::default()).await.unwrap();
+        let _ = batcher.flush().await;
+    }
+
+    #[tokio::test]
+    async fn test_pause() {
+        let client = MockClient::new();
+        let mut batcher = Batcher::new(None);
+        batcher.set_max_batch_messages(2);
+        let mut batcher = AutoBatcher::new(client.clone(), batcher, WriteKey::new("key").unwrap());
+
+        batcher.pause();
+        for _ in 0..5 {
+            batcher.push(Track::default()).await.unwrap();
+        }
+        batcher.flush().await.unwrap();
+        assert!(client.messages().is_empty());
+        assert_eq!(batcher.len(), 5);
+
+        batcher.resume();
+        batcher.flush().await.unwrap();
+        assert_eq!(client.messages().len(), 3);
+        assert_eq!(client.tracks().len(), 5);
+        assert!(batcher.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_catch_up_rate() {
+        let client = MockClient::new();
+        let clock = MockClock::new(time::OffsetDateTime::UNIX_EPOCH);
+        let mut batcher = Batcher::new(None);
+        batcher.set_max_batch_messages(2);
+        batcher.set_clock(clock.clone());
+        let mut batcher = AutoBatcher::new(client.clone(), batcher, WriteKey::new("key").unwrap());
+        batcher.set_catch_up_rate(2);
+
+        batcher.pause();
+        for i in 0..6 {
+            batcher
+                .push(Track {
+                    event: format!("Event {}", i),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+        clock.advance(Duration::from_secs(3600));
+        batcher.resume();
+        batcher.flush().await.unwrap();
+        assert_eq!(client.tracks().len(), 4);
+
+        // New messages are sent after the backlog.
+        batcher
+            .push(Track {
+                event: "Event 6".to_owned(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        batcher.flush().await.unwrap();
+        assert_eq!(client.tracks().len(), 4);
+
+        clock.advance(Duration::from_secs(1));
+        batcher.flush().await.unwrap();
+        assert_eq!(client.tracks().len(), 6);
+        clock.advance(Duration::from_secs(1));
+        batcher.flush().await.unwrap();
+        let tracks = client.tracks();
+        let events: Vec<_> = tracks.iter().map(|track| track.event.as_str()).collect();
+        assert_eq!(
+            events,
+            ["Event 0", "Event 1", "Event 2", "Event 3", "Event 4", "Event 5", "Event 6"]
+        );
+        assert_eq!(tracks[0].timestamp, Some(time::OffsetDateTime::UNIX_EPOCH));
+        assert!(batcher.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_spool_backoff() {
+        let dir = std::env::temp_dir().join(format!("segment-spool-{}", uuid::Uuid::new_v4()));
+        let clock = MockClock::new(time::OffsetDateTime::UNIX_EPOCH);
+        let client = FaultyClient::new(MockClient::new(), vec![Fault::Status(503)]);
+        let new_batcher = || {
+            let mut batcher = Batcher::new(None);
+            batcher.set_clock(clock.clone());
+            let mut batcher =
+                AutoBatcher::new(client.clone(), batcher, WriteKey::new("key").unwrap());
+            batcher.set_spool(Spool::open(&dir).unwrap());
+            batcher
+        };
+
+        let mut batcher = new_batcher();
+        batcher.push(Track::default()).await.unwrap();
+        assert!(batcher.flush().await.is_err());
+        assert_eq!(batcher.len(), 1);
+
+        // A restarted process doesn't retry before the backoff elapsed.
+        let mut batcher = new_batcher();
+        assert_eq!(batcher.len(), 1);
+        batcher.flush().await.unwrap();
+        assert_eq!(client.calls(), 1);
+
+        clock.advance(Duration::from_secs(1));
+        batcher.flush().await.unwrap();
+        assert_eq!(client.calls(), 2);
+        assert!(batcher.is_empty());
+        assert!(new_batcher().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_spool_write_failure() {
+        let dir = std::env::temp_dir().join(format!("segment-spool-{}", uuid::Uuid::new_v4()));
+        let client = MockClient::new();
+        let mut batcher = AutoBatcher::new(
+            client.clone(),
+            Batcher::new(None),
+            WriteKey::new("key").unwrap(),
+        );
+        batcher.set_spool(Spool::open(&dir).unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        batcher.push(Track::default()).await.unwrap();
+        assert!(batcher.flush().await.is_err());
+        assert_eq!(batcher.len(), 1);
+
+        // The batch which could not be spooled is sent once it can be.
+        std::fs::create_dir(&dir).unwrap();
+        batcher.flush().await.unwrap();
+        assert_eq!(client.tracks().len(), 1);
+        assert!(batcher.is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_alias_and_identify() {
+        let client = MockClient::new();
+        let mut batcher = Batcher::new(None);
+        batcher.set_max_batch_messages(2);
+        let mut batcher = AutoBatcher::new(client.clone(), batcher, WriteKey::new("key").unwrap());
+
+        batcher.push(Track::default()).await.unwrap();
+        batcher
+            .alias_and_identify("anon", "foo", Traits::new().with("plan", "pro"))
+            .await
+            .unwrap();
+        batcher.flush().await.unwrap();
+
+        let batches = client.messages();
+        assert_eq!(batches.len(), 2);
+        match &batches[1] {
+            Message::Batch(batch) => assert!(matches!(
+                batch.batch.as_slice(),
+                [BatchMessage::Alias(_), BatchMessage::Identify(_)]
+            )),
+            _ => panic!("expected a batch"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deadline() {
+        let path =
+            std::env::temp_dir().join(format!("segment-dead-{}.jsonl", uuid::Uuid::new_v4()));
+        let client = FaultyClient::cycling(MockClient::new(), vec![Fault::Status(503)]);
+        let mut batcher = AutoBatcher::new(
+            client.clone(),
+            Batcher::new(None),
+            WriteKey::new("key").unwrap(),
+        );
+        batcher.set_retry_policy(RetryPolicy {
+            max_retries: 1000,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(10),
+            deadline: Some(Duration::from_millis(100)),
+        });
+        batcher.set_dead_letter(DeadLetterFile::open(&path).unwrap());
+
+        batcher.push(Track::default()).await.unwrap();
+        assert!(batcher.flush().await.is_err());
+        assert!(client.calls() <= 10);
+
+        let dead: Vec<StoredMessage> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(matches!(dead.as_slice(), [StoredMessage::Track(_)]));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_payload_too_large() {
+        let path =
+            std::env::temp_dir().join(format!("segment-dead-{}.jsonl", uuid::Uuid::new_v4()));
+        let client = MockClient::new();
+        let faulty =
+            FaultyClient::new(client.clone(), vec![Fault::Status(413), Fault::Status(413)]);
+        let mut batcher =
+            AutoBatcher::new(faulty, Batcher::new(None), WriteKey::new("key").unwrap());
+        batcher.set_dead_letter(DeadLetterFile::open(&path).unwrap());
+        for user_id in ["huge", "foo", "bar"] {
+            let msg = Track {
+                user: User::UserId {
+                    user_id: user_id.to_owned(),
+                },
+                event: "Example".to_owned(),
+                ..Default::default()
+            };
+            batcher.push(msg).await.unwrap();
+        }
+
+        // The batch is bisected until the message Segment rejects is alone.
+        assert!(matches!(
+            batcher.flush().await,
+            Err(Error::Rejected { status: 413, .. })
+        ));
+        assert_eq!(client.messages().len(), 1);
+        assert_eq!(client.tracks().len(), 2);
+        assert!(client.for_user("huge").is_empty());
+
+        let dead = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(dead.lines().count(), 1);
+        assert!(dead.contains("huge"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_partially_rejected() {
+        let path =
+            std::env::temp_dir().join(format!("segment-dead-{}.jsonl", uuid::Uuid::new_v4()));
+        let client = MockClient::new();
+        let faulty = FaultyClient::new(client.clone(), vec![Fault::RejectFirst(400)]);
+        let mut batcher =
+            AutoBatcher::new(faulty, Batcher::new(None), WriteKey::new("key").unwrap());
+        batcher.set_dead_letter(DeadLetterFile::open(&path).unwrap());
+        for user_id in ["invalid", "foo", "bar"] {
+            let msg = Track {
+                user: User::UserId {
+                    user_id: user_id.to_owned(),
+                },
+                event: "Example".to_owned(),
+                ..Default::default()
+            };
+            batcher.push(msg).await.unwrap();
+        }
+
+        // The rest of the batch was accepted, so the flush succeeds.
+        batcher.flush().await.unwrap();
+        assert_eq!(client.messages().len(), 1);
+
+        let dead: Vec<StoredMessage> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        match dead.as_slice() {
+            [StoredMessage::Track(track)] => assert_eq!(track.user.key(), "invalid"),
+            other => panic!("unexpected dead letters: {:?}", other),
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_validate_on_flush() {
+        let path =
+            std::env::temp_dir().join(format!("segment-dead-{}.jsonl", uuid::Uuid::new_v4()));
+        let client = MockClient::new();
+        let mut inner = Batcher::new(None);
+        inner.enable_strict_mode();
+        inner.set_validation_mode(ValidationMode::Flush);
+        let mut batcher = AutoBatcher::new(client.clone(), inner, WriteKey::new("key").unwrap());
+        batcher.set_dead_letter(DeadLetterFile::open(&path).unwrap());
+
+        batcher.push(Track::default()).await.unwrap();
+        batcher
+            .push(Track {
+                user: User::UserId {
+                    user_id: "foo".to_owned(),
+                },
+                event: "Example".to_owned(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        batcher.flush().await.unwrap();
+
+        assert_eq!(client.tracks().len(), 1);
+        let dead = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(dead.lines().count(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retry_after() {
+        let dir = std::env::temp_dir().join(format!("segment-spool-{}", uuid::Uuid::new_v4()));
+        let server = FakeServer::start();
+        let client = HttpClient::new(reqwest::Client::new(), server.url());
+        let clock = MockClock::new(time::OffsetDateTime::UNIX_EPOCH);
+        let mut batcher = Batcher::new(None);
+        batcher.set_clock(clock.clone());
+        let mut batcher = AutoBatcher::new(client, batcher, WriteKey::new("key").unwrap());
+        batcher.set_retry_policy(RetryPolicy {
+            max_retries: 1,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            deadline: None,
+        });
+
+        // Retries wait as long as Segment asked to.
+        server.rate_limit_next("1", 1);
+        batcher.push(Track::default()).await.unwrap();
+        let start = std::time::Instant::now();
+        batcher.flush().await.unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(server.accepted().len(), 1);
+
+        // So do spooled batches.
+        batcher.set_retry_policy(RetryPolicy::none());
+        batcher.set_spool(Spool::open(&dir).unwrap());
+        server.rate_limit_next("60", 1);
+        batcher.push(Track::default()).await.unwrap();
+        assert!(matches!(
+            batcher.flush().await,
+            Err(Error::RateLimited { .. })
+        ));
+        clock.advance(Duration::from_secs(30));
+        batcher.flush().await.unwrap();
+        assert_eq!(server.requests().len(), 3);
+        clock.advance(Duration::from_secs(30));
+        batcher.flush().await.unwrap();
+        assert_eq!(server.accepted().len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_spool_deadline() {
+        let dir = std::env::temp_dir().join(format!("segment-spool-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("dead.jsonl");
+        let clock = MockClock::new(time::OffsetDateTime::UNIX_EPOCH);
+        let client = FaultyClient::cycling(MockClient::new(), vec![Fault::Status(503)]);
+        let mut batcher = Batcher::new(None);
+        batcher.set_clock(clock.clone());
+        let mut batcher = AutoBatcher::new(client, batcher, WriteKey::new("key").unwrap());
+        batcher.set_retry_policy(RetryPolicy {
+            deadline: Some(Duration::from_secs(60)),
+            ..RetryPolicy::none()
+        });
+        batcher.set_spool(Spool::open(&dir).unwrap());
+        batcher.set_dead_letter(DeadLetterFile::open(&path).unwrap());
+
+        batcher.push(Track::default()).await.unwrap();
+        assert!(batcher.flush().await.is_err());
+        clock.advance(Duration::from_secs(30));
+        assert!(batcher.flush().await.is_err());
+        assert_eq!(batcher.len(), 1);
+
+        clock.advance(Duration::from_secs(30));
+        assert!(batcher.flush().await.is_err());
+        assert!(batcher.is_empty());
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flush_windows() {
+        let clock = MockClock::new(time::OffsetDateTime::UNIX_EPOCH);
+        let client = MockClient::new();
+        let mut batcher = Batcher::new(None);
+        batcher.set_clock(clock.clone());
+        let mut batcher = AutoBatcher::new(client.clone(), batcher, WriteKey::new("key").unwrap());
+        batcher.set_flush_windows(vec!["01:00-02:00".parse().unwrap()]);
+
+        batcher.push(Track::default()).await.unwrap();
+        batcher.flush().await.unwrap();
+        assert!(client.messages().is_empty());
+        assert_eq!(batcher.len(), 1);
+
+        clock.advance(Duration::from_secs(3600));
+        batcher.flush().await.unwrap();
+        assert_eq!(client.tracks().len(), 1);
+        assert!(batcher.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_max_held_batches() {
+        let client = MockClient::new();
+        let mut batcher = AutoBatcher::new(
+            client.clone(),
+            Batcher::new(None),
+            WriteKey::new("key").unwrap(),
+        );
+        batcher.set_max_held_batches(2);
+
+        batcher.pause();
+        for i in 0..3 {
+            let msg = Track {
+                event: format!("Event {}", i),
+                ..Default::default()
+            };
+            batcher.push(msg).await.unwrap();
+            batcher.flush().await.unwrap();
+        }
+        assert_eq!(batcher.len(), 2);
+        assert_eq!(batcher.batcher.metrics().dropped_held(), 1);
+
+        batcher.resume();
+        batcher.flush().await.unwrap();
+        let tracks = client.tracks();
+        let events: Vec<_> = tracks.iter().map(|track| track.event.as_str()).collect();
+        assert_eq!(events, ["Event 1", "Event 2"]);
+    }
 }
@@ -0,0 +1,80 @@
+//! Counting the messages waiting for a background task, to slow producers
+//! down.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::task::{Poll, Waker};
+
+/// The messages handed to the background tasks of an
+/// [`Analytics`](crate::Analytics) handle which they did not take yet, and the
+/// producers waiting for their number to drop below the high-water mark.
+#[derive(Debug)]
+pub(crate) struct Backlog {
+    queued: AtomicUsize,
+    high_water: AtomicUsize,
+    waiters: Mutex<Vec<Waker>>,
+}
+
+impl Default for Backlog {
+    fn default() -> Self {
+        Self {
+            queued: AtomicUsize::new(0),
+            high_water: AtomicUsize::new(usize::MAX),
+            waiters: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl Backlog {
+    pub(crate) fn len(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_high_water_mark(&self, high_water: Option<usize>) {
+        self.high_water
+            .store(high_water.unwrap_or(usize::MAX), Ordering::SeqCst);
+        self.wake();
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.len() >= self.high_water.load(Ordering::SeqCst)
+    }
+
+    /// Whether `messages` more fit below the high-water mark.
+    pub(crate) fn has_room(&self, messages: usize) -> bool {
+        self.len().saturating_add(messages) <= self.high_water.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn add(&self, messages: usize) {
+        self.queued.fetch_add(messages, Ordering::SeqCst);
+    }
+
+    pub(crate) fn remove(&self, messages: usize) {
+        self.queued.fetch_sub(messages, Ordering::SeqCst);
+        if !self.is_full() {
+            self.wake();
+        }
+    }
+
+    /// Wait until the backlog is below the high-water mark.
+    pub(crate) fn ready(&self) -> impl Future<Output = ()> + '_ {
+        futures_util::future::poll_fn(move |cx| {
+            // Checked with the lock held, so that `wake` can't run between the
+            // check and the registration of the waker.
+            let mut waiters = self.waiters.lock().unwrap();
+            if !self.is_full() {
+                return Poll::Ready(());
+            }
+            if !waiters.iter().any(|waker| waker.will_wake(cx.waker())) {
+                waiters.push(cx.waker().clone());
+            }
+            Poll::Pending
+        })
+    }
+
+    fn wake(&self) {
+        let waiters = std::mem::take(&mut *self.waiters.lock().unwrap());
+        waiters.into_iter().for_each(Waker::wake);
+    }
+}
@@ -1,12 +1,28 @@
 //! Utilities for batching up messages.
 
-use crate::message::{Batch, BatchMessage, Message};
-use crate::{Error, Result};
+use crate::cardinality::EventNames;
+use crate::dedup::{GroupDeduplicator, ViewDeduplicator};
+use crate::memory::HeapSize;
+use crate::message::{self, Batch, BatchMessage, Message, StoredMessage};
+use crate::rate_limit::RateLimiter;
+use crate::scope;
+use crate::session::Sessions;
+use crate::traits_cache::TraitCache;
+use crate::validation::{self, WarningHook};
+use crate::{
+    check_context, BatchConfig, Clock, Config, ContextPrivacy, Error, EventNameGuard, FieldHashing,
+    Filter, IdGenerator, LargeIntegerPolicy, MergePolicy, Metrics, PruningRules, RateLimit, Result,
+    Sampling, SessionConfig, SystemClock, TimestampBounds, Transform, Truncation, UuidGenerator,
+    ValidationMode,
+};
+use futures_util::stream::{self, Stream};
 use serde_json::{Map, Value};
-use time::OffsetDateTime;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
 
-const MAX_MESSAGE_SIZE: usize = 1024 * 32;
-const MAX_BATCH_SIZE: usize = 1024 * 512;
+pub(crate) const MAX_MESSAGE_SIZE: usize = 1024 * 32;
+pub(crate) const MAX_BATCH_SIZE: usize = 1024 * 512;
 
 /// A batcher can accept messages into an internal buffer, and report when
 /// messages must be flushed.
@@ -15,8 +31,7 @@ const MAX_BATCH_SIZE: usize = 1024 * 512;
 ///
 /// ```
 /// use segment::{Batcher, Client, HttpClient};
-/// use segment::message::{BatchMessage, Track, User};
-/// use serde_json::json;
+/// use segment::message::{BatchMessage, Properties, Track, User};
 ///
 /// let mut batcher = Batcher::new(None);
 /// let client = HttpClient::default();
@@ -25,7 +40,7 @@ const MAX_BATCH_SIZE: usize = 1024 * 512;
 ///     let msg = Track {
 ///         user: User::UserId { user_id: format!("user-{}", i) },
 ///         event: "Example".to_owned(),
-///         properties: json!({ "foo": "bar" }),
+///         properties: Properties::new().with("foo", "bar"),
 ///         ..Default::default()
 ///     };
 ///
@@ -55,12 +70,87 @@ const MAX_BATCH_SIZE: usize = 1024 * 512;
 /// added to your message.
 /// You can disable this behaviour with the [without_auto_timestamp] method
 /// though.
+///
+/// Similarly, messages without a `messageId` get one generated at the time of
+/// the push, unless [without_auto_message_id] is called.
+///
+/// The source of those timestamps and IDs can be replaced with [set_clock]
+/// and [set_id_generator], which is useful to get reproducible payloads in
+/// tests.
+///
+/// In [strict mode](Batcher::enable_strict_mode), messages which can't be
+/// attributed to a user or `track` events without a name are rejected instead
+/// of being batched, or removed from their batch when it is flushed,
+/// depending on the [`ValidationMode`].
+///
+/// Timestamps can also be checked against [`TimestampBounds`] with
+/// [set_timestamp_bounds]. Out of bounds messages are rejected in strict mode,
+/// and only logged as a warning (and reported to the hook set with
+/// [on_timestamp_warning]) otherwise.
+///
+/// [set_timestamp_bounds]: Batcher::set_timestamp_bounds
+/// [on_timestamp_warning]: Batcher::on_timestamp_warning
+/// [without_auto_timestamp]: Batcher::without_auto_timestamp
+/// [without_auto_message_id]: Batcher::without_auto_message_id
+/// [set_clock]: Batcher::set_clock
+/// [set_id_generator]: Batcher::set_id_generator
 #[derive(Clone, Debug)]
 pub struct Batcher {
     pub(crate) buf: Vec<BatchMessage>,
     pub(crate) byte_count: usize,
     pub(crate) context: Option<Value>,
+    pub(crate) max_bytes: usize,
+    pub(crate) max_messages: Option<usize>,
+    pub(crate) merge_policy: MergePolicy,
+    pub(crate) dedup: Option<ViewDeduplicator>,
+    pub(crate) group_dedup: Option<GroupDeduplicator>,
+    pub(crate) sessions: Option<Sessions>,
+    pub(crate) traits: Option<TraitCache>,
+    pub(crate) routes: HashMap<String, Value>,
+    pub(crate) privacy: Option<ContextPrivacy>,
+    pub(crate) hashing: Option<FieldHashing>,
+    pub(crate) pruning: Option<PruningRules>,
+    pub(crate) large_integers: Option<LargeIntegerPolicy>,
+    pub(crate) truncation: Option<Truncation>,
+    pub(crate) filters: Vec<Filter>,
+    pub(crate) transforms: Vec<Transform>,
+    pub(crate) sampling: Option<Sampling>,
+    pub(crate) event_names: Option<EventNames>,
+    pub(crate) rate_limiter: Option<RateLimiter>,
+    pub(crate) metrics: Arc<Metrics>,
     pub(crate) auto_timestamp: bool,
+    pub(crate) auto_message_id: bool,
+    pub(crate) strict: bool,
+    pub(crate) check_context: bool,
+    pub(crate) validation_mode: ValidationMode,
+    pub(crate) timestamp_bounds: Option<TimestampBounds>,
+    pub(crate) timestamp_warning: Option<WarningHook>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) id_generator: Arc<dyn IdGenerator>,
+}
+
+/// A message prepared to be pushed, or dropped.
+enum Prepared {
+    Kept(BatchMessage, usize),
+    /// Dropped, and why if it is counted in the metrics.
+    Dropped(Option<Dropped>),
+}
+
+/// Why a message counted in the metrics was dropped.
+enum Dropped {
+    SampledOut,
+    BlockedEventName,
+    RateLimited(String),
+}
+
+/// The state of a batcher which preparing messages changes.
+struct SavedState {
+    dedup: Option<ViewDeduplicator>,
+    group_dedup: Option<GroupDeduplicator>,
+    sessions: Option<Sessions>,
+    traits: Option<TraitCache>,
+    event_names: Option<EventNames>,
+    rate_limiter: Option<RateLimiter>,
 }
 
 impl Batcher {
@@ -73,7 +163,34 @@ impl Batcher {
             buf: Vec::new(),
             byte_count: 0,
             context,
+            max_bytes: MAX_BATCH_SIZE,
+            max_messages: None,
+            merge_policy: MergePolicy::default(),
+            dedup: None,
+            group_dedup: None,
+            sessions: None,
+            traits: None,
+            routes: HashMap::new(),
+            privacy: None,
+            hashing: None,
+            pruning: None,
+            large_integers: None,
+            truncation: None,
+            filters: Vec::new(),
+            transforms: Vec::new(),
+            sampling: None,
+            event_names: None,
+            rate_limiter: None,
+            metrics: Arc::default(),
             auto_timestamp: true,
+            auto_message_id: true,
+            strict: false,
+            check_context: false,
+            validation_mode: ValidationMode::Enqueue,
+            timestamp_bounds: None,
+            timestamp_warning: None,
+            clock: Arc::new(SystemClock),
+            id_generator: Arc::new(UuidGenerator),
         }
     }
 
@@ -81,10 +198,303 @@ impl Batcher {
         self.auto_timestamp = false;
     }
 
+    /// Consider the batch full once its serialized size would exceed
+    /// `max_bytes`.
+    ///
+    /// Values larger than the 512KB Segment accepts are capped.
+    pub fn set_max_batch_bytes(&mut self, max_bytes: usize) {
+        self.max_bytes = max_bytes.min(MAX_BATCH_SIZE);
+    }
+
+    /// Consider the batch full once it contains `max_messages` messages.
+    pub fn set_max_batch_messages(&mut self, max_messages: usize) {
+        self.max_messages = Some(max_messages);
+    }
+
+    /// Combine the context of the batch with the ones of its messages
+    /// according to `policy` when building the batch.
+    pub fn set_merge_policy(&mut self, policy: MergePolicy) {
+        self.merge_policy = policy;
+    }
+
+    /// Apply the context, view and group deduplication, trait cache, sessions,
+    /// routes, context privacy, field hashing, pruning rules, large integer
+    /// policy, truncation, filters, transforms, sampling, event name guard,
+    /// rate limits, merge policy, strict mode, context check, validation mode
+    /// and batch thresholds of `config`.
+    pub(crate) fn apply_config(&mut self, config: &Config) {
+        self.context = config.context.clone();
+        match (config.deduplicate_views, &mut self.dedup) {
+            (Some(window), Some(dedup)) => dedup.set_window(window),
+            (Some(window), None) => self.deduplicate_views(window),
+            (None, _) => self.dedup = None,
+        }
+        match (config.deduplicate_groups, &mut self.group_dedup) {
+            (Some(ttl), Some(dedup)) => dedup.set_ttl(ttl),
+            (Some(ttl), None) => self.deduplicate_groups(ttl),
+            (None, _) => self.group_dedup = None,
+        }
+        if config.cache_traits {
+            self.enable_trait_cache();
+        } else {
+            self.traits = None;
+        }
+        match (config.sessions, &mut self.sessions) {
+            (Some(config), Some(sessions)) => sessions.config = config,
+            (Some(config), None) => self.track_sessions(config),
+            (None, _) => self.sessions = None,
+        }
+        self.routes = config.routes.clone();
+        self.privacy = config.context_privacy;
+        self.hashing = config.hashed_fields.clone();
+        self.pruning = config.pruning.clone();
+        self.large_integers = config.large_integers.clone();
+        self.truncation = config.truncation.clone();
+        self.filters = config.filters.clone();
+        self.transforms = config.transforms.clone();
+        self.sampling = config.sampling.clone();
+        match (config.event_name_guard, &mut self.event_names) {
+            (Some(guard), Some(names)) => names.guard = guard,
+            (Some(guard), None) => self.guard_event_names(guard),
+            (None, _) => self.event_names = None,
+        }
+        if config.rate_limits.is_empty() {
+            self.rate_limiter = None;
+        } else {
+            self.rate_limiter
+                .get_or_insert_with(RateLimiter::default)
+                .limits = config.rate_limits.clone();
+        }
+        self.merge_policy = config.merge_policy;
+        self.strict = config.strict;
+        self.check_context = config.check_context;
+        self.validation_mode = config.validation_mode;
+        self.set_max_batch_bytes(config.batch.max_bytes);
+        self.max_messages = config.batch.max_messages;
+    }
+
+    /// Stop generating a `messageId` for messages which don't have one.
+    pub fn without_auto_message_id(&mut self) {
+        self.auto_message_id = false;
+    }
+
+    /// Reject messages with neither a user ID nor an anonymous ID, and `track`
+    /// events with an empty event name, instead of sending them.
+    pub fn enable_strict_mode(&mut self) {
+        self.strict = true;
+    }
+
+    /// Log a warning for each field of the context of a message which isn't
+    /// part of Segment's common spec or doesn't have the type it defines, since
+    /// destinations silently ignore them. See [`check_context`].
+    ///
+    /// [`check_context`]: crate::check_context
+    pub fn enable_context_check(&mut self) {
+        self.check_context = true;
+    }
+
+    /// Validate messages in strict mode when their batch is flushed rather
+    /// than when they are pushed, or the other way around.
+    pub fn set_validation_mode(&mut self, mode: ValidationMode) {
+        self.validation_mode = mode;
+    }
+
+    /// Whether messages are rejected when they are pushed.
+    pub(crate) fn validates_on_push(&self) -> bool {
+        self.strict && self.validation_mode == ValidationMode::Enqueue
+    }
+
+    /// Check that message timestamps are within the given bounds of the
+    /// current time.
+    pub fn set_timestamp_bounds(&mut self, bounds: TimestampBounds) {
+        self.timestamp_bounds = Some(bounds);
+    }
+
+    /// Call `hook` with every message whose timestamp is out of bounds but
+    /// which is batched anyway, because strict mode is disabled.
+    pub fn on_timestamp_warning(
+        &mut self,
+        hook: impl Fn(&BatchMessage, &Error) + Send + Sync + 'static,
+    ) {
+        self.timestamp_warning = Some(WarningHook(Arc::new(hook)));
+    }
+
+    /// Drop `page` and `screen` events identical to the previous one of the
+    /// same user, if it was pushed less than `window` ago.
+    ///
+    /// Pages are identical if they have the same name and `url` property, and
+    /// screens if they have the same name. This avoids counting the same view
+    /// twice when a client retries or re-renders.
+    pub fn deduplicate_views(&mut self, window: Duration) {
+        self.dedup = Some(ViewDeduplicator::new(window));
+    }
+
+    /// Drop `group` messages associating a user with a group with the same
+    /// traits as the previous one, unless it was pushed `ttl` ago or more.
+    ///
+    /// This cuts the noise of services calling `group` on every request,
+    /// while still sending the membership again once in a while, in case a
+    /// destination lost it.
+    pub fn deduplicate_groups(&mut self, ttl: Duration) {
+        self.group_dedup = Some(GroupDeduplicator::new(ttl));
+    }
+
+    /// Set a session ID on every message, identifying the current session of
+    /// its user. A new session starts when a user sends their first message,
+    /// or a message after `config.timeout` of inactivity.
+    ///
+    /// Messages which already have a session ID keep it.
+    ///
+    /// With [`stamp_group_id`](SessionConfig::stamp_group_id) enabled, the
+    /// messages of a user following a `group` message in the same session get
+    /// its `groupId` in `context.groupId`, unless they already have one.
+    pub fn track_sessions(&mut self, config: SessionConfig) {
+        self.sessions = Some(Sessions::new(config));
+    }
+
+    /// Remember the traits of the users identified through this batcher, and
+    /// set them in the `context.traits` of their later `track`, `page` and
+    /// `screen` events, where destinations resolving audiences read them.
+    pub fn enable_trait_cache(&mut self) {
+        self.traits.get_or_insert_with(TraitCache::default);
+    }
+
+    /// Route the `track` events named `event` with `integrations`, e.g.
+    /// [`Integrations::none().enable("Data Warehouse")`](crate::integrations::Integrations::none)
+    /// to only send debugging events to the warehouse, so routing rules live
+    /// in one place rather than at every call site.
+    ///
+    /// The `integrations` an event is given are
+    /// [merged](crate::message::merge_context) into the ones of its route,
+    /// so they take precedence over them.
+    pub fn route(&mut self, event: impl Into<String>, integrations: impl Into<Value>) {
+        self.routes.insert(event.into(), integrations.into());
+    }
+
+    /// Strip or coarsen the `context.ip` and `context.userAgent` of messages
+    /// and of the batch according to `privacy`, whether they were set on the
+    /// message, by a [scope](crate::with_context) or by an
+    /// [enricher](crate::Enricher).
+    pub fn set_context_privacy(&mut self, privacy: ContextPrivacy) {
+        self.privacy = Some(privacy);
+    }
+
+    /// Replace the traits and properties of messages listed by `hashing`,
+    /// such as `traits.email`, with the SHA-256 hash of their normalized
+    /// value, so they are never sent in clear.
+    pub fn set_field_hashing(&mut self, hashing: FieldHashing) {
+        self.hashing = Some(hashing);
+    }
+
+    /// Remove from messages whose `integrations` disable all destinations but
+    /// a known set (`"All": false`) the properties and traits which `rules`
+    /// declare to only be used by the other destinations.
+    ///
+    /// This reduces the size of high-volume events sent to few destinations.
+    /// Fields not mentioned in `rules` are always kept.
+    pub fn set_pruning_rules(&mut self, rules: PruningRules) {
+        self.pruning = Some(rules);
+    }
+
+    /// Send the integers of the fields listed by `policy` which don't fit
+    /// in a double as strings, or reject their messages, since destinations
+    /// storing numbers as doubles would round them, e.g. `u64` IDs.
+    pub fn set_large_integer_policy(&mut self, policy: LargeIntegerPolicy) {
+        self.large_integers = Some(policy);
+    }
+
+    /// Shrink the properties `truncation` lists of the `track` events larger
+    /// than the size limit of a message, instead of rejecting them, so the
+    /// events are still sent without their least valuable data.
+    pub fn set_truncation(&mut self, truncation: Truncation) {
+        self.truncation = Some(truncation);
+    }
+
+    /// Drop the messages matching `filter`, such as a destination filter
+    /// exported from the Segment UI, instead of sending them.
+    ///
+    /// Filters are evaluated against messages as they would be sent, once the
+    /// fields set by the batcher are filled in.
+    pub fn drop_matching(&mut self, filter: Filter) {
+        self.filters.push(filter);
+    }
+
+    /// Apply `transform` to the messages it matches, after the transforms
+    /// added before it.
+    ///
+    /// Transforms run as messages are pushed, before the filters, sampling
+    /// and privacy settings apply, so those see the fixed data.
+    pub fn add_transform(&mut self, transform: Transform) {
+        self.transforms.push(transform);
+    }
+
+    /// Only keep the `track` events of the fraction of the users `sampling`
+    /// gives for their name, dropping the others.
+    ///
+    /// The number of events dropped is counted in the [`metrics`](Batcher::metrics)
+    /// of the batcher.
+    pub fn set_sampling(&mut self, sampling: Sampling) {
+        self.sampling = Some(sampling);
+    }
+
+    /// Watch the number of distinct `track` event names, and once it passes
+    /// `guard.max_events`, warn or drop the events with a new name, to keep
+    /// interpolated names from creating thousands of tables downstream.
+    ///
+    /// The names seen so far keep being sent. The number of events dropped is
+    /// counted in the [`metrics`](Batcher::metrics) of the batcher.
+    pub fn guard_event_names(&mut self, guard: EventNameGuard) {
+        self.event_names = Some(EventNames::new(guard));
+    }
+
+    /// Drop the `track` events named `event` a user sends once they sent
+    /// `limit.max` of them in the last `limit.per`, to protect against
+    /// runaway loops in clients.
+    ///
+    /// The events and users exempt from [sampling](Sampling::exempt_user) are
+    /// not limited. To exempt them without sampling, set a [`Sampling`]
+    /// without rates, which keeps every event:
+    ///
+    /// ```
+    /// use segment::{Batcher, RateLimit, Sampling};
+    ///
+    /// let mut batcher = Batcher::new(None);
+    /// batcher.rate_limit("Search", RateLimit::per_minute(10));
+    /// batcher.set_sampling(Sampling::default().exempt_user("qa"));
+    /// ```
+    ///
+    /// The number of events dropped is counted in the [`metrics`](Batcher::metrics)
+    /// of the batcher.
+    pub fn rate_limit(&mut self, event: impl Into<String>, limit: RateLimit) {
+        self.rate_limiter
+            .get_or_insert_with(RateLimiter::default)
+            .limits
+            .insert(event.into(), limit);
+    }
+
+    /// The counters of the messages this batcher, or any of its clones, did
+    /// not send.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Use the given clock to timestamp messages.
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Arc::new(clock);
+    }
+
+    /// Use the given generator to generate message IDs.
+    pub fn set_id_generator(&mut self, id_generator: impl IdGenerator + 'static) {
+        self.id_generator = Arc::new(id_generator);
+    }
+
     /// Push a message into the batcher.
     ///
     /// Returns `Ok(None)` if the message was accepted and is now owned by the
-    /// batcher.
+    /// batcher, or if it was dropped as a [duplicate
+    /// view](Batcher::deduplicate_views) or
+    /// [group](Batcher::deduplicate_groups), by a [filter](Batcher::drop_matching),
+    /// by [sampling](Batcher::set_sampling) or by a [rate limit](Batcher::rate_limit).
     ///
     /// Returns `Ok(Some(msg))` if the message was rejected because the current
     /// batch would be oversized if this message were accepted. The given
@@ -92,44 +502,477 @@ impl Batcher {
     /// current batch before attempting to push `msg` in again.
     ///
     /// Returns an error if the message is too large to be sent to Segment's
-    /// API.
+    /// API, if it is incomplete and strict mode is enabled, or if it holds an
+    /// integer the [large integer policy](Batcher::set_large_integer_policy)
+    /// rejects.
     pub fn push(&mut self, msg: impl Into<BatchMessage>) -> Result<Option<BatchMessage>> {
-        let mut msg: BatchMessage = msg.into();
+        let (msg, size) = match self.prepare(msg.into())? {
+            Prepared::Kept(msg, size) => (msg, size),
+            Prepared::Dropped(reason) => {
+                self.count_dropped(reason);
+                return Ok(None);
+            }
+        };
+        if self.is_full(size, 1) && !self.buf.is_empty() {
+            return Ok(Some(msg));
+        }
+        self.record(&msg);
+        self.accept(msg, size);
+        Ok(None)
+    }
+
+    /// Push messages which must be sent in the same batch, in order, such as
+    /// the ones built by [`alias_and_identify`](crate::message::alias_and_identify).
+    ///
+    /// The messages go through the same steps as with [`push`](Batcher::push)
+    /// one after the other, so they are deduplicated and rate limited against
+    /// each other too.
+    ///
+    /// Returns `Ok(Some(msgs))` if the messages were rejected because they
+    /// don't all fit in the current batch; it is then recommended that you
+    /// flush the current batch before attempting to push `msgs` in again. An
+    /// empty batcher accepts them regardless of the configured thresholds.
+    ///
+    /// Returns an error if any of the messages would be rejected by
+    /// [`push`](Batcher::push).
+    ///
+    /// Either way, none of them is pushed, and the batcher is left as it was:
+    /// the messages returned are the ones given, and nothing is counted in
+    /// the [`metrics`](Batcher::metrics).
+    pub fn push_all(
+        &mut self,
+        msgs: impl IntoIterator<Item = impl Into<BatchMessage>>,
+    ) -> Result<Option<Vec<BatchMessage>>> {
+        let msgs: Vec<BatchMessage> = msgs.into_iter().map(Into::into).collect();
+        let saved = self.save_state();
+        let mut kept = Vec::new();
+        let mut dropped = Vec::new();
+        for msg in msgs.iter().cloned() {
+            match self.prepare(msg) {
+                Ok(Prepared::Kept(msg, size)) => {
+                    self.record(&msg);
+                    kept.push((msg, size));
+                }
+                Ok(Prepared::Dropped(reason)) => dropped.push(reason),
+                Err(e) => {
+                    self.restore_state(saved);
+                    return Err(e);
+                }
+            }
+        }
+        let size = kept.iter().map(|(_, size)| size).sum();
+        if self.is_full(size, kept.len()) && !self.buf.is_empty() {
+            self.restore_state(saved);
+            return Ok(Some(msgs));
+        }
+        for reason in dropped {
+            self.count_dropped(reason);
+        }
+        for (msg, size) in kept {
+            self.accept(msg, size);
+        }
+        Ok(None)
+    }
+
+    /// Validate `msg` and fill in its missing fields, returning it with its
+    /// serialized size, or why it must be dropped.
+    ///
+    /// This changes the sessions, trait cache and event name guard, but not
+    /// the metrics: the caller counts the dropped messages.
+    fn prepare(&mut self, mut msg: BatchMessage) -> Result<Prepared> {
+        scope::apply(&mut msg);
+        if let Some(privacy) = &self.privacy {
+            privacy.apply(msg.context_mut());
+        }
+        if let Some(hashing) = &self.hashing {
+            hashing.apply(&mut msg);
+        }
+        if self.validates_on_push() {
+            validation::check_complete(&msg)?;
+        }
         let timestamp = msg.timestamp_mut();
         if self.auto_timestamp && timestamp.is_none() {
-            *timestamp = Some(OffsetDateTime::now_utc());
+            *timestamp = Some(self.clock.now());
         }
-        let size = serde_json::to_vec(&msg)?.len();
+        if let (Some(bounds), Some(timestamp)) = (&self.timestamp_bounds, *timestamp) {
+            if let Err(e) = bounds.check(timestamp, self.clock.now()) {
+                if self.validates_on_push() {
+                    return Err(e);
+                }
+                // In strict mode, the message is checked again when flushed.
+                if !self.strict {
+                    log::warn!("{}", e);
+                    if let Some(WarningHook(hook)) = &self.timestamp_warning {
+                        hook(&msg, &e);
+                    }
+                }
+            }
+        }
+        if let Some(dedup) = &self.dedup {
+            if dedup.is_duplicate(&msg, self.clock.now()) {
+                log::debug!("dropping duplicate view of {}", msg.user());
+                return Ok(Prepared::Dropped(None));
+            }
+        }
+        if let Some(dedup) = &self.group_dedup {
+            if dedup.is_duplicate(&msg, self.clock.now()) {
+                log::debug!("dropping duplicate group of {}", msg.user());
+                return Ok(Prepared::Dropped(None));
+            }
+        }
+        for transform in &self.transforms {
+            transform.apply(&mut msg);
+        }
+        if let Some(traits) = &mut self.traits {
+            traits.apply(&mut msg);
+        }
+        if let Some(sessions) = &mut self.sessions {
+            sessions.apply(&mut msg, self.clock.now());
+        }
+        if self.check_context {
+            for issue in msg.context().map(check_context).unwrap_or_default() {
+                log::warn!("{} in a message of {}", issue, msg.user());
+            }
+        }
+        if let BatchMessage::Track(track) = &mut msg {
+            if let Some(route) = self.routes.get(&track.event) {
+                message::merge_optional_context(&mut track.integrations, route);
+            }
+        }
+        if let Some(pruning) = &self.pruning {
+            pruning.apply(&mut msg);
+        }
+        if let Some(policy) = &self.large_integers {
+            policy.apply(&mut msg)?;
+        }
+        if self.auto_message_id {
+            msg.extra_mut()
+                .entry("messageId")
+                .or_insert_with(|| Value::String(self.id_generator.generate()));
+        }
+        if let Some(filter) = self.filters.iter().find(|filter| filter.matches(&msg)) {
+            log::debug!("dropping message of {} matching {:?}", msg.user(), filter);
+            return Ok(Prepared::Dropped(None));
+        }
+        let exempt = self
+            .sampling
+            .as_ref()
+            .is_some_and(|sampling| sampling.exempts(&msg));
+        if let Some(sampling) = self.sampling.as_ref().filter(|_| !exempt) {
+            if !sampling.keeps(&msg) {
+                return Ok(Prepared::Dropped(Some(Dropped::SampledOut)));
+            }
+        }
+        if let Some(names) = &mut self.event_names {
+            if let Some(event) = names.check(&msg) {
+                log::debug!(
+                    "dropping {:?} event of {} past the event name limit",
+                    event,
+                    msg.user()
+                );
+                return Ok(Prepared::Dropped(Some(Dropped::BlockedEventName)));
+            }
+        }
+        if let Some(limiter) = self.rate_limiter.as_ref().filter(|_| !exempt) {
+            if let Some(event) = limiter.is_limited(&msg, self.clock.now()) {
+                log::debug!("dropping rate limited {:?} event of {}", event, msg.user());
+                let event = event.to_owned();
+                return Ok(Prepared::Dropped(Some(Dropped::RateLimited(event))));
+            }
+        }
+        let mut size = serde_json::to_vec(&msg)?.len();
         if size > MAX_MESSAGE_SIZE {
-            return Err(Error::MessageTooLarge);
+            match &self.truncation {
+                Some(truncation) => size = truncation.apply(&mut msg, size, MAX_MESSAGE_SIZE)?,
+                None => return Err(Error::MessageTooLarge),
+            }
         }
+        Ok(Prepared::Kept(msg, size))
+    }
 
-        self.byte_count += size + 1; // +1 to account for Serialized data's extra commas
-        if self.byte_count > MAX_BATCH_SIZE {
-            return Ok(Some(msg));
+    /// Count a message dropped for `reason` in the metrics.
+    fn count_dropped(&self, reason: Option<Dropped>) {
+        match reason {
+            Some(Dropped::SampledOut) => self.metrics.record_sampled_out(),
+            Some(Dropped::BlockedEventName) => self.metrics.record_blocked_event_name(),
+            Some(Dropped::RateLimited(event)) => self.metrics.record_rate_limited(&event),
+            None => (),
+        }
+    }
+
+    /// A copy of the state pushing messages changes, to roll it back.
+    fn save_state(&self) -> SavedState {
+        SavedState {
+            dedup: self.dedup.clone(),
+            group_dedup: self.group_dedup.clone(),
+            sessions: self.sessions.clone(),
+            traits: self.traits.clone(),
+            event_names: self.event_names.clone(),
+            rate_limiter: self.rate_limiter.clone(),
         }
+    }
+
+    fn restore_state(&mut self, saved: SavedState) {
+        self.dedup = saved.dedup;
+        self.group_dedup = saved.group_dedup;
+        self.sessions = saved.sessions;
+        self.traits = saved.traits;
+        self.event_names = saved.event_names;
+        self.rate_limiter = saved.rate_limiter;
+    }
 
+    /// Whether `count` more messages of `size` bytes in total would exceed the
+    /// thresholds of the current batch.
+    fn is_full(&self, size: usize, count: usize) -> bool {
+        // +1 to account for Serialized data's extra commas
+        self.byte_count + size + count > self.max_bytes
+            || self
+                .max_messages
+                .is_some_and(|max| self.buf.len() + count > max)
+    }
+
+    /// Record `msg` as sent for deduplication and rate limiting.
+    fn record(&mut self, msg: &BatchMessage) {
+        if let Some(dedup) = &mut self.dedup {
+            dedup.record(msg, self.clock.now());
+        }
+        if let Some(dedup) = &mut self.group_dedup {
+            dedup.record(msg, self.clock.now());
+        }
+        if let Some(limiter) = &mut self.rate_limiter {
+            limiter.record(msg, self.clock.now());
+        }
+    }
+
+    fn accept(&mut self, msg: BatchMessage, size: usize) {
+        self.byte_count += size + 1;
         self.buf.push(msg);
-        Ok(None)
+    }
+
+    /// The number of messages in the batcher.
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Whether the batcher contains no message.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// An estimate of the memory used by the queued messages, in bytes.
+    ///
+    /// Unlike the serialized size batches are filled by, this accounts for
+    /// the in-memory representation of the messages, including their JSON
+    /// trees, which is usually several times larger. It walks every queued
+    /// message, so avoid calling it on every push.
+    pub fn approx_memory_bytes(&self) -> usize {
+        self.buf.heap_size() + self.context.heap_size()
+    }
+
+    /// Remove the invalid messages from the batcher if they are validated
+    /// when flushed, returning them with the reason they are invalid.
+    pub(crate) fn take_invalid(&mut self) -> Vec<(BatchMessage, Error)> {
+        if !self.strict || self.validation_mode != ValidationMode::Flush {
+            return Vec::new();
+        }
+        let now = self.clock.now();
+        let bounds = self.timestamp_bounds;
+        let check = |msg: &mut BatchMessage| {
+            validation::check_complete(msg)?;
+            match (bounds, *msg.timestamp_mut()) {
+                (Some(bounds), Some(timestamp)) => bounds.check(timestamp, now),
+                _ => Ok(()),
+            }
+        };
+        let mut invalid = Vec::new();
+        for mut msg in std::mem::take(&mut self.buf) {
+            match check(&mut msg) {
+                Ok(()) => self.buf.push(msg),
+                Err(e) => invalid.push((msg, e)),
+            }
+        }
+        invalid
+    }
+
+    /// Take the messages out of this batcher as a batch, leaving it empty but
+    /// otherwise configured the same.
+    pub(crate) fn take(&mut self) -> Batch {
+        self.byte_count = 0;
+        let mut batch = Batch {
+            batch: std::mem::take(&mut self.buf),
+            context: self.context.clone(),
+            integrations: None,
+            sent_at: None,
+            extra: Map::default(),
+        };
+        if let Some(privacy) = &self.privacy {
+            privacy.apply(&mut batch.context);
+        }
+        batch.apply_merge_policy(self.merge_policy);
+        batch
+    }
+
+    /// Take the queued messages out of this batcher, leaving it empty but
+    /// otherwise configured the same, to hand them to another transport, e.g.
+    /// while migrating away from Segment or shutting down.
+    ///
+    /// The messages are taken as soon as this is called, not as the stream
+    /// is polled, so that messages pushed afterwards are not part of it. The
+    /// context of the batch is [deep-merged](MergePolicy::DeepMerge) into
+    /// each message, so they can be sent on their own, and the messages found
+    /// invalid when [validated on flush](ValidationMode::Flush) are logged
+    /// and left out.
+    pub fn drain(&mut self) -> impl Stream<Item = StoredMessage> {
+        for (msg, e) in self.take_invalid() {
+            log::warn!("dropping invalid message of {}: {}", msg.user(), e);
+        }
+        let mut batch = self.take();
+        batch.apply_merge_policy(MergePolicy::DeepMerge);
+        stream::iter(batch.batch.into_iter().map(StoredMessage::from))
+    }
+
+    /// A copy of the queued messages, e.g. for an orchestrator to hand them
+    /// from an instance being drained to its replacement, which
+    /// [restores](Batcher::restore) them.
+    ///
+    /// As with [`drain`](Batcher::drain), the context of the batch is
+    /// deep-merged into each message. The messages stay in this batcher, so it
+    /// should be dropped rather than flushed once its snapshot is handed off,
+    /// lest they are sent twice.
+    pub fn snapshot(&self) -> Vec<StoredMessage> {
+        let mut batch = Batch {
+            batch: self.buf.clone(),
+            context: self.context.clone(),
+            ..Default::default()
+        };
+        if let Some(privacy) = &self.privacy {
+            privacy.apply(&mut batch.context);
+        }
+        batch.apply_merge_policy(MergePolicy::DeepMerge);
+        batch.batch.into_iter().map(StoredMessage::from).collect()
+    }
+
+    /// Queue messages taken from the [snapshot](Batcher::snapshot) of another
+    /// batcher, in order.
+    ///
+    /// They were prepared when first pushed, so they are queued as they are,
+    /// without being filtered, sampled or given new timestamps and IDs again.
+    /// Returns the messages which don't fit in the current batch, to restore
+    /// once it is flushed; an empty batcher accepts at least one message.
+    ///
+    /// Returns an error if a message is too large to be sent to Segment's API.
+    pub fn restore(&mut self, msgs: Vec<StoredMessage>) -> Result<Vec<BatchMessage>> {
+        let mut msgs = msgs
+            .into_iter()
+            .flat_map(StoredMessage::into_batch_messages)
+            .peekable();
+        while let Some(msg) = msgs.peek() {
+            let size = serde_json::to_vec(msg)?.len();
+            if size > MAX_MESSAGE_SIZE {
+                return Err(Error::MessageTooLarge);
+            }
+            if self.is_full(size, 1) && !self.buf.is_empty() {
+                break;
+            }
+            let msg = msgs.next().expect("the message was peeked");
+            self.record(&msg);
+            self.accept(msg, size);
+        }
+        Ok(msgs.collect())
     }
 
     /// Consumes this batcher and converts it into a message that can be sent to
     /// Segment.
-    pub fn into_message(self) -> Message {
-        Message::Batch(Batch {
-            batch: self.buf,
-            context: self.context,
+    ///
+    /// Messages found invalid when [validated on
+    /// flush](ValidationMode::Flush) are logged and left out.
+    pub fn into_message(mut self) -> Message {
+        for (msg, e) in self.take_invalid() {
+            log::warn!("dropping invalid message of {}: {}", msg.user(), e);
+        }
+        Message::Batch(self.take())
+    }
+}
+
+/// Split `msgs` into the batches Segment accepts, in order, each within
+/// `limits`, for callers building their batches themselves.
+///
+/// Unlike a [`Batcher`], this leaves the messages as they are: no timestamp,
+/// `messageId` or context is added, and nothing is dropped. A message alone
+/// in its batch is accepted regardless of `limits.max_messages`.
+///
+/// Returns [`Error::MessageTooLarge`] if any of the messages is larger than
+/// the 32KB Segment accepts for a message.
+///
+/// ```
+/// use segment::{split_batches, BatchConfig};
+/// use segment::message::{BatchMessage, Track, User};
+///
+/// let msgs: Vec<BatchMessage> = (0..250)
+///     .map(|i| {
+///         Track {
+///             user: User::UserId { user_id: format!("user-{}", i) },
+///             event: "Example".to_owned(),
+///             ..Default::default()
+///         }
+///         .into()
+///     })
+///     .collect();
+///
+/// let limits = BatchConfig { max_messages: Some(100), ..Default::default() };
+/// let batches = split_batches(msgs, limits).unwrap();
+/// assert_eq!(batches.len(), 3);
+/// assert_eq!(batches[2].batch.len(), 50);
+/// ```
+pub fn split_batches(
+    msgs: impl IntoIterator<Item = BatchMessage>,
+    limits: BatchConfig,
+) -> Result<Vec<Batch>> {
+    let max_bytes = limits.max_bytes.min(MAX_BATCH_SIZE);
+    let mut batches = Vec::new();
+    let mut current = Vec::new();
+    let mut byte_count = 0;
+    for msg in msgs {
+        let size = serde_json::to_vec(&msg)?.len();
+        if size > MAX_MESSAGE_SIZE {
+            return Err(Error::MessageTooLarge);
+        }
+        // +1 to account for Serialized data's extra commas
+        let full = byte_count + size + 1 > max_bytes
+            || limits.max_messages.is_some_and(|max| current.len() >= max);
+        if full && !current.is_empty() {
+            batches.push(std::mem::take(&mut current));
+            byte_count = 0;
+        }
+        byte_count += size + 1;
+        current.push(msg);
+    }
+    if !current.is_empty() {
+        batches.push(current);
+    }
+    Ok(batches
+        .into_iter()
+        .map(|batch| Batch {
+            batch,
+            context: None,
             integrations: None,
+            sent_at: None,
             extra: Map::default(),
         })
-    }
+        .collect())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::message::{Track, User};
+    use crate::message::{Group, Identify, Page, Properties, Track, Traits, User};
+    use crate::test_utils::{track, MockClock, SequentialIdGenerator};
+    use crate::SessionField;
+    use futures::StreamExt;
     use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use time::OffsetDateTime;
 
     #[test]
     fn test_push_and_into() {
@@ -143,6 +986,7 @@ mod tests {
 
         let mut batcher = Batcher::new(Some(context.clone()));
         batcher.without_auto_timestamp();
+        batcher.without_auto_message_id();
         let result = batcher.push(batch_msg.clone());
         assert_eq!(None, result.ok().unwrap());
 
@@ -157,6 +1001,54 @@ mod tests {
         assert_eq!(inner_batch.batch, vec![batch_msg]);
     }
 
+    #[test]
+    fn test_split_batches() {
+        let track = |i: usize| {
+            BatchMessage::from(Track {
+                user: User::UserId {
+                    user_id: format!("user-{}", i),
+                },
+                event: "Example".to_owned(),
+                properties: Properties::new().with("padding", "a".repeat(1000)),
+                ..Default::default()
+            })
+        };
+        let msgs: Vec<_> = (0..10).map(track).collect();
+
+        let limits = BatchConfig {
+            max_bytes: 3500,
+            max_messages: None,
+        };
+        let batches = split_batches(msgs.clone(), limits).unwrap();
+        let lens: Vec<_> = batches.iter().map(|batch| batch.batch.len()).collect();
+        assert_eq!(lens, [3, 3, 3, 1]);
+        let split: Vec<_> = batches.into_iter().flat_map(|batch| batch.batch).collect();
+        assert_eq!(split, msgs);
+
+        let limits = BatchConfig {
+            max_messages: Some(4),
+            ..Default::default()
+        };
+        let lens: Vec<_> = split_batches(msgs, limits)
+            .unwrap()
+            .iter()
+            .map(|batch| batch.batch.len())
+            .collect();
+        assert_eq!(lens, [4, 4, 2]);
+
+        assert!(split_batches(Vec::new(), BatchConfig::default())
+            .unwrap()
+            .is_empty());
+        let too_large = Track {
+            properties: Properties::new().with("padding", "a".repeat(1024 * 33)),
+            ..Default::default()
+        };
+        assert!(matches!(
+            split_batches(vec![too_large.into()], BatchConfig::default()),
+            Err(Error::MessageTooLarge)
+        ));
+    }
+
     #[test]
     fn test_bad_message_size() {
         let batch_msg = Track {
@@ -184,6 +1076,7 @@ mod tests {
 
         let mut batcher = Batcher::new(None);
         batcher.without_auto_timestamp();
+        batcher.without_auto_message_id();
         let mut result = Ok(None);
         for _i in 0..20 {
             result = batcher.push(batch_msg.clone());
@@ -196,4 +1089,531 @@ mod tests {
         let msg = result.ok().unwrap();
         assert_eq!(BatchMessage::from(batch_msg), msg.unwrap());
     }
+
+    #[test]
+    fn test_auto_fill() {
+        let mut batcher = Batcher::new(None);
+        batcher.set_clock(MockClock::default());
+        batcher.set_id_generator(SequentialIdGenerator::default());
+
+        for user_id in ["foo", "bar"].iter() {
+            let msg = Track {
+                user: User::UserId {
+                    user_id: user_id.to_string(),
+                },
+                event: "Foo".to_owned(),
+                properties: Properties::new(),
+                ..Default::default()
+            };
+            assert!(batcher.push(msg).unwrap().is_none());
+        }
+
+        assert_eq!(
+            serde_json::to_string(&batcher.into_message()).unwrap(),
+            r#"{"batch":[{"type":"track","userId":"foo","event":"Foo","properties":{},"timestamp":"1970-01-01T00:00:00Z","messageId":"message-1"},{"type":"track","userId":"bar","event":"Foo","properties":{},"timestamp":"1970-01-01T00:00:00Z","messageId":"message-2"}]}"#,
+        );
+    }
+
+    #[test]
+    fn test_strict_mode() {
+        let mut batcher = Batcher::new(None);
+        batcher.enable_strict_mode();
+
+        let anonymous = Track {
+            event: "Foo".to_owned(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            batcher.push(anonymous),
+            Err(Error::InvalidMessage { .. })
+        ));
+
+        let unnamed = Track {
+            user: User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            ..Default::default()
+        };
+        assert!(matches!(
+            batcher.push(unnamed),
+            Err(Error::InvalidMessage { .. })
+        ));
+
+        let complete = Track {
+            user: User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            event: "Foo".to_owned(),
+            ..Default::default()
+        };
+        assert!(batcher.push(complete).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_timestamp_bounds() {
+        let clock = MockClock::new(OffsetDateTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+        let warnings = Arc::new(AtomicUsize::new(0));
+        let mut batcher = Batcher::new(None);
+        batcher.set_clock(clock.clone());
+        batcher.set_timestamp_bounds(TimestampBounds {
+            max_past: Duration::from_secs(60),
+            max_future: Duration::from_secs(60),
+        });
+        let counter = warnings.clone();
+        batcher.on_timestamp_warning(move |_, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        let at = |secs| Track {
+            user: User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            event: "Foo".to_owned(),
+            timestamp: Some(OffsetDateTime::UNIX_EPOCH + Duration::from_secs(secs)),
+            ..Default::default()
+        };
+
+        batcher.push(at(1_000_030)).unwrap();
+        batcher.push(at(1_000_090)).unwrap();
+        batcher.push(at(999_900)).unwrap();
+        assert_eq!(warnings.load(Ordering::SeqCst), 2);
+        assert_eq!(batcher.buf.len(), 3);
+
+        batcher.enable_strict_mode();
+        assert!(matches!(
+            batcher.push(at(999_900)),
+            Err(Error::InvalidMessage { .. })
+        ));
+        assert!(batcher.push(at(999_990)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_max_messages() {
+        let mut batcher = Batcher::new(None);
+        batcher.set_max_batch_messages(2);
+
+        assert!(batcher.push(Track::default()).unwrap().is_none());
+        assert!(batcher.push(Track::default()).unwrap().is_none());
+        assert!(batcher.push(Track::default()).unwrap().is_some());
+        assert_eq!(batcher.len(), 2);
+    }
+
+    #[test]
+    fn test_approx_memory_bytes() {
+        let mut batcher = Batcher::new(None);
+        assert_eq!(batcher.approx_memory_bytes(), 0);
+
+        let track = Track {
+            event: "Example".to_owned(),
+            properties: Properties::new().with("tags", vec!["a"; 1000]),
+            ..Default::default()
+        };
+        batcher.push(track.clone()).unwrap();
+        let one = batcher.approx_memory_bytes();
+        assert!(one > 1000 * std::mem::size_of::<Value>());
+        assert!(one > batcher.byte_count);
+
+        batcher.push(track).unwrap();
+        assert!(batcher.approx_memory_bytes() > one);
+    }
+
+    #[test]
+    fn test_deduplicate_views() {
+        let clock = MockClock::new(OffsetDateTime::UNIX_EPOCH);
+        let mut batcher = Batcher::new(None);
+        batcher.set_clock(clock.clone());
+        batcher.deduplicate_views(Duration::from_secs(1));
+        let page = |user_id: &str, url: &str| Page {
+            user: User::UserId {
+                user_id: user_id.to_owned(),
+            },
+            name: Some("Home".to_owned()),
+            properties: Properties::new().with("url", url),
+            ..Default::default()
+        };
+
+        batcher.push(page("foo", "/")).unwrap();
+        batcher.push(page("foo", "/")).unwrap();
+        batcher.push(page("bar", "/")).unwrap();
+        batcher.push(page("foo", "/?tab=1")).unwrap();
+        batcher.push(page("foo", "/?tab=1")).unwrap();
+        assert_eq!(batcher.len(), 3);
+
+        clock.advance(Duration::from_secs(1));
+        batcher.push(page("foo", "/?tab=1")).unwrap();
+        assert_eq!(batcher.len(), 4);
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut batcher = Batcher::new(Some(json!({ "library": { "name": "segment" } })));
+        batcher.without_auto_message_id();
+        for user_id in ["foo", "bar"] {
+            batcher
+                .push(Track {
+                    user: User::UserId {
+                        user_id: user_id.to_owned(),
+                    },
+                    event: "Drained".to_owned(),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+
+        let drained = batcher.drain();
+        assert!(batcher.is_empty());
+        batcher.push(Identify::default()).unwrap();
+        let drained: Vec<StoredMessage> = futures::executor::block_on(drained.collect());
+        assert_eq!(drained.len(), 2);
+        match &drained[1] {
+            StoredMessage::Track(track) => {
+                assert_eq!(track.user.key(), "bar");
+                assert_eq!(
+                    track.context,
+                    Some(json!({ "library": { "name": "segment" } }))
+                );
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+        assert_eq!(batcher.len(), 1);
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let mut draining = Batcher::new(Some(json!({ "library": { "name": "segment" } })));
+        for user_id in ["foo", "bar", "baz"] {
+            draining
+                .push(Track {
+                    user: User::UserId {
+                        user_id: user_id.to_owned(),
+                    },
+                    event: "Handed Off".to_owned(),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        let snapshot = draining.snapshot();
+        assert_eq!(snapshot.len(), 3);
+        assert_eq!(draining.len(), 3);
+
+        let mut replacement = Batcher::new(None);
+        replacement.set_max_batch_messages(2);
+        let rest = replacement.restore(snapshot.clone()).unwrap();
+        assert_eq!(replacement.len(), 2);
+        assert_eq!(rest.len(), 1);
+        match (&snapshot[0], &replacement.buf[0]) {
+            (StoredMessage::Track(stored), BatchMessage::Track(restored)) => {
+                assert_eq!(stored, restored);
+                assert_eq!(
+                    restored.context,
+                    Some(json!({ "library": { "name": "segment" } }))
+                );
+                assert!(restored.timestamp.is_some());
+            }
+            other => panic!("unexpected messages: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_deduplicate_groups() {
+        let clock = MockClock::new(OffsetDateTime::UNIX_EPOCH);
+        let mut batcher = Batcher::new(None);
+        batcher.set_clock(clock.clone());
+        batcher.deduplicate_groups(Duration::from_secs(60));
+        let group = |user_id: &str, group_id: &str, plan: &str| Group {
+            user: User::UserId {
+                user_id: user_id.to_owned(),
+            },
+            group_id: group_id.to_owned(),
+            traits: Traits::new().with("plan", plan),
+            ..Default::default()
+        };
+
+        batcher.push(group("foo", "acme", "pro")).unwrap();
+        batcher.push(group("foo", "acme", "pro")).unwrap();
+        batcher.push(group("bar", "acme", "pro")).unwrap();
+        batcher.push(group("foo", "other", "pro")).unwrap();
+        batcher.push(group("foo", "acme", "free")).unwrap();
+        batcher.push(group("foo", "acme", "free")).unwrap();
+        assert_eq!(batcher.len(), 4);
+
+        clock.advance(Duration::from_secs(60));
+        batcher.push(group("foo", "acme", "free")).unwrap();
+        assert_eq!(batcher.len(), 5);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let clock = MockClock::new(OffsetDateTime::UNIX_EPOCH);
+        let mut batcher = Batcher::new(None);
+        batcher.set_clock(clock.clone());
+        batcher.rate_limit("Search", RateLimit::per_minute(2));
+        for _ in 0..3 {
+            batcher.push(track("foo", "Search")).unwrap();
+            batcher.push(track("bar", "Search")).unwrap();
+            batcher.push(track("foo", "Bought")).unwrap();
+        }
+        assert_eq!(batcher.len(), 7);
+        assert_eq!(batcher.metrics().rate_limited()["Search"], 2);
+
+        clock.advance(Duration::from_secs(60));
+        batcher.push(track("foo", "Search")).unwrap();
+        assert_eq!(batcher.len(), 8);
+
+        batcher.set_sampling(Sampling::new("salt").exempt_user("qa"));
+        for _ in 0..3 {
+            batcher.push(track("qa", "Search")).unwrap();
+        }
+        assert_eq!(batcher.len(), 11);
+    }
+
+    #[test]
+    fn test_push_all() {
+        let clock = MockClock::new(OffsetDateTime::UNIX_EPOCH);
+        let mut batcher = Batcher::new(None);
+        batcher.set_clock(clock.clone());
+        batcher.rate_limit("Search", RateLimit::per_minute(2));
+        batcher.guard_event_names(EventNameGuard::block(3));
+        // The messages of a group are rate limited against each other.
+        batcher
+            .push_all((0..5).map(|_| track("foo", "Search")))
+            .unwrap();
+        assert_eq!(batcher.len(), 2);
+        assert_eq!(batcher.metrics().rate_limited()["Search"], 3);
+
+        // A group failing midway leaves the batcher as it was.
+        let mut large = track("foo", "Bought");
+        large.properties = Properties::new().with("blob", "x".repeat(MAX_MESSAGE_SIZE));
+        let group = vec![track("bar", "Search"), track("foo", "Viewed"), large];
+        assert!(matches!(
+            batcher.push_all(group),
+            Err(Error::MessageTooLarge)
+        ));
+        assert_eq!(batcher.len(), 2);
+        batcher.push(track("bar", "Search")).unwrap();
+        batcher.push(track("bar", "Search")).unwrap();
+        batcher.push(track("foo", "Other")).unwrap();
+        batcher.push(track("foo", "Bought")).unwrap();
+        assert_eq!(batcher.len(), 6);
+        assert_eq!(batcher.metrics().rate_limited()["Search"], 3);
+        assert_eq!(batcher.metrics().blocked_event_names(), 0);
+
+        // A group which doesn't fit is handed back as it was given, and
+        // nothing is counted.
+        batcher.set_max_batch_messages(6);
+        let group = vec![track("baz", "Search"), track("baz", "New")];
+        let rejected = batcher.push_all(group.clone()).unwrap().unwrap();
+        let group: Vec<BatchMessage> = group.into_iter().map(Into::into).collect();
+        assert_eq!(rejected, group);
+        assert_eq!(batcher.metrics().blocked_event_names(), 0);
+        batcher.take();
+        batcher.push_all(rejected).unwrap();
+        assert_eq!(batcher.len(), 1);
+        assert_eq!(batcher.metrics().blocked_event_names(), 1);
+    }
+
+    #[test]
+    fn test_rate_limit_exempt_without_sampling() {
+        let config = Config::from_json_str(
+            r#"{
+                "rate_limits": { "Search": { "max": 1, "per": "1m" } },
+                "sampling": { "exempt_users": ["qa"], "exempt_events": ["Bought"] }
+            }"#,
+        )
+        .unwrap();
+        let mut batcher = Batcher::new(None);
+        batcher.apply_config(&config);
+        for i in 0..100 {
+            batcher.push(track(&i.to_string(), "Bought")).unwrap();
+            batcher.push(track("qa", "Search")).unwrap();
+        }
+        batcher.push(track("foo", "Search")).unwrap();
+        batcher.push(track("foo", "Search")).unwrap();
+        // Only the second search of a user who is not exempt is dropped.
+        assert_eq!(batcher.len(), 201);
+        assert_eq!(batcher.metrics().sampled_out(), 0);
+        assert_eq!(batcher.metrics().rate_limited()["Search"], 1);
+    }
+
+    #[test]
+    fn test_route() {
+        let mut batcher = Batcher::new(None);
+        batcher.route(
+            "Debug Ping",
+            json!({ "All": false, "Data Warehouse": true }),
+        );
+        let routed = |event: &str, integrations| Track {
+            integrations,
+            ..track("foo", event)
+        };
+        batcher.push(routed("Debug Ping", None)).unwrap();
+        batcher
+            .push(routed("Debug Ping", Some(json!({ "All": true }))))
+            .unwrap();
+        batcher.push(routed("Bought", None)).unwrap();
+
+        let integrations: Vec<_> = batcher
+            .take()
+            .batch
+            .into_iter()
+            .map(|msg| match msg {
+                BatchMessage::Track(track) => track.integrations,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            integrations,
+            [
+                Some(json!({ "All": false, "Data Warehouse": true })),
+                Some(json!({ "All": true, "Data Warehouse": true })),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_track_sessions() {
+        let clock = MockClock::new(OffsetDateTime::UNIX_EPOCH + Duration::from_secs(1));
+        let mut batcher = Batcher::new(None);
+        batcher.set_clock(clock.clone());
+        batcher.track_sessions(SessionConfig {
+            timeout: Duration::from_secs(60),
+            field: SessionField::Properties,
+            stamp_group_id: false,
+        });
+        batcher.push(track("foo", "Example")).unwrap();
+        clock.advance(Duration::from_secs(60));
+        batcher.push(track("foo", "Example")).unwrap();
+        batcher.push(track("bar", "Example")).unwrap();
+        batcher
+            .push(Identify {
+                user: User::UserId {
+                    user_id: "foo".to_owned(),
+                },
+                ..Default::default()
+            })
+            .unwrap();
+        clock.advance(Duration::from_secs(61));
+        batcher.push(track("foo", "Example")).unwrap();
+
+        let batch = batcher.take().batch;
+        let session_ids: Vec<_> = batch
+            .iter()
+            .map(|msg| match msg {
+                BatchMessage::Track(track) => track.properties["session_id"].clone(),
+                BatchMessage::Identify(identify) => {
+                    identify.context.as_ref().unwrap()["session_id"].clone()
+                }
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            session_ids,
+            vec![
+                json!(1000),
+                json!(1000),
+                json!(61000),
+                json!(1000),
+                json!(122000)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stamp_group_id() {
+        let clock = MockClock::new(OffsetDateTime::UNIX_EPOCH);
+        let mut batcher = Batcher::new(None);
+        batcher.set_clock(clock.clone());
+        batcher.track_sessions(SessionConfig {
+            timeout: Duration::from_secs(60),
+            stamp_group_id: true,
+            ..Default::default()
+        });
+        let user = |user_id: &str| User::UserId {
+            user_id: user_id.to_owned(),
+        };
+        batcher.push(track("foo", "Example")).unwrap();
+        batcher
+            .push(Group {
+                user: user("foo"),
+                group_id: "acme".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+        batcher.push(track("foo", "Example")).unwrap();
+        batcher.push(track("bar", "Example")).unwrap();
+        batcher
+            .push(Track {
+                context: Some(json!({ "groupId": "initech" })),
+                ..track("foo", "Example")
+            })
+            .unwrap();
+        clock.advance(Duration::from_secs(61));
+        batcher.push(track("foo", "Example")).unwrap();
+
+        let group_ids: Vec<_> = batcher
+            .take()
+            .batch
+            .iter()
+            .map(|msg| {
+                msg.context()
+                    .and_then(|context| context.get("groupId"))
+                    .cloned()
+            })
+            .collect();
+        assert_eq!(
+            group_ids,
+            [
+                None,
+                None,
+                Some(json!("acme")),
+                None,
+                Some(json!("initech")),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trait_cache() {
+        let mut batcher = Batcher::new(None);
+        batcher.enable_trait_cache();
+        let user = User::UserId {
+            user_id: "foo".to_owned(),
+        };
+
+        batcher
+            .push(Identify {
+                user: user.clone(),
+                traits: Traits::new()
+                    .with("plan", "pro")
+                    .with("email", "foo@example.com"),
+                ..Default::default()
+            })
+            .unwrap();
+        batcher
+            .push(Track {
+                user: user.clone(),
+                event: "Example".to_owned(),
+                context: Some(json!({ "traits": { "plan": "free" } })),
+                ..Default::default()
+            })
+            .unwrap();
+        batcher
+            .push(Track {
+                user: User::AnonymousId {
+                    anonymous_id: "bar".to_owned(),
+                },
+                event: "Example".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let batch = batcher.take().batch;
+        assert_eq!(
+            batch[1].clone().context_mut().take(),
+            Some(json!({ "traits": { "plan": "free", "email": "foo@example.com" } }))
+        );
+        assert_eq!(batch[2].clone().context_mut().take(), None);
+    }
 }
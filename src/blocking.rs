@@ -0,0 +1,231 @@
+//! A handle batching messages on background threads, for applications which
+//! don't run an async runtime.
+
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::analytics::Task;
+use crate::message::BatchMessage;
+use crate::{runtime, Analytics, AutoBatcher, Client, Config, Error, Metrics, Result};
+
+/// A handle to background threads batching messages and sending them to
+/// Segment, for game servers, daemons and other applications without an
+/// async runtime.
+///
+/// It works like [`Analytics`], with the same flush semantics: a batch is
+/// sent whenever it is full, and at least every `flush_interval`. Each
+/// partition runs on its own thread, so the messages of a given user are still
+/// sent in order.
+///
+/// None of the threads needs a runtime set up by the application. With the
+/// `tokio` or `smol` feature, each one drives a single-threaded runtime of its
+/// own. Without either, they only use std threads and channels, with their
+/// timers fired by a helper thread. [`HttpClient`](crate::HttpClient) is built
+/// on `reqwest` though, which needs Tokio: sending through it needs the
+/// `tokio` feature, while a [`Client`] of your own may need none.
+///
+/// `BlockingAnalytics` is cheap to clone, and all the clones share the same
+/// threads. It is usually built with
+/// [`ClientBuilder::build_blocking`](crate::ClientBuilder::build_blocking):
+///
+/// ```no_run
+/// use segment::ClientBuilder;
+/// use segment::message::{Track, User};
+///
+/// fn main() -> segment::Result<()> {
+///     let analytics = ClientBuilder::from_env()?.partitions(4).build_blocking()?;
+///
+///     analytics.enqueue(Track {
+///         user: User::UserId { user_id: "user".to_owned() },
+///         event: "Example".to_owned(),
+///         ..Default::default()
+///     })?;
+///
+///     analytics.close()
+/// }
+/// ```
+///
+/// The methods which don't wait, such as
+/// [`enqueue_at`](Analytics::enqueue_at), are available on the underlying
+/// [`analytics`](BlockingAnalytics::analytics) handle.
+#[derive(Clone, Debug)]
+pub struct BlockingAnalytics {
+    analytics: Analytics,
+    threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl BlockingAnalytics {
+    /// Start `threads` background threads, each sending messages through a
+    /// clone of `batcher`, flushing it at least every `flush_interval`.
+    ///
+    /// Messages are assigned to a thread like to the partitions of
+    /// [`Analytics::partitioned`], which lists the restrictions on `batcher`.
+    ///
+    /// Returns an error if a thread could not be started.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `threads` is zero.
+    pub fn new<C>(batcher: AutoBatcher<C>, flush_interval: Duration, threads: usize) -> Result<Self>
+    where
+        C: Client + Clone + Send + Sync + 'static,
+    {
+        assert!(threads > 0, "there must be at least one thread");
+        let mut started = Vec::with_capacity(threads);
+        let analytics = Analytics::spawn_with(vec![batcher; threads], flush_interval, |task| {
+            let name = format!("segment-{}", started.len());
+            started.push(spawn_thread(name, task));
+        });
+        // The threads already started stop once `analytics` is dropped.
+        let threads = started.into_iter().collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            analytics,
+            threads: Arc::new(Mutex::new(threads)),
+        })
+    }
+
+    /// The same threads, fed from `analytics`, e.g. a handle with a
+    /// namespace.
+    pub(crate) fn with_analytics(self, analytics: Analytics) -> Self {
+        Self { analytics, ..self }
+    }
+
+    /// The handle the background threads are fed from.
+    pub fn analytics(&self) -> &Analytics {
+        &self.analytics
+    }
+
+    /// Queue a message to be sent in the background. See
+    /// [`Analytics::enqueue`].
+    pub fn enqueue(&self, msg: impl Into<BatchMessage>) -> Result<()> {
+        self.analytics.enqueue(msg)
+    }
+
+    /// Queue a message to be sent in the background, unless the queue has
+    /// reached the high-water mark. See [`Analytics::enqueue_if_room`].
+    pub fn enqueue_if_room(&self, msg: impl Into<BatchMessage>) -> Result<()> {
+        self.analytics.enqueue_if_room(msg)
+    }
+
+    /// Queue a message, reporting errors to the error hook instead of
+    /// returning them. See [`Analytics::try_enqueue`].
+    pub fn try_enqueue(&self, msg: impl Into<BatchMessage>) {
+        self.analytics.try_enqueue(msg)
+    }
+
+    /// Call `hook` with the errors happening in the background threads,
+    /// instead of logging them.
+    pub fn on_error(&self, hook: impl Fn(&Error) + Send + Sync + 'static) {
+        self.analytics.on_error(hook)
+    }
+
+    /// The counters of the messages the background threads did not send.
+    pub fn metrics(&self) -> &Metrics {
+        self.analytics.metrics()
+    }
+
+    /// Apply `config` to the running pipeline, blocking until the messages
+    /// queued so far are sent with the previous settings. See
+    /// [`Analytics::reconfigure`].
+    pub fn reconfigure(&self, config: Config) -> Result<()> {
+        runtime::wait(self.analytics.reconfigure(config))
+    }
+
+    /// Send all the queued messages now, blocking until they are sent.
+    pub fn flush(&self) -> Result<()> {
+        runtime::wait(self.analytics.flush())
+    }
+
+    /// Send all the queued messages, and block until the background threads
+    /// stopped.
+    ///
+    /// Any later call on this handle or its clones returns an error.
+    pub fn close(&self) -> Result<()> {
+        let result = runtime::wait(self.analytics.close());
+        let threads = std::mem::take(&mut *self.threads.lock().unwrap());
+        for thread in threads {
+            if thread.join().is_err() {
+                log::error!("a segment background thread panicked");
+            }
+        }
+        result
+    }
+}
+
+/// Start a thread named `name`, running `task` to completion.
+pub(crate) fn spawn_thread(name: String, task: Task) -> Result<JoinHandle<()>> {
+    let thread = thread::Builder::new().name(name).spawn(move || {
+        if let Err(e) = runtime::block_on(task) {
+            log::error!("could not start the runtime of a background thread: {}", e);
+        }
+    })?;
+    Ok(thread)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Track, User};
+    use crate::test_utils::MockClient;
+    use crate::{Batcher, ClientBuilder, WriteKey};
+
+    fn track(user_id: &str) -> Track {
+        Track {
+            user: User::UserId {
+                user_id: user_id.to_owned(),
+            },
+            event: "Example".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_blocking() {
+        let client = MockClient::new();
+        let analytics = ClientBuilder::new(WriteKey::new("key").unwrap())
+            .partitions(3)
+            .build_blocking_with_client(client.clone())
+            .unwrap();
+
+        let producers: Vec<_> = (0..4)
+            .map(|i| {
+                let analytics = analytics.clone();
+                thread::spawn(move || {
+                    for j in 0..25 {
+                        analytics.enqueue(track(&format!("{}-{}", i, j))).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        analytics.flush().unwrap();
+        assert_eq!(client.batch_messages().len(), 100);
+
+        analytics.enqueue(track("foo")).unwrap();
+        analytics.close().unwrap();
+        assert_eq!(client.batch_messages().len(), 101);
+        assert!(matches!(
+            analytics.enqueue(track("foo")),
+            Err(Error::Closed)
+        ));
+    }
+
+    #[test]
+    fn test_flush_interval() {
+        let client = MockClient::new();
+        let batcher = AutoBatcher::new(
+            client.clone(),
+            Batcher::new(None),
+            WriteKey::new("key").unwrap(),
+        );
+        let analytics = BlockingAnalytics::new(batcher, Duration::from_millis(50), 1).unwrap();
+
+        analytics.enqueue(track("foo")).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(client.batch_messages().len(), 1);
+        analytics.close().unwrap();
+    }
+}
@@ -0,0 +1,837 @@
+//! Configuration of a client from code and from the environment.
+
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::analytics::ErrorHook;
+use crate::blocking;
+use crate::config::{parse_bool, parse_duration};
+use crate::disabled::DisabledClient;
+use crate::http;
+use crate::provider::ProvidedProperties;
+use crate::retry_budget::AlertHook;
+#[cfg(any(feature = "tokio", feature = "smol"))]
+use crate::runtime;
+use crate::{
+    Analytics, AuditFile, AutoBatcher, Batcher, BlockingAnalytics, Client, Clock, Compression,
+    Config, ContextPrivacy, DeadLetterFile, DeadLetterRotation, Error, ErrorPolicy, EventNameGuard,
+    FieldHashing, Filter, FlushWindow, Heartbeat, HttpClient, IdGenerator, LargeIntegerPolicy,
+    PropertyProvider, PruningRules, RateLimit, Recorder, Redactor, Result, RetryBudget,
+    RetryBudgetAlert, RetryPolicy, Sampling, SessionConfig, Spool, SpoolKey, Transform, Truncation,
+    ValidationMode, WriteKey,
+};
+
+/// A builder for an [`HttpClient`] and the [`Analytics`] pipeline on top of
+/// it.
+///
+/// Settings can be loaded from a [`Config`] with
+/// [`from_config`](ClientBuilder::from_config), read from the environment with
+/// [`from_env`](ClientBuilder::from_env), and then overridden from code:
+///
+/// | Variable                  | Setting                                              |
+/// |---------------------------|------------------------------------------------------|
+/// | `SEGMENT_WRITE_KEY`       | [`write_key`](ClientBuilder::write_key)              |
+/// | `SEGMENT_HOST`            | [`host`](ClientBuilder::host)                        |
+/// | `SEGMENT_FLUSH_INTERVAL`  | [`flush_interval`](ClientBuilder::flush_interval)    |
+/// | `SEGMENT_CONNECT_TIMEOUT` | [`connect_timeout`](ClientBuilder::connect_timeout)  |
+/// | `SEGMENT_TIMEOUT`         | [`timeout`](ClientBuilder::timeout)                  |
+/// | `SEGMENT_DEBUG`           | [`debug_logging`](ClientBuilder::debug_logging)      |
+/// | `SEGMENT_STRICT`          | [`strict`](ClientBuilder::strict)                    |
+/// | `SEGMENT_MAX_RETRIES`     | [`retry`](ClientBuilder::retry)                      |
+/// | `SEGMENT_DISABLE`         | [`disabled`](ClientBuilder::disabled)                |
+///
+/// Durations are given as a number followed by a unit (`ms`, `s`, `m` or
+/// `h`), or as a number of seconds without unit. Booleans are given as
+/// `true`/`false`, `yes`/`no`, `on`/`off` or `1`/`0`.
+///
+/// ```no_run
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> segment::Result<()> {
+/// use std::time::Duration;
+/// use segment::ClientBuilder;
+///
+/// let analytics = ClientBuilder::from_env()?
+///     .flush_interval(Duration::from_secs(30))
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ClientBuilder {
+    config: Config,
+    redactor: Option<Redactor>,
+    error_hook: Option<ErrorHook>,
+    budget_alert: Option<AlertHook>,
+    clock: Option<Arc<dyn Clock>>,
+    id_generator: Option<Arc<dyn IdGenerator>>,
+    providers: Vec<ProvidedProperties>,
+}
+
+impl ClientBuilder {
+    /// Construct a builder with the default settings, sending messages with
+    /// the given write key.
+    pub fn new(write_key: WriteKey) -> Self {
+        Self::default().write_key(write_key)
+    }
+
+    /// Construct a builder with the given settings.
+    pub fn from_config(config: Config) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    /// Construct a builder with the settings found in the `SEGMENT_*`
+    /// environment variables, and the default settings otherwise.
+    ///
+    /// Returns an error if a variable is set to an invalid value.
+    pub fn from_env() -> Result<Self> {
+        Self::default().with_env()
+    }
+
+    /// Override the settings of this builder with the ones found in the
+    /// `SEGMENT_*` environment variables.
+    ///
+    /// Returns an error if a variable is set to an invalid value, except for
+    /// `SEGMENT_DISABLE`, which then disables sending, as it does in
+    /// [`build`](ClientBuilder::build).
+    pub fn with_env(self) -> Result<Self> {
+        self.with_vars(|name| env::var(name).ok())
+    }
+
+    pub(crate) fn with_vars(mut self, var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let config = &mut self.config;
+        if let Some(key) = var("SEGMENT_WRITE_KEY") {
+            config.write_key = Some(WriteKey::new(key)?);
+        }
+        if let Some(host) = var("SEGMENT_HOST") {
+            config.host = host;
+        }
+        if let Some(interval) = var("SEGMENT_FLUSH_INTERVAL") {
+            config.flush_interval = parse_duration("SEGMENT_FLUSH_INTERVAL", &interval)?;
+        }
+        if let Some(timeout) = var("SEGMENT_CONNECT_TIMEOUT") {
+            config.connect_timeout = parse_duration("SEGMENT_CONNECT_TIMEOUT", &timeout)?;
+        }
+        if let Some(timeout) = var("SEGMENT_TIMEOUT") {
+            config.timeout = Some(parse_duration("SEGMENT_TIMEOUT", &timeout)?);
+        }
+        if let Some(debug) = var("SEGMENT_DEBUG") {
+            config.debug = parse_bool("SEGMENT_DEBUG", &debug)?;
+        }
+        if let Some(strict) = var("SEGMENT_STRICT") {
+            config.strict = parse_bool("SEGMENT_STRICT", &strict)?;
+        }
+        if let Some(disabled) = var("SEGMENT_DISABLE") {
+            config.disabled = parse_disable(&disabled);
+        }
+        if let Some(retries) = var("SEGMENT_MAX_RETRIES") {
+            config.retry.max_retries = retries.trim().parse().map_err(|_| {
+                Error::InvalidConfig(format!(
+                    "SEGMENT_MAX_RETRIES is not a valid number: {:?}",
+                    retries
+                ))
+            })?;
+        }
+        Ok(self)
+    }
+
+    /// The settings of this builder.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Send messages with the given write key.
+    pub fn write_key(mut self, write_key: WriteKey) -> Self {
+        self.config.write_key = Some(write_key);
+        self
+    }
+
+    /// Send messages to the given scheme and host instead of
+    /// `https://api.segment.io`.
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.config.host = host.into();
+        self
+    }
+
+    /// Send the queued messages at least this often. Defaults to 10 seconds.
+    pub fn flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.config.flush_interval = flush_interval;
+        self
+    }
+
+    /// Give up connecting to Segment's API after this long. Defaults to 10
+    /// seconds.
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.config.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Give up on a request to Segment's API after this long. There is no
+    /// timeout by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeout = Some(timeout);
+        self
+    }
+
+    /// Set this context on every batch sent.
+    pub fn context(mut self, context: Value) -> Self {
+        self.config.context = Some(context);
+        self
+    }
+
+    /// Log outgoing payloads, masking the fields known to `redactor`. See
+    /// [`HttpClient::enable_debug_logging`].
+    pub fn debug_logging(mut self, redactor: Redactor) -> Self {
+        self.config.debug = true;
+        self.redactor = Some(redactor);
+        self
+    }
+
+    /// Record whether each request reused a connection. See
+    /// [`HttpClient::enable_connection_audit`].
+    pub fn audit_connections(mut self, audit: bool) -> Self {
+        self.config.audit_connections = audit;
+        self
+    }
+
+    /// Gzip the bodies of requests as `compression` says. See
+    /// [`HttpClient::enable_compression`].
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.config.compression = Some(compression);
+        self
+    }
+
+    /// Reject incomplete messages instead of sending them. See
+    /// [`Batcher::enable_strict_mode`].
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.config.strict = strict;
+        self
+    }
+
+    /// Warn about the fields of message contexts outside of the common spec.
+    /// See [`Batcher::enable_context_check`].
+    pub fn check_context(mut self, check: bool) -> Self {
+        self.config.check_context = check;
+        self
+    }
+
+    /// Validate messages in strict mode when they are queued, or when they
+    /// are flushed. See [`ValidationMode`].
+    pub fn validation_mode(mut self, mode: ValidationMode) -> Self {
+        self.config.validation_mode = mode;
+        self
+    }
+
+    /// Drop `page` and `screen` events identical to the previous one of the
+    /// same user within `window`. See [`Batcher::deduplicate_views`].
+    pub fn deduplicate_views(mut self, window: Duration) -> Self {
+        self.config.deduplicate_views = Some(window);
+        self
+    }
+
+    /// Drop `group` messages identical to the previous one of the same user
+    /// and group, until `ttl` after it. See [`Batcher::deduplicate_groups`].
+    pub fn deduplicate_groups(mut self, ttl: Duration) -> Self {
+        self.config.deduplicate_groups = Some(ttl);
+        self
+    }
+
+    /// Set the traits of identified users in the context of their later
+    /// events. See [`Batcher::enable_trait_cache`].
+    pub fn cache_traits(mut self, cache_traits: bool) -> Self {
+        self.config.cache_traits = cache_traits;
+        self
+    }
+
+    /// Set a session ID on every message. See [`Batcher::track_sessions`].
+    pub fn track_sessions(mut self, sessions: SessionConfig) -> Self {
+        self.config.sessions = Some(sessions);
+        self
+    }
+
+    /// Strip or coarsen the IP address and user agent of the context. See
+    /// [`Batcher::set_context_privacy`].
+    pub fn context_privacy(mut self, privacy: ContextPrivacy) -> Self {
+        self.config.context_privacy = Some(privacy);
+        self
+    }
+
+    /// Send the traits and properties listed by `hashing`, such as the email,
+    /// hashed. See [`Batcher::set_field_hashing`].
+    pub fn hash_fields(mut self, hashing: FieldHashing) -> Self {
+        self.config.hashed_fields = Some(hashing);
+        self
+    }
+
+    /// Remove the properties and traits only used by disabled destinations.
+    /// See [`Batcher::set_pruning_rules`].
+    pub fn prune_fields(mut self, rules: PruningRules) -> Self {
+        self.config.pruning = Some(rules);
+        self
+    }
+
+    /// Stringify or reject the integers of the given fields which don't fit
+    /// in a double. See [`Batcher::set_large_integer_policy`].
+    pub fn large_integers(mut self, policy: LargeIntegerPolicy) -> Self {
+        self.config.large_integers = Some(policy);
+        self
+    }
+
+    /// Shrink the given properties of `track` events too large to be sent.
+    /// See [`Batcher::set_truncation`].
+    pub fn truncation(mut self, truncation: Truncation) -> Self {
+        self.config.truncation = Some(truncation);
+        self
+    }
+
+    /// Drop the messages matching `filter`. See [`Batcher::drop_matching`].
+    pub fn drop_matching(mut self, filter: Filter) -> Self {
+        self.config.filters.push(filter);
+        self
+    }
+
+    /// Apply `transform` to the messages it matches. See
+    /// [`Batcher::add_transform`].
+    pub fn transform(mut self, transform: Transform) -> Self {
+        self.config.transforms.push(transform);
+        self
+    }
+
+    /// Only keep the events of a fraction of the users. See
+    /// [`Batcher::set_sampling`].
+    pub fn sampling(mut self, sampling: Sampling) -> Self {
+        self.config.sampling = Some(sampling);
+        self
+    }
+
+    /// Warn about or drop the events named past a number of distinct event
+    /// names. See [`Batcher::guard_event_names`].
+    pub fn guard_event_names(mut self, guard: EventNameGuard) -> Self {
+        self.config.event_name_guard = Some(guard);
+        self
+    }
+
+    /// Prefix the names of `track` events with `prefix`. See
+    /// [`Analytics::with_namespace`].
+    pub fn event_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.config.event_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Limit how often each user may send the `track` event named `event`.
+    /// See [`Batcher::rate_limit`].
+    pub fn rate_limit(mut self, event: impl Into<String>, limit: RateLimit) -> Self {
+        self.config.rate_limits.insert(event.into(), limit);
+        self
+    }
+
+    /// Send the `track` events named `event` with `integrations`. See
+    /// [`Batcher::route`].
+    pub fn route(mut self, event: impl Into<String>, integrations: impl Into<Value>) -> Self {
+        self.config.routes.insert(event.into(), integrations.into());
+        self
+    }
+
+    /// Remember the addresses of Segment's API for `ttl` instead of resolving
+    /// them again for every new connection.
+    pub fn cache_dns(mut self, ttl: Duration) -> Self {
+        self.config.dns_cache_ttl = Some(ttl);
+        self
+    }
+
+    /// Connect to Segment's API in the background as soon as the pipeline is
+    /// built, so the first flush doesn't pay for the connection. See
+    /// [`HttpClient::warm_up`].
+    pub fn warm_up(mut self, warm_up: bool) -> Self {
+        self.config.warm_up = warm_up;
+        self
+    }
+
+    /// Send an `SDK Heartbeat` event with the statistics of the pipeline
+    /// periodically. See [`Analytics::start_heartbeat`].
+    pub fn heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.config.heartbeat = Some(heartbeat);
+        self
+    }
+
+    /// Only send batches during `window`, in addition to the other windows
+    /// given. See [`AutoBatcher::set_flush_windows`].
+    pub fn flush_window(mut self, window: FlushWindow) -> Self {
+        self.config.flush_windows.push(window);
+        self
+    }
+
+    /// Send a batch once its serialized size reaches this many bytes.
+    pub fn max_batch_bytes(mut self, max_bytes: usize) -> Self {
+        self.config.batch.max_bytes = max_bytes;
+        self
+    }
+
+    /// Send a batch once it contains this many messages.
+    pub fn max_batch_messages(mut self, max_messages: usize) -> Self {
+        self.config.batch.max_messages = Some(max_messages);
+        self
+    }
+
+    /// Retry failed requests according to this policy. Defaults to
+    /// [`RetryPolicy::default`].
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.config.retry = retry;
+        self
+    }
+
+    /// Alert when batches are retried more than `budget` allows. See
+    /// [`AutoBatcher::set_retry_budget`].
+    pub fn retry_budget(mut self, budget: RetryBudget) -> Self {
+        self.config.retry_budget = Some(budget);
+        self
+    }
+
+    /// Call `hook` instead of logging when the [retry
+    /// budget](ClientBuilder::retry_budget) is exceeded.
+    pub fn on_retry_budget_exceeded(
+        mut self,
+        hook: impl Fn(&RetryBudgetAlert) + Send + Sync + 'static,
+    ) -> Self {
+        self.budget_alert = Some(AlertHook(Arc::new(hook)));
+        self
+    }
+
+    /// Send the backlog left after a pause at most this many messages per
+    /// second. See [`AutoBatcher::set_catch_up_rate`].
+    pub fn catch_up_rate(mut self, messages_per_second: u32) -> Self {
+        self.config.catch_up_rate = Some(messages_per_second);
+        self
+    }
+
+    /// Handle the batches which fail according to `policy`. See
+    /// [`AutoBatcher::set_error_policy`].
+    pub fn error_policy(mut self, policy: ErrorPolicy) -> Self {
+        self.config.error_policy = policy;
+        self
+    }
+
+    /// Send batches from this many background tasks concurrently, keeping the
+    /// messages of each user in order. See [`Analytics::partitioned`].
+    pub fn partitions(mut self, partitions: usize) -> Self {
+        self.config.partitions = partitions;
+        self
+    }
+
+    /// Ask producers to slow down once this many messages are queued. See
+    /// [`Analytics::set_high_water_mark`].
+    pub fn high_water_mark(mut self, high_water: usize) -> Self {
+        self.config.high_water_mark = Some(high_water);
+        self
+    }
+
+    /// Keep the batches which were not sent yet in the given directory, so
+    /// they survive restarts. See [`Spool`].
+    pub fn spool(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.spool = Some(dir.into());
+        self
+    }
+
+    /// Sign the spooled batches with `key`, and refuse to send the ones whose
+    /// signature does not match. See [`Spool::open_signed`].
+    pub fn spool_key(mut self, key: impl Into<SpoolKey>) -> Self {
+        self.config.spool_key = Some(key.into());
+        self
+    }
+
+    /// Encrypt the spooled batches with `key`, and refuse to send the ones
+    /// which don't decrypt. See [`Spool::open_encrypted`].
+    pub fn spool_encryption_key(mut self, key: impl Into<SpoolKey>) -> Self {
+        self.config.spool_encryption_key = Some(key.into());
+        self
+    }
+
+    /// Re-encrypt the spooled batches encrypted with `keys`, the keys used
+    /// before the [`spool_encryption_key`](ClientBuilder::spool_encryption_key),
+    /// with it. See [`Spool::open_encrypted_with_previous`].
+    pub fn previous_spool_encryption_keys<I, S>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<SpoolKey>,
+    {
+        self.config.previous_spool_encryption_keys = keys.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Append a record of every message sent to the file at `path`. See
+    /// [`AuditFile`].
+    pub fn audit_log(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.audit_log = Some(path.into());
+        self
+    }
+
+    /// Keep the last `capacity` messages sent in memory, with their outcomes,
+    /// available from [`Analytics::recorder`]. See [`Recorder`].
+    pub fn recent_messages(mut self, capacity: usize) -> Self {
+        self.config.recent_messages = Some(capacity);
+        self
+    }
+
+    /// Append the messages which could not be delivered to the file at
+    /// `path`. See [`DeadLetterFile`].
+    pub fn dead_letter(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.dead_letter = Some(path.into());
+        self
+    }
+
+    /// Rotate the [dead letter file](ClientBuilder::dead_letter) according to
+    /// `rotation`. See [`DeadLetterFile::open_rotating`].
+    pub fn rotate_dead_letters(mut self, rotation: DeadLetterRotation) -> Self {
+        self.config.dead_letter_rotation = Some(rotation);
+        self
+    }
+
+    /// Call `hook` with the errors happening in the background, instead of
+    /// logging them. See [`Analytics::on_error`].
+    pub fn on_error(mut self, hook: impl Fn(&Error) + Send + Sync + 'static) -> Self {
+        self.error_hook = Some(ErrorHook(Arc::new(hook)));
+        self
+    }
+
+    /// Timestamp messages with `clock` instead of the system time. See
+    /// [`Batcher::set_clock`].
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Generate the `messageId` of messages with `id_generator` instead of
+    /// random UUIDs. See [`Batcher::set_id_generator`].
+    pub fn id_generator(mut self, id_generator: impl IdGenerator + 'static) -> Self {
+        self.id_generator = Some(Arc::new(id_generator));
+        self
+    }
+
+    /// Add the properties of `provider` to the messages of every batch when
+    /// it is sent, giving up on it after `timeout`. See
+    /// [`AutoBatcher::add_property_provider`].
+    pub fn inject_properties(
+        mut self,
+        provider: impl PropertyProvider + 'static,
+        timeout: Duration,
+    ) -> Self {
+        self.providers.push(ProvidedProperties {
+            provider: Arc::new(provider),
+            timeout,
+        });
+        self
+    }
+
+    /// Never send messages over the network, e.g. while testing or in CI.
+    ///
+    /// [`build`](ClientBuilder::build) then returns a pipeline which doesn't
+    /// need a write key, and whose messages are recorded by
+    /// `test_utils::recorder` with the `test-utils` feature, or else dropped.
+    ///
+    /// `build` also checks the `SEGMENT_DISABLE` environment variable, even
+    /// if the builder was not made [from the environment](ClientBuilder::from_env),
+    /// and disables sending if it is set to `1`, or to a value which isn't a
+    /// boolean, so that no CI job sends events to production by accident.
+    ///
+    /// This crate can't know whether the application is compiled for its
+    /// tests, so pass `cfg!(test)` to disable it there.
+    pub fn disabled(mut self, disabled: bool) -> Self {
+        self.config.disabled = disabled;
+        self
+    }
+
+    /// Build the HTTP client described by this builder.
+    pub fn build_client(&self) -> Result<HttpClient> {
+        let config = &self.config;
+        let mut client = HttpClient::new(http::reqwest_client(config)?, config.host.clone());
+        if config.debug {
+            let redactor = match (&self.redactor, &config.redact) {
+                (Some(redactor), _) => redactor.clone(),
+                (None, Some(fields)) => Redactor::new(fields),
+                (None, None) => Redactor::default(),
+            };
+            client.enable_debug_logging(redactor);
+        }
+        if config.audit_connections {
+            client.enable_connection_audit();
+        }
+        if let Some(compression) = &config.compression {
+            client.enable_compression(compression.clone());
+        }
+        Ok(client)
+    }
+
+    /// Build the batcher described by this builder.
+    pub fn build_batcher(&self) -> Batcher {
+        let mut batcher = Batcher::new(None);
+        batcher.apply_config(&self.config);
+        if let Some(clock) = &self.clock {
+            batcher.clock = clock.clone();
+        }
+        if let Some(id_generator) = &self.id_generator {
+            batcher.id_generator = id_generator.clone();
+        }
+        batcher
+    }
+
+    /// Build the pipeline described by this builder, sending messages through
+    /// an [`HttpClient`].
+    ///
+    /// Returns an error if no write key was configured, or if the settings are
+    /// [invalid](Config::validate). See [`Analytics::new`] for the runtime
+    /// requirements.
+    ///
+    /// Nothing is sent if the builder is [disabled](ClientBuilder::disabled).
+    #[cfg(any(feature = "tokio", feature = "smol"))]
+    pub fn build(mut self) -> Result<Analytics> {
+        if self.config.disabled || disabled_by_env() {
+            if self.config.write_key.is_none() {
+                self.config.write_key = Some(WriteKey::new("disabled")?);
+            }
+            return self.build_with_client(DisabledClient);
+        }
+        let client = self.build_client()?;
+        let warm_up = self.config.warm_up.then(|| client.clone());
+        let analytics = self.build_with_client(client)?;
+        if let Some(client) = warm_up {
+            runtime::spawn(warm_up_task(client));
+        }
+        Ok(analytics)
+    }
+
+    /// Build the pipeline described by this builder, sending messages through
+    /// the given client.
+    ///
+    /// Returns an error if no write key was configured, or if the settings are
+    /// [invalid](Config::validate). See [`Analytics::new`] for the runtime
+    /// requirements.
+    #[cfg(any(feature = "tokio", feature = "smol"))]
+    pub fn build_with_client<C>(self, client: C) -> Result<Analytics>
+    where
+        C: Client + Clone + Send + Sync + 'static,
+    {
+        let batcher = self.build_auto_batcher(client)?;
+        let analytics =
+            Analytics::partitioned(batcher, self.config.flush_interval, self.config.partitions);
+        self.finish(analytics, |analytics, heartbeat| {
+            analytics.start_heartbeat(heartbeat);
+            Ok(())
+        })
+    }
+
+    /// Build the pipeline described by this builder, sending messages through
+    /// an [`HttpClient`] from background threads, one per
+    /// [partition](ClientBuilder::partitions), for applications without an
+    /// async runtime. See [`BlockingAnalytics`].
+    ///
+    /// [`HttpClient`] is built on `reqwest`, which needs a Tokio runtime, so
+    /// this needs the `tokio` feature, with which each thread drives its own.
+    /// Use [`build_blocking_with_client`](ClientBuilder::build_blocking_with_client)
+    /// and a [`Client`] which doesn't need Tokio to go without.
+    ///
+    /// Returns an error if no write key was configured, if the settings are
+    /// [invalid](Config::validate), or if a thread could not be started.
+    ///
+    /// Nothing is sent if the builder is [disabled](ClientBuilder::disabled).
+    pub fn build_blocking(mut self) -> Result<BlockingAnalytics> {
+        if self.config.disabled || disabled_by_env() {
+            if self.config.write_key.is_none() {
+                self.config.write_key = Some(WriteKey::new("disabled")?);
+            }
+            return self.build_blocking_with_client(DisabledClient);
+        }
+        let client = self.build_client()?;
+        let warm_up = self.config.warm_up.then(|| client.clone());
+        let analytics = self.build_blocking_with_client(client)?;
+        if let Some(client) = warm_up {
+            blocking::spawn_thread("segment-warm-up".to_owned(), Box::pin(warm_up_task(client)))?;
+        }
+        Ok(analytics)
+    }
+
+    /// Build the pipeline described by this builder, sending messages through
+    /// the given client from background threads. See
+    /// [`build_blocking`](ClientBuilder::build_blocking).
+    ///
+    /// This needs no runtime feature, unless `client` needs a runtime itself.
+    pub fn build_blocking_with_client<C>(self, client: C) -> Result<BlockingAnalytics>
+    where
+        C: Client + Clone + Send + Sync + 'static,
+    {
+        let batcher = self.build_auto_batcher(client)?;
+        let blocking =
+            BlockingAnalytics::new(batcher, self.config.flush_interval, self.config.partitions)?;
+        let analytics = self.finish(blocking.analytics().clone(), |analytics, heartbeat| {
+            let task = Box::pin(analytics.heartbeat(heartbeat));
+            blocking::spawn_thread("segment-heartbeat".to_owned(), task).map(drop)
+        })?;
+        Ok(blocking.with_analytics(analytics))
+    }
+
+    /// Build the batcher of the background tasks described by this builder.
+    fn build_auto_batcher<C>(&self, client: C) -> Result<AutoBatcher<C>>
+    where
+        C: Client + Clone + Send + Sync + 'static,
+    {
+        self.config.validate()?;
+        let write_key = self
+            .config
+            .write_key
+            .clone()
+            .ok_or_else(|| Error::InvalidConfig("no write key was configured".to_owned()))?;
+        let mut batcher = AutoBatcher::new(client, self.build_batcher(), write_key);
+        batcher.set_retry_policy(self.config.retry);
+        if let Some(budget) = self.config.retry_budget {
+            batcher.set_retry_budget(budget);
+        }
+        if let Some(rate) = self.config.catch_up_rate {
+            batcher.set_catch_up_rate(rate);
+        }
+        if let Some(AlertHook(hook)) = self.budget_alert.clone() {
+            batcher.on_retry_budget_exceeded(move |alert| hook(alert));
+        }
+        batcher.set_error_policy(self.config.error_policy.clone());
+        batcher.set_flush_windows(self.config.flush_windows.clone());
+        batcher.providers.extend(self.providers.iter().cloned());
+        if let Some(dir) = &self.config.spool {
+            batcher.set_spool(
+                match (&self.config.spool_encryption_key, &self.config.spool_key) {
+                    (Some(key), _) => Spool::open_encrypted_with_previous(
+                        dir,
+                        key.clone(),
+                        self.config.previous_spool_encryption_keys.iter().cloned(),
+                    )?,
+                    (None, Some(key)) => Spool::open_signed(dir, key.clone())?,
+                    (None, None) => Spool::open(dir)?,
+                },
+            );
+        }
+        if let Some(path) = &self.config.audit_log {
+            batcher.set_audit_log(AuditFile::open(path)?);
+        }
+        if let Some(capacity) = self.config.recent_messages {
+            batcher.set_recorder(Recorder::new(capacity));
+        }
+        if let Some(path) = &self.config.dead_letter {
+            batcher.set_dead_letter(match self.config.dead_letter_rotation {
+                Some(rotation) => DeadLetterFile::open_rotating(path, rotation)?,
+                None => DeadLetterFile::open(path)?,
+            });
+        }
+        Ok(batcher)
+    }
+
+    /// Apply the settings of the handle to the freshly spawned `analytics`,
+    /// starting its heartbeat with `start_heartbeat`.
+    fn finish(
+        self,
+        analytics: Analytics,
+        start_heartbeat: impl FnOnce(&Analytics, Heartbeat) -> Result<()>,
+    ) -> Result<Analytics> {
+        analytics.set_high_water_mark(self.config.high_water_mark);
+        if let Some(hook) = self.error_hook {
+            analytics.set_error_hook(hook);
+        }
+        if let Some(heartbeat) = self.config.heartbeat {
+            start_heartbeat(&analytics, heartbeat)?;
+        }
+        Ok(match self.config.event_prefix {
+            Some(prefix) => analytics.with_namespace(prefix),
+            None => analytics,
+        })
+    }
+}
+
+/// Connect to Segment's API ahead of the first flush.
+async fn warm_up_task(client: HttpClient) {
+    if let Err(e) = client.warm_up().await {
+        log::warn!("could not connect to Segment: {}", e);
+    }
+}
+
+/// Whether the `SEGMENT_DISABLE` environment variable disables sending.
+fn disabled_by_env() -> bool {
+    env::var("SEGMENT_DISABLE").is_ok_and(|disabled| parse_disable(&disabled))
+}
+
+/// Whether `value` of `SEGMENT_DISABLE` disables sending. Invalid values do
+/// too, to err on the side of not sending.
+fn parse_disable(value: &str) -> bool {
+    parse_bool("SEGMENT_DISABLE", value).unwrap_or_else(|e| {
+        log::warn!("{}, not sending anything", e);
+        true
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_from_vars() {
+        let vars: HashMap<_, _> = [
+            ("SEGMENT_WRITE_KEY", "abcdEFGH1234"),
+            ("SEGMENT_HOST", "http://localhost:8080"),
+            ("SEGMENT_FLUSH_INTERVAL", "500ms"),
+            ("SEGMENT_TIMEOUT", "2.5"),
+            ("SEGMENT_STRICT", "yes"),
+            ("SEGMENT_MAX_RETRIES", "7"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        let builder = ClientBuilder::default()
+            .with_vars(|name| vars.get(name).cloned())
+            .unwrap();
+        let config = builder.config();
+        assert_eq!(config.write_key.as_ref().unwrap().expose(), "abcdEFGH1234");
+        assert_eq!(config.host, "http://localhost:8080");
+        assert_eq!(config.flush_interval, Duration::from_millis(500));
+        assert_eq!(config.timeout, Some(Duration::from_millis(2500)));
+        assert!(config.strict);
+        assert_eq!(config.retry.max_retries, 7);
+        assert!(!format!("{:?}", builder).contains("abcdEFGH1234"));
+
+        let builder = builder.flush_interval(Duration::from_secs(1));
+        assert_eq!(builder.config().flush_interval, Duration::from_secs(1));
+
+        let invalid = ClientBuilder::default().with_vars(|name| {
+            Some("forever".to_owned()).filter(|_| name == "SEGMENT_FLUSH_INTERVAL")
+        });
+        assert!(matches!(invalid, Err(Error::InvalidConfig(_))));
+
+        // An invalid SEGMENT_DISABLE disables sending, as it does in build.
+        let disabled = ClientBuilder::default()
+            .with_vars(|name| Some("maybe".to_owned()).filter(|_| name == "SEGMENT_DISABLE"))
+            .unwrap();
+        assert!(disabled.config().disabled);
+    }
+
+    #[cfg(any(feature = "tokio", feature = "smol"))]
+    #[tokio::test]
+    async fn test_disabled() {
+        use crate::message::{Track, User};
+
+        let analytics = ClientBuilder::default().disabled(true).build().unwrap();
+        let user_id = uuid::Uuid::new_v4().to_string();
+        analytics
+            .enqueue(Track {
+                user: User::UserId {
+                    user_id: user_id.clone(),
+                },
+                event: "Tested".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+        analytics.close().await.unwrap();
+        assert_eq!(crate::test_utils::recorder().for_user(&user_id).len(), 1);
+    }
+}
@@ -0,0 +1,141 @@
+//! Guarding against an explosion of distinct event names.
+
+use std::collections::HashSet;
+
+use serde::Deserialize;
+
+use crate::message::BatchMessage;
+
+/// What to do with the `track` events whose name is new once the
+/// [`EventNameGuard`] limit is reached.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CardinalityAction {
+    /// Send them, logging a warning the first time the limit is passed.
+    #[default]
+    Warn,
+    /// Drop them, logging a warning the first time the limit is passed.
+    Block,
+}
+
+/// A limit on the number of distinct `track` event names sent. See
+/// [`Batcher::guard_event_names`](crate::Batcher::guard_event_names).
+///
+/// Event names are meant to come from a small fixed set, and each of them
+/// becomes a table in most warehouses. Thousands of distinct names are
+/// usually the symptom of a value interpolated into them, such as
+/// `Viewed Product 1234`.
+///
+/// It deserializes from:
+///
+/// ```json
+/// { "max_events": 500, "action": "block" }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EventNameGuard {
+    /// The number of distinct event names accepted.
+    pub max_events: usize,
+    /// What to do with the events named otherwise past that number.
+    #[serde(default)]
+    pub action: CardinalityAction,
+}
+
+impl EventNameGuard {
+    /// Warn once more than `max_events` distinct event names were seen.
+    pub fn warn(max_events: usize) -> Self {
+        Self {
+            max_events,
+            action: CardinalityAction::Warn,
+        }
+    }
+
+    /// Drop the events with a new name once `max_events` distinct event names
+    /// were seen.
+    pub fn block(max_events: usize) -> Self {
+        Self {
+            max_events,
+            action: CardinalityAction::Block,
+        }
+    }
+}
+
+/// The event names seen so far, up to the limit of the guard.
+#[derive(Clone, Debug)]
+pub(crate) struct EventNames {
+    pub(crate) guard: EventNameGuard,
+    seen: HashSet<String>,
+    exceeded: bool,
+}
+
+impl EventNames {
+    pub(crate) fn new(guard: EventNameGuard) -> Self {
+        Self {
+            guard,
+            seen: HashSet::new(),
+            exceeded: false,
+        }
+    }
+
+    /// Record the name of `msg` if it is a `track` event, returning it if the
+    /// event must be dropped because it is new past the limit.
+    pub(crate) fn check<'a>(&mut self, msg: &'a BatchMessage) -> Option<&'a str> {
+        let event = match msg {
+            BatchMessage::Track(track) => track.event.as_str(),
+            _ => return None,
+        };
+        if self.seen.contains(event) {
+            return None;
+        }
+        if self.seen.len() < self.guard.max_events {
+            self.seen.insert(event.to_owned());
+            return None;
+        }
+        // Names past the limit are not remembered, so junk names don't grow
+        // the set without bound.
+        if !self.exceeded {
+            self.exceeded = true;
+            log::warn!(
+                "more than {} distinct event names were sent, the latest being {:?}; \
+                 are values interpolated into event names?",
+                self.guard.max_events,
+                event
+            );
+        }
+        match self.guard.action {
+            CardinalityAction::Warn => None,
+            CardinalityAction::Block => Some(event),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Identify, Track};
+
+    fn track(event: &str) -> BatchMessage {
+        BatchMessage::from(Track {
+            event: event.to_owned(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_event_names() {
+        let mut names = EventNames::new(EventNameGuard::block(2));
+        assert_eq!(names.check(&track("Signed Up")), None);
+        assert_eq!(names.check(&track("Viewed Product 1")), None);
+        assert_eq!(names.check(&track("Signed Up")), None);
+        assert_eq!(
+            names.check(&track("Viewed Product 2")),
+            Some("Viewed Product 2")
+        );
+        assert_eq!(names.check(&track("Viewed Product 1")), None);
+        assert_eq!(names.check(&Identify::default().into()), None);
+
+        let mut names = EventNames::new(EventNameGuard::warn(1));
+        assert_eq!(names.check(&track("Signed Up")), None);
+        assert_eq!(names.check(&track("Viewed Product 1")), None);
+    }
+}
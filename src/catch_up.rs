@@ -0,0 +1,71 @@
+//! Pacing the batches sent to catch up on a backlog.
+
+use time::OffsetDateTime;
+
+/// The messages which may be sent to catch up on a backlog, refilled at a
+/// steady rate. See
+/// [`AutoBatcher::set_catch_up_rate`](crate::AutoBatcher::set_catch_up_rate).
+#[derive(Clone, Debug)]
+pub(crate) struct CatchUp {
+    pub(crate) rate: u32,
+    /// The messages which may be sent right now, negative after a batch
+    /// larger than what was left.
+    budget: f64,
+    refilled: Option<OffsetDateTime>,
+}
+
+impl CatchUp {
+    /// At most `rate` messages per second, on average.
+    pub(crate) fn new(rate: u32) -> Self {
+        Self {
+            rate,
+            budget: f64::from(rate),
+            refilled: None,
+        }
+    }
+
+    /// Whether a batch of `count` messages may be sent at `now`, taking them
+    /// from the budget if so.
+    ///
+    /// A batch is let through whenever the budget isn't overdrawn, even if it
+    /// is larger than the budget, so that batches larger than the rate are
+    /// still sent, only followed by a longer pause.
+    pub(crate) fn allows(&mut self, count: usize, now: OffsetDateTime) -> bool {
+        let rate = f64::from(self.rate);
+        if let Some(refilled) = self.refilled {
+            let elapsed = (now - refilled).as_seconds_f64().max(0.0);
+            self.budget = (self.budget + elapsed * rate).min(rate);
+        }
+        self.refilled = Some(now);
+        if self.budget < 0.0 {
+            return false;
+        }
+        self.budget -= count as f64;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_catch_up() {
+        let start = OffsetDateTime::UNIX_EPOCH;
+        let mut catch_up = CatchUp::new(100);
+
+        assert!(catch_up.allows(60, start));
+        assert!(catch_up.allows(60, start));
+        assert!(!catch_up.allows(60, start));
+        // 20 messages overdrawn, refilled in 200ms.
+        assert!(!catch_up.allows(60, start + Duration::from_millis(100)));
+        assert!(catch_up.allows(60, start + Duration::from_millis(200)));
+
+        // The budget doesn't accumulate past one second.
+        let later = start + Duration::from_secs(60);
+        assert!(catch_up.allows(100, later));
+        assert!(catch_up.allows(1, later));
+        assert!(!catch_up.allows(1, later));
+    }
+}
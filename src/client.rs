@@ -1,6 +1,6 @@
 //! Interfaces to the Segment tracking API.
 
-use crate::{Message, Result};
+use crate::{Config, Message, Result};
 
 /// `Client` is a trait representing the HTTP transport layer of the analytics library.
 #[async_trait::async_trait]
@@ -11,4 +11,15 @@ pub trait Client {
     /// documentation](https://segment.com/docs/guides/setup/how-do-i-find-my-write-key/)
     /// for how to find this value.
     async fn send(&self, write_key: String, msg: Message) -> Result<()>;
+
+    /// Apply the transport settings of `config`, such as the host and the
+    /// timeouts, to this client.
+    ///
+    /// This is called when a running pipeline is
+    /// [reconfigured](crate::Analytics::reconfigure). The default
+    /// implementation ignores the new settings.
+    fn reconfigure(&mut self, config: &Config) -> Result<()> {
+        let _ = config;
+        Ok(())
+    }
 }
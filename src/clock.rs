@@ -0,0 +1,25 @@
+//! Sources of time used to timestamp messages.
+
+use std::fmt::Debug;
+
+use time::OffsetDateTime;
+
+/// A source of the current time.
+///
+/// The [`Batcher`](crate::Batcher) uses a `Clock` to fill in the timestamp of
+/// messages which don't have one. The default is the [`SystemClock`]; tests
+/// can use a fixed clock instead to produce reproducible payloads.
+pub trait Clock: Debug + Send + Sync {
+    /// The current time.
+    fn now(&self) -> OffsetDateTime;
+}
+
+/// A [`Clock`] reading the system time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc()
+    }
+}
@@ -0,0 +1,50 @@
+//! Compressing the bodies of the requests sent to Segment.
+
+use std::collections::BTreeSet;
+use std::io::Write;
+
+use flate2::write::GzEncoder;
+use serde::Deserialize;
+
+/// When the bodies of requests are sent gzipped. See
+/// [`HttpClient::enable_compression`](crate::HttpClient::enable_compression).
+///
+/// Small bodies are sent as they are, since compressing them saves little
+/// and costs CPU time. It deserializes from:
+///
+/// ```json
+/// { "threshold": 1024, "disabled_paths": ["/v1/identify"] }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Compression {
+    /// The size in bytes from which bodies are compressed.
+    pub threshold: usize,
+    /// The paths of the API whose requests are never compressed, e.g.
+    /// `/v1/batch`, for collectors which don't accept gzip there.
+    pub disabled_paths: BTreeSet<String>,
+}
+
+impl Default for Compression {
+    /// Compress the bodies of 1 KiB or more, on every path.
+    fn default() -> Self {
+        Self {
+            threshold: 1024,
+            disabled_paths: BTreeSet::new(),
+        }
+    }
+}
+
+impl Compression {
+    /// Whether a body of `len` bytes sent to `path` is compressed.
+    pub(crate) fn applies(&self, path: &str, len: usize) -> bool {
+        len >= self.threshold && !self.disabled_paths.contains(path)
+    }
+}
+
+/// `body` compressed with gzip.
+pub(crate) fn gzip(body: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
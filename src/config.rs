@@ -0,0 +1,684 @@
+//! Configuration of the pipeline, loadable from a file.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::batcher::{MAX_BATCH_SIZE, MAX_MESSAGE_SIZE};
+use crate::transform;
+use crate::{
+    Compression, ConfigProblem, ContextPrivacy, DeadLetterRotation, Error, ErrorPolicy,
+    EventNameGuard, FieldHashing, Filter, FlushWindow, Heartbeat, LargeIntegerPolicy, MergePolicy,
+    PruningRules, RateLimit, Result, RetryBudget, RetryPolicy, Sampling, SessionConfig, SpoolKey,
+    Transform, Truncation, ValidationMode, WriteKey,
+};
+
+/// The full configuration of an [`Analytics`](crate::Analytics) pipeline.
+///
+/// `Config` can be deserialized from any format supported by serde, which lets
+/// analytics settings be managed centrally rather than in code. Every field is
+/// optional and falls back to its default value, and unknown fields are
+/// rejected to catch typos. Durations are given either as a number of seconds,
+/// or as a string such as `"500ms"`, `"10s"`, `"5m"` or `"1h"`.
+///
+/// With the `toml` feature enabled, a configuration looks like this:
+///
+/// ```toml
+/// write_key = "YOUR_WRITE_KEY"
+/// host = "https://api.segment.io"
+/// flush_interval = "10s"
+/// connect_timeout = "10s"
+/// timeout = "30s"
+/// strict = true
+/// partitions = 4
+///
+/// [batch]
+/// max_bytes = 262144
+/// max_messages = 100
+///
+/// [retry]
+/// max_retries = 5
+/// initial_backoff = "200ms"
+/// max_backoff = "30s"
+/// ```
+///
+/// A `Config` is turned into a pipeline with
+/// [`ClientBuilder::from_config`](crate::ClientBuilder::from_config).
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The write key to send messages with.
+    pub write_key: Option<WriteKey>,
+    /// The scheme and host of Segment's API.
+    pub host: String,
+    /// Send the queued messages at least this often.
+    #[serde(with = "duration")]
+    pub flush_interval: Duration,
+    /// Give up connecting to Segment's API after this long.
+    #[serde(with = "duration")]
+    pub connect_timeout: Duration,
+    /// Give up on a request to Segment's API after this long.
+    #[serde(with = "duration::option")]
+    pub timeout: Option<Duration>,
+    /// Remember the addresses of Segment's API for this long, instead of
+    /// resolving them again for every new connection.
+    #[serde(with = "duration::option")]
+    pub dns_cache_ttl: Option<Duration>,
+    /// Never send messages over the network. See
+    /// [`ClientBuilder::disabled`](crate::ClientBuilder::disabled).
+    pub disabled: bool,
+    /// Connect to Segment's API as soon as the pipeline is built, so the
+    /// first flush doesn't wait for the connection to be established.
+    pub warm_up: bool,
+    /// Send an `SDK Heartbeat` event periodically. See
+    /// [`Analytics::start_heartbeat`](crate::Analytics::start_heartbeat).
+    pub heartbeat: Option<Heartbeat>,
+    /// The context set on every batch.
+    pub context: Option<Value>,
+    /// Drop `page` and `screen` events identical to the previous one of the
+    /// same user within this window. See
+    /// [`Batcher::deduplicate_views`](crate::Batcher::deduplicate_views).
+    #[serde(with = "duration::option")]
+    pub deduplicate_views: Option<Duration>,
+    /// Drop `group` messages identical to the previous one of the same user
+    /// and group, until this long after it. See
+    /// [`Batcher::deduplicate_groups`](crate::Batcher::deduplicate_groups).
+    #[serde(with = "duration::option")]
+    pub deduplicate_groups: Option<Duration>,
+    /// Set the traits of identified users in the context of their later
+    /// events. See
+    /// [`Batcher::enable_trait_cache`](crate::Batcher::enable_trait_cache).
+    pub cache_traits: bool,
+    /// Set a session ID on every message. See
+    /// [`Batcher::track_sessions`](crate::Batcher::track_sessions).
+    pub sessions: Option<SessionConfig>,
+    /// Strip or coarsen `context.ip` and `context.userAgent`. See
+    /// [`Batcher::set_context_privacy`](crate::Batcher::set_context_privacy).
+    pub context_privacy: Option<ContextPrivacy>,
+    /// The traits and properties sent hashed. See
+    /// [`Batcher::set_field_hashing`](crate::Batcher::set_field_hashing).
+    pub hashed_fields: Option<FieldHashing>,
+    /// The properties and traits only used by some destinations. See
+    /// [`Batcher::set_pruning_rules`](crate::Batcher::set_pruning_rules).
+    pub pruning: Option<PruningRules>,
+    /// The fields whose integers don't fit in a double. See
+    /// [`Batcher::set_large_integer_policy`](crate::Batcher::set_large_integer_policy).
+    pub large_integers: Option<LargeIntegerPolicy>,
+    /// The properties shrunk in `track` events too large to be sent. See
+    /// [`Batcher::set_truncation`](crate::Batcher::set_truncation).
+    pub truncation: Option<Truncation>,
+    /// Drop the messages matching any of these filters, given as FQL. See
+    /// [`Batcher::drop_matching`](crate::Batcher::drop_matching).
+    pub filters: Vec<Filter>,
+    /// The fixes applied to the messages. See
+    /// [`Batcher::add_transform`](crate::Batcher::add_transform).
+    pub transforms: Vec<Transform>,
+    /// The fraction of the users whose events are kept. See
+    /// [`Batcher::set_sampling`](crate::Batcher::set_sampling).
+    pub sampling: Option<Sampling>,
+    /// The limit on the number of distinct event names. See
+    /// [`Batcher::guard_event_names`](crate::Batcher::guard_event_names).
+    pub event_name_guard: Option<EventNameGuard>,
+    /// The prefix of the names of `track` events, e.g. `"Backend: "`. See
+    /// [`Analytics::with_namespace`](crate::Analytics::with_namespace).
+    pub event_prefix: Option<String>,
+    /// The rate limit of each event name. See
+    /// [`Batcher::rate_limit`](crate::Batcher::rate_limit).
+    pub rate_limits: HashMap<String, RateLimit>,
+    /// The `integrations` of each event name. See
+    /// [`Batcher::route`](crate::Batcher::route).
+    pub routes: HashMap<String, Value>,
+    /// How the context of the batch is combined with the ones of its
+    /// messages.
+    pub merge_policy: MergePolicy,
+    /// Log outgoing payloads, masking PII.
+    pub debug: bool,
+    /// The fields masked in debug logs, instead of the default ones.
+    pub redact: Option<Vec<String>>,
+    /// Record whether each request reused a connection.
+    pub audit_connections: bool,
+    /// When the bodies of requests are gzipped, or never if `None`. See
+    /// [`HttpClient::enable_compression`](crate::HttpClient::enable_compression).
+    pub compression: Option<Compression>,
+    /// Reject incomplete messages instead of sending them.
+    pub strict: bool,
+    /// Warn about the fields of message contexts outside of the common spec.
+    /// See [`Batcher::enable_context_check`](crate::Batcher::enable_context_check).
+    pub check_context: bool,
+    /// When messages are validated in strict mode.
+    pub validation_mode: ValidationMode,
+    /// The daily windows, in UTC, during which batches may be sent. See
+    /// [`AutoBatcher::set_flush_windows`](crate::AutoBatcher::set_flush_windows).
+    pub flush_windows: Vec<FlushWindow>,
+    /// When to send a batch.
+    pub batch: BatchConfig,
+    /// How to retry failed requests.
+    pub retry: RetryPolicy,
+    /// How many retries to expect before alerting. See
+    /// [`AutoBatcher::set_retry_budget`](crate::AutoBatcher::set_retry_budget).
+    pub retry_budget: Option<RetryBudget>,
+    /// The number of messages per second the backlog is sent at after a
+    /// pause. See
+    /// [`AutoBatcher::set_catch_up_rate`](crate::AutoBatcher::set_catch_up_rate).
+    pub catch_up_rate: Option<u32>,
+    /// What to do with the batches which fail, by class of error. See
+    /// [`ErrorPolicy`].
+    pub error_policy: ErrorPolicy,
+    /// The number of background tasks sending batches concurrently. See
+    /// [`Analytics::partitioned`](crate::Analytics::partitioned).
+    pub partitions: usize,
+    /// The number of queued messages above which producers are asked to slow
+    /// down. See
+    /// [`Analytics::set_high_water_mark`](crate::Analytics::set_high_water_mark).
+    pub high_water_mark: Option<usize>,
+    /// The directory to keep the batches which were not sent yet in. See
+    /// [`Spool`](crate::Spool).
+    pub spool: Option<PathBuf>,
+    /// The key to sign and verify the spooled batches with. See
+    /// [`Spool::open_signed`](crate::Spool::open_signed).
+    pub spool_key: Option<SpoolKey>,
+    /// The key to encrypt and decrypt the spooled batches with. See
+    /// [`Spool::open_encrypted`](crate::Spool::open_encrypted).
+    pub spool_encryption_key: Option<SpoolKey>,
+    /// The keys the spooled batches may have been encrypted with before
+    /// `spool_encryption_key`. See
+    /// [`Spool::open_encrypted_with_previous`](crate::Spool::open_encrypted_with_previous).
+    pub previous_spool_encryption_keys: Vec<SpoolKey>,
+    /// The file to append a record of every message sent to. See
+    /// [`AuditFile`](crate::AuditFile).
+    pub audit_log: Option<PathBuf>,
+    /// The number of messages sent to keep in memory for debugging. See
+    /// [`Recorder`](crate::Recorder).
+    pub recent_messages: Option<usize>,
+    /// The file to append the messages which could not be delivered to. See
+    /// [`DeadLetterFile`](crate::DeadLetterFile).
+    pub dead_letter: Option<PathBuf>,
+    /// When the dead letter file is rotated. See
+    /// [`DeadLetterFile::open_rotating`](crate::DeadLetterFile::open_rotating).
+    pub dead_letter_rotation: Option<DeadLetterRotation>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            write_key: None,
+            host: "https://api.segment.io".to_owned(),
+            flush_interval: Duration::from_secs(10),
+            connect_timeout: Duration::from_secs(10),
+            timeout: None,
+            dns_cache_ttl: None,
+            disabled: false,
+            warm_up: false,
+            heartbeat: None,
+            context: None,
+            deduplicate_views: None,
+            deduplicate_groups: None,
+            cache_traits: false,
+            sessions: None,
+            context_privacy: None,
+            hashed_fields: None,
+            pruning: None,
+            large_integers: None,
+            truncation: None,
+            filters: Vec::new(),
+            transforms: Vec::new(),
+            sampling: None,
+            event_name_guard: None,
+            event_prefix: None,
+            rate_limits: HashMap::new(),
+            routes: HashMap::new(),
+            merge_policy: MergePolicy::default(),
+            debug: false,
+            redact: None,
+            audit_connections: false,
+            compression: None,
+            strict: false,
+            check_context: false,
+            validation_mode: ValidationMode::default(),
+            flush_windows: Vec::new(),
+            batch: BatchConfig::default(),
+            retry: RetryPolicy::default(),
+            retry_budget: None,
+            catch_up_rate: None,
+            error_policy: ErrorPolicy::default(),
+            partitions: 1,
+            high_water_mark: None,
+            spool: None,
+            spool_key: None,
+            spool_encryption_key: None,
+            previous_spool_encryption_keys: Vec::new(),
+            audit_log: None,
+            recent_messages: None,
+            dead_letter: None,
+            dead_letter_rotation: None,
+        }
+    }
+}
+
+/// When to send a batch, in addition to the flush interval.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchConfig {
+    /// The maximum size of a serialized batch. Segment does not accept
+    /// batches larger than 500KB.
+    pub max_bytes: usize,
+    /// The maximum number of messages in a batch.
+    pub max_messages: Option<usize>,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: MAX_BATCH_SIZE,
+            max_messages: None,
+        }
+    }
+}
+
+impl Config {
+    /// Parse a configuration from a JSON document.
+    pub fn from_json_str(json: &str) -> Result<Self> {
+        serde_json::from_str(json).map_err(|e| Error::InvalidConfig(e.to_string()))
+    }
+
+    /// Parse a configuration from a TOML document.
+    #[cfg(feature = "toml")]
+    pub fn from_toml_str(toml: &str) -> Result<Self> {
+        toml::from_str(toml).map_err(|e| Error::InvalidConfig(e.to_string()))
+    }
+
+    /// Parse a configuration from a YAML document.
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml_str(yaml: &str) -> Result<Self> {
+        serde_yaml::from_str(yaml).map_err(|e| Error::InvalidConfig(e.to_string()))
+    }
+
+    /// Read a configuration from a file, whose format is guessed from its
+    /// extension.
+    ///
+    /// `.json` files are always supported, `.toml` files are supported with
+    /// the `toml` feature enabled, and `.yaml`/`.yml` files with the `yaml`
+    /// feature enabled.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).map_err(|e| {
+            Error::InvalidConfig(format!("could not read {}: {}", path.display(), e))
+        })?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Self::from_json_str(&content),
+            #[cfg(feature = "toml")]
+            Some("toml") => Self::from_toml_str(&content),
+            #[cfg(feature = "yaml")]
+            Some("yaml" | "yml") => Self::from_yaml_str(&content),
+            _ => Err(Error::InvalidConfig(format!(
+                "unsupported configuration format: {}",
+                path.display()
+            ))),
+        }
+    }
+
+    /// Check that the settings make sense together, so that a misconfigured
+    /// pipeline fails when it is built rather than once traffic flows through
+    /// it. [`ClientBuilder::build`](crate::ClientBuilder::build) calls it.
+    ///
+    /// Returns [`Error::InvalidSettings`] listing every problem found, each
+    /// with the path of the field at fault:
+    ///
+    /// ```
+    /// use segment::{Config, Error};
+    ///
+    /// let config = Config::from_json_str(
+    ///     r#"{
+    ///         "batch": { "max_bytes": 1024 },
+    ///         "sampling": { "rates": { "Search": 10 } }
+    ///     }"#,
+    /// )?;
+    /// match config.validate() {
+    ///     Err(Error::InvalidSettings(problems)) => {
+    ///         let fields: Vec<_> = problems.iter().map(|p| p.field.as_str()).collect();
+    ///         assert_eq!(fields, ["batch.max_bytes", "sampling.rates.Search"]);
+    ///     }
+    ///     _ => unreachable!(),
+    /// }
+    /// # Ok::<_, segment::Error>(())
+    /// ```
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        let mut problem = |field: &str, message: String| {
+            problems.push(ConfigProblem {
+                field: field.to_owned(),
+                message,
+            })
+        };
+
+        if self.flush_interval.is_zero() {
+            problem("flush_interval", "must not be zero".to_owned());
+        }
+        if self.batch.max_bytes < MAX_MESSAGE_SIZE {
+            problem(
+                "batch.max_bytes",
+                format!(
+                    "must be at least {} bytes, the size limit of a single message",
+                    MAX_MESSAGE_SIZE
+                ),
+            );
+        } else if self.batch.max_bytes > MAX_BATCH_SIZE {
+            problem(
+                "batch.max_bytes",
+                format!(
+                    "must be at most {} bytes, the size limit of a batch",
+                    MAX_BATCH_SIZE
+                ),
+            );
+        }
+        if self.batch.max_messages == Some(0) {
+            problem("batch.max_messages", "must be at least 1".to_owned());
+        }
+
+        if self.retry.initial_backoff > self.retry.max_backoff {
+            problem(
+                "retry.initial_backoff",
+                format!(
+                    "must not be longer than retry.max_backoff ({:?})",
+                    self.retry.max_backoff
+                ),
+            );
+        }
+        if let (Some(deadline), Some(timeout)) = (self.retry.deadline, self.timeout) {
+            if deadline <= timeout {
+                problem(
+                    "retry.deadline",
+                    format!(
+                        "must be longer than timeout ({:?}), or no attempt could be retried",
+                        timeout
+                    ),
+                );
+            }
+        }
+        if let Some(budget) = &self.retry_budget {
+            if budget.window.is_zero() {
+                problem("retry_budget.window", "must not be zero".to_owned());
+            }
+        }
+        if self.catch_up_rate == Some(0) {
+            problem(
+                "catch_up_rate",
+                "must be at least 1, or the backlog would never be sent".to_owned(),
+            );
+        }
+
+        if let Some(sampling) = &self.sampling {
+            let valid = |rate: f64| (0.0..=1.0).contains(&rate);
+            if !valid(sampling.default_rate) {
+                problem(
+                    "sampling.default_rate",
+                    format!("must be between 0 and 1, not {}", sampling.default_rate),
+                );
+            }
+            for (event, &rate) in &sampling.rates {
+                if !valid(rate) {
+                    problem(
+                        &format!("sampling.rates.{}", event),
+                        format!("must be between 0 and 1, not {}", rate),
+                    );
+                }
+            }
+        }
+        for (i, transform) in self.transforms.iter().enumerate() {
+            for field in transform
+                .fields()
+                .filter(|field| !transform::is_valid_path(field))
+            {
+                problem(
+                    &format!("transforms[{}].steps", i),
+                    format!("{:?} is not a valid field path", field),
+                );
+            }
+        }
+        for (event, limit) in &self.rate_limits {
+            if limit.per.is_zero() {
+                problem(
+                    &format!("rate_limits.{}.per", event),
+                    "must not be zero".to_owned(),
+                );
+            }
+        }
+        if let Some(guard) = &self.event_name_guard {
+            if guard.max_events == 0 {
+                problem(
+                    "event_name_guard.max_events",
+                    "must be at least 1".to_owned(),
+                );
+            }
+        }
+        if let Some(heartbeat) = &self.heartbeat {
+            if heartbeat.interval.is_zero() {
+                problem("heartbeat.interval", "must not be zero".to_owned());
+            }
+        }
+
+        if self.partitions == 0 {
+            problem("partitions", "must be at least 1".to_owned());
+        }
+        if self.high_water_mark == Some(0) {
+            problem("high_water_mark", "must be at least 1".to_owned());
+        }
+        if self.spool.is_some() && self.partitions > 1 {
+            problem(
+                "spool",
+                "can't be shared by several partitions, set partitions to 1".to_owned(),
+            );
+        }
+        if self.spool_key.is_some() && self.spool.is_none() {
+            problem("spool_key", "is set, but spool isn't".to_owned());
+        }
+        if self.spool_encryption_key.is_some() && self.spool.is_none() {
+            problem("spool_encryption_key", "is set, but spool isn't".to_owned());
+        }
+        if self.spool_encryption_key.is_some() && self.spool_key.is_some() {
+            problem(
+                "spool_encryption_key",
+                "can't be combined with spool_key, encrypted batches are already authenticated"
+                    .to_owned(),
+            );
+        }
+        if !self.previous_spool_encryption_keys.is_empty() && self.spool_encryption_key.is_none() {
+            problem(
+                "previous_spool_encryption_keys",
+                "are set, but spool_encryption_key isn't".to_owned(),
+            );
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidSettings(problems))
+        }
+    }
+}
+
+/// Parse a duration given as a number followed by a unit (`ms`, `s`, `m` or
+/// `h`), or as a number of seconds without unit.
+pub(crate) fn parse_duration(name: &str, value: &str) -> Result<Duration> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let invalid = || Error::InvalidConfig(format!("{} is not a valid duration: {:?}", name, value));
+
+    let number = f64::from_str(number).map_err(|_| invalid())?;
+    let seconds = match unit.trim() {
+        "ms" => number / 1000.,
+        "" | "s" => number,
+        "m" => number * 60.,
+        "h" => number * 3600.,
+        _ => return Err(invalid()),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| invalid())
+}
+
+/// Parse a boolean given as `true`/`false`, `yes`/`no`, `on`/`off` or `1`/`0`.
+pub(crate) fn parse_bool(name: &str, value: &str) -> Result<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" | "" => Ok(false),
+        _ => Err(Error::InvalidConfig(format!(
+            "{} is not a valid boolean: {:?}",
+            name, value
+        ))),
+    }
+}
+
+/// (De)serialization of durations as a number of seconds or a string with a
+/// unit.
+pub(crate) mod duration {
+    use std::time::Duration;
+
+    use serde::{de, Deserialize, Deserializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Seconds(f64),
+        String(String),
+    }
+
+    fn convert<E: de::Error>(raw: Raw) -> Result<Duration, E> {
+        match raw {
+            Raw::Seconds(seconds) => Duration::try_from_secs_f64(seconds)
+                .map_err(|_| E::custom(format!("invalid duration: {}", seconds))),
+            Raw::String(value) => super::parse_duration("duration", &value).map_err(E::custom),
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Duration, D::Error> {
+        convert(Raw::deserialize(deserializer)?)
+    }
+
+    pub(crate) mod option {
+        use std::time::Duration;
+
+        use serde::{Deserialize, Deserializer};
+
+        pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Duration>, D::Error> {
+            Option::<super::Raw>::deserialize(deserializer)?
+                .map(super::convert)
+                .transpose()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_json() {
+        let config = Config::from_json_str(
+            r#"{
+                "write_key": "abcdEFGH1234",
+                "flush_interval": 2.5,
+                "timeout": "500ms",
+                "batch": { "max_messages": 100 },
+                "retry": { "max_retries": 5, "max_backoff": "1m" }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.write_key.unwrap().expose(), "abcdEFGH1234");
+        assert_eq!(config.host, "https://api.segment.io");
+        assert_eq!(config.flush_interval, Duration::from_millis(2500));
+        assert_eq!(config.timeout, Some(Duration::from_millis(500)));
+        assert_eq!(config.batch.max_messages, Some(100));
+        assert_eq!(config.retry.max_retries, 5);
+        assert_eq!(config.retry.max_backoff, Duration::from_secs(60));
+        assert_eq!(
+            config.retry.initial_backoff,
+            RetryPolicy::default().initial_backoff
+        );
+
+        assert!(Config::from_json_str(r#"{ "flush_intreval": 1 }"#).is_err());
+        assert!(Config::from_json_str(r#"{ "write_key": "" }"#).is_err());
+        assert!(Config::from_json_str(r#"{ "flush_interval": -1 }"#).is_err());
+        assert!(Config::from_json_str(r#"{ "flush_interval": 1e300 }"#).is_err());
+        assert!(Config::from_json_str(r#"{ "timeout": "1e300" }"#).is_err());
+        assert!(matches!(
+            parse_duration("SEGMENT_TIMEOUT", "99999999999999999999999h"),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Config::default().validate().is_ok());
+
+        let config = Config {
+            timeout: Some(Duration::from_secs(30)),
+            batch: BatchConfig {
+                max_bytes: 1024 * 1024,
+                max_messages: Some(0),
+            },
+            retry: RetryPolicy {
+                deadline: Some(Duration::from_secs(10)),
+                ..RetryPolicy::default()
+            },
+            sampling: Some(Sampling::new("salt").with_rate("Search", -0.5)),
+            partitions: 0,
+            spool_key: Some("hunter2".into()),
+            spool_encryption_key: Some("hunter3".into()),
+            ..Config::default()
+        };
+        assert!(!format!("{:?}", config).contains("hunter"));
+        let problems = match config.validate() {
+            Err(Error::InvalidSettings(problems)) => problems,
+            other => panic!("unexpected result: {:?}", other),
+        };
+        let fields: Vec<_> = problems.iter().map(|p| p.field.as_str()).collect();
+        assert_eq!(
+            fields,
+            [
+                "batch.max_bytes",
+                "batch.max_messages",
+                "retry.deadline",
+                "sampling.rates.Search",
+                "partitions",
+                "spool_key",
+                "spool_encryption_key",
+                "spool_encryption_key",
+            ]
+        );
+        assert_eq!(
+            problems[3].to_string(),
+            "sampling.rates.Search: must be between 0 and 1, not -0.5"
+        );
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn test_from_toml() {
+        let config = Config::from_toml_str(
+            r#"
+            host = "http://localhost:8080"
+            flush_interval = "1m"
+
+            [retry]
+            max_retries = 0
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.host, "http://localhost:8080");
+        assert_eq!(config.flush_interval, Duration::from_secs(60));
+        assert_eq!(config.retry, RetryPolicy::none());
+    }
+}
@@ -0,0 +1,235 @@
+//! Checking the `context` of messages against Segment's common spec.
+
+use std::fmt;
+
+use serde_json::Value;
+
+/// The type a field of the context must have.
+#[derive(Clone, Copy, Debug)]
+enum Kind {
+    Bool,
+    Number,
+    String,
+    Array,
+    /// An object with the given known fields.
+    Object(&'static [(&'static str, Kind)]),
+    /// An object with any fields.
+    AnyObject,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Bool => "a boolean",
+            Kind::Number => "a number",
+            Kind::String => "a string",
+            Kind::Array => "an array",
+            Kind::Object(_) | Kind::AnyObject => "an object",
+        }
+    }
+
+    fn matches(self, value: &Value) -> bool {
+        match self {
+            Kind::Bool => value.is_boolean(),
+            Kind::Number => value.is_number(),
+            Kind::String => value.is_string(),
+            Kind::Array => value.is_array(),
+            Kind::Object(_) | Kind::AnyObject => value.is_object(),
+        }
+    }
+}
+
+/// The fields of the context defined by the [common
+/// spec](https://segment.com/docs/connections/spec/common/#context), and the
+/// ones this crate sets.
+const SPEC: &[(&str, Kind)] = {
+    use Kind::*;
+    &[
+        ("active", Bool),
+        (
+            "app",
+            Object(&[
+                ("name", String),
+                ("version", String),
+                ("build", String),
+                ("namespace", String),
+            ]),
+        ),
+        (
+            "campaign",
+            Object(&[
+                ("name", String),
+                ("source", String),
+                ("medium", String),
+                ("term", String),
+                ("content", String),
+            ]),
+        ),
+        ("channel", String),
+        ("consent", AnyObject),
+        (
+            "device",
+            Object(&[
+                ("id", String),
+                ("advertisingId", String),
+                ("adTrackingEnabled", Bool),
+                ("manufacturer", String),
+                ("model", String),
+                ("name", String),
+                ("type", String),
+                ("token", String),
+            ]),
+        ),
+        ("groupId", String),
+        ("ip", String),
+        ("library", Object(&[("name", String), ("version", String)])),
+        ("locale", String),
+        (
+            "location",
+            Object(&[
+                ("city", String),
+                ("country", String),
+                ("latitude", Number),
+                ("longitude", Number),
+                ("region", String),
+                ("speed", Number),
+            ]),
+        ),
+        (
+            "network",
+            Object(&[
+                ("bluetooth", Bool),
+                ("carrier", String),
+                ("cellular", Bool),
+                ("wifi", Bool),
+            ]),
+        ),
+        ("os", Object(&[("name", String), ("version", String)])),
+        (
+            "page",
+            Object(&[
+                ("path", String),
+                ("referrer", String),
+                ("search", String),
+                ("title", String),
+                ("url", String),
+            ]),
+        ),
+        ("protocols", AnyObject),
+        (
+            "referrer",
+            Object(&[
+                ("id", String),
+                ("type", String),
+                ("name", String),
+                ("url", String),
+                ("link", String),
+            ]),
+        ),
+        (
+            "screen",
+            Object(&[("width", Number), ("height", Number), ("density", Number)]),
+        ),
+        ("session_id", Number),
+        ("timezone", String),
+        ("traits", AnyObject),
+        ("userAgent", String),
+        (
+            "userAgentData",
+            Object(&[("brands", Array), ("mobile", Bool), ("platform", String)]),
+        ),
+    ]
+};
+
+/// A field of a context which destinations would ignore, found by
+/// [`check_context`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContextIssue {
+    /// The path of the field, e.g. `device.adTrackingEnabled`.
+    pub path: String,
+    /// The type the common spec expects, or `None` if it doesn't define the
+    /// field.
+    pub expected: Option<&'static str>,
+}
+
+impl fmt::Display for ContextIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.expected {
+            Some(expected) => write!(f, "context.{} should be {}", self.path, expected),
+            None => write!(f, "context.{} is not part of the common spec", self.path),
+        }
+    }
+}
+
+/// The fields of `context` which are not defined by Segment's [common
+/// spec](https://segment.com/docs/connections/spec/common/#context), or which
+/// don't have the type it defines, e.g. a `device.adTrackingEnabled` sent as
+/// a string. Destinations silently ignore such fields.
+///
+/// ```
+/// use segment::check_context;
+/// use serde_json::json;
+///
+/// let issues = check_context(&json!({ "os": { "name": "Linux" }, "screen": { "width": "1080" } }));
+/// assert_eq!(issues[0].to_string(), "context.screen.width should be a number");
+/// ```
+pub fn check_context(context: &Value) -> Vec<ContextIssue> {
+    let mut issues = Vec::new();
+    check(context, SPEC, "", &mut issues);
+    issues
+}
+
+fn check(value: &Value, spec: &[(&str, Kind)], prefix: &str, issues: &mut Vec<ContextIssue>) {
+    let fields = match value {
+        Value::Object(fields) => fields,
+        _ => return,
+    };
+    for (key, value) in fields {
+        let path = format!("{}{}", prefix, key);
+        match spec.iter().find(|(name, _)| name == key) {
+            None => issues.push(ContextIssue {
+                path,
+                expected: None,
+            }),
+            // Fields explicitly cleared are fine.
+            Some(_) if value.is_null() => (),
+            Some((_, kind)) if !kind.matches(value) => issues.push(ContextIssue {
+                path,
+                expected: Some(kind.name()),
+            }),
+            Some((_, Kind::Object(fields))) => check(value, fields, &format!("{}.", path), issues),
+            Some(_) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_check_context() {
+        let context = json!({
+            "app": { "name": "shop", "version": "1.2" },
+            "device": { "adTrackingEnabled": "yes", "color": "red" },
+            "library": { "name": "segment", "version": null },
+            "traits": { "anything": true },
+            "ip": "203.0.113.7",
+            "tenant": "acme",
+        });
+        let issues: Vec<String> = check_context(&context)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            issues,
+            [
+                "context.device.adTrackingEnabled should be a boolean",
+                "context.device.color is not part of the common spec",
+                "context.tenant is not part of the common spec",
+            ]
+        );
+        assert!(check_context(&json!({ "os": "Linux" }))[0].expected == Some("an object"));
+    }
+}
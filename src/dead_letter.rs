@@ -0,0 +1,425 @@
+//! A destination for the batches which could not be delivered.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Cursor, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use time::OffsetDateTime;
+
+use crate::message::{Batch, StoredMessage};
+use crate::{Error, Result};
+
+/// A destination for the batches an [`AutoBatcher`](crate::AutoBatcher) gave
+/// up on, set with
+/// [`set_dead_letter`](crate::AutoBatcher::set_dead_letter).
+///
+/// A batch is given up on when Segment rejects it, when its retries are
+/// exhausted, or when its [delivery deadline](crate::RetryPolicy::deadline)
+/// passed. Failing to dead-letter a batch is logged.
+pub trait DeadLetter: Send + Sync {
+    /// Keep `batch`, which could not be delivered because of `error`.
+    fn dead_letter(&self, batch: &Batch, error: &Error) -> Result<()>;
+}
+
+/// A [`DeadLetter`] appending the messages of the batches to a file, as one
+/// JSON [`StoredMessage`] per line, which [`Analytics::replay`] reads back.
+///
+/// The file is opened in append mode, so messages written by previous
+/// processes are kept. Next to it, an index file with the `.index` extension
+/// added records the `messageId` of every message, why it was dead-lettered
+/// and when, so they can be [listed](DeadLetterFile::entries) and
+/// [replayed selectively](DeadLetterFile::select) without reading the
+/// messages.
+///
+/// A file [opened as rotating](DeadLetterFile::open_rotating) is renamed once
+/// it would grow past a size, with a sequence number appended, e.g.
+/// `dead-letter.jsonl.3`, and only the most recent files are kept, so the
+/// messages kept take a bounded amount of disk space.
+///
+/// [`Analytics::replay`]: crate::Analytics::replay
+#[derive(Debug)]
+pub struct DeadLetterFile {
+    path: PathBuf,
+    rotation: Option<DeadLetterRotation>,
+    state: Mutex<State>,
+}
+
+/// When a [`DeadLetterFile`] is rotated, and how many of its files are kept.
+///
+/// It deserializes from:
+///
+/// ```json
+/// { "max_file_bytes": 10485760, "max_files": 5 }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeadLetterRotation {
+    /// The size a file is rotated at. A file only made of one batch may be
+    /// larger.
+    pub max_file_bytes: u64,
+    /// The number of files kept, the one being written to included. The
+    /// oldest ones are deleted.
+    pub max_files: usize,
+}
+
+/// A message kept by a [`DeadLetterFile`], as recorded in its index.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeadLetterEntry {
+    /// The `messageId` of the message, if it had one.
+    pub message_id: Option<String>,
+    /// Why the batch of the message could not be delivered.
+    pub error: String,
+    /// The status code Segment's API answered with, if it answered. See
+    /// [`Error::status`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// When the message was dead-lettered.
+    #[serde(with = "time::serde::rfc3339")]
+    pub timestamp: OffsetDateTime,
+    /// The offset of the message in its file.
+    offset: u64,
+    /// The file the message is in.
+    #[serde(skip)]
+    file: PathBuf,
+}
+
+#[derive(Debug)]
+struct State {
+    file: File,
+    index: File,
+    size: u64,
+    /// The sequence number the file is given when rotated.
+    next: u64,
+}
+
+impl DeadLetterFile {
+    /// Open the file at `path` for appending, creating it if needed. It is
+    /// never rotated.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with(path.into(), None)
+    }
+
+    /// Open the file at `path` for appending, creating it if needed, and
+    /// rotate it according to `rotation`.
+    pub fn open_rotating(path: impl Into<PathBuf>, rotation: DeadLetterRotation) -> Result<Self> {
+        if rotation.max_files == 0 {
+            return Err(Error::InvalidConfig(
+                "at least one dead letter file must be kept".to_owned(),
+            ));
+        }
+        let dead_letters = Self::open_with(path.into(), Some(rotation))?;
+        dead_letters.prune()?;
+        Ok(dead_letters)
+    }
+
+    fn open_with(path: PathBuf, rotation: Option<DeadLetterRotation>) -> Result<Self> {
+        let file = open_append(&path)?;
+        let index = open_append(&index_path(&path))?;
+        let size = file.metadata()?.len();
+        let next = rotated(&path)?.last().map_or(1, |(seq, _)| seq + 1);
+        Ok(Self {
+            path,
+            rotation,
+            state: Mutex::new(State {
+                file,
+                index,
+                size,
+                next,
+            }),
+        })
+    }
+
+    /// The messages kept, oldest first, including the ones written by
+    /// previous processes.
+    ///
+    /// Messages written before the index was introduced are not listed.
+    pub fn entries(&self) -> Result<Vec<DeadLetterEntry>> {
+        // Held so that the files aren't rotated while being read.
+        let _state = self.state.lock().unwrap();
+        let mut files: Vec<_> = rotated(&self.path)?
+            .into_iter()
+            .map(|(_, path)| path)
+            .collect();
+        files.push(self.path.clone());
+        let mut entries = Vec::new();
+        for file in files {
+            let index = match fs::read_to_string(index_path(&file)) {
+                Ok(index) => index,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for line in index.lines().filter(|line| !line.trim().is_empty()) {
+                let mut entry: DeadLetterEntry = serde_json::from_str(line)?;
+                entry.file = file.clone();
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// The lines of the given `entries`, in order, to
+    /// [replay](crate::Analytics::replay) them:
+    ///
+    /// ```no_run
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() -> segment::Result<()> {
+    /// use segment::{ClientBuilder, DeadLetterFile, ReplayOptions};
+    ///
+    /// let dead_letters = DeadLetterFile::open("dead-letter.jsonl")?;
+    /// let throttled: Vec<_> = dead_letters
+    ///     .entries()?
+    ///     .into_iter()
+    ///     .filter(|entry| entry.status == Some(429))
+    ///     .collect();
+    ///
+    /// let analytics = ClientBuilder::from_env()?.build()?;
+    /// analytics
+    ///     .replay(dead_letters.select(&throttled)?, ReplayOptions::new())
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// Returns an error if the file of an entry was deleted by rotation since
+    /// it was listed.
+    pub fn select(&self, entries: &[DeadLetterEntry]) -> Result<Cursor<Vec<u8>>> {
+        let _state = self.state.lock().unwrap();
+        let mut lines = Vec::new();
+        let mut open: Option<(&Path, BufReader<File>)> = None;
+        for entry in entries {
+            let reader = match &mut open {
+                Some((path, reader)) if *path == entry.file => reader,
+                _ => {
+                    let file = File::open(&entry.file)?;
+                    &mut open.insert((&entry.file, BufReader::new(file))).1
+                }
+            };
+            reader.seek(SeekFrom::Start(entry.offset))?;
+            reader.read_until(b'\n', &mut lines)?;
+            if lines.last() != Some(&b'\n') {
+                lines.push(b'\n');
+            }
+        }
+        Ok(Cursor::new(lines))
+    }
+
+    /// Rename the file being written to, and open a new one.
+    fn rotate(&self, state: &mut State) -> Result<()> {
+        let rotated = rotated_path(&self.path, state.next);
+        fs::rename(&self.path, &rotated)?;
+        fs::rename(index_path(&self.path), index_path(&rotated))?;
+        state.next += 1;
+        state.file = open_append(&self.path)?;
+        state.index = open_append(&index_path(&self.path))?;
+        state.size = 0;
+        self.prune()
+    }
+
+    /// Delete the oldest rotated files, past the number kept.
+    fn prune(&self) -> Result<()> {
+        let max_files = match self.rotation {
+            Some(rotation) => rotation.max_files,
+            None => return Ok(()),
+        };
+        let rotated = rotated(&self.path)?;
+        let excess = (rotated.len() + 1).saturating_sub(max_files);
+        for (_, path) in rotated.into_iter().take(excess) {
+            fs::remove_file(&path)?;
+            match fs::remove_file(index_path(&path)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DeadLetter for DeadLetterFile {
+    fn dead_letter(&self, batch: &Batch, error: &Error) -> Result<()> {
+        let mut lines = Vec::new();
+        let mut offsets = Vec::new();
+        for msg in &batch.batch {
+            offsets.push(lines.len() as u64);
+            serde_json::to_writer(&mut lines, &StoredMessage::from(msg.clone()))?;
+            lines.push(b'\n');
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Some(rotation) = self.rotation {
+            let size = state.size + lines.len() as u64;
+            if state.size > 0 && size > rotation.max_file_bytes {
+                self.rotate(&mut state)?;
+            }
+        }
+
+        let timestamp = OffsetDateTime::now_utc();
+        let mut index = Vec::new();
+        for (msg, offset) in batch.batch.iter().zip(offsets) {
+            let entry = DeadLetterEntry {
+                message_id: msg
+                    .extra()
+                    .get("messageId")
+                    .and_then(Value::as_str)
+                    .map(str::to_owned),
+                error: error.to_string(),
+                status: error.status(),
+                timestamp,
+                offset: state.size + offset,
+                file: PathBuf::new(),
+            };
+            serde_json::to_writer(&mut index, &entry)?;
+            index.push(b'\n');
+        }
+
+        state.file.write_all(&lines)?;
+        state.file.flush()?;
+        state.size += lines.len() as u64;
+        state.index.write_all(&index)?;
+        state.index.flush()?;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+fn index_path(path: &Path) -> PathBuf {
+    let mut index = path.as_os_str().to_owned();
+    index.push(".index");
+    PathBuf::from(index)
+}
+
+fn rotated_path(path: &Path, seq: u64) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", seq));
+    PathBuf::from(rotated)
+}
+
+/// The rotated files of the file at `path`, by increasing sequence number.
+fn rotated(path: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => format!("{}.", name),
+        None => return Ok(Vec::new()),
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut rotated = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let seq = entry
+            .file_name()
+            .to_str()
+            .and_then(|file| file.strip_prefix(&name))
+            .and_then(|seq| seq.parse().ok());
+        if let Some(seq) = seq {
+            rotated.push((seq, entry.path()));
+        }
+    }
+    rotated.sort();
+    Ok(rotated)
+}
+
+/// The dead letter destination of an [`AutoBatcher`](crate::AutoBatcher).
+#[derive(Clone)]
+pub(crate) struct DeadLetterSink(pub(crate) Arc<dyn DeadLetter>);
+
+impl DeadLetterSink {
+    pub(crate) fn send(&self, batch: &Batch, error: &Error) {
+        log::error!(
+            "giving up on a batch of {} messages: {}",
+            batch.batch.len(),
+            error
+        );
+        if let Err(e) = self.0.dead_letter(batch, error) {
+            log::error!("could not dead-letter a batch: {}", e);
+        }
+    }
+}
+
+impl fmt::Debug for DeadLetterSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DeadLetterSink")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{BatchMessage, Track};
+    use serde_json::json;
+
+    fn batch(ids: &[&str]) -> Batch {
+        Batch {
+            batch: ids
+                .iter()
+                .map(|id| {
+                    let mut track = Track {
+                        event: "Example".to_owned(),
+                        ..Default::default()
+                    };
+                    track.extra.insert("messageId".to_owned(), json!(id));
+                    BatchMessage::from(track)
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = std::env::temp_dir().join(format!("segment-dead-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("dead-letter.jsonl");
+        let rotation = DeadLetterRotation {
+            max_file_bytes: 100,
+            max_files: 2,
+        };
+
+        let dead_letters = DeadLetterFile::open_rotating(&path, rotation).unwrap();
+        dead_letters
+            .dead_letter(
+                &batch(&["a", "b"]),
+                &Error::from_status(500, None, String::new()),
+            )
+            .unwrap();
+        dead_letters
+            .dead_letter(&batch(&["c"]), &Error::Timeout)
+            .unwrap();
+        dead_letters
+            .dead_letter(&batch(&["d"]), &Error::RateLimited { retry_after: None })
+            .unwrap();
+        // The file of "a" and "b" was deleted on the second rotation.
+        assert!(!rotated_path(&path, 1).exists());
+        assert!(rotated_path(&path, 2).exists());
+
+        let dead_letters = DeadLetterFile::open_rotating(&path, rotation).unwrap();
+        let entries = dead_letters.entries().unwrap();
+        let ids: Vec<_> = entries
+            .iter()
+            .map(|entry| entry.message_id.as_deref().unwrap())
+            .collect();
+        assert_eq!(ids, ["c", "d"]);
+        assert_eq!(entries[0].error, "request timed out");
+
+        let selected: Vec<_> = entries
+            .into_iter()
+            .filter(|entry| entry.status == Some(429))
+            .collect();
+        let lines = dead_letters.select(&selected).unwrap().into_inner();
+        let lines = String::from_utf8(lines).unwrap();
+        let msgs: Vec<_> = lines
+            .lines()
+            .map(|line| serde_json::from_str::<StoredMessage>(line).unwrap())
+            .collect();
+        assert_eq!(msgs, [StoredMessage::from(batch(&["d"]).batch[0].clone())]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+//! Suppression of repeated `page`, `screen` and `group` messages.
+
+use std::time::Duration;
+
+use serde_json::Value;
+use time::OffsetDateTime;
+
+use crate::message::{BatchMessage, Traits};
+use crate::user_map::UserMap;
+
+/// Remembers the last `page` or `screen` event of each user, to drop the
+/// identical ones following it within a window.
+#[derive(Clone, Debug)]
+pub(crate) struct ViewDeduplicator {
+    window: Duration,
+    last: UserMap<String, (View, OffsetDateTime)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct View {
+    screen: bool,
+    name: Option<String>,
+    url: Option<Value>,
+}
+
+impl ViewDeduplicator {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            last: UserMap::new(),
+        }
+    }
+
+    pub(crate) fn set_window(&mut self, window: Duration) {
+        self.window = window;
+    }
+
+    /// Whether `msg` is the same view as the last one of its user, seen less
+    /// than a window ago.
+    pub(crate) fn is_duplicate(&self, msg: &BatchMessage, now: OffsetDateTime) -> bool {
+        let (user, view) = match view(msg) {
+            Some(view) => view,
+            None => return false,
+        };
+        self.last
+            .get(&user)
+            .is_some_and(|(last, seen)| *last == view && now - *seen < self.window)
+    }
+
+    /// Remember `msg` as the last view of its user.
+    pub(crate) fn record(&mut self, msg: &BatchMessage, now: OffsetDateTime) {
+        if let Some((user, view)) = view(msg) {
+            let window = self.window;
+            self.last
+                .prune(|_, (_, seen)| now - *seen >= window, |(_, seen)| *seen);
+            self.last.insert(user, (view, now));
+        }
+    }
+}
+
+fn view(msg: &BatchMessage) -> Option<(String, View)> {
+    let view = match msg {
+        BatchMessage::Page(page) => View {
+            screen: false,
+            name: page.name.clone(),
+            url: page.properties.get("url").cloned(),
+        },
+        BatchMessage::Screen(screen) => View {
+            screen: true,
+            name: Some(screen.name.clone()),
+            url: None,
+        },
+        _ => return None,
+    };
+    Some((msg.user().key().to_owned(), view))
+}
+
+/// Remembers the `group` messages sent for each user and group, to drop the
+/// ones with the same traits until they are due for a refresh.
+#[derive(Clone, Debug)]
+pub(crate) struct GroupDeduplicator {
+    ttl: Duration,
+    last: UserMap<(String, String), (Traits, OffsetDateTime)>,
+}
+
+impl GroupDeduplicator {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            last: UserMap::new(),
+        }
+    }
+
+    pub(crate) fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    /// Whether `msg` is a `group` with the same traits as the last one sent
+    /// for its user and group, less than a TTL ago.
+    pub(crate) fn is_duplicate(&self, msg: &BatchMessage, now: OffsetDateTime) -> bool {
+        match msg {
+            BatchMessage::Group(group) => self
+                .last
+                .get(&(msg.user().key().to_owned(), group.group_id.clone()))
+                .is_some_and(|(traits, seen)| *traits == group.traits && now - *seen < self.ttl),
+            _ => false,
+        }
+    }
+
+    /// Remember `msg` as the last `group` of its user and group.
+    pub(crate) fn record(&mut self, msg: &BatchMessage, now: OffsetDateTime) {
+        if let BatchMessage::Group(group) = msg {
+            let ttl = self.ttl;
+            self.last
+                .prune(|_, (_, seen)| now - *seen >= ttl, |(_, seen)| *seen);
+            let key = (msg.user().key().to_owned(), group.group_id.clone());
+            self.last.insert(key, (group.traits.clone(), now));
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Caching of the addresses of Segment's API.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+
+use crate::runtime;
+
+/// The addresses resolved for each host, and when they were resolved.
+type Cache = HashMap<String, (Instant, Vec<SocketAddr>)>;
+
+/// A DNS resolver remembering the addresses it resolved for `ttl`, so that
+/// requests following a long idle period don't wait for a DNS lookup.
+#[derive(Debug)]
+pub(crate) struct CachingResolver {
+    ttl: Duration,
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl CachingResolver {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            cache: Arc::default(),
+        }
+    }
+}
+
+impl Resolve for CachingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_owned();
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(&host)
+            .filter(|(resolved_at, _)| resolved_at.elapsed() < self.ttl)
+            .map(|(_, addrs)| addrs.clone());
+        if let Some(addrs) = cached {
+            return Box::pin(async move { Ok(Box::new(addrs.into_iter()) as Addrs) });
+        }
+
+        let cache = self.cache.clone();
+        Box::pin(async move {
+            let addrs = runtime::unblock({
+                let host = host.clone();
+                move || -> io::Result<Vec<SocketAddr>> {
+                    Ok((host.as_str(), 0).to_socket_addrs()?.collect())
+                }
+            })
+            .await?;
+            cache
+                .lock()
+                .unwrap()
+                .insert(host, (Instant::now(), addrs.clone()));
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cache() {
+        let resolver = CachingResolver::new(Duration::from_secs(60));
+        let addrs: Vec<_> = resolver
+            .resolve("localhost".parse().unwrap())
+            .await
+            .unwrap()
+            .collect();
+        assert!(!addrs.is_empty());
+
+        let cached = resolver.cache.lock().unwrap()["localhost"].clone();
+        assert_eq!(cached.1, addrs);
+        let again: Vec<_> = resolver
+            .resolve("localhost".parse().unwrap())
+            .await
+            .unwrap()
+            .collect();
+        assert_eq!(again, addrs);
+        assert_eq!(resolver.cache.lock().unwrap()["localhost"].0, cached.0);
+    }
+}
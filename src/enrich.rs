@@ -0,0 +1,117 @@
+//! Asynchronous enrichment of messages before they are batched.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::future::{self, Either};
+
+use crate::message::BatchMessage;
+use crate::{runtime, Error, Result, Stage};
+
+/// A step adding data to messages before they are batched, such as the tier
+/// of the account of the user looked up in a cache. Enrichers are added to an
+/// [`AutoBatcher`](crate::AutoBatcher) with
+/// [`add_enricher`](crate::AutoBatcher::add_enricher).
+///
+/// ```
+/// use segment::{Enricher, Result};
+/// use segment::message::BatchMessage;
+///
+/// struct Region;
+///
+/// #[async_trait::async_trait]
+/// impl Enricher for Region {
+///     async fn enrich(&self, msg: &mut BatchMessage) -> Result<()> {
+///         if let BatchMessage::Track(track) = msg {
+///             track.properties.insert("region".to_owned(), "eu-west-1".into());
+///         }
+///         Ok(())
+///     }
+/// }
+/// ```
+#[async_trait::async_trait]
+pub trait Enricher: Send + Sync {
+    /// Add data to `msg`.
+    ///
+    /// If this fails or takes longer than the timeout of the enricher, the
+    /// message is sent as the enricher left it, so an enricher should only
+    /// change it once it has everything it needs.
+    async fn enrich(&self, msg: &mut BatchMessage) -> Result<()>;
+}
+
+/// An enricher and how long it may take per message.
+#[derive(Clone)]
+pub(crate) struct Enrichment {
+    pub(crate) enricher: Arc<dyn Enricher>,
+    pub(crate) timeout: Duration,
+}
+
+impl fmt::Debug for Enrichment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Enrichment")
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait::async_trait]
+impl Stage for Enrichment {
+    /// Run the enricher on `msg`, logging its failure or timeout.
+    async fn process(&self, mut msg: BatchMessage) -> Result<Option<BatchMessage>> {
+        let result = {
+            let enriched = self.enricher.enrich(&mut msg);
+            let timeout = runtime::sleep(self.timeout);
+            futures_util::pin_mut!(timeout);
+            match future::select(enriched, timeout).await {
+                Either::Left((result, _)) => result,
+                Either::Right(((), _)) => Err(Error::Timeout),
+            }
+        };
+        if let Err(e) = result {
+            log::warn!("could not enrich a message of {}: {}", msg.user(), e);
+        }
+        Ok(Some(msg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{track, MockClient};
+    use crate::{AutoBatcher, Batcher, WriteKey};
+
+    struct Tier(Duration);
+
+    #[async_trait::async_trait]
+    impl Enricher for Tier {
+        async fn enrich(&self, msg: &mut BatchMessage) -> Result<()> {
+            runtime::sleep(self.0).await;
+            if let BatchMessage::Track(track) = msg {
+                track.properties.insert("tier".to_owned(), "gold".into());
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_enrich() {
+        let client = MockClient::new();
+        let mut batcher = AutoBatcher::new(
+            client.clone(),
+            Batcher::new(None),
+            WriteKey::new("key").unwrap(),
+        );
+        batcher.add_enricher(Tier(Duration::ZERO), Duration::from_millis(100));
+        batcher.push(track("foo", "Bought")).await.unwrap();
+        batcher.add_enricher(Tier(Duration::from_secs(3600)), Duration::from_millis(10));
+        batcher.push(track("foo", "Bought")).await.unwrap();
+        batcher.flush().await.unwrap();
+
+        let tracks = client.tracks();
+        assert_eq!(tracks.len(), 2);
+        assert!(tracks
+            .iter()
+            .all(|track| track.properties["tier"] == "gold"));
+    }
+}
@@ -0,0 +1,210 @@
+//! Choosing what to do with a batch depending on why sending it failed.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::{Error, Result};
+
+/// A class of errors sending a batch may fail with.
+///
+/// Classes are written as a status code such as `413`, `4xx` or `5xx` for the
+/// client or server errors not matched by a status code, `network` or
+/// `timeout`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ErrorClass {
+    /// Segment's API answered with this status code.
+    Status(u16),
+    /// Segment's API answered with a `4xx` status code.
+    ClientError,
+    /// Segment's API answered with a `5xx` status code.
+    ServerError,
+    /// The request failed before Segment's API answered.
+    Network,
+    /// The request did not complete in time.
+    Timeout,
+}
+
+impl ErrorClass {
+    /// The classes `error` belongs to, from the most to the least specific.
+    fn of(error: &Error) -> Vec<ErrorClass> {
+        let status = match error {
+            Error::Transport(e) if e.status().is_none() => {
+                return if e.is_timeout() {
+                    vec![ErrorClass::Timeout]
+                } else {
+                    vec![ErrorClass::Network]
+                };
+            }
+            Error::Timeout => return vec![ErrorClass::Timeout],
+            _ => error.status(),
+        };
+        match status {
+            Some(status @ 400..=499) => vec![ErrorClass::Status(status), ErrorClass::ClientError],
+            Some(status @ 500..=599) => vec![ErrorClass::Status(status), ErrorClass::ServerError],
+            Some(status) => vec![ErrorClass::Status(status)],
+            None => Vec::new(),
+        }
+    }
+}
+
+impl FromStr for ErrorClass {
+    type Err = Error;
+
+    fn from_str(class: &str) -> Result<Self> {
+        match class {
+            "4xx" => Ok(ErrorClass::ClientError),
+            "5xx" => Ok(ErrorClass::ServerError),
+            "network" => Ok(ErrorClass::Network),
+            "timeout" => Ok(ErrorClass::Timeout),
+            status => status
+                .parse()
+                .ok()
+                .filter(|status| (100..600).contains(status))
+                .map(ErrorClass::Status)
+                .ok_or_else(|| Error::InvalidConfig(format!("invalid error class: {:?}", class))),
+        }
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorClass::Status(status) => write!(f, "{}", status),
+            ErrorClass::ClientError => f.write_str("4xx"),
+            ErrorClass::ServerError => f.write_str("5xx"),
+            ErrorClass::Network => f.write_str("network"),
+            ErrorClass::Timeout => f.write_str("timeout"),
+        }
+    }
+}
+
+/// What to do with a batch which failed with a given [`ErrorClass`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorAction {
+    /// Retry according to the [`RetryPolicy`](crate::RetryPolicy), even if
+    /// the error is not transient, then dead-letter the batch.
+    Retry,
+    /// Don't retry, and hand the batch to the
+    /// [`DeadLetter`](crate::DeadLetter), if any.
+    DeadLetter,
+    /// Don't retry, and drop the batch, even if there is a
+    /// [`DeadLetter`](crate::DeadLetter).
+    Drop,
+    /// Send each half of the batch separately, e.g. when Segment answers with
+    /// `413 Payload Too Large`. A batch of a single message is dead-lettered.
+    Split,
+    /// [Pause](crate::AutoBatcher::pause) the batcher, keeping the batch to
+    /// send it first once resumed, e.g. when the write key is revoked. The
+    /// error is still returned, or reported to the
+    /// [`on_error`](crate::Analytics::on_error) hook.
+    Halt,
+    /// Panic, to make tests fail loudly. Only available with the
+    /// `test-utils` feature, so it can't bring down the background task of
+    /// a production build.
+    #[cfg(any(test, feature = "test-utils"))]
+    Panic,
+}
+
+/// The [`ErrorAction`]s to take when sending a batch fails, by
+/// [`ErrorClass`]. See
+/// [`AutoBatcher::set_error_policy`](crate::AutoBatcher::set_error_policy).
+///
+/// Errors not matched by any class are handled as usual: transient ones are
+/// retried, and batches are then dead-lettered, except for `413 Payload Too
+/// Large` ones, which are [split](ErrorAction::Split) until only the
+/// oversized messages are dead-lettered. Policies deserialize from a map of
+/// classes to actions:
+///
+/// ```json
+/// { "413": "split", "401": "halt", "4xx": "drop" }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ErrorPolicy {
+    actions: BTreeMap<ErrorClass, ErrorAction>,
+}
+
+impl ErrorPolicy {
+    /// Construct a policy handling every error as usual.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take `action` on the errors of `class`, returning `self` for chaining.
+    pub fn on(mut self, class: ErrorClass, action: ErrorAction) -> Self {
+        self.actions.insert(class, action);
+        self
+    }
+
+    /// The action to take on `error`, from its most specific class with one.
+    pub(crate) fn action(&self, error: &Error) -> Option<ErrorAction> {
+        let classes = ErrorClass::of(error);
+        classes
+            .iter()
+            .find_map(|class| self.actions.get(class).copied())
+            .or_else(|| {
+                // Segment may find a batch too large despite the size checks
+                // of the batcher.
+                classes
+                    .contains(&ErrorClass::Status(413))
+                    .then_some(ErrorAction::Split)
+            })
+    }
+
+    /// Whether a batch which failed with `error` may be retried.
+    pub(crate) fn is_retryable(&self, error: &Error) -> bool {
+        match self.action(error) {
+            Some(ErrorAction::Retry) => true,
+            Some(_) => false,
+            None => error.is_retryable(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ErrorPolicy {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let actions = HashMap::<String, ErrorAction>::deserialize(deserializer)?;
+        let actions = actions
+            .into_iter()
+            .map(|(class, action)| Ok((class.parse()?, action)))
+            .collect::<Result<_>>()
+            .map_err(serde::de::Error::custom)?;
+        Ok(Self { actions })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_action() {
+        let policy: ErrorPolicy =
+            serde_json::from_str(r#"{ "413": "split", "4xx": "drop", "timeout": "retry" }"#)
+                .unwrap();
+        let rejected = |status| Error::Rejected {
+            status,
+            body: String::new(),
+        };
+        assert_eq!(policy.action(&rejected(413)), Some(ErrorAction::Split));
+        assert_eq!(policy.action(&rejected(400)), Some(ErrorAction::Drop));
+        let unavailable = || Error::Server {
+            status: 503,
+            body: String::new(),
+        };
+        assert_eq!(policy.action(&unavailable()), None);
+        assert_eq!(
+            ErrorPolicy::new().action(&rejected(413)),
+            Some(ErrorAction::Split)
+        );
+        assert_eq!(policy.action(&Error::Timeout), Some(ErrorAction::Retry));
+        assert!(!policy.is_retryable(&Error::RateLimited { retry_after: None }));
+        assert!(policy.is_retryable(&unavailable()));
+
+        assert!(serde_json::from_str::<ErrorPolicy>(r#"{ "4xy": "drop" }"#).is_err());
+        assert!(serde_json::from_str::<ErrorPolicy>(r#"{ "401": "explode" }"#).is_err());
+    }
+}
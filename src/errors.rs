@@ -1,18 +1,220 @@
 //! Errors which may arise from this crate.
 
+use std::fmt;
+use std::time::Duration;
+
 use thiserror::Error;
 
 /// An enum of errors this crate may produce. These are compatible with
 /// `failure` errors.
+///
+/// Errors are told apart by their variant and its fields rather than by
+/// their message, which may change. New variants may be added in minor
+/// releases.
+///
+/// [`Result`] is the result type of the fallible functions of this crate.
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum Error {
     /// The given message is too large to be sent to Segment's API.
     #[error("message too large")]
     MessageTooLarge,
-    #[error("Deserialize error")]
-    DeserializeError(#[from] serde_json::Error),
-    #[error("Network error")]
-    NetworkError(#[from] reqwest::Error),
+    /// A message or a response could not be serialized or deserialized.
+    #[error("serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+    /// The request to Segment's API failed before it answered, e.g. because
+    /// the connection was refused or dropped.
+    #[error("transport error: {0}")]
+    Transport(#[from] reqwest::Error),
+    /// The message is incomplete or malformed, and was rejected because
+    /// strict mode is enabled or the fault can't be fixed.
+    #[error("invalid message: {}", match .field {
+        Some(field) => format!("{}: {}", field, .reason),
+        None => .reason.clone(),
+    })]
+    InvalidMessage {
+        /// The path of the field at fault, e.g. `properties.revenue`, if a
+        /// single one is.
+        field: Option<String>,
+        /// What is wrong with the message.
+        reason: String,
+    },
+    /// The given write key is malformed.
+    #[error("invalid write key: {0}")]
+    InvalidWriteKey(&'static str),
+    /// The configuration is invalid.
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+    /// Some settings are invalid, or contradict each other. See
+    /// [`Config::validate`](crate::Config::validate).
+    #[error("invalid configuration: {}", join(.0))]
+    InvalidSettings(Vec<ConfigProblem>),
+    /// The given [`Filter`](crate::Filter) is not valid FQL.
+    #[error("invalid filter: {0}")]
+    InvalidFilter(String),
+    /// The [`Analytics`](crate::Analytics) pipeline was closed.
+    #[error("the analytics pipeline is closed")]
+    Closed,
+    /// Too many messages are already waiting to be sent.
+    #[error("too many messages are queued")]
+    QueueFull,
+    /// The [`Analytics`](crate::Analytics) pipeline was closed before the
+    /// given number of [scheduled](crate::Analytics::enqueue_at) messages
+    /// were due, and there was no [`Spool`](crate::Spool) to keep them in.
+    #[error("{0} scheduled messages not due yet were dropped")]
+    ScheduledDropped(usize),
+    /// The global [`Analytics`](crate::Analytics) instance was already
+    /// initialized.
+    #[error("the global analytics instance is already initialized")]
+    AlreadyInitialized,
+    /// The request to Segment's API did not complete in time.
+    #[error("request timed out")]
+    Timeout,
+    /// Reading or writing the [`Spool`](crate::Spool) failed.
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    /// Segment's API rejected the request with a `4xx` status code other
+    /// than `429 Too Many Requests`.
+    #[error("server rejected the request with status {status}: {body}")]
+    Rejected {
+        /// The status code of the response.
+        status: u16,
+        /// The body of the response, truncated to a few kilobytes, which
+        /// usually tells why.
+        body: String,
+    },
+    /// Segment's API answered with `429 Too Many Requests`.
+    #[error("rate limited by the server")]
+    RateLimited {
+        /// How long the server asked to wait before sending again, if it
+        /// did. The [`AutoBatcher`](crate::AutoBatcher) waits at least this
+        /// long before retrying, whatever its backoff.
+        retry_after: Option<Duration>,
+    },
+    /// Segment's API failed to handle the request, answering with a `5xx`
+    /// status code.
+    #[error("server responded with status {status}")]
+    Server {
+        /// The status code of the response.
+        status: u16,
+        /// The body of the response, truncated to a few kilobytes.
+        body: String,
+    },
+    /// Segment's API, or a collector answering like it, accepted a batch but
+    /// rejected some of its messages.
+    #[error("server rejected {} messages of the batch", .0.len())]
+    PartiallyRejected(Vec<RejectedMessage>),
+}
+
+/// A message of a batch rejected by Segment's API, while the rest of the
+/// batch was accepted. See [`Error::PartiallyRejected`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RejectedMessage {
+    /// The index of the message in the batch.
+    pub index: usize,
+    /// The status code given to the message.
+    pub status: u16,
+    /// Why the message was rejected.
+    pub reason: String,
+}
+
+impl RejectedMessage {
+    /// The error of the message on its own.
+    pub(crate) fn to_error(&self) -> Error {
+        Error::from_status(self.status, None, self.reason.clone())
+    }
+}
+
+/// A setting found invalid by [`Config::validate`](crate::Config::validate).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigProblem {
+    /// The path of the field, e.g. `retry.deadline`.
+    pub field: String,
+    /// What is wrong with it.
+    pub message: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+fn join(problems: &[ConfigProblem]) -> String {
+    let problems: Vec<_> = problems.iter().map(ToString::to_string).collect();
+    problems.join("; ")
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// The error of a response with the given error `status`, `Retry-After`
+    /// header and `body`.
+    pub(crate) fn from_status(status: u16, retry_after: Option<Duration>, body: String) -> Self {
+        match status {
+            429 => Error::RateLimited { retry_after },
+            500..=599 => Error::Server { status, body },
+            _ => Error::Rejected { status, body },
+        }
+    }
+
+    /// A message invalid as a whole.
+    pub(crate) fn invalid_message(reason: impl Into<String>) -> Self {
+        Error::InvalidMessage {
+            field: None,
+            reason: reason.into(),
+        }
+    }
+
+    /// A message invalid because of the field at `field`.
+    pub(crate) fn invalid_field(field: impl Into<String>, reason: impl Into<String>) -> Self {
+        Error::InvalidMessage {
+            field: Some(field.into()),
+            reason: reason.into(),
+        }
+    }
+
+    /// Whether the error is transient, and the request which caused it may
+    /// succeed if retried.
+    ///
+    /// Transport errors, timeouts, rate limits and server errors are
+    /// retryable.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Transport(e) => e
+                .status()
+                .is_none_or(|status| status.as_u16() == 429 || status.is_server_error()),
+            Error::Timeout | Error::RateLimited { .. } | Error::Server { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// The status code Segment's API answered with, if it answered with an
+    /// error.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Error::Rejected { status, .. } | Error::Server { status, .. } => Some(*status),
+            Error::RateLimited { .. } => Some(429),
+            Error::Transport(e) => e.status().map(|status| status.as_u16()),
+            _ => None,
+        }
+    }
+
+    /// How long Segment's API asked to wait before sending again, if it rate
+    /// limited the request and said so.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::RateLimited { retry_after } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// The body of the response, if Segment's API rejected the request or
+    /// failed to handle it.
+    pub fn response_body(&self) -> Option<&str> {
+        match self {
+            Error::Rejected { body, .. } | Error::Server { body, .. } => Some(body),
+            _ => None,
+        }
+    }
+}
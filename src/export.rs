@@ -0,0 +1,306 @@
+//! Conversion of stored messages into Parquet files.
+
+use std::io::{BufRead, Write};
+use std::sync::Arc;
+
+use parquet::data_type::{ByteArray, ByteArrayType, DataType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{SerializedFileWriter, SerializedRowGroupWriter};
+use parquet::schema::parser::parse_message_type;
+use serde_json::{Map, Value};
+
+use crate::message::{BatchMessage, StoredMessage, User};
+use crate::{Error, Result};
+
+/// The schema of the exported files. Columns are only ever added to it, at the
+/// end, so files exported by different versions can be loaded into the same
+/// table.
+const SCHEMA: &str = "
+message segment_message {
+    REQUIRED BYTE_ARRAY type (UTF8);
+    OPTIONAL BYTE_ARRAY messageId (UTF8);
+    OPTIONAL BYTE_ARRAY userId (UTF8);
+    OPTIONAL BYTE_ARRAY anonymousId (UTF8);
+    OPTIONAL BYTE_ARRAY event (UTF8);
+    OPTIONAL INT64 timestamp (TIMESTAMP(MICROS, true));
+    OPTIONAL BYTE_ARRAY properties (JSON);
+}
+";
+
+/// The number of messages in each row group.
+const ROW_GROUP_SIZE: usize = 10_000;
+
+/// Convert the messages read from `reader`, one JSON [`StoredMessage`] per
+/// line, into a Parquet file written to `writer`, returning the number of
+/// messages exported.
+///
+/// Lines holding a batch, such as the ones a [`Spool`](crate::Spool) mirror
+/// or a [`DeadLetterFile`](crate::DeadLetterFile) write, are exported as one
+/// row per message. Each row has the columns:
+///
+/// | Column        | Type                   | Content                                   |
+/// |---------------|------------------------|-------------------------------------------|
+/// | `type`        | string                 | `track`, `identify`, ...                  |
+/// | `messageId`   | string, optional       |                                           |
+/// | `userId`      | string, optional       |                                           |
+/// | `anonymousId` | string, optional       |                                           |
+/// | `event`       | string, optional       | The event of a `track`, or the name of a `page` or `screen` |
+/// | `timestamp`   | timestamp (µs, UTC), optional |                                    |
+/// | `properties`  | JSON string, optional  | The properties, or the traits of an `identify` or `group` |
+///
+/// Empty lines are skipped, and any other line which isn't a stored message
+/// fails the export. Requires the `parquet` feature.
+///
+/// ```no_run
+/// # fn main() -> segment::Result<()> {
+/// use std::fs::File;
+/// use std::io::BufReader;
+///
+/// let dead_letters = BufReader::new(File::open("dead-letters.jsonl")?);
+/// segment::export_parquet(dead_letters, File::create("dead-letters.parquet")?)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn export_parquet<R: BufRead, W: Write + Send>(reader: R, writer: W) -> Result<u64> {
+    let schema = Arc::new(parse_message_type(SCHEMA).map_err(parquet_error)?);
+    let properties = Arc::new(WriterProperties::builder().build());
+    let mut writer =
+        SerializedFileWriter::new(writer, schema, properties).map_err(parquet_error)?;
+
+    let mut rows = Rows::default();
+    let mut exported = 0;
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let msg: StoredMessage = serde_json::from_str(&line)?;
+        for msg in msg.into_batch_messages() {
+            rows.push(&msg)?;
+            exported += 1;
+            if rows.len() == ROW_GROUP_SIZE {
+                rows.write(&mut writer)?;
+            }
+        }
+    }
+    if rows.len() > 0 {
+        rows.write(&mut writer)?;
+    }
+    writer.close().map_err(parquet_error)?;
+    Ok(exported)
+}
+
+/// The columns of a row group being built.
+#[derive(Default)]
+struct Rows {
+    kinds: Vec<ByteArray>,
+    message_ids: Column<ByteArray>,
+    user_ids: Column<ByteArray>,
+    anonymous_ids: Column<ByteArray>,
+    events: Column<ByteArray>,
+    timestamps: Column<i64>,
+    properties: Column<ByteArray>,
+}
+
+/// The values of an optional column, and whether each row has one.
+struct Column<T> {
+    values: Vec<T>,
+    levels: Vec<i16>,
+}
+
+impl<T> Default for Column<T> {
+    fn default() -> Self {
+        Self {
+            values: Vec::new(),
+            levels: Vec::new(),
+        }
+    }
+}
+
+impl<T> Column<T> {
+    fn push(&mut self, value: Option<T>) {
+        self.levels.push(value.is_some() as i16);
+        self.values.extend(value);
+    }
+}
+
+impl Rows {
+    fn len(&self) -> usize {
+        self.kinds.len()
+    }
+
+    fn push(&mut self, msg: &BatchMessage) -> Result<()> {
+        let (kind, event, timestamp, fields, extra) = match msg {
+            BatchMessage::Identify(identify) => (
+                "identify",
+                None,
+                identify.timestamp,
+                Some(&*identify.traits),
+                &identify.extra,
+            ),
+            BatchMessage::Track(track) => (
+                "track",
+                Some(track.event.as_str()),
+                track.timestamp,
+                Some(&*track.properties),
+                &track.extra,
+            ),
+            BatchMessage::Page(page) => (
+                "page",
+                page.name.as_deref(),
+                page.timestamp,
+                Some(&*page.properties),
+                &page.extra,
+            ),
+            BatchMessage::Screen(screen) => (
+                "screen",
+                Some(screen.name.as_str()),
+                screen.timestamp,
+                Some(&*screen.properties),
+                &screen.extra,
+            ),
+            BatchMessage::Group(group) => (
+                "group",
+                None,
+                group.timestamp,
+                Some(&*group.traits),
+                &group.extra,
+            ),
+            BatchMessage::Alias(alias) => ("alias", None, alias.timestamp, None, &alias.extra),
+        };
+        let (user_id, anonymous_id) = match msg.user() {
+            User::UserId { user_id } => (Some(user_id), None),
+            User::AnonymousId { anonymous_id } => (None, Some(anonymous_id)),
+            User::Both {
+                user_id,
+                anonymous_id,
+            } => (Some(user_id), Some(anonymous_id)),
+        };
+        let string = |s: &str| ByteArray::from(s);
+
+        self.kinds.push(string(kind));
+        self.message_ids
+            .push(extra.get("messageId").and_then(Value::as_str).map(string));
+        self.user_ids.push(user_id.map(|id| string(id)));
+        self.anonymous_ids.push(anonymous_id.map(|id| string(id)));
+        self.events.push(event.map(string));
+        self.timestamps
+            .push(timestamp.map(|timestamp| (timestamp.unix_timestamp_nanos() / 1_000) as i64));
+        self.properties.push(
+            fields
+                .map(|fields: &Map<String, Value>| serde_json::to_vec(fields))
+                .transpose()?
+                .map(ByteArray::from),
+        );
+        Ok(())
+    }
+
+    /// Write the rows as a row group, and clear them.
+    fn write<W: Write + Send>(&mut self, writer: &mut SerializedFileWriter<W>) -> Result<()> {
+        let rows = std::mem::take(self);
+        let mut group = writer.next_row_group().map_err(parquet_error)?;
+        write_column::<ByteArrayType, _>(&mut group, &rows.kinds, None)?;
+        for column in [
+            &rows.message_ids,
+            &rows.user_ids,
+            &rows.anonymous_ids,
+            &rows.events,
+        ] {
+            write_column::<ByteArrayType, _>(&mut group, &column.values, Some(&column.levels))?;
+        }
+        let timestamps = &rows.timestamps;
+        write_column::<Int64Type, _>(&mut group, &timestamps.values, Some(&timestamps.levels))?;
+        let properties = &rows.properties;
+        write_column::<ByteArrayType, _>(&mut group, &properties.values, Some(&properties.levels))?;
+        group.close().map_err(parquet_error)?;
+        Ok(())
+    }
+}
+
+/// Write the next column of a row group.
+fn write_column<T: DataType, W: Write + Send>(
+    group: &mut SerializedRowGroupWriter<'_, W>,
+    values: &[T::T],
+    levels: Option<&[i16]>,
+) -> Result<()> {
+    let mut column = group
+        .next_column()
+        .map_err(parquet_error)?
+        .expect("the schema has a column for each field of the rows");
+    column
+        .typed::<T>()
+        .write_batch(values, levels, None)
+        .map_err(parquet_error)?;
+    column.close().map_err(parquet_error)
+}
+
+fn parquet_error(error: parquet::errors::ParquetError) -> Error {
+    Error::Io(std::io::Error::other(error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Batch, Identify, Properties, Track, Traits};
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::RowAccessor;
+    use time::OffsetDateTime;
+
+    #[test]
+    fn test_export() {
+        let track = Track {
+            user: User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            event: "Bought".to_owned(),
+            properties: Properties::new().with("price", 10),
+            timestamp: Some(OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap()),
+            ..Default::default()
+        };
+        let identify = Identify {
+            user: User::AnonymousId {
+                anonymous_id: "bar".to_owned(),
+            },
+            traits: Traits::new().with("plan", "pro"),
+            ..Default::default()
+        };
+        let mut lines = Vec::new();
+        for msg in [
+            StoredMessage::Track(track.clone()),
+            StoredMessage::Batch(Batch {
+                batch: vec![identify.into(), track.into()],
+                ..Default::default()
+            }),
+        ] {
+            serde_json::to_writer(&mut lines, &msg).unwrap();
+            lines.extend_from_slice(b"\n\n");
+        }
+
+        let path =
+            std::env::temp_dir().join(format!("segment-export-{}.parquet", uuid::Uuid::new_v4()));
+        let exported = export_parquet(&lines[..], std::fs::File::create(&path).unwrap());
+        assert_eq!(exported.unwrap(), 3);
+
+        let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        let rows: Vec<_> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].get_string(0).unwrap(), "track");
+        assert_eq!(rows[0].get_string(2).unwrap(), "foo");
+        assert_eq!(rows[0].get_string(4).unwrap(), "Bought");
+        assert_eq!(
+            rows[0].get_timestamp_micros(5).unwrap(),
+            1_700_000_000_000_000
+        );
+        assert_eq!(rows[0].get_string(6).unwrap(), r#"{"price":10}"#);
+        assert_eq!(rows[1].get_string(0).unwrap(), "identify");
+        assert_eq!(rows[1].get_string(3).unwrap(), "bar");
+        assert!(rows[1].get_string(2).is_err());
+        assert_eq!(rows[1].get_string(6).unwrap(), r#"{"plan":"pro"}"#);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,513 @@
+//! Client-side evaluation of Segment's destination filters.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+
+use crate::message::BatchMessage;
+use crate::{Error, Result};
+
+/// A condition on messages, written in the subset of Segment's [Filter Query
+/// Language](https://segment.com/docs/api/public-api/fql/) used by destination
+/// filters.
+///
+/// Filters exported from the Segment UI can be given to
+/// [`Batcher::drop_matching`](crate::Batcher::drop_matching) to drop the
+/// messages they match before they are sent, rather than once they reach
+/// Segment.
+///
+/// The supported subset consists of:
+///
+/// * fields of the message as sent to Segment, such as `type`, `event`,
+///   `userId` or `properties.price`, which are `null` when missing;
+/// * string, number, `true`, `false` and `null` literals;
+/// * the `=`, `!=`, `<`, `<=`, `>` and `>=` comparisons;
+/// * the `and`, `or` and `!` operators, and parentheses;
+/// * the `contains(field, string)`, `match(field, glob)`, `lowercase(field)`
+///   and `length(field)` functions.
+///
+/// ```
+/// use segment::Filter;
+///
+/// let filter: Filter = r#"type = "track" and match(event, "Debug *")"#.parse().unwrap();
+/// ```
+#[derive(Clone)]
+pub struct Filter {
+    source: String,
+    expr: Expr,
+}
+
+impl Filter {
+    /// Parse a filter from its FQL source.
+    pub fn parse(source: &str) -> Result<Self> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(Error::InvalidFilter(format!("unexpected {:?}", token)));
+        }
+        Ok(Self {
+            source: source.to_owned(),
+            expr,
+        })
+    }
+
+    /// The FQL source of this filter.
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Whether `msg` matches this filter.
+    pub fn matches(&self, msg: &BatchMessage) -> bool {
+        match serde_json::to_value(msg) {
+            Ok(msg) => self.matches_value(&msg),
+            Err(_) => false,
+        }
+    }
+
+    /// Whether the message serialized as `msg` matches this filter.
+    pub(crate) fn matches_value(&self, msg: &Value) -> bool {
+        truthy(&self.expr.eval(msg))
+    }
+}
+
+impl FromStr for Filter {
+    type Err = Error;
+
+    fn from_str(source: &str) -> Result<Self> {
+        Self::parse(source)
+    }
+}
+
+impl fmt::Debug for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Filter").field(&self.source).finish()
+    }
+}
+
+impl PartialEq for Filter {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl<'de> Deserialize<'de> for Filter {
+    /// Deserialize from the FQL source of the filter.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Self::parse(&source).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Literal(Value),
+    Field(Vec<String>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(Comparison, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Function {
+    Contains,
+    Match,
+    Lowercase,
+    Length,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<(Self, usize)> {
+        match name {
+            "contains" => Some((Self::Contains, 2)),
+            "match" => Some((Self::Match, 2)),
+            "lowercase" => Some((Self::Lowercase, 1)),
+            "length" => Some((Self::Length, 1)),
+            _ => None,
+        }
+    }
+}
+
+impl Expr {
+    fn eval(&self, msg: &Value) -> Value {
+        match self {
+            Expr::Literal(value) => value.clone(),
+            Expr::Field(path) => path
+                .iter()
+                .try_fold(msg, |value, key| value.get(key))
+                .cloned()
+                .unwrap_or(Value::Null),
+            Expr::Not(expr) => Value::Bool(!truthy(&expr.eval(msg))),
+            Expr::And(left, right) => {
+                Value::Bool(truthy(&left.eval(msg)) && truthy(&right.eval(msg)))
+            }
+            Expr::Or(left, right) => {
+                Value::Bool(truthy(&left.eval(msg)) || truthy(&right.eval(msg)))
+            }
+            Expr::Compare(comparison, left, right) => {
+                Value::Bool(compare(*comparison, &left.eval(msg), &right.eval(msg)))
+            }
+            Expr::Call(function, args) => {
+                let args: Vec<Value> = args.iter().map(|arg| arg.eval(msg)).collect();
+                call(*function, &args)
+            }
+        }
+    }
+}
+
+fn truthy(value: &Value) -> bool {
+    !matches!(value, Value::Null | Value::Bool(false))
+}
+
+fn compare(comparison: Comparison, left: &Value, right: &Value) -> bool {
+    let ordering = match (left, right) {
+        (Value::Number(left), Value::Number(right)) => left.as_f64().partial_cmp(&right.as_f64()),
+        (Value::String(left), Value::String(right)) => Some(left.cmp(right)),
+        _ if left == right => Some(Ordering::Equal),
+        _ => None,
+    };
+    match comparison {
+        Comparison::Eq => ordering == Some(Ordering::Equal),
+        Comparison::Ne => ordering != Some(Ordering::Equal),
+        Comparison::Lt => ordering == Some(Ordering::Less),
+        Comparison::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+        Comparison::Gt => ordering == Some(Ordering::Greater),
+        Comparison::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+    }
+}
+
+fn call(function: Function, args: &[Value]) -> Value {
+    match (function, args) {
+        (Function::Contains, [Value::String(haystack), Value::String(needle)]) => {
+            Value::Bool(haystack.contains(needle.as_str()))
+        }
+        (Function::Contains, [Value::Array(values), needle]) => {
+            Value::Bool(values.contains(needle))
+        }
+        (Function::Match, [Value::String(value), Value::String(pattern)]) => {
+            let pattern: Vec<char> = pattern.chars().collect();
+            let value: Vec<char> = value.chars().collect();
+            Value::Bool(glob(&pattern, &value))
+        }
+        (Function::Lowercase, [Value::String(value)]) => Value::String(value.to_lowercase()),
+        (Function::Length, [Value::String(value)]) => value.chars().count().into(),
+        (Function::Length, [Value::Array(values)]) => values.len().into(),
+        (Function::Length, [Value::Object(values)]) => values.len().into(),
+        (Function::Contains | Function::Match, _) => Value::Bool(false),
+        _ => Value::Null,
+    }
+}
+
+/// Whether `value` matches `pattern`, where `*` matches any sequence of
+/// characters and `?` any single character.
+fn glob(pattern: &[char], value: &[char]) -> bool {
+    let (mut p, mut v) = (0, 0);
+    let mut backtrack = None;
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, v));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    v = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Literal(Value),
+    Compare(Comparison),
+    Not,
+    And,
+    Or,
+    Open,
+    Close,
+    Comma,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        let token = match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '(' | ')' | ',' => {
+                chars.next();
+                match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    _ => Token::Comma,
+                }
+            }
+            '=' | '!' | '<' | '>' => {
+                chars.next();
+                let equals = chars.next_if_eq(&'=').is_some();
+                match (c, equals) {
+                    ('=', _) => Token::Compare(Comparison::Eq),
+                    ('!', true) => Token::Compare(Comparison::Ne),
+                    ('!', false) => Token::Not,
+                    ('<', true) => Token::Compare(Comparison::Le),
+                    ('<', false) => Token::Compare(Comparison::Lt),
+                    ('>', true) => Token::Compare(Comparison::Ge),
+                    _ => Token::Compare(Comparison::Gt),
+                }
+            }
+            '"' | '\'' => {
+                chars.next();
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => match chars.next() {
+                            Some(escaped) => string.push(escaped),
+                            None => {
+                                return Err(Error::InvalidFilter("unterminated string".to_owned()))
+                            }
+                        },
+                        Some(end) if end == c => {
+                            tokens.push(Token::Literal(Value::String(string)));
+                            break;
+                        }
+                        Some(other) => string.push(other),
+                        None => return Err(Error::InvalidFilter("unterminated string".to_owned())),
+                    }
+                }
+                continue;
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut number = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_digit() || "-+.eE".contains(*c)) {
+                    number.push(c);
+                }
+                let number: serde_json::Number = number
+                    .parse()
+                    .map_err(|_| Error::InvalidFilter(format!("invalid number: {}", number)))?;
+                Token::Literal(Value::Number(number))
+            }
+            c if c.is_alphabetic() || c == '_' || c == '$' => {
+                let mut ident = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_alphanumeric() || "_$.-".contains(*c)) {
+                    ident.push(c);
+                }
+                match ident.as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "true" => Token::Literal(Value::Bool(true)),
+                    "false" => Token::Literal(Value::Bool(false)),
+                    "null" => Token::Literal(Value::Null),
+                    _ => Token::Ident(ident),
+                }
+            }
+            other => {
+                return Err(Error::InvalidFilter(format!(
+                    "unexpected character {:?}",
+                    other
+                )))
+            }
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| Error::InvalidFilter("unexpected end of filter".to_owned()))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        let found = self.peek() == Some(token);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, expected: &Token) -> Result<()> {
+        match self.next()? {
+            token if token == *expected => Ok(()),
+            token => Err(Error::InvalidFilter(format!(
+                "expected {:?}, found {:?}",
+                expected, token
+            ))),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+        while self.eat(&Token::Or) {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.not()?;
+        while self.eat(&Token::And) {
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr> {
+        if self.eat(&Token::Not) {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        let left = self.primary()?;
+        match self.peek() {
+            Some(&Token::Compare(comparison)) => {
+                self.pos += 1;
+                let right = self.primary()?;
+                Ok(Expr::Compare(comparison, Box::new(left), Box::new(right)))
+            }
+            _ => Ok(left),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        match self.next()? {
+            Token::Literal(value) => Ok(Expr::Literal(value)),
+            Token::Open => {
+                let expr = self.or()?;
+                self.expect(&Token::Close)?;
+                Ok(expr)
+            }
+            Token::Ident(name) if self.eat(&Token::Open) => {
+                let (function, arity) = Function::from_name(&name)
+                    .ok_or_else(|| Error::InvalidFilter(format!("unknown function {}", name)))?;
+                let mut args = Vec::new();
+                if !self.eat(&Token::Close) {
+                    loop {
+                        args.push(self.or()?);
+                        if self.eat(&Token::Close) {
+                            break;
+                        }
+                        self.expect(&Token::Comma)?;
+                    }
+                }
+                if args.len() != arity {
+                    return Err(Error::InvalidFilter(format!(
+                        "{} takes {} arguments, got {}",
+                        name,
+                        arity,
+                        args.len()
+                    )));
+                }
+                Ok(Expr::Call(function, args))
+            }
+            Token::Ident(name) => Ok(Expr::Field(name.split('.').map(str::to_owned).collect())),
+            token => Err(Error::InvalidFilter(format!("unexpected {:?}", token))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Identify, Properties, Track, User};
+
+    fn track(event: &str, properties: Properties) -> BatchMessage {
+        BatchMessage::from(Track {
+            user: User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            event: event.to_owned(),
+            properties,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_matches() {
+        let filter = Filter::parse(
+            r#"type = "track" and (match(event, "Debug *") or properties.price < 1.5)"#,
+        )
+        .unwrap();
+        assert!(filter.matches(&track("Debug click", Properties::new())));
+        assert!(filter.matches(&track("Bought", Properties::new().with("price", 1))));
+        assert!(!filter.matches(&track("Bought", Properties::new().with("price", 2))));
+        assert!(!filter.matches(&track("Bought", Properties::new())));
+        assert!(!filter.matches(&BatchMessage::from(Identify::default())));
+
+        let filter =
+            Filter::parse(r#"!contains(lowercase(userId), "FOO") and length(event) >= 3"#).unwrap();
+        assert!(filter.matches(&track("Bought", Properties::new())));
+        assert!(!filter.matches(&track("Ok", Properties::new())));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for source in [
+            "",
+            "event =",
+            r#"event = "unterminated"#,
+            "(type = 'track'",
+            "unknown(event)",
+            "contains(event)",
+            "event = 'a' 'b'",
+            "event # 'a'",
+        ] {
+            assert!(Filter::parse(source).is_err(), "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn test_glob() {
+        let glob = |pattern: &str, value: &str| {
+            let pattern: Vec<char> = pattern.chars().collect();
+            let value: Vec<char> = value.chars().collect();
+            glob(&pattern, &value)
+        };
+        assert!(glob("*", ""));
+        assert!(glob("a*c", "abbbc"));
+        assert!(glob("a?c*", "aéch"));
+        assert!(!glob("a*c", "abcd"));
+        assert!(glob("*b*b", "abab"));
+    }
+}
@@ -0,0 +1,153 @@
+//! A process-wide [`Analytics`] instance, for applications which would rather
+//! not pass a handle around.
+
+use std::sync::OnceLock;
+
+use crate::message::{Alias, BatchMessage, Group, Identify, Page, Screen, Track};
+use crate::{Analytics, Error, Result};
+#[cfg(any(feature = "tokio", feature = "smol"))]
+use crate::{ClientBuilder, Config};
+
+static GLOBAL: OnceLock<Analytics> = OnceLock::new();
+
+/// Build the global [`Analytics`] instance from `config`.
+///
+/// Like [`log::set_logger`], this may only be called once; later calls return
+/// [`Error::AlreadyInitialized`]. Until it is called, [`track`] and the other
+/// free functions silently drop their messages.
+///
+/// ```no_run
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> segment::Result<()> {
+/// use segment::message::{Track, User};
+///
+/// segment::init(segment::Config::from_file("segment.toml")?)?;
+///
+/// segment::track(Track {
+///     user: User::UserId { user_id: "user".to_owned() },
+///     event: "Example".to_owned(),
+///     ..Default::default()
+/// })?;
+///
+/// segment::close().await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Available with the `tokio` or `smol` feature, which runs the background
+/// task.
+#[cfg(any(feature = "tokio", feature = "smol"))]
+pub fn init(config: Config) -> Result<()> {
+    if GLOBAL.get().is_some() {
+        return Err(Error::AlreadyInitialized);
+    }
+    set_global(ClientBuilder::from_config(config).build()?)
+}
+
+/// Use `analytics` as the global instance.
+///
+/// Returns [`Error::AlreadyInitialized`] if a global instance was already set.
+pub fn set_global(analytics: Analytics) -> Result<()> {
+    GLOBAL.set(analytics).map_err(|_| Error::AlreadyInitialized)
+}
+
+/// The global instance, if it was initialized.
+pub fn global() -> Option<&'static Analytics> {
+    GLOBAL.get()
+}
+
+fn enqueue(msg: impl Into<BatchMessage>) -> Result<()> {
+    match GLOBAL.get() {
+        Some(analytics) => analytics.enqueue(msg),
+        None => Ok(()),
+    }
+}
+
+/// Queue an `identify` message on the global instance.
+pub fn identify(msg: Identify) -> Result<()> {
+    enqueue(msg)
+}
+
+/// Queue a `track` message on the global instance.
+pub fn track(msg: Track) -> Result<()> {
+    enqueue(msg)
+}
+
+/// Queue a `page` message on the global instance.
+pub fn page(msg: Page) -> Result<()> {
+    enqueue(msg)
+}
+
+/// Queue a `screen` message on the global instance.
+pub fn screen(msg: Screen) -> Result<()> {
+    enqueue(msg)
+}
+
+/// Queue a `group` message on the global instance.
+pub fn group(msg: Group) -> Result<()> {
+    enqueue(msg)
+}
+
+/// Queue an `alias` message on the global instance.
+pub fn alias(msg: Alias) -> Result<()> {
+    enqueue(msg)
+}
+
+/// Send the messages queued on the global instance, and wait until they are
+/// sent.
+pub async fn flush() -> Result<()> {
+    match GLOBAL.get() {
+        Some(analytics) => analytics.flush().await,
+        None => Ok(()),
+    }
+}
+
+/// Send the messages queued on the global instance and stop it. Later calls
+/// to [`track`] and the other free functions return [`Error::Closed`].
+pub async fn close() -> Result<()> {
+    match GLOBAL.get() {
+        Some(analytics) => analytics.close().await,
+        None => Ok(()),
+    }
+}
+
+#[cfg(all(test, any(feature = "tokio", feature = "smol")))]
+mod tests {
+    use super::*;
+    use crate::message::User;
+    use crate::test_utils::MockClient;
+    use crate::{AutoBatcher, Batcher, WriteKey};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_global() {
+        let msg = Track {
+            user: User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            event: "Example".to_owned(),
+            ..Default::default()
+        };
+        // Dropped silently before initialization.
+        track(msg.clone()).unwrap();
+
+        let client = MockClient::new();
+        let batcher = AutoBatcher::new(
+            client.clone(),
+            Batcher::new(None),
+            WriteKey::new("key").unwrap(),
+        );
+        set_global(Analytics::new(batcher, Duration::from_secs(3600))).unwrap();
+        assert!(matches!(
+            init(Config::default()),
+            Err(Error::AlreadyInitialized)
+        ));
+
+        track(msg.clone()).unwrap();
+        flush().await.unwrap();
+        assert_eq!(client.tracks().len(), 1);
+
+        close().await.unwrap();
+        assert!(matches!(track(msg), Err(Error::Closed)));
+    }
+}
@@ -0,0 +1,137 @@
+//! Periodic events proving that a pipeline is still alive.
+
+use std::convert::TryFrom;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::config::duration;
+use crate::message::{Properties, Track, User};
+use crate::{runtime, Analytics, Error};
+
+/// The name of heartbeat events.
+pub const HEARTBEAT: &str = "SDK Heartbeat";
+
+/// How often an [`Analytics`] pipeline sends an `SDK Heartbeat` event. See
+/// [`Analytics::start_heartbeat`].
+///
+/// A service whose analytics silently died stops sending heartbeats, which
+/// can be noticed from Segment's side, unlike the lack of other events.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Heartbeat {
+    /// The time between two heartbeats.
+    #[serde(with = "duration")]
+    pub interval: Duration,
+    /// The anonymous ID heartbeats are sent with, or a new one for each
+    /// process if `None`, so the heartbeats of several instances can be told
+    /// apart.
+    pub anonymous_id: Option<String>,
+}
+
+impl Default for Heartbeat {
+    /// A heartbeat every 5 minutes.
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5 * 60),
+            anonymous_id: None,
+        }
+    }
+}
+
+impl Analytics {
+    /// Send an `SDK Heartbeat` event right away, and then every
+    /// `heartbeat.interval` until this handle is [closed](Analytics::close).
+    ///
+    /// Its properties are the statistics of the pipeline at the time:
+    ///
+    /// - `sequence`: the number of heartbeats sent before this one;
+    /// - `uptime`: the number of seconds since the first heartbeat;
+    /// - `queued`: the number of messages waiting for the background tasks;
+    /// - `retries`, `time_in_backoff` (in milliseconds) and `rate_limited`:
+    ///   the totals of the [metrics](Analytics::metrics);
+    /// - `library_version`: the version of this crate.
+    ///
+    /// See [`Analytics::new`] for the runtime requirements.
+    #[cfg(any(feature = "tokio", feature = "smol"))]
+    pub fn start_heartbeat(&self, heartbeat: Heartbeat) {
+        runtime::spawn(self.heartbeat(heartbeat));
+    }
+
+    /// The task sending the heartbeats, until this handle is closed.
+    pub(crate) fn heartbeat(&self, heartbeat: Heartbeat) -> impl Future<Output = ()> + Send {
+        let analytics = self.clone();
+        let interval = heartbeat.interval;
+        let anonymous_id = heartbeat
+            .anonymous_id
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        async move {
+            let started = Instant::now();
+            for sequence in 0u64.. {
+                let track = Track {
+                    user: User::AnonymousId {
+                        anonymous_id: anonymous_id.clone(),
+                    },
+                    event: HEARTBEAT.to_owned(),
+                    properties: analytics.heartbeat_properties(sequence, started),
+                    ..Default::default()
+                };
+                match analytics.enqueue(track) {
+                    Ok(()) => (),
+                    Err(Error::Closed) => break,
+                    Err(e) => log::warn!("could not send a heartbeat: {}", e),
+                }
+                runtime::sleep(interval).await;
+            }
+        }
+    }
+
+    fn heartbeat_properties(&self, sequence: u64, started: Instant) -> Properties {
+        let metrics = self.metrics();
+        Properties::new()
+            .with("sequence", sequence)
+            .with("uptime", started.elapsed().as_secs())
+            .with("queued", self.queued())
+            .with("retries", metrics.retries())
+            .with(
+                "time_in_backoff",
+                u64::try_from(metrics.time_in_backoff().as_millis()).unwrap_or(u64::MAX),
+            )
+            .with("rate_limited", metrics.rate_limited().values().sum::<u64>())
+            .with("library_version", env!("CARGO_PKG_VERSION"))
+    }
+}
+
+#[cfg(all(test, any(feature = "tokio", feature = "smol")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::MockClient;
+    use crate::{AutoBatcher, Batcher, WriteKey};
+
+    #[tokio::test]
+    async fn test_heartbeat() {
+        let client = MockClient::new();
+        let batcher = AutoBatcher::new(
+            client.clone(),
+            Batcher::new(None),
+            WriteKey::new("key").unwrap(),
+        );
+        let analytics = Analytics::new(batcher, Duration::from_secs(3600));
+        analytics.start_heartbeat(Heartbeat {
+            interval: Duration::from_millis(10),
+            anonymous_id: Some("instance".to_owned()),
+        });
+
+        tokio::time::sleep(Duration::from_millis(35)).await;
+        analytics.close().await.unwrap();
+        let tracks = client.tracks();
+        assert!(tracks.len() >= 2);
+        assert!(tracks.iter().all(|track| track.event == HEARTBEAT));
+        assert_eq!(tracks[1].properties["sequence"], 1);
+        assert_eq!(
+            tracks[0].properties["library_version"],
+            env!("CARGO_PKG_VERSION")
+        );
+    }
+}
@@ -1,19 +1,136 @@
 //! Low-level HTTP bindings to the Segment tracking API.
 
+use crate::compression;
+use crate::dns::CachingResolver;
+use crate::validation;
 use crate::Client;
+use crate::Compression;
+use crate::Config;
+use crate::Error;
 use crate::Message;
+use crate::Redactor;
+use crate::RejectedMessage;
 use crate::Result;
-use std::time::Duration;
+use hyper::client::connect::HttpInfo;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use time::format_description::well_known::{Rfc2822, Rfc3339};
+use time::OffsetDateTime;
+
+/// The number of bytes of the body of a rejected request kept in the error.
+const MAX_ERROR_BODY: usize = 4096;
+
+/// How long idle connections are kept in the pool, as reqwest does by
+/// default. A connection idle for longer can't be reused, so one seen again
+/// is a new connection which happens to have the same addresses.
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
 
 /// A client which synchronously sends single messages to the Segment tracking
 /// API.
 ///
 /// `HttpClient` implements [`Client`](../client/trait.Client.html); see the
 /// documentation for `Client` for more on how to send events to Segment.
+///
+/// When debug logging is enabled with
+/// [`enable_debug_logging`](HttpClient::enable_debug_logging), every outgoing
+/// payload is logged at the `debug` level through the [`log`] crate, with the
+/// fields configured in the [`Redactor`] masked.
+///
+/// In [strict mode](HttpClient::enable_strict_mode), incomplete messages are
+/// rejected instead of being sent.
+///
+/// The difference between the local clock and the clock of Segment's servers
+/// is measured from the `Date` header of every response, and is available
+/// through [`clock_skew`](HttpClient::clock_skew).
+///
+/// When Segment rejects a request with a `4xx` status code, the beginning of
+/// the body of its response is returned in [`Error::Rejected`](crate::Error::Rejected),
+/// or in [`Error::Server`](crate::Error::Server) for `5xx` status codes.
+/// `429 Too Many Requests` is returned as
+/// [`Error::RateLimited`](crate::Error::RateLimited), along with its
+/// `Retry-After` header.
+/// When it accepts a batch but lists some of its messages as failed, they are
+/// returned in [`Error::PartiallyRejected`](crate::Error::PartiallyRejected);
+/// see [`send`](HttpClient::send).
+///
+/// With the [connection audit](HttpClient::enable_connection_audit), whether
+/// each request reused a connection, and whether its body was compressed, is
+/// recorded in a [`FlushReport`].
 #[derive(Clone, Debug)]
 pub struct HttpClient {
     client: reqwest::Client,
     host: String,
+    debug: Option<Redactor>,
+    strict: bool,
+    skew: Arc<Mutex<Option<time::Duration>>>,
+    compensate_skew: bool,
+    audit: Option<Arc<Mutex<ConnectionAudit>>>,
+    compression: Option<Compression>,
+    /// The paths which answered a compressed body with `415 Unsupported Media
+    /// Type`, and are sent uncompressed bodies since.
+    uncompressed: Arc<Mutex<HashSet<&'static str>>>,
+}
+
+/// How each request sent since the last [`FlushReport`] was taken got its
+/// connection. See [`HttpClient::enable_connection_audit`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FlushReport {
+    /// The requests, in the order their responses were received.
+    pub requests: Vec<RequestReport>,
+}
+
+impl FlushReport {
+    /// The number of requests which reused a connection.
+    pub fn reused(&self) -> usize {
+        self.requests
+            .iter()
+            .filter(|request| request.reused)
+            .count()
+    }
+
+    /// The number of requests which established a new connection.
+    pub fn established(&self) -> usize {
+        self.requests.len() - self.reused()
+    }
+}
+
+/// The connection a request was sent on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestReport {
+    /// The path of the API the request was sent to, e.g. `/v1/batch`.
+    pub path: &'static str,
+    /// The local address of the connection, if it is known.
+    pub local_addr: Option<SocketAddr>,
+    /// The address the connection was established to, e.g. a proxy.
+    pub remote_addr: Option<SocketAddr>,
+    /// Whether the connection was used by a previous request.
+    pub reused: bool,
+    /// Whether the body of the request was sent gzipped.
+    pub compressed: bool,
+}
+
+/// The requests recorded by the connection audit, shared between the clones
+/// of a client.
+#[derive(Debug, Default)]
+struct ConnectionAudit {
+    /// When each connection, by its local and remote addresses, was last
+    /// used, for the connections which may still be in the pool.
+    seen: HashMap<(SocketAddr, SocketAddr), Instant>,
+    requests: Vec<RequestReport>,
+}
+
+impl ConnectionAudit {
+    /// Whether `connection` was used by a previous request, still recent
+    /// enough for the connection to have been kept in the pool since.
+    fn is_reused(&mut self, connection: (SocketAddr, SocketAddr), now: Instant) -> bool {
+        self.seen
+            .retain(|_, used| now.duration_since(*used) < POOL_IDLE_TIMEOUT);
+        self.seen.insert(connection, now).is_some()
+    }
 }
 
 impl Default for HttpClient {
@@ -24,6 +141,13 @@ impl Default for HttpClient {
                 .build()
                 .unwrap(),
             host: "https://api.segment.io".to_owned(),
+            debug: None,
+            strict: false,
+            skew: Arc::default(),
+            compensate_skew: false,
+            audit: None,
+            compression: None,
+            uncompressed: Arc::default(),
         }
     }
 }
@@ -36,13 +160,237 @@ impl HttpClient {
     /// the `Default::default` value, which will send events to
     /// `https://api.segment.io`.
     pub fn new(client: reqwest::Client, host: String) -> HttpClient {
-        HttpClient { client, host }
+        HttpClient {
+            client,
+            host,
+            debug: None,
+            strict: false,
+            skew: Arc::default(),
+            compensate_skew: false,
+            audit: None,
+            compression: None,
+            uncompressed: Arc::default(),
+        }
+    }
+
+    /// Log every outgoing payload at the `debug` level, masking the fields
+    /// known to the given `redactor`.
+    ///
+    /// The write key is never logged.
+    pub fn enable_debug_logging(&mut self, redactor: Redactor) {
+        self.debug = Some(redactor);
+    }
+
+    /// Return an error instead of sending messages with neither a user ID nor
+    /// an anonymous ID, or `track` events with an empty event name.
+    pub fn enable_strict_mode(&mut self) {
+        self.strict = true;
+    }
+
+    /// Stamp every outgoing message with a `sentAt` field, and shift both it
+    /// and the `timestamp` of the messages by the last measured [clock
+    /// skew](HttpClient::clock_skew).
+    ///
+    /// Segment corrects the timestamp of a message as `receivedAt - (sentAt -
+    /// timestamp)`, so shifting both by the same skew keeps that correction
+    /// right, while the timestamps themselves are on the clock of Segment's
+    /// servers.
+    pub fn compensate_clock_skew(&mut self) {
+        self.compensate_skew = true;
+    }
+
+    /// Record, for every request, whether it reused a connection or
+    /// established a new one, to check that keep-alive works, e.g. through a
+    /// proxy. The requests are reported by
+    /// [`take_flush_report`](HttpClient::take_flush_report).
+    ///
+    /// A connection is recognized by its local and remote addresses, so
+    /// requests whose addresses are unknown, e.g. through a Unix socket, count
+    /// as establishing a new one. A new connection with the addresses of one
+    /// idle for longer than the 90 seconds reqwest keeps idle connections by
+    /// default is recognized as new too, but not one reusing the local port of
+    /// a connection closed more recently, which is rare.
+    pub fn enable_connection_audit(&mut self) {
+        self.audit.get_or_insert_with(Arc::default);
+    }
+
+    /// Send the bodies of requests gzipped, when `compression` says so.
+    ///
+    /// If a path answers a compressed body with `415 Unsupported Media Type`,
+    /// as collectors which don't accept gzip do, the request is sent again
+    /// uncompressed, and so are the later requests to that path.
+    pub fn enable_compression(&mut self, compression: Compression) {
+        self.compression = Some(compression);
+    }
+
+    /// Whether a body of `len` bytes sent to `path` is compressed.
+    fn compresses(&self, path: &'static str, len: usize) -> bool {
+        self.compression
+            .as_ref()
+            .is_some_and(|compression| compression.applies(path, len))
+            && !self.uncompressed.lock().unwrap().contains(path)
+    }
+
+    /// Post `body` to `url`, gzipped if `gzip` is set.
+    async fn post(
+        &self,
+        url: &str,
+        write_key: &str,
+        body: &[u8],
+        gzip: bool,
+    ) -> Result<reqwest::Response> {
+        let request = self
+            .client
+            .post(url)
+            .basic_auth(write_key, Some(""))
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        let request = if gzip {
+            request
+                .header(reqwest::header::CONTENT_ENCODING, "gzip")
+                .body(compression::gzip(body)?)
+        } else {
+            request.body(body.to_vec())
+        };
+        Ok(request.send().await?)
+    }
+
+    /// The requests sent since the report was last taken, or an empty report
+    /// if the [connection audit](HttpClient::enable_connection_audit) is not
+    /// enabled.
+    ///
+    /// Taking the report after each flush reports the connections of its
+    /// batches.
+    pub fn take_flush_report(&self) -> FlushReport {
+        let requests = match &self.audit {
+            Some(audit) => std::mem::take(&mut audit.lock().unwrap().requests),
+            None => Vec::new(),
+        };
+        FlushReport { requests }
+    }
+
+    /// How far ahead of the local clock the clock of Segment's servers was on
+    /// the last response, or `None` if no response was received yet.
+    ///
+    /// The `Date` header only has a precision of one second, so neither has
+    /// the skew.
+    pub fn clock_skew(&self) -> Option<time::Duration> {
+        *self.skew.lock().unwrap()
+    }
+
+    /// Resolve the address of Segment's API and connect to it, so the next
+    /// request reuses the connection instead of waiting for it to be
+    /// established.
+    ///
+    /// Any response counts as a success, since only the connection matters.
+    pub async fn warm_up(&self) -> Result<()> {
+        self.client.head(&self.host).send().await?;
+        Ok(())
+    }
+}
+
+/// Build a `reqwest::Client` with the timeouts of `config`.
+pub(crate) fn reqwest_client(config: &Config) -> Result<reqwest::Client> {
+    let mut client = reqwest::Client::builder()
+        .connect_timeout(config.connect_timeout)
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT);
+    if let Some(timeout) = config.timeout {
+        client = client.timeout(timeout);
+    }
+    if let Some(ttl) = config.dns_cache_ttl {
+        client = client.dns_resolver(Arc::new(CachingResolver::new(ttl)));
+    }
+    Ok(client.build()?)
+}
+
+/// Parse a `Retry-After` header, either a number of seconds or the date after
+/// which to retry, relative to `now`.
+fn parse_retry_after(value: &str, now: OffsetDateTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = OffsetDateTime::parse(value, &Rfc2822).ok()?;
+    let wait = date - now;
+    Some(if wait.is_positive() {
+        wait.unsigned_abs()
+    } else {
+        Duration::ZERO
+    })
+}
+
+/// Read at most `max` bytes of the body of `response`, ignoring errors.
+async fn read_capped(mut response: reqwest::Response, max: usize) -> String {
+    let mut body = Vec::new();
+    while body.len() < max {
+        match response.chunk().await {
+            Ok(Some(chunk)) => body.extend_from_slice(&chunk),
+            _ => break,
+        }
+    }
+    body.truncate(max);
+    String::from_utf8_lossy(&body).into_owned()
+}
+
+/// The body of a successful response to a batch, which may give the status
+/// of each of its messages, in order:
+///
+/// ```json
+/// { "results": [{ "status": 200 }, { "status": 400, "error": "invalid userId" }] }
+/// ```
+#[derive(Deserialize)]
+struct BatchResponse {
+    #[serde(default)]
+    results: Vec<MessageResult>,
+}
+
+#[derive(Deserialize)]
+struct MessageResult {
+    status: u16,
+    #[serde(default)]
+    error: String,
+}
+
+/// Fail with the messages a successful response to a batch rejects, if any.
+/// Responses without per-message results, like Segment's, accept them all.
+fn check_results(body: &[u8]) -> Result<()> {
+    let results = match serde_json::from_slice::<BatchResponse>(body) {
+        Ok(response) => response.results,
+        Err(_) => return Ok(()),
+    };
+    let rejected: Vec<RejectedMessage> = results
+        .into_iter()
+        .enumerate()
+        .filter(|(_, result)| result.status >= 400)
+        .map(|(index, result)| RejectedMessage {
+            index,
+            status: result.status,
+            reason: result.error,
+        })
+        .collect();
+    if rejected.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::PartiallyRejected(rejected))
     }
 }
 
 #[async_trait::async_trait]
 impl Client for HttpClient {
-    async fn send(&self, write_key: String, msg: Message) -> Result<()> {
+    /// Send `msg` to Segment.
+    ///
+    /// If the response to a batch lists a `results` array, with a `status`
+    /// and an optional `error` for each message in order, the messages with
+    /// a `4xx` or `5xx` status are returned in
+    /// [`Error::PartiallyRejected`](crate::Error::PartiallyRejected), and the
+    /// others count as accepted.
+    async fn send(&self, write_key: String, mut msg: Message) -> Result<()> {
+        if self.strict {
+            validation::check_message_complete(&msg)?;
+        }
+        if self.compensate_skew {
+            compensate_skew(&mut msg, self.clock_skew().unwrap_or_default());
+        }
+
         let path = match msg {
             Message::Identify(_) => "/v1/identify",
             Message::Track(_) => "/v1/track",
@@ -53,15 +401,353 @@ impl Client for HttpClient {
             Message::Batch(_) => "/v1/batch",
         };
 
-        let _ = self
-            .client
-            .post(&format!("{}{}", self.host, path))
-            .basic_auth(write_key, Some(""))
-            .json(&msg)
-            .send()
-            .await?
-            .error_for_status()?;
+        if let Some(redactor) = &self.debug {
+            if log::log_enabled!(log::Level::Debug) {
+                let mut payload = serde_json::to_value(&msg)?;
+                redactor.redact(&mut payload);
+                log::debug!("sending to {}{}: {}", self.host, path, payload);
+            }
+        }
+
+        let url = format!("{}{}", self.host, path);
+        let body = serde_json::to_vec(&msg)?;
+        let mut compressed = self.compresses(path, body.len());
+        let mut response = self.post(&url, &write_key, &body, compressed).await?;
+        if compressed && response.status() == reqwest::StatusCode::UNSUPPORTED_MEDIA_TYPE {
+            log::warn!(
+                "{} does not accept gzip, sending it uncompressed bodies",
+                url
+            );
+            self.uncompressed.lock().unwrap().insert(path);
+            compressed = false;
+            response = self.post(&url, &write_key, &body, false).await?;
+        }
+
+        if let Some(audit) = &self.audit {
+            let info = response.extensions().get::<HttpInfo>();
+            let local_addr = info.map(HttpInfo::local_addr);
+            let mut audit = audit.lock().unwrap();
+            let reused = match info {
+                Some(info) => {
+                    audit.is_reused((info.local_addr(), info.remote_addr()), Instant::now())
+                }
+                None => false,
+            };
+            audit.requests.push(RequestReport {
+                path,
+                local_addr,
+                remote_addr: info.map(HttpInfo::remote_addr),
+                reused,
+                compressed,
+            });
+        }
+
+        let server_date = response
+            .headers()
+            .get(reqwest::header::DATE)
+            .and_then(|date| date.to_str().ok())
+            .and_then(|date| OffsetDateTime::parse(date, &Rfc2822).ok());
+        if let Some(server_date) = server_date {
+            *self.skew.lock().unwrap() = Some(server_date - OffsetDateTime::now_utc());
+        }
+
+        if response.status().is_client_error() || response.status().is_server_error() {
+            let status = response.status().as_u16();
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|retry_after| retry_after.to_str().ok())
+                .and_then(|retry_after| parse_retry_after(retry_after, OffsetDateTime::now_utc()));
+            return Err(Error::from_status(
+                status,
+                retry_after,
+                read_capped(response, MAX_ERROR_BODY).await,
+            ));
+        }
+        let mut response = response.error_for_status()?;
+        if path == "/v1/batch" {
+            return check_results(&response.bytes().await?);
+        }
+        // Read the rest of the body, or the connection can't be reused.
+        while let Ok(Some(_)) = response.chunk().await {}
 
         Ok(())
     }
+
+    /// Use the host and timeouts of `config`, and enable or disable debug
+    /// logging. If `config` doesn't list fields to redact, the current
+    /// redactor is kept.
+    fn reconfigure(&mut self, config: &Config) -> Result<()> {
+        self.client = reqwest_client(config)?;
+        self.host = config.host.clone();
+        // The new client starts with no connection.
+        self.audit = config.audit_connections.then(Arc::default);
+        self.compression = config.compression.clone();
+        self.uncompressed = Arc::default();
+        self.debug = match (config.debug, &config.redact) {
+            (false, _) => None,
+            (true, Some(fields)) => Some(Redactor::new(fields)),
+            (true, None) => Some(self.debug.take().unwrap_or_default()),
+        };
+        Ok(())
+    }
+}
+
+/// Stamp `msg` with a `sentAt` field, shifting it and the timestamps of the
+/// messages by `skew`.
+fn compensate_skew(msg: &mut Message, skew: time::Duration) {
+    let sent_at = OffsetDateTime::now_utc() + skew;
+    match msg {
+        Message::Batch(batch) => {
+            batch.sent_at = Some(sent_at);
+            for msg in &mut batch.batch {
+                if let Some(timestamp) = msg.timestamp_mut() {
+                    *timestamp += skew;
+                }
+            }
+        }
+        msg => {
+            if let Some(Some(timestamp)) = msg.timestamp_mut() {
+                *timestamp += skew;
+            }
+            if let Ok(sent_at) = sent_at.format(&Rfc3339) {
+                msg.extra_mut()
+                    .insert("sentAt".to_owned(), Value::String(sent_at));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Batch, BatchMessage, Track, User};
+    use crate::test_utils::FakeServer;
+
+    #[tokio::test]
+    async fn test_clock_skew() {
+        let server = FakeServer::start();
+        let mut client = HttpClient::new(reqwest::Client::new(), server.url());
+        client.compensate_clock_skew();
+        assert_eq!(client.clock_skew(), None);
+
+        let msg = Track {
+            user: User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            event: "Foo".to_owned(),
+            ..Default::default()
+        };
+        client
+            .send("key".to_owned(), msg.clone().into())
+            .await
+            .unwrap();
+
+        let skew = client.clock_skew().unwrap();
+        assert!(skew.abs() <= time::Duration::seconds(2));
+        assert!(server.accepted()[0]["sentAt"].is_string());
+
+        // The local clock is an hour behind Segment's.
+        server.set_clock_offset(time::Duration::hours(1));
+        client.send("key".to_owned(), msg.into()).await.unwrap();
+        let skew = client.clock_skew().unwrap();
+        assert!((skew - time::Duration::hours(1)).abs() <= time::Duration::seconds(2));
+
+        let timestamp = OffsetDateTime::now_utc() - time::Duration::minutes(5);
+        let batch = Batch {
+            batch: vec![BatchMessage::Track(Track {
+                user: User::UserId {
+                    user_id: "foo".to_owned(),
+                },
+                event: "Foo".to_owned(),
+                timestamp: Some(timestamp),
+                ..Default::default()
+            })],
+            ..Default::default()
+        };
+        client.send("key".to_owned(), batch.into()).await.unwrap();
+        let received_at = OffsetDateTime::now_utc() + time::Duration::hours(1);
+
+        let body = &server.accepted()[2];
+        let parse = |value: &Value| OffsetDateTime::parse(value.as_str().unwrap(), &Rfc3339);
+        let sent_at = parse(&body["sentAt"]).unwrap();
+        let sent = parse(&body["batch"][0]["timestamp"]).unwrap();
+        assert_eq!(sent, timestamp + skew);
+        // The timestamp Segment computes is on its own clock.
+        let corrected = received_at - (sent_at - sent);
+        let expected = timestamp + time::Duration::hours(1);
+        assert!((corrected - expected).abs() <= time::Duration::seconds(2));
+    }
+
+    #[test]
+    fn test_check_results() {
+        assert!(check_results(br#"{"success":true}"#).is_ok());
+        assert!(check_results(b"OK").is_ok());
+        let body =
+            br#"{"results":[{"status":200},{"status":400,"error":"no userId"},{"status":202}]}"#;
+        match check_results(body) {
+            Err(Error::PartiallyRejected(rejected)) => assert_eq!(
+                rejected,
+                [RejectedMessage {
+                    index: 1,
+                    status: 400,
+                    reason: "no userId".to_owned(),
+                }]
+            ),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejected_body() {
+        let server = FakeServer::start();
+        let client = HttpClient::new(reqwest::Client::new(), server.url());
+        server.fail_next(400, 1);
+
+        let error = client
+            .send("key".to_owned(), Track::default().into())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::Rejected { status: 400, .. }));
+        assert!(!error.is_retryable());
+        assert!(error.response_body().unwrap().contains("Bad Request"));
+    }
+
+    #[tokio::test]
+    async fn test_error_status() {
+        let server = FakeServer::start();
+        let client = HttpClient::new(reqwest::Client::new(), server.url());
+        let send = || client.send("key".to_owned(), Track::default().into());
+
+        server.rate_limit_next("7", 1);
+        let error = send().await.unwrap_err();
+        assert!(matches!(error, Error::RateLimited { .. }));
+        assert_eq!(error.status(), Some(429));
+        assert_eq!(error.retry_after(), Some(Duration::from_secs(7)));
+        assert!(error.is_retryable());
+
+        server.fail_next(503, 1);
+        let error = send().await.unwrap_err();
+        assert!(matches!(error, Error::Server { status: 503, .. }));
+        assert_eq!(error.status(), Some(503));
+        assert_eq!(error.retry_after(), None);
+        assert!(error.is_retryable());
+
+        server.fail_next(413, 1);
+        let error = send().await.unwrap_err();
+        assert!(matches!(error, Error::Rejected { status: 413, .. }));
+        assert_eq!(error.status(), Some(413));
+        assert!(!error.is_retryable());
+
+        send().await.unwrap();
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = OffsetDateTime::parse("Wed, 21 Oct 2015 07:28:00 GMT", &Rfc2822).unwrap();
+        assert_eq!(
+            parse_retry_after(" 120 ", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:29:30 GMT", now),
+            Some(Duration::from_secs(90))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[tokio::test]
+    async fn test_connection_audit() {
+        let server = FakeServer::start();
+        let mut client = HttpClient::new(reqwest::Client::new(), server.url());
+        client.enable_connection_audit();
+
+        for _ in 0..3 {
+            client
+                .send("key".to_owned(), Track::default().into())
+                .await
+                .unwrap();
+        }
+        let report = client.take_flush_report();
+        assert_eq!(report.requests.len(), 3);
+        assert_eq!(report.requests[0].path, "/v1/track");
+        assert!(!report.requests[0].reused);
+        assert_eq!((report.established(), report.reused()), (1, 2));
+        assert_eq!(report.requests[1].local_addr, report.requests[0].local_addr);
+        assert_eq!(client.take_flush_report(), FlushReport::default());
+    }
+
+    #[test]
+    fn test_recycled_port() {
+        let mut audit = ConnectionAudit::default();
+        let connection = (
+            "127.0.0.1:50000".parse().unwrap(),
+            "127.0.0.1:443".parse().unwrap(),
+        );
+        let other = ("127.0.0.1:50001".parse().unwrap(), connection.1);
+        let start = Instant::now();
+        assert!(!audit.is_reused(connection, start));
+        assert!(audit.is_reused(connection, start + Duration::from_secs(60)));
+        assert!(!audit.is_reused(other, start + Duration::from_secs(60)));
+
+        // Past the idle timeout, the port must have been recycled.
+        assert!(!audit.is_reused(connection, start + Duration::from_secs(180)));
+        assert_eq!(audit.seen.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let server = FakeServer::start();
+        let mut client = HttpClient::new(reqwest::Client::new(), server.url());
+        client.enable_connection_audit();
+        client.enable_compression(Compression {
+            threshold: 100,
+            ..Default::default()
+        });
+        let large = Track {
+            event: "Large".to_owned(),
+            properties: crate::message::Properties::new().with("padding", "x".repeat(200)),
+            ..Default::default()
+        };
+
+        client
+            .send("key".to_owned(), Track::default().into())
+            .await
+            .unwrap();
+        client
+            .send("key".to_owned(), large.clone().into())
+            .await
+            .unwrap();
+        // A collector which doesn't accept gzip is sent uncompressed bodies.
+        server.fail_next(415, 1);
+        for _ in 0..2 {
+            client
+                .send("key".to_owned(), large.clone().into())
+                .await
+                .unwrap();
+        }
+
+        let compressed: Vec<bool> = client
+            .take_flush_report()
+            .requests
+            .iter()
+            .map(|request| request.compressed)
+            .collect();
+        assert_eq!(compressed, [false, true, false, false]);
+        let accepted = server.accepted();
+        assert_eq!(accepted.len(), 4);
+        assert_eq!(accepted[1]["event"], "Large");
+    }
+
+    #[tokio::test]
+    async fn test_warm_up() {
+        let server = FakeServer::start();
+        let client = HttpClient::new(reqwest::Client::new(), server.url());
+        client.warm_up().await.unwrap();
+        assert_eq!(server.requests().len(), 1);
+    }
 }
@@ -0,0 +1,24 @@
+//! Generation of message IDs.
+
+use std::fmt::Debug;
+
+/// A generator of unique message IDs.
+///
+/// The [`Batcher`](crate::Batcher) uses an `IdGenerator` to fill in the
+/// `messageId` of messages which don't have one, which Segment uses to
+/// deduplicate messages. The default is the [`UuidGenerator`]; tests can use a
+/// sequential generator instead to produce reproducible payloads.
+pub trait IdGenerator: Debug + Send + Sync {
+    /// Generate a new unique ID.
+    fn generate(&self) -> String;
+}
+
+/// An [`IdGenerator`] producing random (v4) UUIDs.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UuidGenerator;
+
+impl IdGenerator for UuidGenerator {
+    fn generate(&self) -> String {
+        uuid::Uuid::new_v4().to_string()
+    }
+}
@@ -0,0 +1,204 @@
+//! Typed builders for the `integrations` field of messages, which enables or
+//! disables destinations and passes them options.
+//!
+//! ```
+//! use segment::integrations::{Amplitude, Integrations};
+//! use segment::message::{Track, User};
+//!
+//! let track = Track {
+//!     user: User::UserId { user_id: "user".to_owned() },
+//!     event: "Example".to_owned(),
+//!     integrations: Some(
+//!         Integrations::new()
+//!             .disable("Salesforce")
+//!             .with(Amplitude::session(1_700_000_000_000))
+//!             .into(),
+//!     ),
+//!     ..Default::default()
+//! };
+//! ```
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// The `integrations` field of a message.
+///
+/// Destinations are enabled unless disabled, or unless built from
+/// [`none`](Integrations::none). Options are set with
+/// [`with`](Integrations::with), which also enables the destination.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct Integrations(Map<String, Value>);
+
+impl Integrations {
+    /// Send the message to every destination.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only send the message to the destinations enabled afterwards.
+    pub fn none() -> Self {
+        Self::new().set("All", false)
+    }
+
+    /// Send the message to `destination`, returning `self` for chaining.
+    pub fn enable(self, destination: impl Into<String>) -> Self {
+        self.set(destination, true)
+    }
+
+    /// Don't send the message to `destination`, returning `self` for
+    /// chaining.
+    pub fn disable(self, destination: impl Into<String>) -> Self {
+        self.set(destination, false)
+    }
+
+    /// Send the message to the destination of `options`, with these options,
+    /// returning `self` for chaining.
+    pub fn with<O: DestinationOptions>(self, options: O) -> Self {
+        let options = serde_json::to_value(options).expect("destination options serialize");
+        self.set(O::DESTINATION, options)
+    }
+
+    fn set(mut self, destination: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.0.insert(destination.into(), value.into());
+        self
+    }
+}
+
+impl From<Integrations> for Value {
+    fn from(integrations: Integrations) -> Self {
+        Value::Object(integrations.0)
+    }
+}
+
+/// The options of a destination, set with [`Integrations::with`].
+///
+/// Implement it for the options of destinations not covered here.
+pub trait DestinationOptions: Serialize {
+    /// The name of the destination, as Segment knows it.
+    const DESTINATION: &'static str;
+}
+
+/// The options of the Amplitude destination.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct Amplitude {
+    /// The session of the user, as a Unix timestamp in milliseconds, to
+    /// group the events of a server-side session together.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<i64>,
+}
+
+impl Amplitude {
+    /// The options putting events in the session started at `session_id`, a
+    /// Unix timestamp in milliseconds.
+    pub fn session(session_id: i64) -> Self {
+        Self {
+            session_id: Some(session_id),
+        }
+    }
+}
+
+impl DestinationOptions for Amplitude {
+    const DESTINATION: &'static str = "Amplitude";
+}
+
+/// The options of the Google Analytics destination.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[non_exhaustive]
+pub struct GoogleAnalytics {
+    /// The client ID of the browser the user was on, read from the `_ga`
+    /// cookie, to tie server-side events to their web session.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+}
+
+impl GoogleAnalytics {
+    /// The options tying events to the browser with the given client ID.
+    pub fn client(client_id: impl Into<String>) -> Self {
+        Self {
+            client_id: Some(client_id.into()),
+        }
+    }
+}
+
+impl DestinationOptions for GoogleAnalytics {
+    const DESTINATION: &'static str = "Google Analytics";
+}
+
+/// The options of the Intercom destination.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct Intercom {
+    /// The HMAC of the user ID, for Intercom's identity verification.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_hash: Option<String>,
+}
+
+impl Intercom {
+    /// The options verifying the identity of the user with `user_hash`.
+    pub fn user_hash(user_hash: impl Into<String>) -> Self {
+        Self {
+            user_hash: Some(user_hash.into()),
+        }
+    }
+}
+
+impl DestinationOptions for Intercom {
+    const DESTINATION: &'static str = "Intercom";
+}
+
+/// The options of the AppsFlyer destination.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[non_exhaustive]
+pub struct AppsFlyer {
+    /// The AppsFlyer ID of the device, required to send server-side events
+    /// to AppsFlyer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub apps_flyer_id: Option<String>,
+}
+
+impl AppsFlyer {
+    /// The options attributing events to the device with the given AppsFlyer
+    /// ID.
+    pub fn device(apps_flyer_id: impl Into<String>) -> Self {
+        Self {
+            apps_flyer_id: Some(apps_flyer_id.into()),
+        }
+    }
+}
+
+impl DestinationOptions for AppsFlyer {
+    const DESTINATION: &'static str = "AppsFlyer";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_integrations() {
+        let integrations = Integrations::none()
+            .enable("Mixpanel")
+            .with(Amplitude::session(1234))
+            .with(GoogleAnalytics::client("GA1.2.3"))
+            .with(AppsFlyer::device("af-1"));
+        assert_eq!(
+            Value::from(integrations),
+            json!({
+                "All": false,
+                "Mixpanel": true,
+                "Amplitude": { "session_id": 1234 },
+                "Google Analytics": { "clientId": "GA1.2.3" },
+                "AppsFlyer": { "appsFlyerId": "af-1" },
+            })
+        );
+        assert_eq!(
+            Value::from(Integrations::new().disable("Intercom")),
+            json!({ "Intercom": false })
+        );
+    }
+}
@@ -1,15 +1,137 @@
 #![doc = include_str!("../README.md")]
 
+mod analytics;
+mod anonymous_id;
+#[cfg(any(test, feature = "testing"))]
+mod arbitrary;
+mod audit;
 mod auto_batcher;
+mod backlog;
 mod batcher;
+mod blocking;
+mod builder;
+mod cardinality;
+mod catch_up;
 mod client;
+mod clock;
+mod compression;
+mod config;
+mod context_spec;
+mod dead_letter;
+mod dedup;
+mod disabled;
+mod dns;
+mod enrich;
+mod error_policy;
 mod errors;
+#[cfg(feature = "parquet")]
+mod export;
+mod filter;
+mod global;
+mod heartbeat;
 mod http;
+mod id;
+pub mod integrations;
+mod macros;
+mod memory;
+mod merge;
 pub mod message;
+mod metrics;
+#[cfg(any(feature = "tokio", feature = "smol"))]
+mod multi_tenant;
+mod numbers;
+mod opt_out;
+mod pipeline;
+mod privacy;
+mod provider;
+mod pruning;
+mod rate_limit;
+mod receiver;
+mod recorder;
+mod redact;
+mod replay;
+mod retry;
+mod retry_budget;
+mod runtime;
+#[cfg(feature = "safe-debug")]
+mod safe_debug;
+mod sampling;
+mod scope;
+pub mod semantic;
+mod session;
+#[cfg(feature = "signal")]
+mod signal;
+mod sink;
+mod spool;
+mod stdout;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+mod trace;
+mod traits_cache;
+mod transform;
+mod truncation;
+mod user_map;
+mod validation;
+mod window;
+mod write_key;
 
+pub use analytics::Analytics;
+pub use anonymous_id::AnonymousIdStore;
+pub use audit::{AuditFile, AuditLog, AuditOutcome, AuditRecord};
 pub use auto_batcher::AutoBatcher;
-pub use batcher::Batcher;
+pub use batcher::{split_batches, Batcher};
+pub use blocking::BlockingAnalytics;
+pub use builder::ClientBuilder;
+pub use cardinality::{CardinalityAction, EventNameGuard};
 pub use client::Client;
-pub use errors::{Error, Result};
-pub use http::HttpClient;
+pub use clock::{Clock, SystemClock};
+pub use compression::Compression;
+pub use config::{BatchConfig, Config};
+pub use context_spec::{check_context, ContextIssue};
+pub use dead_letter::{DeadLetter, DeadLetterEntry, DeadLetterFile, DeadLetterRotation};
+pub use enrich::Enricher;
+pub use error_policy::{ErrorAction, ErrorClass, ErrorPolicy};
+pub use errors::{ConfigProblem, Error, RejectedMessage, Result};
+#[cfg(feature = "parquet")]
+pub use export::export_parquet;
+pub use filter::Filter;
+#[cfg(any(feature = "tokio", feature = "smol"))]
+pub use global::init;
+pub use global::{alias, close, flush, global, group, identify, page, screen, set_global, track};
+pub use heartbeat::{Heartbeat, HEARTBEAT};
+pub use http::{FlushReport, HttpClient, RequestReport};
+pub use id::{IdGenerator, UuidGenerator};
+#[doc(hidden)]
+pub use macros::__private;
+pub use merge::MergePolicy;
 pub use message::Message;
+pub use metrics::Metrics;
+#[cfg(any(feature = "tokio", feature = "smol"))]
+pub use multi_tenant::MultiTenantAnalytics;
+pub use numbers::{LargeIntegerAction, LargeIntegerPolicy};
+pub use opt_out::{honor_do_not_track, is_opted_out, set_opted_out, OptOutFile};
+pub use pipeline::{Pipeline, PipelineBuilder, Stage, Validate};
+pub use privacy::{ContextPrivacy, FieldHashing, PrivacyMode};
+pub use provider::PropertyProvider;
+pub use pruning::PruningRules;
+pub use rate_limit::RateLimit;
+pub use receiver::ClientPayload;
+pub use recorder::{RecentMessage, Recorder};
+pub use redact::Redactor;
+pub use replay::{ReplayOptions, ReplayProgress};
+pub use retry::RetryPolicy;
+pub use retry_budget::{RetryBudget, RetryBudgetAlert};
+pub use sampling::{bucket, Sampling, SAMPLING_BUCKETS};
+pub use scope::{current_context, enter_context, with_context, ContextGuard, WithContext};
+pub use session::{SessionConfig, SessionField};
+#[cfg(feature = "signal")]
+pub use signal::shutdown_on_signal;
+pub use sink::BatchSink;
+pub use spool::{Checkpoint, Spool, SpoolKey};
+pub use stdout::StdoutClient;
+pub use trace::{propagate_trace_context, stop_trace_propagation, TraceContext};
+pub use transform::{Transform, TransformStep};
+pub use truncation::Truncation;
+pub use validation::{TimestampBounds, ValidationMode};
+pub use window::FlushWindow;
+pub use write_key::WriteKey;
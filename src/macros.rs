@@ -0,0 +1,124 @@
+//! Macros building and queueing messages in a single call.
+
+/// Queue a `track` event on an [`Analytics`](crate::Analytics) handle,
+/// building its properties from a JSON object literal.
+///
+/// Evaluates to the result of [`Analytics::enqueue`](crate::Analytics::enqueue).
+///
+/// ```no_run
+/// # fn main() -> segment::Result<()> {
+/// # let analytics: segment::Analytics = unimplemented!();
+/// use segment::track;
+/// use segment::message::User;
+///
+/// let user = User::UserId { user_id: "user".to_owned() };
+/// let seats = 5;
+///
+/// track!(analytics, user.clone(), "Plan Upgraded", { "plan": "pro", "seats": seats })?;
+/// track!(analytics, user, "Logged Out")?;
+/// # Ok(())
+/// # }
+/// ```
+#[macro_export]
+macro_rules! track {
+    ($analytics:expr, $user:expr, $event:expr $(,)?) => {
+        $analytics.enqueue($crate::message::Track {
+            user: $user,
+            event: ::std::convert::Into::into($event),
+            ..::std::default::Default::default()
+        })
+    };
+    ($analytics:expr, $user:expr, $event:expr, { $($properties:tt)* } $(,)?) => {
+        $analytics.enqueue($crate::message::Track {
+            user: $user,
+            event: ::std::convert::Into::into($event),
+            properties: $crate::message::Properties::from($crate::__private::object(
+                $crate::__private::json!({ $($properties)* }),
+            )),
+            ..::std::default::Default::default()
+        })
+    };
+}
+
+/// Queue an `identify` message on an [`Analytics`](crate::Analytics) handle,
+/// building its traits from a JSON object literal.
+///
+/// Evaluates to the result of [`Analytics::enqueue`](crate::Analytics::enqueue).
+///
+/// ```no_run
+/// # fn main() -> segment::Result<()> {
+/// # let analytics: segment::Analytics = unimplemented!();
+/// use segment::identify;
+/// use segment::message::User;
+///
+/// let user = User::UserId { user_id: "user".to_owned() };
+///
+/// identify!(analytics, user, { "email": "user@example.com", "plan": "pro" })?;
+/// # Ok(())
+/// # }
+/// ```
+#[macro_export]
+macro_rules! identify {
+    ($analytics:expr, $user:expr $(,)?) => {
+        $analytics.enqueue($crate::message::Identify {
+            user: $user,
+            ..::std::default::Default::default()
+        })
+    };
+    ($analytics:expr, $user:expr, { $($traits:tt)* } $(,)?) => {
+        $analytics.enqueue($crate::message::Identify {
+            user: $user,
+            traits: $crate::message::Traits::from($crate::__private::object(
+                $crate::__private::json!({ $($traits)* }),
+            )),
+            ..::std::default::Default::default()
+        })
+    };
+}
+
+#[doc(hidden)]
+pub mod __private {
+    pub use serde_json::json;
+    use serde_json::{Map, Value};
+
+    /// Unwrap the object built by `json!({ ... })`.
+    pub fn object(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(map) => map,
+            _ => unreachable!("`json!` built a non-object from an object literal"),
+        }
+    }
+}
+
+#[cfg(all(test, any(feature = "tokio", feature = "smol")))]
+mod tests {
+    use crate::message::User;
+    use crate::test_utils::MockClient;
+    use crate::{Analytics, AutoBatcher, Batcher, WriteKey};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_macros() {
+        let client = MockClient::new();
+        let batcher = AutoBatcher::new(
+            client.clone(),
+            Batcher::new(None),
+            WriteKey::new("key").unwrap(),
+        );
+        let analytics = Analytics::new(batcher, Duration::from_secs(3600));
+        let user = User::UserId {
+            user_id: "foo".to_owned(),
+        };
+
+        let seats = 5;
+        track!(analytics, user.clone(), "Plan Upgraded", { "plan": "pro", "seats": seats })
+            .unwrap();
+        track!(analytics, user.clone(), String::from("Logged Out")).unwrap();
+        identify!(analytics, user, { "plan": "pro" }).unwrap();
+        analytics.flush().await.unwrap();
+
+        crate::assert_tracked!(client, "Plan Upgraded", user = "foo", props contains { "seats": 5 });
+        crate::assert_tracked!(client, "Logged Out");
+        crate::assert_identified!(client, "foo", traits contains { "plan": "pro" });
+    }
+}
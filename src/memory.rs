@@ -0,0 +1,106 @@
+//! Estimating the memory used by queued messages.
+
+use std::mem::size_of;
+
+use serde_json::{Map, Value};
+
+use crate::message::{BatchMessage, User};
+
+/// The memory a value owns on the heap, approximately.
+///
+/// Allocator overhead is ignored, and maps are assumed to use their nodes
+/// efficiently, so this underestimates somewhat.
+pub(crate) trait HeapSize {
+    fn heap_size(&self) -> usize;
+}
+
+impl HeapSize for String {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Option<T> {
+    fn heap_size(&self) -> usize {
+        self.as_ref().map_or(0, T::heap_size)
+    }
+}
+
+impl<T: HeapSize> HeapSize for Vec<T> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * size_of::<T>() + self.iter().map(T::heap_size).sum::<usize>()
+    }
+}
+
+impl HeapSize for Value {
+    fn heap_size(&self) -> usize {
+        match self {
+            Value::Null | Value::Bool(_) | Value::Number(_) => 0,
+            Value::String(s) => s.heap_size(),
+            Value::Array(values) => values.heap_size(),
+            Value::Object(map) => map.heap_size(),
+        }
+    }
+}
+
+impl HeapSize for Map<String, Value> {
+    fn heap_size(&self) -> usize {
+        self.iter()
+            .map(|(key, value)| {
+                size_of::<String>() + size_of::<Value>() + key.heap_size() + value.heap_size()
+            })
+            .sum()
+    }
+}
+
+impl HeapSize for User {
+    fn heap_size(&self) -> usize {
+        match self {
+            User::UserId { user_id } => user_id.heap_size(),
+            User::AnonymousId { anonymous_id } => anonymous_id.heap_size(),
+            User::Both {
+                user_id,
+                anonymous_id,
+            } => user_id.heap_size() + anonymous_id.heap_size(),
+        }
+    }
+}
+
+impl HeapSize for BatchMessage {
+    fn heap_size(&self) -> usize {
+        let common = |user: &User,
+                      context: &Option<Value>,
+                      integrations: &Option<Value>,
+                      extra: &Map<String, Value>| {
+            user.heap_size() + context.heap_size() + integrations.heap_size() + extra.heap_size()
+        };
+        match self {
+            BatchMessage::Identify(m) => {
+                common(&m.user, &m.context, &m.integrations, &m.extra) + m.traits.heap_size()
+            }
+            BatchMessage::Track(m) => {
+                common(&m.user, &m.context, &m.integrations, &m.extra)
+                    + m.event.heap_size()
+                    + m.properties.heap_size()
+            }
+            BatchMessage::Page(m) => {
+                common(&m.user, &m.context, &m.integrations, &m.extra)
+                    + m.name.heap_size()
+                    + m.properties.heap_size()
+            }
+            BatchMessage::Screen(m) => {
+                common(&m.user, &m.context, &m.integrations, &m.extra)
+                    + m.name.heap_size()
+                    + m.properties.heap_size()
+            }
+            BatchMessage::Group(m) => {
+                common(&m.user, &m.context, &m.integrations, &m.extra)
+                    + m.group_id.heap_size()
+                    + m.traits.heap_size()
+            }
+            BatchMessage::Alias(m) => {
+                common(&m.user, &m.context, &m.integrations, &m.extra) + m.previous_id.heap_size()
+            }
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Merging the context and integrations of a batch into its messages.
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::message::{merge_context, Batch};
+
+/// How the `context` and `integrations` of a [`Batch`] are combined with the
+/// ones of the messages it contains.
+///
+/// By default, both are sent as they are and Segment combines them. The other
+/// policies combine them before sending, and clear the ones of the batch:
+///
+/// ```
+/// use segment::MergePolicy;
+/// use segment::message::{Batch, BatchMessage, Track};
+/// use serde_json::json;
+///
+/// let mut batch = Batch {
+///     batch: vec![BatchMessage::Track(Track {
+///         context: Some(json!({ "app": { "name": "foo" }, "ip": "198.51.100.1" })),
+///         ..Default::default()
+///     })],
+///     context: Some(json!({ "app": { "version": "1.0" }, "ip": "203.0.113.7" })),
+///     ..Default::default()
+/// };
+/// batch.apply_merge_policy(MergePolicy::DeepMerge);
+///
+/// assert_eq!(batch.context, None);
+/// match &batch.batch[0] {
+///     BatchMessage::Track(track) => assert_eq!(
+///         track.context,
+///         Some(json!({ "app": { "name": "foo", "version": "1.0" }, "ip": "198.51.100.1" }))
+///     ),
+///     _ => unreachable!(),
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergePolicy {
+    /// Leave the batch and its messages untouched.
+    #[default]
+    Envelope,
+    /// Copy the top-level fields of the batch missing from each message.
+    MessageWins,
+    /// Copy the top-level fields of the batch into each message, replacing
+    /// the ones it already has.
+    BatchWins,
+    /// Copy the fields of the batch missing from each message, recursively.
+    /// See [`merge_context`](crate::message::merge_context) for the rules.
+    DeepMerge,
+}
+
+impl Batch {
+    /// Combine the `context` and `integrations` of this batch with the ones
+    /// of its messages according to `policy`.
+    pub fn apply_merge_policy(&mut self, policy: MergePolicy) {
+        if policy == MergePolicy::Envelope {
+            return;
+        }
+        let context = self.context.take();
+        let integrations = self.integrations.take();
+        for msg in &mut self.batch {
+            if let Some(context) = &context {
+                merge_field(msg.context_mut(), context, policy);
+            }
+            if let Some(integrations) = &integrations {
+                merge_field(msg.integrations_mut(), integrations, policy);
+            }
+        }
+    }
+}
+
+fn merge_field(field: &mut Option<Value>, batch: &Value, policy: MergePolicy) {
+    let value = match field {
+        Some(value) => value,
+        None => {
+            *field = Some(batch.clone());
+            return;
+        }
+    };
+    match (policy, &mut *value, batch) {
+        (MergePolicy::DeepMerge, _, _) => merge_context(value, batch),
+        (MergePolicy::MessageWins, Value::Object(fields), Value::Object(batch)) => {
+            for (key, batch) in batch {
+                fields.entry(key.clone()).or_insert_with(|| batch.clone());
+            }
+        }
+        (MergePolicy::BatchWins, Value::Object(fields), Value::Object(batch)) => {
+            for (key, batch) in batch {
+                fields.insert(key.clone(), batch.clone());
+            }
+        }
+        (MergePolicy::BatchWins, _, _) => *value = batch.clone(),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{BatchMessage, Track};
+    use serde_json::json;
+
+    fn merged(policy: MergePolicy) -> (Option<Value>, Option<Value>) {
+        let mut batch = Batch {
+            batch: vec![BatchMessage::Track(Track {
+                context: Some(json!({ "app": { "name": "foo" }, "ip": "198.51.100.1" })),
+                ..Default::default()
+            })],
+            context: Some(json!({ "app": { "version": "1.0" }, "locale": "en-US" })),
+            integrations: Some(json!({ "All": false })),
+            ..Default::default()
+        };
+        batch.apply_merge_policy(policy);
+        match batch.batch.pop() {
+            Some(BatchMessage::Track(track)) => (track.context, batch.context),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_merge_policies() {
+        let message = json!({ "app": { "name": "foo" }, "ip": "198.51.100.1" });
+        assert_eq!(
+            merged(MergePolicy::Envelope),
+            (
+                Some(message),
+                Some(json!({ "app": { "version": "1.0" }, "locale": "en-US" }))
+            )
+        );
+        assert_eq!(
+            merged(MergePolicy::MessageWins).0,
+            Some(json!({ "app": { "name": "foo" }, "ip": "198.51.100.1", "locale": "en-US" }))
+        );
+        assert_eq!(
+            merged(MergePolicy::BatchWins).0,
+            Some(json!({ "app": { "version": "1.0" }, "ip": "198.51.100.1", "locale": "en-US" }))
+        );
+        assert_eq!(
+            merged(MergePolicy::DeepMerge),
+            (
+                Some(json!({
+                    "app": { "name": "foo", "version": "1.0" },
+                    "ip": "198.51.100.1",
+                    "locale": "en-US",
+                })),
+                None
+            )
+        );
+    }
+}
@@ -26,14 +26,17 @@
 //!   docs](https://segment.com/docs/spec/common/#integrations) for how to use
 //!   this field.
 
-use std::fmt::Display;
+use std::convert::TryFrom;
+use std::iter::FromIterator;
+use std::ops::{Deref, DerefMut};
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::{Map, Value};
 use time::OffsetDateTime;
 
 /// An enum containing all values which may be sent to Segment's tracking API.
-#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(not(feature = "safe-debug"), derive(Debug))]
+#[derive(PartialEq, Eq, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 #[serde(untagged)]
 pub enum Message {
@@ -58,10 +61,36 @@ impl Message {
             Message::Batch(_) => "/v1/batch",
         }
     }
+
+    pub(crate) fn extra_mut(&mut self) -> &mut Map<String, Value> {
+        match self {
+            Message::Identify(identify) => &mut identify.extra,
+            Message::Track(track) => &mut track.extra,
+            Message::Page(page) => &mut page.extra,
+            Message::Screen(screen) => &mut screen.extra,
+            Message::Group(group) => &mut group.extra,
+            Message::Alias(alias) => &mut alias.extra,
+            Message::Batch(batch) => &mut batch.extra,
+        }
+    }
+
+    /// The timestamp of this message, to change it, or `None` for a batch.
+    pub(crate) fn timestamp_mut(&mut self) -> Option<&mut Option<OffsetDateTime>> {
+        match self {
+            Message::Identify(identify) => Some(&mut identify.timestamp),
+            Message::Track(track) => Some(&mut track.timestamp),
+            Message::Page(page) => Some(&mut page.timestamp),
+            Message::Screen(screen) => Some(&mut screen.timestamp),
+            Message::Group(group) => Some(&mut group.timestamp),
+            Message::Alias(alias) => Some(&mut alias.timestamp),
+            Message::Batch(_) => None,
+        }
+    }
 }
 
 /// An enum containing all values which may be sent to Segment's tracking API.
-#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(not(feature = "safe-debug"), derive(Debug))]
+#[derive(PartialEq, Eq, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type")]
 pub enum StoredMessage {
@@ -88,21 +117,66 @@ impl From<StoredMessage> for Message {
     }
 }
 
+impl From<Message> for StoredMessage {
+    fn from(other: Message) -> StoredMessage {
+        match other {
+            Message::Identify(i) => StoredMessage::Identify(i),
+            Message::Track(t) => StoredMessage::Track(t),
+            Message::Page(p) => StoredMessage::Page(p),
+            Message::Screen(s) => StoredMessage::Screen(s),
+            Message::Group(g) => StoredMessage::Group(g),
+            Message::Alias(a) => StoredMessage::Alias(a),
+            Message::Batch(b) => StoredMessage::Batch(b),
+        }
+    }
+}
+
+impl StoredMessage {
+    /// The messages of a stored batch, or the stored message itself.
+    pub fn into_batch_messages(self) -> Vec<BatchMessage> {
+        match self {
+            StoredMessage::Identify(i) => vec![BatchMessage::Identify(i)],
+            StoredMessage::Track(t) => vec![BatchMessage::Track(t)],
+            StoredMessage::Page(p) => vec![BatchMessage::Page(p)],
+            StoredMessage::Screen(s) => vec![BatchMessage::Screen(s)],
+            StoredMessage::Group(g) => vec![BatchMessage::Group(g)],
+            StoredMessage::Alias(a) => vec![BatchMessage::Alias(a)],
+            StoredMessage::Batch(b) => b.batch,
+        }
+    }
+}
+
+impl From<BatchMessage> for StoredMessage {
+    fn from(other: BatchMessage) -> StoredMessage {
+        match other {
+            BatchMessage::Identify(i) => StoredMessage::Identify(i),
+            BatchMessage::Track(t) => StoredMessage::Track(t),
+            BatchMessage::Page(p) => StoredMessage::Page(p),
+            BatchMessage::Screen(s) => StoredMessage::Screen(s),
+            BatchMessage::Group(g) => StoredMessage::Group(g),
+            BatchMessage::Alias(a) => StoredMessage::Alias(a),
+        }
+    }
+}
+
 /// An identify event.
 ///
 /// See [Segment's documentation](https://segment.com/docs/spec/identify/) for
 /// how to use `identify` events.
-#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize, Default)]
+#[cfg_attr(not(feature = "safe-debug"), derive(Debug))]
+#[derive(PartialEq, Eq, Clone, Serialize, Deserialize, Default)]
 pub struct Identify {
     /// The user associated with this message.
     #[serde(flatten)]
     pub user: User,
 
     /// The traits to assign to the user.
-    pub traits: Value,
+    #[serde(default)]
+    pub traits: Traits,
 
     /// The timestamp associated with this message.
     #[serde(
+        default,
         skip_serializing_if = "Option::is_none",
         with = "time::serde::rfc3339::option"
     )]
@@ -125,7 +199,8 @@ pub struct Identify {
 ///
 /// See [Segment's documentation](https://segment.com/docs/spec/track/) for
 /// how to use `track` events.
-#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize, Default)]
+#[cfg_attr(not(feature = "safe-debug"), derive(Debug))]
+#[derive(PartialEq, Eq, Clone, Serialize, Deserialize, Default)]
 pub struct Track {
     /// The user associated with this message.
     #[serde(flatten)]
@@ -135,10 +210,12 @@ pub struct Track {
     pub event: String,
 
     /// The properties associated with the event.
-    pub properties: Value,
+    #[serde(default)]
+    pub properties: Properties,
 
     /// The timestamp associated with this message.
     #[serde(
+        default,
         skip_serializing_if = "Option::is_none",
         with = "time::serde::rfc3339::option"
     )]
@@ -157,11 +234,55 @@ pub struct Track {
     pub extra: Map<String, Value>,
 }
 
+impl Track {
+    /// Set the version of the schema of the event, sent as
+    /// `context.protocols.event_version`, where Protocols validates the event
+    /// against that version of its tracking plan and warehouses record it.
+    /// Returns `self` for chaining.
+    ///
+    /// ```
+    /// use segment::message::Track;
+    ///
+    /// let track = Track {
+    ///     event: "Order Completed".to_owned(),
+    ///     ..Default::default()
+    /// }
+    /// .with_version(2);
+    /// assert_eq!(track.version(), Some(2));
+    /// ```
+    ///
+    /// The version is not set if the context is not an object, or holds a
+    /// `protocols` field which is not one.
+    pub fn with_version(mut self, version: u32) -> Self {
+        let context = self
+            .context
+            .get_or_insert_with(|| Value::Object(Map::new()));
+        if let Err(e) = set_path(context, "protocols.event_version", version.into()) {
+            log::warn!("could not set the version of {:?}: {}", self.event, e);
+        }
+        self
+    }
+
+    /// The version of the schema of the event, set by
+    /// [`with_version`](Self::with_version).
+    pub fn version(&self) -> Option<u32> {
+        let version = self
+            .context
+            .as_ref()?
+            .get("protocols")?
+            .get("event_version")?;
+        version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+    }
+}
+
 /// A page event.
 ///
 /// See [Segment's documentation](https://segment.com/docs/spec/page/) for how
 /// to use `page` events.
-#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize, Default)]
+#[cfg_attr(not(feature = "safe-debug"), derive(Debug))]
+#[derive(PartialEq, Eq, Clone, Serialize, Deserialize, Default)]
 pub struct Page {
     /// The user associated with this message.
     #[serde(flatten)]
@@ -171,10 +292,12 @@ pub struct Page {
     pub name: Option<String>,
 
     /// The properties associated with the event.
-    pub properties: Value,
+    #[serde(default)]
+    pub properties: Properties,
 
     /// The timestamp associated with this message.
     #[serde(
+        default,
         skip_serializing_if = "Option::is_none",
         with = "time::serde::rfc3339::option"
     )]
@@ -197,7 +320,8 @@ pub struct Page {
 ///
 /// See [Segment's documentation](https://segment.com/docs/spec/screen/) for how
 /// to use `screen` events.
-#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize, Default)]
+#[cfg_attr(not(feature = "safe-debug"), derive(Debug))]
+#[derive(PartialEq, Eq, Clone, Serialize, Deserialize, Default)]
 pub struct Screen {
     /// The user associated with this message.
     #[serde(flatten)]
@@ -207,10 +331,12 @@ pub struct Screen {
     pub name: String,
 
     /// The properties associated with the event.
-    pub properties: Value,
+    #[serde(default)]
+    pub properties: Properties,
 
     /// The timestamp associated with this message.
     #[serde(
+        default,
         skip_serializing_if = "Option::is_none",
         with = "time::serde::rfc3339::option"
     )]
@@ -233,7 +359,8 @@ pub struct Screen {
 ///
 /// See [Segment's documentation](https://segment.com/docs/spec/group/) for how
 /// to use `group` events.
-#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize, Default)]
+#[cfg_attr(not(feature = "safe-debug"), derive(Debug))]
+#[derive(PartialEq, Eq, Clone, Serialize, Deserialize, Default)]
 pub struct Group {
     /// The user associated with this message.
     #[serde(flatten)]
@@ -244,10 +371,12 @@ pub struct Group {
     pub group_id: String,
 
     /// The traits to assign to the group.
-    pub traits: Value,
+    #[serde(default)]
+    pub traits: Traits,
 
     /// The timestamp associated with this message.
     #[serde(
+        default,
         skip_serializing_if = "Option::is_none",
         with = "time::serde::rfc3339::option"
     )]
@@ -270,7 +399,8 @@ pub struct Group {
 ///
 /// See [Segment's documentation](https://segment.com/docs/spec/alias/) for how
 /// to use `alias` events.
-#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize, Default)]
+#[cfg_attr(not(feature = "safe-debug"), derive(Debug))]
+#[derive(PartialEq, Eq, Clone, Serialize, Deserialize, Default)]
 pub struct Alias {
     /// The user associated with this message.
     #[serde(flatten)]
@@ -282,6 +412,7 @@ pub struct Alias {
 
     /// The timestamp associated with this message.
     #[serde(
+        default,
         skip_serializing_if = "Option::is_none",
         with = "time::serde::rfc3339::option"
     )]
@@ -305,7 +436,8 @@ pub struct Alias {
 /// See [Segment's
 /// documentation](https://segment.com/docs/sources/server/http/#batch) for how
 /// to send batches of events.
-#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize, Default)]
+#[cfg_attr(not(feature = "safe-debug"), derive(Debug))]
+#[derive(PartialEq, Eq, Clone, Serialize, Deserialize, Default)]
 pub struct Batch {
     /// The batch of messages to send.
     pub batch: Vec<BatchMessage>,
@@ -318,13 +450,24 @@ pub struct Batch {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub integrations: Option<Value>,
 
+    /// When the batch was sent, according to the sender's clock, which
+    /// Segment uses to correct the timestamps of its messages.
+    #[serde(
+        rename = "sentAt",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "time::serde::rfc3339::option"
+    )]
+    pub sent_at: Option<OffsetDateTime>,
+
     /// Extra fields to put at the top level of this message.
     #[serde(flatten)]
     pub extra: Map<String, Value>,
 }
 
 /// An enum containing all messages which may be placed inside a batch.
-#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(not(feature = "safe-debug"), derive(Debug))]
+#[derive(PartialEq, Eq, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum BatchMessage {
     #[serde(rename = "identify")]
@@ -342,7 +485,55 @@ pub enum BatchMessage {
 }
 
 impl BatchMessage {
-    pub(crate) fn timestamp_mut(&mut self) -> &mut Option<OffsetDateTime> {
+    /// The type of this message, as sent in its `type` field, e.g. `track`.
+    pub fn message_type(&self) -> &'static str {
+        match self {
+            Self::Identify(_) => "identify",
+            Self::Track(_) => "track",
+            Self::Page(_) => "page",
+            Self::Screen(_) => "screen",
+            Self::Group(_) => "group",
+            Self::Alias(_) => "alias",
+        }
+    }
+
+    /// The event name of a `track` event, or the name of a `page` or `screen`
+    /// event.
+    pub fn event_name(&self) -> Option<&str> {
+        match self {
+            Self::Track(track) => Some(&track.event),
+            Self::Page(page) => page.name.as_deref(),
+            Self::Screen(screen) => Some(&screen.name),
+            Self::Identify(_) | Self::Group(_) | Self::Alias(_) => None,
+        }
+    }
+
+    /// The user this message is about.
+    pub fn user(&self) -> &User {
+        match self {
+            Self::Identify(identify) => &identify.user,
+            Self::Track(track) => &track.user,
+            Self::Page(page) => &page.user,
+            Self::Screen(screen) => &screen.user,
+            Self::Group(group) => &group.user,
+            Self::Alias(alias) => &alias.user,
+        }
+    }
+
+    /// The timestamp of this message, if it has one yet.
+    pub fn timestamp(&self) -> Option<OffsetDateTime> {
+        match self {
+            Self::Identify(identify) => identify.timestamp,
+            Self::Track(track) => track.timestamp,
+            Self::Page(page) => page.timestamp,
+            Self::Screen(screen) => screen.timestamp,
+            Self::Group(group) => group.timestamp,
+            Self::Alias(alias) => alias.timestamp,
+        }
+    }
+
+    /// The timestamp of this message, to change it.
+    pub fn timestamp_mut(&mut self) -> &mut Option<OffsetDateTime> {
         match self {
             Self::Identify(identify) => &mut identify.timestamp,
             Self::Track(track) => &mut track.timestamp,
@@ -352,6 +543,68 @@ impl BatchMessage {
             Self::Alias(alias) => &mut alias.timestamp,
         }
     }
+
+    /// The context of this message.
+    pub fn context(&self) -> Option<&Value> {
+        match self {
+            Self::Identify(identify) => identify.context.as_ref(),
+            Self::Track(track) => track.context.as_ref(),
+            Self::Page(page) => page.context.as_ref(),
+            Self::Screen(screen) => screen.context.as_ref(),
+            Self::Group(group) => group.context.as_ref(),
+            Self::Alias(alias) => alias.context.as_ref(),
+        }
+    }
+
+    /// Replace the context of this message.
+    pub fn set_context(&mut self, context: Option<Value>) {
+        *self.context_mut() = context;
+    }
+
+    /// The context of this message, to change it.
+    pub fn context_mut(&mut self) -> &mut Option<Value> {
+        match self {
+            Self::Identify(identify) => &mut identify.context,
+            Self::Track(track) => &mut track.context,
+            Self::Page(page) => &mut page.context,
+            Self::Screen(screen) => &mut screen.context,
+            Self::Group(group) => &mut group.context,
+            Self::Alias(alias) => &mut alias.context,
+        }
+    }
+
+    pub(crate) fn integrations_mut(&mut self) -> &mut Option<Value> {
+        match self {
+            Self::Identify(identify) => &mut identify.integrations,
+            Self::Track(track) => &mut track.integrations,
+            Self::Page(page) => &mut page.integrations,
+            Self::Screen(screen) => &mut screen.integrations,
+            Self::Group(group) => &mut group.integrations,
+            Self::Alias(alias) => &mut alias.integrations,
+        }
+    }
+
+    pub(crate) fn extra(&self) -> &Map<String, Value> {
+        match self {
+            Self::Identify(identify) => &identify.extra,
+            Self::Track(track) => &track.extra,
+            Self::Page(page) => &page.extra,
+            Self::Screen(screen) => &screen.extra,
+            Self::Group(group) => &group.extra,
+            Self::Alias(alias) => &alias.extra,
+        }
+    }
+
+    pub(crate) fn extra_mut(&mut self) -> &mut Map<String, Value> {
+        match self {
+            Self::Identify(identify) => &mut identify.extra,
+            Self::Track(track) => &mut track.extra,
+            Self::Page(page) => &mut page.extra,
+            Self::Screen(screen) => &mut screen.extra,
+            Self::Group(group) => &mut group.extra,
+            Self::Alias(alias) => &mut alias.extra,
+        }
+    }
 }
 
 /// User ID information.
@@ -360,8 +613,9 @@ impl BatchMessage {
 /// See [Segment's
 /// documentation](https://segment.com/docs/spec/identify/#identities) for how
 /// user IDs and anonymous IDs should be used.
-#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
-#[serde(untagged)]
+#[cfg_attr(not(feature = "safe-debug"), derive(Debug))]
+#[derive(PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(untagged, try_from = "RawUser")]
 pub enum User {
     /// The user is identified only by a user ID.
     UserId {
@@ -385,7 +639,48 @@ pub enum User {
     },
 }
 
-impl Display for User {
+/// The shape of a [`User`] on the wire.
+///
+/// Deserializing `User` through a struct, rather than as an untagged enum,
+/// lets serde consume the `userId` and `anonymousId` fields so they don't also
+/// end up in the `extra` map of the message they are flattened into.
+#[derive(Deserialize)]
+struct RawUser {
+    #[serde(rename = "userId")]
+    user_id: Option<String>,
+    #[serde(rename = "anonymousId")]
+    anonymous_id: Option<String>,
+}
+
+impl TryFrom<RawUser> for User {
+    type Error = &'static str;
+
+    fn try_from(raw: RawUser) -> Result<Self, Self::Error> {
+        match (raw.user_id, raw.anonymous_id) {
+            (Some(user_id), Some(anonymous_id)) => Ok(User::Both {
+                user_id,
+                anonymous_id,
+            }),
+            (Some(user_id), None) => Ok(User::UserId { user_id }),
+            (None, Some(anonymous_id)) => Ok(User::AnonymousId { anonymous_id }),
+            (None, None) => Err("missing field `userId` or `anonymousId`"),
+        }
+    }
+}
+
+impl User {
+    /// The ID identifying this user across messages: the user ID if known, or
+    /// else the anonymous ID.
+    pub(crate) fn key(&self) -> &str {
+        match self {
+            User::UserId { user_id } | User::Both { user_id, .. } => user_id,
+            User::AnonymousId { anonymous_id } => anonymous_id,
+        }
+    }
+}
+
+#[cfg(not(feature = "safe-debug"))]
+impl std::fmt::Display for User {
     /// Display a `UserId`. If he has both an `anonymous_id` and a `user_id` we display the `user_id`
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -404,6 +699,462 @@ impl Default for User {
     }
 }
 
+macro_rules! json_object {
+    ($(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        #[derive(PartialEq, Eq, Clone, Default, Serialize)]
+        #[cfg_attr(not(feature = "safe-debug"), derive(Debug))]
+        #[serde(transparent)]
+        pub struct $name(pub Map<String, Value>);
+
+        impl $name {
+            /// Construct an empty object.
+            pub fn new() -> Self {
+                Self::default()
+            }
+
+            /// Set `key` to `value`, returning `self` for chaining.
+            pub fn with(mut self, key: impl Into<String>, value: impl Into<Value>) -> Self {
+                self.0.insert(key.into(), value.into());
+                self
+            }
+        }
+
+        impl Deref for $name {
+            type Target = Map<String, Value>;
+
+            fn deref(&self) -> &Self::Target {
+                &self.0
+            }
+        }
+
+        impl DerefMut for $name {
+            fn deref_mut(&mut self) -> &mut Self::Target {
+                &mut self.0
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            /// Deserialize from a JSON object, or from `null` as an empty
+            /// object.
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                Option::<Map<String, Value>>::deserialize(deserializer)
+                    .map(|map| Self(map.unwrap_or_default()))
+            }
+        }
+
+        impl From<Map<String, Value>> for $name {
+            fn from(map: Map<String, Value>) -> Self {
+                Self(map)
+            }
+        }
+
+        impl TryFrom<Value> for $name {
+            type Error = crate::Error;
+
+            /// Fails if `value` is not a JSON object.
+            fn try_from(value: Value) -> crate::Result<Self> {
+                match value {
+                    Value::Object(map) => Ok(Self(map)),
+                    other => Err(crate::Error::invalid_message(format!(
+                        "{} must be a JSON object, got {}",
+                        stringify!($name),
+                        other
+                    ))),
+                }
+            }
+        }
+
+        impl From<$name> for Value {
+            fn from(object: $name) -> Self {
+                Value::Object(object.0)
+            }
+        }
+
+        impl<K: Into<String>, V: Into<Value>> FromIterator<(K, V)> for $name {
+            fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+                Self(iter.into_iter().map(|(k, v)| (k.into(), v.into())).collect())
+            }
+        }
+    };
+}
+
+json_object! {
+    /// The properties of a `track`, `page` or `screen` event.
+    ///
+    /// Segment only accepts JSON objects as properties, which this type
+    /// guarantees. It derefs to the underlying [`Map`], and can be built from
+    /// one, from an iterator of key/value pairs, or with [`with`](Self::with):
+    ///
+    /// ```
+    /// use segment::message::Properties;
+    ///
+    /// let properties = Properties::new().with("plan", "pro").with("seats", 5);
+    /// assert_eq!(properties["seats"], 5);
+    /// ```
+    ///
+    /// A [`Value`] can be converted with `TryFrom`, which fails if it isn't an
+    /// object.
+    Properties
+}
+
+json_object! {
+    /// The traits of a user or a group.
+    ///
+    /// Segment only accepts JSON objects as traits, which this type
+    /// guarantees. It derefs to the underlying [`Map`], and can be built from
+    /// one, from an iterator of key/value pairs, or with [`with`](Self::with):
+    ///
+    /// ```
+    /// use segment::message::Traits;
+    ///
+    /// let traits = Traits::new().with("email", "jane@example.com");
+    /// assert_eq!(traits["email"], "jane@example.com");
+    /// ```
+    ///
+    /// A [`Value`] can be converted with `TryFrom`, which fails if it isn't an
+    /// object.
+    Traits
+}
+
+json_object! {
+    /// The context of a message: the app, device, library, locale and so on
+    /// it was produced with.
+    ///
+    /// It derefs to the underlying [`Map`], and converts into the [`Value`]
+    /// the `context` field of messages holds. Contexts coming from several
+    /// places, such as a [scope](crate::with_context) and the message itself,
+    /// are combined with [`merge`](Self::merge):
+    ///
+    /// ```
+    /// use segment::message::Context;
+    /// use serde_json::json;
+    ///
+    /// let mut context = Context::new().with("app", json!({ "name": "foo" }));
+    /// context.merge(&Context::new().with("app", json!({ "name": "bar", "version": "1.0" })));
+    /// assert_eq!(context["app"], json!({ "name": "foo", "version": "1.0" }));
+    /// ```
+    Context
+}
+
+impl Properties {
+    /// Build nested properties by setting each value at a path. See
+    /// [`PropertiesBuilder`].
+    pub fn builder() -> PropertiesBuilder {
+        PropertiesBuilder::default()
+    }
+}
+
+/// A builder of nested [`Properties`], setting each value at a path made of
+/// object keys separated by dots, each followed by array indices between
+/// brackets:
+///
+/// ```
+/// # fn main() -> segment::Result<()> {
+/// use segment::message::Properties;
+///
+/// let properties = Properties::builder()
+///     .set("cart.id", "c1")
+///     .set("cart.items[0].sku", "X")
+///     .set("cart.items[0].quantity", 2)
+///     .set("cart.items[1].sku", "Y")
+///     .build()?;
+/// assert_eq!(properties["cart"]["items"][1]["sku"], "Y");
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The objects and arrays along the path are created as needed, and arrays
+/// are padded with `null`s up to the index. [`build`](Self::build) fails if
+/// a path is malformed, or goes through a value which is not an object or an
+/// array as expected, e.g. `cart.id.value` after `cart.id` was set to a
+/// string.
+#[derive(Clone, Debug, Default)]
+pub struct PropertiesBuilder {
+    properties: Map<String, Value>,
+    error: Option<String>,
+}
+
+impl PropertiesBuilder {
+    /// Set the value at `path`, replacing the one set before, if any.
+    pub fn set(mut self, path: &str, value: impl Into<Value>) -> Self {
+        if self.error.is_none() {
+            let mut root = Value::Object(std::mem::take(&mut self.properties));
+            if let Err(e) = set_path(&mut root, path, value.into()) {
+                self.error = Some(e);
+            }
+            if let Value::Object(properties) = root {
+                self.properties = properties;
+            }
+        }
+        self
+    }
+
+    /// The properties built, or an error describing the first path which
+    /// could not be set.
+    pub fn build(self) -> crate::Result<Properties> {
+        match self.error {
+            Some(error) => Err(crate::Error::invalid_message(error)),
+            None => Ok(Properties(self.properties)),
+        }
+    }
+}
+
+/// A step of a property path.
+enum PathStep<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+/// The steps of a path such as `cart.items[0].sku`, or `None` if it is
+/// malformed.
+fn parse_path(path: &str) -> Option<Vec<PathStep<'_>>> {
+    let mut steps = Vec::new();
+    for segment in path.split('.') {
+        let (key, mut indices) = segment.split_at(segment.find('[').unwrap_or(segment.len()));
+        if key.is_empty() || key.contains(']') {
+            return None;
+        }
+        steps.push(PathStep::Key(key));
+        while let Some(rest) = indices.strip_prefix('[') {
+            let (index, rest) = rest.split_once(']')?;
+            steps.push(PathStep::Index(index.parse().ok()?));
+            indices = rest;
+        }
+        if !indices.is_empty() {
+            return None;
+        }
+    }
+    Some(steps)
+}
+
+fn set_path(root: &mut Value, path: &str, value: Value) -> Result<(), String> {
+    let steps = parse_path(path).ok_or_else(|| format!("invalid property path {:?}", path))?;
+    let conflict = || format!("property path {:?} conflicts with a value set before", path);
+    let mut current = root;
+    for step in steps {
+        current = match step {
+            PathStep::Key(key) => {
+                if current.is_null() {
+                    *current = Value::Object(Map::new());
+                }
+                match current {
+                    Value::Object(object) => object.entry(key).or_insert(Value::Null),
+                    _ => return Err(conflict()),
+                }
+            }
+            PathStep::Index(index) => {
+                if current.is_null() {
+                    *current = Value::Array(Vec::new());
+                }
+                match current {
+                    Value::Array(array) => {
+                        if array.len() <= index {
+                            array.resize(index + 1, Value::Null);
+                        }
+                        &mut array[index]
+                    }
+                    _ => return Err(conflict()),
+                }
+            }
+        };
+    }
+    *current = value;
+    Ok(())
+}
+
+impl Context {
+    /// Add the fields of `other` to this context, recursively. See
+    /// [`merge_context`] for the rules.
+    pub fn merge(&mut self, other: &Context) {
+        merge_maps(&mut self.0, &other.0);
+    }
+}
+
+/// Add the fields of the `other` context to `context`, recursively.
+///
+/// The rules are the same everywhere contexts are combined, be it the
+/// context of a [scope](crate::with_context), the one of a batch with
+/// [`MergePolicy::DeepMerge`](crate::MergePolicy::DeepMerge), or the one a
+/// batch gives its messages in a [`ClientPayload`](crate::ClientPayload):
+///
+/// - a field of `other` missing from `context` is added;
+/// - if both have an object under the same key, `other`'s is merged into
+///   `context`'s with these rules;
+/// - otherwise, the value of `context` is kept, including arrays, which are
+///   not concatenated, and explicit `null`s.
+///
+/// In short, `context` is the more specific one, and `other` only fills in
+/// its gaps. Nothing happens if either is not an object.
+pub fn merge_context(context: &mut Value, other: &Value) {
+    if let (Value::Object(context), Value::Object(other)) = (context, other) {
+        merge_maps(context, other);
+    }
+}
+
+fn merge_maps(context: &mut Map<String, Value>, other: &Map<String, Value>) {
+    for (key, other) in other {
+        match context.get_mut(key) {
+            Some(value) => merge_context(value, other),
+            None => {
+                context.insert(key.clone(), other.clone());
+            }
+        }
+    }
+}
+
+/// Merge `other` into the optional `context` of a message, which is set to
+/// `other` if it has none. See [`merge_context`].
+pub(crate) fn merge_optional_context(context: &mut Option<Value>, other: &Value) {
+    match context {
+        Some(context) => merge_context(context, other),
+        None => *context = Some(other.clone()),
+    }
+}
+
+/// The messages linking the anonymous ID a user had before signing up or
+/// logging in to their new user ID: an `alias` from the anonymous ID to the
+/// user ID, followed by an `identify` of the user with both IDs and the given
+/// traits.
+///
+/// Both messages must reach Segment in this order, so push them together with
+/// [`Batcher::push_all`](crate::Batcher::push_all), or use
+/// [`AutoBatcher::alias_and_identify`](crate::AutoBatcher::alias_and_identify)
+/// or [`Analytics::alias_and_identify`](crate::Analytics::alias_and_identify).
+///
+/// ```
+/// use segment::message::{alias_and_identify, BatchMessage, Traits};
+///
+/// let [alias, identify] = alias_and_identify("anon-1", "user-1", Traits::new());
+/// assert!(matches!(alias, BatchMessage::Alias(_)));
+/// assert!(matches!(identify, BatchMessage::Identify(_)));
+/// ```
+pub fn alias_and_identify(
+    anonymous_id: impl Into<String>,
+    user_id: impl Into<String>,
+    traits: Traits,
+) -> [BatchMessage; 2] {
+    let anonymous_id = anonymous_id.into();
+    let user_id = user_id.into();
+    [
+        BatchMessage::Alias(Alias {
+            user: User::UserId {
+                user_id: user_id.clone(),
+            },
+            previous_id: anonymous_id.clone(),
+            ..Default::default()
+        }),
+        BatchMessage::Identify(Identify {
+            user: User::Both {
+                user_id,
+                anonymous_id,
+            },
+            traits,
+            ..Default::default()
+        }),
+    ]
+}
+
+/// The page a message was produced on, sent as `context.page` and, for `page`
+/// events, as their reserved properties.
+///
+/// Building both from the same URL keeps them consistent:
+///
+/// ```
+/// use segment::message::{BatchMessage, Page, PageContext, User};
+///
+/// let page = PageContext::from_url("https://example.com/pricing?plan=pro#faq")
+///     .unwrap()
+///     .with_title("Pricing");
+/// let mut msg = BatchMessage::from(Page {
+///     user: User::AnonymousId { anonymous_id: "anon".to_owned() },
+///     ..Default::default()
+/// });
+/// page.apply(&mut msg);
+/// ```
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct PageContext {
+    /// The path of the URL, such as `/pricing`.
+    pub path: String,
+    /// The URL of the previous page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub referrer: Option<String>,
+    /// The query string of the URL, including the leading `?`, or an empty
+    /// string.
+    pub search: String,
+    /// The title of the page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// The URL, without its fragment.
+    pub url: String,
+}
+
+impl PageContext {
+    /// The page at `url`, which must be absolute, such as
+    /// `https://example.com/pricing?plan=pro`.
+    pub fn from_url(url: &str) -> crate::Result<Self> {
+        let url = url.split('#').next().unwrap_or_default();
+        let (scheme, rest) = url.split_once("://").ok_or_else(|| {
+            crate::Error::invalid_field("context.page.url", format!("{:?} is not absolute", url))
+        })?;
+        if scheme.is_empty() || rest.is_empty() {
+            return Err(crate::Error::invalid_field(
+                "context.page.url",
+                format!("{:?} is not absolute", url),
+            ));
+        }
+        let path_and_query = rest.find(['/', '?']).map_or("", |start| &rest[start..]);
+        let (path, search) = match path_and_query.find('?') {
+            Some(start) => path_and_query.split_at(start),
+            None => (path_and_query, ""),
+        };
+        Ok(Self {
+            path: if path.is_empty() { "/" } else { path }.to_owned(),
+            referrer: None,
+            search: search.to_owned(),
+            title: None,
+            url: url.to_owned(),
+        })
+    }
+
+    /// Set the URL of the previous page, returning `self` for chaining.
+    pub fn with_referrer(mut self, referrer: impl Into<String>) -> Self {
+        self.referrer = Some(referrer.into());
+        self
+    }
+
+    /// Set the title of the page, returning `self` for chaining.
+    pub fn with_title(mut self, title: impl Into<String>) -> Self {
+        self.title = Some(title.into());
+        self
+    }
+
+    /// Set `context.page` of `msg` to this page, and if it is a `page` event,
+    /// its `path`, `referrer`, `search`, `title` and `url` properties too,
+    /// replacing any previous value.
+    ///
+    /// The other fields of the context are kept, unless it isn't an object.
+    pub fn apply(&self, msg: &mut BatchMessage) {
+        let page = match serde_json::to_value(self) {
+            Ok(Value::Object(page)) => page,
+            _ => unreachable!("a page context serializes to an object"),
+        };
+        if let BatchMessage::Page(msg) = msg {
+            for field in ["referrer", "title"] {
+                msg.properties.remove(field);
+            }
+            msg.properties.extend(page.clone());
+        }
+        let context = msg.context_mut();
+        if !matches!(context, Some(Value::Object(_))) {
+            *context = Some(Value::Object(Map::new()));
+        }
+        if let Some(Value::Object(context)) = context {
+            context.insert("page".to_owned(), Value::Object(page));
+        }
+    }
+}
+
 macro_rules! into {
     (from $from:ident into $for:ident) => {
         impl From<$from> for $for {
@@ -440,6 +1191,122 @@ into! {
 mod tests {
     use super::*;
     use serde_json::json;
+    use std::convert::TryInto;
+
+    #[test]
+    fn test_batch_message_accessors() {
+        let mut msg = BatchMessage::from(Screen {
+            user: User::AnonymousId {
+                anonymous_id: "anon".to_owned(),
+            },
+            name: "Home".to_owned(),
+            ..Default::default()
+        });
+        assert_eq!(msg.message_type(), "screen");
+        assert_eq!(msg.event_name(), Some("Home"));
+        assert_eq!(msg.user().key(), "anon");
+        assert_eq!(msg.timestamp(), None);
+        assert_eq!(msg.context(), None);
+
+        let now = OffsetDateTime::now_utc();
+        *msg.timestamp_mut() = Some(now);
+        msg.set_context(Some(json!({ "locale": "fr-FR" })));
+        assert_eq!(msg.timestamp(), Some(now));
+        assert_eq!(msg.context(), Some(&json!({ "locale": "fr-FR" })));
+        assert_eq!(BatchMessage::from(Group::default()).event_name(), None);
+    }
+
+    #[test]
+    fn test_page_context() {
+        let page = PageContext::from_url("https://example.com/docs/?q=rust#intro")
+            .unwrap()
+            .with_referrer("https://google.com");
+        assert_eq!(page.path, "/docs/");
+        assert_eq!(page.search, "?q=rust");
+        assert_eq!(page.url, "https://example.com/docs/?q=rust");
+        assert_eq!(
+            PageContext::from_url("http://example.com?a=1")
+                .unwrap()
+                .path,
+            "/"
+        );
+        assert!(PageContext::from_url("/docs").is_err());
+
+        let mut msg = BatchMessage::from(Page {
+            properties: Properties::new().with("title", "Stale").with("price", 1),
+            context: Some(json!({ "ip": "1.2.3.4" })),
+            ..Default::default()
+        });
+        page.apply(&mut msg);
+        let msg = serde_json::to_value(msg).unwrap();
+        assert_eq!(msg["context"]["ip"], "1.2.3.4");
+        assert_eq!(msg["context"]["page"]["search"], "?q=rust");
+        assert_eq!(msg["context"]["page"]["referrer"], "https://google.com");
+        assert_eq!(msg["properties"]["path"], "/docs/");
+        assert_eq!(msg["properties"]["price"], 1);
+        assert!(msg["properties"].get("title").is_none());
+    }
+
+    #[test]
+    fn test_properties_builder() {
+        let properties = Properties::builder()
+            .set("cart.items[1].sku", "Y")
+            .set("cart.items[0].sku", "X")
+            .set("cart.items[0].tags[0]", "new")
+            .set("total", 12.5)
+            .set("total", 10)
+            .build()
+            .unwrap();
+        assert_eq!(
+            Value::from(properties),
+            json!({
+                "cart": { "items": [{ "sku": "X", "tags": ["new"] }, { "sku": "Y" }] },
+                "total": 10,
+            })
+        );
+
+        for path in ["", "a..b", "a[", "a[x]", "a[0]b", "[0]", "a]"] {
+            assert!(
+                Properties::builder().set(path, 1).build().is_err(),
+                "{:?}",
+                path
+            );
+        }
+        let conflict = Properties::builder()
+            .set("cart.id", "c1")
+            .set("cart.id.value", "c2")
+            .set("other", 1);
+        assert!(conflict.build().is_err());
+        assert!(Properties::builder()
+            .set("cart", json!([]))
+            .set("cart.id", 1)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_merge_context() {
+        let mut context = Context::new()
+            .with("ip", Value::Null)
+            .with("library", json!({ "name": "segment" }))
+            .with("tags", json!(["a"]));
+        context.merge(
+            &Context::new()
+                .with("ip", "1.2.3.4")
+                .with("library", json!({ "name": "other", "version": "1.0" }))
+                .with("tags", json!(["b"]))
+                .with("locale", "en-US"),
+        );
+        assert_eq!(
+            Value::from(context),
+            json!({
+                "ip": null,
+                "library": { "name": "segment", "version": "1.0" },
+                "tags": ["a"],
+                "locale": "en-US",
+            })
+        );
+    }
 
     #[test]
     fn serialize() {
@@ -451,7 +1318,9 @@ mod tests {
                 traits: json!({
                     "foo": "bar",
                     "baz": "quux",
-                }),
+                })
+                .try_into()
+                .unwrap(),
                 extra: [("messageId".to_owned(), json!("123"))]
                     .iter()
                     .cloned()
@@ -471,7 +1340,9 @@ mod tests {
                 properties: json!({
                     "foo": "bar",
                     "baz": "quux",
-                }),
+                })
+                .try_into()
+                .unwrap(),
                 ..Default::default()
             }))
             .unwrap(),
@@ -489,7 +1360,7 @@ mod tests {
                 properties: json!({
                     "foo": "bar",
                     "baz": "quux",
-                }),
+                }).try_into().unwrap(),
                 ..Default::default()
             }))
             .unwrap(),
@@ -507,7 +1378,7 @@ mod tests {
                 properties: json!({
                     "foo": "bar",
                     "baz": "quux",
-                }),
+                }).try_into().unwrap(),
                 ..Default::default()
             }))
             .unwrap(),
@@ -524,7 +1395,9 @@ mod tests {
                 traits: json!({
                     "foo": "bar",
                     "baz": "quux",
-                }),
+                })
+                .try_into()
+                .unwrap(),
                 ..Default::default()
             }))
             .unwrap(),
@@ -551,7 +1424,7 @@ mod tests {
                             user_id: "foo".to_owned()
                         },
                         event: "Foo".to_owned(),
-                        properties: json!({}),
+                        properties: Properties::new(),
                         ..Default::default()
                     }),
                     BatchMessage::Track(Track {
@@ -559,7 +1432,7 @@ mod tests {
                             user_id: "bar".to_owned()
                         },
                         event: "Bar".to_owned(),
-                        properties: json!({}),
+                        properties: Properties::new(),
                         ..Default::default()
                     }),
                     BatchMessage::Track(Track {
@@ -567,7 +1440,7 @@ mod tests {
                             user_id: "baz".to_owned()
                         },
                         event: "Baz".to_owned(),
-                        properties: json!({}),
+                        properties: Properties::new(),
                         ..Default::default()
                     })
                 ],
@@ -581,4 +1454,55 @@ mod tests {
                 .to_owned(),
         );
     }
+
+    #[test]
+    fn deserialize_both_ids() {
+        let track: Track = serde_json::from_str(
+            r#"{"userId":"foo","anonymousId":"bar","event":"Foo","properties":{}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            track.user,
+            User::Both {
+                user_id: "foo".to_owned(),
+                anonymous_id: "bar".to_owned()
+            }
+        );
+        assert!(track.extra.is_empty());
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn stored_message_round_trip(msg: StoredMessage) {
+            let json = serde_json::to_string(&msg).unwrap();
+            proptest::prop_assert_eq!(serde_json::from_str::<StoredMessage>(&json).unwrap(), msg);
+        }
+
+        #[test]
+        fn batch_message_round_trip(msg: BatchMessage) {
+            let json = serde_json::to_string(&msg).unwrap();
+            proptest::prop_assert_eq!(serde_json::from_str::<BatchMessage>(&json).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn properties_must_be_objects() {
+        assert!(Properties::try_from(json!({ "foo": "bar" })).is_ok());
+        assert!(Properties::try_from(json!(["foo", "bar"])).is_err());
+        assert!(Traits::try_from(json!("foo")).is_err());
+
+        let track: Track = serde_json::from_str(r#"{"userId":"foo","event":"Foo"}"#).unwrap();
+        assert!(track.properties.is_empty());
+        assert!(serde_json::from_str::<Track>(
+            r#"{"userId":"foo","event":"Foo","properties":[1]}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn deserialize_without_timestamp() {
+        let track: Track =
+            serde_json::from_str(r#"{"userId":"foo","event":"Foo","properties":{}}"#).unwrap();
+        assert_eq!(track.timestamp, None);
+    }
 }
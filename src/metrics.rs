@@ -0,0 +1,89 @@
+//! Counters of what happened to the messages given to a batcher.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Counters of the messages a [`Batcher`](crate::Batcher) did not send, and
+/// of the retries of the [`AutoBatcher`](crate::AutoBatcher) it runs in.
+///
+/// They are shared between a batcher and its clones, and can be read from the
+/// [`Analytics`](crate::Analytics) handle the batcher runs in with
+/// [`Analytics::metrics`](crate::Analytics::metrics).
+#[derive(Debug, Default)]
+pub struct Metrics {
+    rate_limited: Mutex<HashMap<String, u64>>,
+    sampled_out: AtomicU64,
+    blocked_event_names: AtomicU64,
+    retries: AtomicU64,
+    backoff_micros: AtomicU64,
+    dropped_held: AtomicU64,
+}
+
+impl Metrics {
+    /// The number of `track` events dropped by [rate
+    /// limits](crate::Batcher::rate_limit), by event name.
+    pub fn rate_limited(&self) -> HashMap<String, u64> {
+        self.rate_limited.lock().unwrap().clone()
+    }
+
+    /// The number of `track` events dropped by
+    /// [sampling](crate::Batcher::set_sampling).
+    pub fn sampled_out(&self) -> u64 {
+        self.sampled_out.load(Ordering::Relaxed)
+    }
+
+    /// The number of `track` events dropped because their name was new past
+    /// the limit of the [event name guard](crate::Batcher::guard_event_names).
+    pub fn blocked_event_names(&self) -> u64 {
+        self.blocked_event_names.load(Ordering::Relaxed)
+    }
+
+    /// The number of times a batch was retried.
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// The time spent waiting to retry batches, or until a spooled batch
+    /// could be retried.
+    pub fn time_in_backoff(&self) -> Duration {
+        Duration::from_micros(self.backoff_micros.load(Ordering::Relaxed))
+    }
+
+    /// The number of messages dropped because more batches than the
+    /// [maximum](crate::AutoBatcher::set_max_held_batches) were held while
+    /// paused or outside of the flush windows.
+    pub fn dropped_held(&self) -> u64 {
+        self.dropped_held.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_retry(&self, backoff: Duration) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+        let micros = u64::try_from(backoff.as_micros()).unwrap_or(u64::MAX);
+        self.backoff_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_dropped_held(&self, messages: usize) {
+        let messages = u64::try_from(messages).unwrap_or(u64::MAX);
+        self.dropped_held.fetch_add(messages, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_sampled_out(&self) {
+        self.sampled_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_blocked_event_name(&self) {
+        self.blocked_event_names.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_rate_limited(&self, event: &str) {
+        *self
+            .rate_limited
+            .lock()
+            .unwrap()
+            .entry(event.to_owned())
+            .or_default() += 1;
+    }
+}
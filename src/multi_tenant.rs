@@ -0,0 +1,351 @@
+//! Many pipelines, one per write key, sharing a background task.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures_channel::{mpsc, oneshot};
+use futures_util::future::{self, Either};
+use futures_util::StreamExt;
+
+use crate::analytics::{ErrorHook, Errors};
+use crate::message::BatchMessage;
+use crate::{runtime, scope, AutoBatcher, Client, Error, Result, WriteKey};
+
+enum Command {
+    Enqueue(WriteKey, Box<BatchMessage>),
+    EnqueueAll(WriteKey, Vec<BatchMessage>),
+    Flush(oneshot::Sender<Result<()>>),
+    Close(oneshot::Sender<Result<()>>),
+}
+
+/// The number of messages of each tenant which were queued but not sent yet.
+#[derive(Debug, Default)]
+struct Queues(Mutex<HashMap<WriteKey, Queue>>);
+
+#[derive(Debug, Default)]
+struct Queue {
+    /// Messages sent to the background task, but not received by it yet.
+    in_flight: usize,
+    /// Messages waiting in the batcher of the tenant.
+    buffered: usize,
+}
+
+/// A handle to a background task batching the messages of many tenants, each
+/// with its own write key, and sending them to Segment.
+///
+/// Running an [`Analytics`](crate::Analytics) pipeline per tenant means one
+/// background task and one connection pool per tenant, which doesn't scale to
+/// hundreds of tenants. `MultiTenantAnalytics` keeps a batcher per write key,
+/// created the first time a message is queued for it, but sends all of them
+/// from the same task through the same client.
+///
+/// To keep a busy tenant from using all the memory, each tenant may only have
+/// a limited number of messages waiting to be sent; messages queued beyond
+/// that are rejected with [`Error::QueueFull`].
+///
+/// ```no_run
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> segment::Result<()> {
+/// use std::time::Duration;
+/// use segment::{AutoBatcher, Batcher, HttpClient, MultiTenantAnalytics, WriteKey};
+/// use segment::message::{Track, User};
+///
+/// let key = WriteKey::new("unused")?;
+/// let template = AutoBatcher::new(HttpClient::default(), Batcher::new(None), key);
+/// let analytics = MultiTenantAnalytics::new(template, Duration::from_secs(10), 10_000);
+///
+/// analytics.enqueue(&WriteKey::new("tenant-a-key")?, Track {
+///     user: User::UserId { user_id: "user".to_owned() },
+///     event: "Example".to_owned(),
+///     ..Default::default()
+/// })?;
+///
+/// analytics.close().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct MultiTenantAnalytics {
+    commands: mpsc::UnboundedSender<Command>,
+    queues: Arc<Queues>,
+    max_queued: usize,
+    errors: Errors,
+}
+
+impl MultiTenantAnalytics {
+    /// Spawn a background task sending the messages of every tenant through a
+    /// clone of `template` using the tenant's write key, flushing them at
+    /// least every `flush_interval`.
+    ///
+    /// Each tenant may have at most `max_queued` messages waiting to be sent.
+    /// The clones of `template` share its client, and must not have a
+    /// [`Spool`](crate::Spool), which can't be shared between write keys. See
+    /// [`Analytics::new`](crate::Analytics::new) for the runtime requirements.
+    pub fn new<C>(template: AutoBatcher<C>, flush_interval: Duration, max_queued: usize) -> Self
+    where
+        C: Client + Clone + Send + Sync + 'static,
+    {
+        let (commands, receiver) = mpsc::unbounded();
+        let queues = Arc::new(Queues::default());
+        let errors = Errors::default();
+        runtime::spawn(run(
+            template,
+            receiver,
+            flush_interval,
+            queues.clone(),
+            errors.clone(),
+        ));
+        Self {
+            commands,
+            queues,
+            max_queued,
+            errors,
+        }
+    }
+
+    /// Call `hook` with the errors happening in the background task, instead
+    /// of logging them.
+    pub fn on_error(&self, hook: impl Fn(&Error) + Send + Sync + 'static) {
+        *self.errors.0.write().unwrap() = Some(ErrorHook(Arc::new(hook)));
+    }
+
+    /// Queue a message of the tenant with the given write key to be sent in
+    /// the background.
+    ///
+    /// Returns [`Error::QueueFull`] if the tenant already has the maximum
+    /// number of messages waiting to be sent, or an error if the handle was
+    /// [closed](MultiTenantAnalytics::close).
+    pub fn enqueue(&self, write_key: &WriteKey, msg: impl Into<BatchMessage>) -> Result<()> {
+        let mut msg = msg.into();
+        scope::apply(&mut msg);
+        let mut queues = self.queues.0.lock().unwrap();
+        let queue = queues.entry(write_key.clone()).or_default();
+        if queue.in_flight + queue.buffered >= self.max_queued {
+            return Err(Error::QueueFull);
+        }
+        self.commands
+            .unbounded_send(Command::Enqueue(write_key.clone(), Box::new(msg)))
+            .map_err(|_| Error::Closed)?;
+        queue.in_flight += 1;
+        Ok(())
+    }
+
+    /// Queue messages of the tenant with the given write key which must be
+    /// sent in the same batch, in order. See
+    /// [`AutoBatcher::push_all`].
+    ///
+    /// The messages are queued as a whole or not at all: returns
+    /// [`Error::QueueFull`] if the tenant doesn't have room for all of them.
+    pub fn enqueue_all(
+        &self,
+        write_key: &WriteKey,
+        msgs: impl IntoIterator<Item = impl Into<BatchMessage>>,
+    ) -> Result<()> {
+        let mut msgs: Vec<BatchMessage> = msgs.into_iter().map(Into::into).collect();
+        if msgs.is_empty() {
+            return Ok(());
+        }
+        msgs.iter_mut().for_each(scope::apply);
+        let len = msgs.len();
+        let mut queues = self.queues.0.lock().unwrap();
+        let queue = queues.entry(write_key.clone()).or_default();
+        if queue.in_flight + queue.buffered + len > self.max_queued {
+            return Err(Error::QueueFull);
+        }
+        self.commands
+            .unbounded_send(Command::EnqueueAll(write_key.clone(), msgs))
+            .map_err(|_| Error::Closed)?;
+        queue.in_flight += len;
+        Ok(())
+    }
+
+    /// The number of messages of the tenant with the given write key waiting
+    /// to be sent.
+    pub fn queued(&self, write_key: &WriteKey) -> usize {
+        self.queues
+            .0
+            .lock()
+            .unwrap()
+            .get(write_key)
+            .map_or(0, |queue| queue.in_flight + queue.buffered)
+    }
+
+    /// Send the messages queued so far for every tenant, and wait until they
+    /// are sent. Returns the first error encountered.
+    pub async fn flush(&self) -> Result<()> {
+        self.request(Command::Flush).await
+    }
+
+    /// Send the messages queued so far, and stop the background task.
+    pub async fn close(&self) -> Result<()> {
+        self.request(Command::Close).await
+    }
+
+    async fn request(
+        &self,
+        command: impl FnOnce(oneshot::Sender<Result<()>>) -> Command,
+    ) -> Result<()> {
+        let (done, result) = oneshot::channel();
+        self.commands
+            .unbounded_send(command(done))
+            .map_err(|_| Error::Closed)?;
+        result.await.map_err(|_| Error::Closed)?
+    }
+}
+
+async fn run<C: Client + Clone>(
+    template: AutoBatcher<C>,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    flush_interval: Duration,
+    queues: Arc<Queues>,
+    errors: Errors,
+) {
+    let mut tenants: HashMap<WriteKey, AutoBatcher<C>> = HashMap::new();
+    let mut next_flush = Instant::now() + flush_interval;
+
+    loop {
+        let tick = runtime::sleep(next_flush.saturating_duration_since(Instant::now()));
+        futures_util::pin_mut!(tick);
+        match future::select(commands.next(), tick).await {
+            Either::Left((command, _)) => match command {
+                Some(Command::Enqueue(write_key, msg)) => {
+                    let batcher = tenants
+                        .entry(write_key.clone())
+                        .or_insert_with(|| template.with_write_key(write_key.clone()));
+                    if let Err(e) = batcher.push(*msg).await {
+                        errors.report(&e);
+                    }
+                    let mut queues = queues.0.lock().unwrap();
+                    let queue = queues.entry(write_key).or_default();
+                    queue.in_flight = queue.in_flight.saturating_sub(1);
+                    queue.buffered = batcher.len();
+                }
+                Some(Command::EnqueueAll(write_key, msgs)) => {
+                    let len = msgs.len();
+                    let batcher = tenants
+                        .entry(write_key.clone())
+                        .or_insert_with(|| template.with_write_key(write_key.clone()));
+                    if let Err(e) = batcher.push_all(msgs).await {
+                        errors.report(&e);
+                    }
+                    let mut queues = queues.0.lock().unwrap();
+                    let queue = queues.entry(write_key).or_default();
+                    queue.in_flight = queue.in_flight.saturating_sub(len);
+                    queue.buffered = batcher.len();
+                }
+                Some(Command::Flush(done)) => {
+                    let _ = done.send(flush(&mut tenants, &queues).await);
+                }
+                Some(Command::Close(done)) => {
+                    commands.close();
+                    let _ = done.send(flush(&mut tenants, &queues).await);
+                    break;
+                }
+                None => {
+                    if let Err(e) = flush(&mut tenants, &queues).await {
+                        errors.report(&e);
+                    }
+                    break;
+                }
+            },
+            Either::Right(((), _)) => {
+                next_flush = Instant::now() + flush_interval;
+                if let Err(e) = flush(&mut tenants, &queues).await {
+                    errors.report(&e);
+                }
+            }
+        }
+    }
+}
+
+/// Flush the batchers of every tenant, returning the first error.
+async fn flush<C: Client>(
+    tenants: &mut HashMap<WriteKey, AutoBatcher<C>>,
+    queues: &Queues,
+) -> Result<()> {
+    let mut outcome = Ok(());
+    for (write_key, batcher) in tenants.iter_mut() {
+        if batcher.is_empty() {
+            continue;
+        }
+        let result = batcher.flush().await;
+        if outcome.is_ok() {
+            outcome = result;
+        }
+        if let Some(queue) = queues.0.lock().unwrap().get_mut(write_key) {
+            queue.buffered = batcher.len();
+        }
+    }
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{track, MockClient};
+    use crate::Batcher;
+
+    #[tokio::test]
+    async fn test_tenants() {
+        let client = MockClient::new();
+        let template = AutoBatcher::new(
+            client.clone(),
+            Batcher::new(None),
+            WriteKey::new("template").unwrap(),
+        );
+        let analytics = MultiTenantAnalytics::new(template, Duration::from_secs(3600), 2);
+        let (a, b) = (WriteKey::new("a").unwrap(), WriteKey::new("b").unwrap());
+        analytics.enqueue(&a, track("foo", "Example")).unwrap();
+        analytics.enqueue(&a, track("foo", "Example")).unwrap();
+        assert!(matches!(
+            analytics.enqueue(&a, track("foo", "Example")),
+            Err(Error::QueueFull)
+        ));
+        analytics.enqueue(&b, track("foo", "Example")).unwrap();
+        assert_eq!(analytics.queued(&a), 2);
+
+        analytics.flush().await.unwrap();
+        assert_eq!(analytics.queued(&a), 0);
+        assert_eq!(client.messages().len(), 2);
+        assert_eq!(client.tracks().len(), 3);
+
+        analytics.enqueue(&a, track("foo", "Example")).unwrap();
+        analytics.close().await.unwrap();
+        assert_eq!(client.tracks().len(), 4);
+        assert!(analytics.enqueue(&a, track("foo", "Example")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_all() {
+        let client = MockClient::new();
+        let template = AutoBatcher::new(
+            client.clone(),
+            Batcher::new(None),
+            WriteKey::new("template").unwrap(),
+        );
+        let analytics = MultiTenantAnalytics::new(template, Duration::from_secs(3600), 3);
+        let a = WriteKey::new("a").unwrap();
+        analytics.enqueue(&a, track("foo", "Example")).unwrap();
+        assert!(matches!(
+            analytics.enqueue_all(
+                &a,
+                vec![
+                    track("foo", "Example"),
+                    track("foo", "Example"),
+                    track("foo", "Example")
+                ]
+            ),
+            Err(Error::QueueFull)
+        ));
+        assert_eq!(analytics.queued(&a), 1);
+        analytics
+            .enqueue_all(&a, vec![track("foo", "Example"), track("foo", "Example")])
+            .unwrap();
+        assert_eq!(analytics.queued(&a), 3);
+
+        analytics.close().await.unwrap();
+        assert_eq!(client.messages().len(), 1);
+        assert_eq!(client.tracks().len(), 3);
+    }
+}
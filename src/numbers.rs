@@ -0,0 +1,167 @@
+//! Protecting large integers from destinations storing numbers as doubles.
+
+use std::collections::BTreeSet;
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::message::BatchMessage;
+use crate::{Error, Result};
+
+/// The largest integer a double represents exactly, `2^53 - 1`.
+const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+/// What to do with an integer which does not fit in a double.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LargeIntegerAction {
+    /// Send the integer as a string of its digits.
+    #[default]
+    Stringify,
+    /// Reject the message.
+    Reject,
+}
+
+/// The properties and traits holding integers, such as IDs, which some
+/// destinations would round because they store numbers as doubles. See
+/// [`Batcher::set_large_integer_policy`](crate::Batcher::set_large_integer_policy).
+///
+/// Fields are matched by name at any depth, e.g. in the objects of a
+/// `products` array, and only their integers above `2^53 - 1` or below
+/// `-(2^53 - 1)` are affected.
+///
+/// A policy deserializes from:
+///
+/// ```json
+/// { "fields": ["order_id", "account_id"], "action": "reject" }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LargeIntegerPolicy {
+    /// The names of the fields to check.
+    pub fields: BTreeSet<String>,
+    /// What to do with their large integers.
+    pub action: LargeIntegerAction,
+}
+
+impl LargeIntegerPolicy {
+    /// Apply `action` to the large integers of the given fields.
+    pub fn new<I, S>(fields: I, action: LargeIntegerAction) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            fields: fields.into_iter().map(Into::into).collect(),
+            action,
+        }
+    }
+
+    /// Stringify the large integers of the properties or traits of `msg`, or
+    /// fail if they must be rejected.
+    pub(crate) fn apply(&self, msg: &mut BatchMessage) -> Result<()> {
+        let fields: &mut Map<String, Value> = match msg {
+            BatchMessage::Track(track) => &mut track.properties,
+            BatchMessage::Page(page) => &mut page.properties,
+            BatchMessage::Screen(screen) => &mut screen.properties,
+            BatchMessage::Identify(identify) => &mut identify.traits,
+            BatchMessage::Group(group) => &mut group.traits,
+            BatchMessage::Alias(_) => return Ok(()),
+        };
+        self.apply_map(fields)
+    }
+
+    fn apply_map(&self, map: &mut Map<String, Value>) -> Result<()> {
+        for (key, value) in map {
+            if self.fields.contains(key) {
+                self.apply_field(key, value)?;
+            } else {
+                self.apply_value(value)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_value(&self, value: &mut Value) -> Result<()> {
+        match value {
+            Value::Object(map) => self.apply_map(map),
+            Value::Array(values) => values.iter_mut().try_for_each(|v| self.apply_value(v)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check a configured field, and the integers of the arrays it holds.
+    fn apply_field(&self, key: &str, value: &mut Value) -> Result<()> {
+        match value {
+            Value::Number(number) if is_large(number) => match self.action {
+                LargeIntegerAction::Stringify => {
+                    *value = Value::String(number.to_string());
+                    Ok(())
+                }
+                LargeIntegerAction::Reject => Err(Error::invalid_field(
+                    key,
+                    format!("{} is too large to be sent as a number", number),
+                )),
+            },
+            Value::Array(values) => values.iter_mut().try_for_each(|v| self.apply_field(key, v)),
+            value => self.apply_value(value),
+        }
+    }
+}
+
+fn is_large(number: &serde_json::Number) -> bool {
+    match (number.as_u64(), number.as_i64()) {
+        (Some(n), _) => n > MAX_SAFE_INTEGER,
+        (None, Some(n)) => n.unsigned_abs() > MAX_SAFE_INTEGER,
+        (None, None) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Properties, Track};
+    use serde_json::json;
+
+    #[test]
+    fn test_large_integers() {
+        let track = || {
+            BatchMessage::from(Track {
+                properties: Properties::new()
+                    .with("order_id", u64::MAX)
+                    .with("account_id", -(1i64 << 60))
+                    .with("count", u64::MAX)
+                    .with("products", json!([{ "order_id": 1, "sku": u64::MAX }]))
+                    .with("small", json!({ "order_id": [7, 1u64 << 53] })),
+                ..Default::default()
+            })
+        };
+        let policy = LargeIntegerPolicy::new(["order_id", "account_id"], Default::default());
+
+        let mut msg = track();
+        policy.apply(&mut msg).unwrap();
+        let properties = match msg {
+            BatchMessage::Track(track) => Value::from(track.properties),
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            properties,
+            json!({
+                "order_id": u64::MAX.to_string(),
+                "account_id": (-(1i64 << 60)).to_string(),
+                "count": u64::MAX,
+                "products": [{ "order_id": 1, "sku": u64::MAX }],
+                "small": { "order_id": [7, (1u64 << 53).to_string()] },
+            })
+        );
+
+        let policy = LargeIntegerPolicy {
+            action: LargeIntegerAction::Reject,
+            ..policy
+        };
+        assert!(matches!(
+            policy.apply(&mut track()),
+            Err(Error::InvalidMessage { .. })
+        ));
+    }
+}
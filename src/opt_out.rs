@@ -0,0 +1,117 @@
+//! Honoring the user's choice not to be tracked.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::anonymous_id::config_dir;
+use crate::{Error, Result};
+
+static OPTED_OUT: AtomicBool = AtomicBool::new(false);
+
+/// The name of the file marking the user as opted out.
+const FILE_NAME: &str = "opt_out";
+
+/// Stop or resume sending messages from this process.
+///
+/// While opted out, [`Analytics::enqueue`](crate::Analytics::enqueue),
+/// [`AutoBatcher::push`](crate::AutoBatcher::push), [`track`](crate::track())
+/// and the other methods queueing messages silently drop them. Messages
+/// queued before are still sent.
+pub fn set_opted_out(opted_out: bool) {
+    OPTED_OUT.store(opted_out, Ordering::Relaxed);
+}
+
+/// Whether messages are dropped because of [`set_opted_out`].
+pub fn is_opted_out() -> bool {
+    OPTED_OUT.load(Ordering::Relaxed)
+}
+
+/// Opt out if the `DO_NOT_TRACK` environment variable is set to anything but
+/// `0`, `false` or an empty string, as proposed by
+/// <https://consoledonottrack.com>. Returns whether it did.
+///
+/// Nothing happens if it is not set, so this never opts back in.
+pub fn honor_do_not_track() -> bool {
+    let do_not_track = env::var("DO_NOT_TRACK")
+        .map(|value| !matches!(value.trim(), "" | "0" | "false"))
+        .unwrap_or(false);
+    if do_not_track {
+        set_opted_out(true);
+    }
+    do_not_track
+}
+
+/// The user's choice to opt out, remembered across the runs of an application
+/// by the presence of a file.
+///
+/// ```no_run
+/// # fn main() -> segment::Result<()> {
+/// use segment::OptOutFile;
+///
+/// let opt_out = OptOutFile::in_config_dir("my-cli")?;
+/// // Opts out if the user did in a previous run.
+/// opt_out.load()?;
+/// segment::honor_do_not_track();
+///
+/// // Later, when running `my-cli telemetry disable`:
+/// opt_out.set(true)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OptOutFile {
+    path: PathBuf,
+}
+
+impl OptOutFile {
+    /// The choice remembered by the file at `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// The choice remembered in the `app` directory of the user's
+    /// configuration directory, next to the ID of an
+    /// [`AnonymousIdStore::in_config_dir`](crate::AnonymousIdStore::in_config_dir).
+    pub fn in_config_dir(app: &str) -> Result<Self> {
+        let dir = config_dir().ok_or_else(|| {
+            Error::InvalidConfig("could not find the configuration directory".to_owned())
+        })?;
+        Ok(Self::new(dir.join(app).join(FILE_NAME)))
+    }
+
+    /// The file marking the user as opted out.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Opt out if the user did before. Returns whether they did.
+    ///
+    /// Nothing happens if they did not, so this never opts back in.
+    pub fn load(&self) -> Result<bool> {
+        let opted_out = self.path.try_exists()?;
+        if opted_out {
+            set_opted_out(true);
+        }
+        Ok(opted_out)
+    }
+
+    /// Opt out or back in, and remember it for the next runs.
+    pub fn set(&self, opted_out: bool) -> Result<()> {
+        if opted_out {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(&self.path, "")?;
+        } else {
+            match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+        }
+        set_opted_out(opted_out);
+        Ok(())
+    }
+}
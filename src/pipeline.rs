@@ -0,0 +1,407 @@
+//! Composing the steps messages go through before they are batched.
+
+use std::any::Any;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::enrich::Enrichment;
+use crate::message::BatchMessage;
+use crate::{
+    validation, AutoBatcher, Batcher, Client, ContextPrivacy, Enricher, Error, Filter, HttpClient,
+    LargeIntegerPolicy, PruningRules, Result, Sampling, WriteKey,
+};
+
+/// A step of a [`Pipeline`], run on every message pushed before it is
+/// batched. Stages are added to a pipeline with [`PipelineBuilder::stage`],
+/// or to an [`AutoBatcher`] with [`add_stage`](AutoBatcher::add_stage).
+///
+/// A stage returns the message, changed or not, to hand it to the next stage,
+/// `None` to drop it, or an error to reject it, in which case pushing it
+/// fails.
+///
+/// The filters, sampling, pruning rules, context privacy and large integer
+/// policy of this crate are stages too, so they can be run in any order, and
+/// with stages of your own in between, when they are added as stages rather
+/// than set on the [`Batcher`]. The ones set on the batcher run in a fixed
+/// order, after all the stages. A feature can't be set both ways, as it would
+/// apply twice: adding it as a stage to a batcher which has it set, or
+/// setting it on the batcher of a pipeline which has it as a stage, fails
+/// with [`Error::InvalidConfig`].
+///
+/// ```
+/// use segment::{Result, Stage};
+/// use segment::message::BatchMessage;
+///
+/// struct DropDebugEvents;
+///
+/// #[async_trait::async_trait]
+/// impl Stage for DropDebugEvents {
+///     async fn process(&self, msg: BatchMessage) -> Result<Option<BatchMessage>> {
+///         match &msg {
+///             BatchMessage::Track(track) if track.event.starts_with("Debug ") => Ok(None),
+///             _ => Ok(Some(msg)),
+///         }
+///     }
+/// }
+/// ```
+#[async_trait::async_trait]
+pub trait Stage: Send + Sync {
+    /// Process `msg`, returning it to keep it.
+    async fn process(&self, msg: BatchMessage) -> Result<Option<BatchMessage>>;
+}
+
+/// A stage shared by the clones of a batcher.
+#[derive(Clone)]
+pub(crate) struct SharedStage {
+    stage: Arc<dyn Stage>,
+    /// The feature of the [`Batcher`] the stage is, if any.
+    feature: Option<&'static str>,
+}
+
+impl SharedStage {
+    pub(crate) fn new<S: Stage + 'static>(stage: S) -> Self {
+        let any: &dyn Any = &stage;
+        let feature = if any.is::<Filter>() {
+            Some("filters")
+        } else if any.is::<Sampling>() {
+            Some("sampling")
+        } else if any.is::<PruningRules>() {
+            Some("pruning rules")
+        } else if any.is::<ContextPrivacy>() {
+            Some("context privacy")
+        } else if any.is::<LargeIntegerPolicy>() {
+            Some("large integer policy")
+        } else {
+            None
+        };
+        Self {
+            stage: Arc::new(stage),
+            feature,
+        }
+    }
+}
+
+impl fmt::Debug for SharedStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedStage").finish_non_exhaustive()
+    }
+}
+
+/// Run `stages` in order on `msg`, until one of them drops it.
+pub(crate) async fn run(stages: &[SharedStage], msg: BatchMessage) -> Result<Option<BatchMessage>> {
+    let mut msg = msg;
+    for SharedStage { stage, .. } in stages {
+        msg = match stage.process(msg).await? {
+            Some(msg) => msg,
+            None => return Ok(None),
+        };
+    }
+    Ok(Some(msg))
+}
+
+/// Return an error if a feature set on `batcher` is one of `stages` too.
+pub(crate) fn check_stages(stages: &[SharedStage], batcher: &Batcher) -> Result<()> {
+    let set = [
+        ("filters", !batcher.filters.is_empty()),
+        ("sampling", batcher.sampling.is_some()),
+        ("pruning rules", batcher.pruning.is_some()),
+        ("context privacy", batcher.privacy.is_some()),
+        ("large integer policy", batcher.large_integers.is_some()),
+    ];
+    let both = stages
+        .iter()
+        .filter_map(|stage| stage.feature)
+        .find(|feature| set.contains(&(feature, true)));
+    match both {
+        Some(feature) => Err(Error::InvalidConfig(format!(
+            "the {} are set both as a stage and on the batcher",
+            feature
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// A stage rejecting the messages which can't be attributed to a user, and
+/// the `track` events without a name, like [strict
+/// mode](Batcher::enable_strict_mode) does.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Validate;
+
+#[async_trait::async_trait]
+impl Stage for Validate {
+    async fn process(&self, msg: BatchMessage) -> Result<Option<BatchMessage>> {
+        validation::check_complete(&msg)?;
+        Ok(Some(msg))
+    }
+}
+
+#[async_trait::async_trait]
+impl Stage for Filter {
+    /// Drop the messages matching this filter.
+    async fn process(&self, msg: BatchMessage) -> Result<Option<BatchMessage>> {
+        if self.matches(&msg) {
+            log::debug!("dropping message of {} matching {:?}", msg.user(), self);
+            return Ok(None);
+        }
+        Ok(Some(msg))
+    }
+}
+
+#[async_trait::async_trait]
+impl Stage for Sampling {
+    /// Drop the `track` events of the users left out of the sample.
+    async fn process(&self, msg: BatchMessage) -> Result<Option<BatchMessage>> {
+        Ok(Some(msg).filter(|msg| self.keeps(msg)))
+    }
+}
+
+#[async_trait::async_trait]
+impl Stage for PruningRules {
+    async fn process(&self, mut msg: BatchMessage) -> Result<Option<BatchMessage>> {
+        self.apply(&mut msg);
+        Ok(Some(msg))
+    }
+}
+
+#[async_trait::async_trait]
+impl Stage for ContextPrivacy {
+    async fn process(&self, mut msg: BatchMessage) -> Result<Option<BatchMessage>> {
+        self.apply(msg.context_mut());
+        Ok(Some(msg))
+    }
+}
+
+#[async_trait::async_trait]
+impl Stage for LargeIntegerPolicy {
+    async fn process(&self, mut msg: BatchMessage) -> Result<Option<BatchMessage>> {
+        self.apply(&mut msg)?;
+        Ok(Some(msg))
+    }
+}
+
+/// An [`AutoBatcher`] whose stages were composed with a [`PipelineBuilder`].
+///
+/// A pipeline dereferences to its batcher, to push messages and flush them,
+/// or to set how batches are sent, e.g. with
+/// [`set_retry_policy`](AutoBatcher::set_retry_policy).
+///
+/// The stages run before the [`Batcher`], but don't replace what it does:
+/// the features set on it, e.g. with
+/// [`drop_matching`](Batcher::drop_matching) or
+/// [`set_sampling`](Batcher::set_sampling), or through a
+/// [`Config`](crate::Config), still run in their fixed order once a message
+/// went through every stage, along with the ones which only the batcher has,
+/// such as deduplication, sessions and rate limits. A feature set both as a
+/// stage and on the batcher is rejected, as it would apply twice.
+///
+/// ```
+/// # fn main() -> segment::Result<()> {
+/// use segment::{Batcher, HttpClient, Pipeline, Sampling, WriteKey};
+///
+/// let pipeline = Pipeline::builder()
+///     .validate()
+///     .filter(r#"match(event, "Debug *")"#.parse()?)
+///     .sample(Sampling::new("2024").with_rate("Search", 0.1))
+///     .batch(Batcher::new(None))
+///     .transport(HttpClient::default(), WriteKey::new("your_write_key")?)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Pipeline<C = HttpClient> {
+    batcher: AutoBatcher<C>,
+}
+
+impl Pipeline {
+    /// Start composing a pipeline, with no stages and a default [`Batcher`].
+    pub fn builder() -> PipelineBuilder {
+        PipelineBuilder::default()
+    }
+}
+
+impl<C> Pipeline<C> {
+    /// The batcher running the stages of this pipeline.
+    pub fn into_inner(self) -> AutoBatcher<C> {
+        self.batcher
+    }
+}
+
+impl<C> Deref for Pipeline<C> {
+    type Target = AutoBatcher<C>;
+
+    fn deref(&self) -> &AutoBatcher<C> {
+        &self.batcher
+    }
+}
+
+impl<C> DerefMut for Pipeline<C> {
+    fn deref_mut(&mut self) -> &mut AutoBatcher<C> {
+        &mut self.batcher
+    }
+}
+
+/// Composes the stages of a [`Pipeline`], in the order they are added, then
+/// its batcher and transport.
+///
+/// The usual order is validation, enrichment, filters and sampling, so that
+/// invalid messages are rejected before any work is spent on them, and
+/// enrichers can add the fields filters look at.
+#[derive(Debug, Default)]
+pub struct PipelineBuilder {
+    stages: Vec<SharedStage>,
+    batcher: Option<Batcher>,
+}
+
+impl PipelineBuilder {
+    /// Run `stage` after the stages added before.
+    pub fn stage(mut self, stage: impl Stage + 'static) -> Self {
+        self.stages.push(SharedStage::new(stage));
+        self
+    }
+
+    /// Reject incomplete messages. See [`Validate`].
+    pub fn validate(self) -> Self {
+        self.stage(Validate)
+    }
+
+    /// Run `enricher`, giving up on a message after `timeout`. See
+    /// [`AutoBatcher::add_enricher`].
+    pub fn enrich(self, enricher: impl Enricher + 'static, timeout: Duration) -> Self {
+        self.stage(Enrichment {
+            enricher: Arc::new(enricher),
+            timeout,
+        })
+    }
+
+    /// Drop the messages matching `filter`.
+    pub fn filter(self, filter: Filter) -> Self {
+        self.stage(filter)
+    }
+
+    /// Drop the `track` events of the users left out by `sampling`.
+    pub fn sample(self, sampling: Sampling) -> Self {
+        self.stage(sampling)
+    }
+
+    /// Batch the messages kept by the stages with `batcher`, whose own
+    /// settings, such as deduplication and rate limits, apply after them.
+    /// The features added as stages must not be set on it too; see
+    /// [`transport`](PipelineBuilder::transport).
+    pub fn batch(mut self, batcher: Batcher) -> Self {
+        self.batcher = Some(batcher);
+        self
+    }
+
+    /// Send the batches with `client`, using `key`, completing the pipeline.
+    ///
+    /// Returns [`Error::InvalidConfig`] if a filter, sampling, pruning rules,
+    /// context privacy or a large integer policy was added as a stage and is
+    /// set on the batcher too.
+    pub fn transport<C: Client>(self, client: C, key: WriteKey) -> Result<Pipeline<C>> {
+        let batcher = self.batcher.unwrap_or_else(|| Batcher::new(None));
+        check_stages(&self.stages, &batcher)?;
+        let mut batcher = AutoBatcher::new(client, batcher, key);
+        batcher.stages = self.stages;
+        Ok(Pipeline { batcher })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{track, MockClient};
+    use crate::Error;
+
+    struct Tag;
+
+    #[async_trait::async_trait]
+    impl Stage for Tag {
+        async fn process(&self, mut msg: BatchMessage) -> Result<Option<BatchMessage>> {
+            if let BatchMessage::Track(track) = &mut msg {
+                track.properties.insert("debug".to_owned(), true.into());
+            }
+            Ok(Some(msg))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pipeline() {
+        let client = MockClient::new();
+        let mut pipeline = Pipeline::builder()
+            .validate()
+            .filter("properties.debug = true".parse().unwrap())
+            .stage(Tag)
+            .transport(client.clone(), WriteKey::new("key").unwrap())
+            .unwrap();
+        pipeline.push(track("foo", "Bought")).await.unwrap();
+        assert!(matches!(
+            pipeline.push(track("foo", "")).await,
+            Err(Error::InvalidMessage { .. })
+        ));
+        pipeline.flush().await.unwrap();
+        let tracks = client.tracks();
+        assert_eq!(tracks.len(), 1);
+        // The filter ran before the stage setting the property it matches.
+        assert_eq!(tracks[0].properties["debug"], true);
+
+        let mut pipeline = Pipeline::builder()
+            .stage(Tag)
+            .filter("properties.debug = true".parse().unwrap())
+            .transport(client.clone(), WriteKey::new("key").unwrap())
+            .unwrap();
+        pipeline.push(track("foo", "Bought")).await.unwrap();
+        pipeline.flush().await.unwrap();
+        assert_eq!(client.tracks().len(), 1);
+
+        // The filters set on the batcher run after every stage.
+        let mut batcher = Batcher::new(None);
+        batcher.drop_matching("properties.debug = true".parse().unwrap());
+        let mut pipeline = Pipeline::builder()
+            .stage(Tag)
+            .batch(batcher)
+            .transport(client.clone(), WriteKey::new("key").unwrap())
+            .unwrap();
+        pipeline.push(track("foo", "Bought")).await.unwrap();
+        pipeline.flush().await.unwrap();
+        assert_eq!(client.tracks().len(), 1);
+    }
+
+    #[test]
+    fn test_feature_set_twice() {
+        let filter: Filter = "properties.debug = true".parse().unwrap();
+        let mut batcher = Batcher::new(None);
+        batcher.drop_matching(filter.clone());
+        let pipeline = Pipeline::builder()
+            .filter(filter)
+            .batch(batcher)
+            .transport(MockClient::new(), WriteKey::new("key").unwrap());
+        assert!(matches!(pipeline, Err(Error::InvalidConfig(_))));
+
+        let mut batcher = Batcher::new(None);
+        batcher.set_sampling(Sampling::new("salt"));
+        let mut batcher =
+            AutoBatcher::new(MockClient::new(), batcher, WriteKey::new("key").unwrap());
+        assert!(matches!(
+            batcher.add_stage(Sampling::new("salt")),
+            Err(Error::InvalidConfig(_))
+        ));
+        batcher.add_stage(Validate).unwrap();
+
+        let mut batcher = Pipeline::builder()
+            .sample(Sampling::new("salt"))
+            .transport(MockClient::new(), WriteKey::new("key").unwrap())
+            .unwrap()
+            .into_inner();
+        let config = crate::Config {
+            sampling: Some(Sampling::new("salt")),
+            ..Default::default()
+        };
+        assert!(matches!(
+            batcher.reconfigure(&config),
+            Err(Error::InvalidConfig(_))
+        ));
+        batcher.reconfigure(&crate::Config::default()).unwrap();
+    }
+}
@@ -0,0 +1,312 @@
+//! Stripping or coarsening the identifying fields of the context, and hashing
+//! personal traits and properties.
+
+use std::collections::BTreeSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::message::BatchMessage;
+
+/// What to do with an identifying field of the context.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrivacyMode {
+    /// Send the field as it is.
+    #[default]
+    Keep,
+    /// Remove the field.
+    Strip,
+    /// Keep only the part of the field which doesn't single out a user.
+    Coarsen,
+}
+
+/// How the `context.ip` and `context.userAgent` of messages are sent. See
+/// [`Batcher::set_context_privacy`](crate::Batcher::set_context_privacy).
+///
+/// Coarsening an IP address keeps its network: the first 24 bits of an IPv4
+/// address and the first 48 bits of an IPv6 address, e.g. `203.0.113.7`
+/// becomes `203.0.113.0`. Coarsening a user agent keeps the names of its
+/// products with their major version, and drops the comments describing the
+/// device, e.g. `Mozilla/5.0 (X11; Linux x86_64) Firefox/121.0` becomes
+/// `Mozilla/5 Firefox/121`. Values which can't be parsed are stripped.
+///
+/// It deserializes from:
+///
+/// ```json
+/// { "ip": "coarsen", "user_agent": "strip" }
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ContextPrivacy {
+    /// What to do with `context.ip`.
+    pub ip: PrivacyMode,
+    /// What to do with `context.userAgent`.
+    pub user_agent: PrivacyMode,
+}
+
+impl ContextPrivacy {
+    /// Strip or coarsen the fields of `context`.
+    pub(crate) fn apply(&self, context: &mut Option<Value>) {
+        let context = match context {
+            Some(Value::Object(context)) => context,
+            _ => return,
+        };
+        for (key, mode, coarsen) in [
+            ("ip", self.ip, coarsen_ip as fn(&str) -> Option<String>),
+            ("userAgent", self.user_agent, coarsen_user_agent),
+        ] {
+            let coarsened = match (mode, context.get(key)) {
+                (PrivacyMode::Keep, _) | (_, None) => continue,
+                (PrivacyMode::Strip, _) => None,
+                (PrivacyMode::Coarsen, Some(value)) => value.as_str().and_then(coarsen),
+            };
+            match coarsened {
+                Some(value) => context.insert(key.to_owned(), Value::String(value)),
+                None => context.remove(key),
+            };
+        }
+    }
+}
+
+/// The traits and properties of messages sent as the SHA-256 hash of their
+/// normalized value rather than in clear. See
+/// [`Batcher::set_field_hashing`](crate::Batcher::set_field_hashing).
+///
+/// String values are normalized by trimming their whitespace and lowercasing
+/// them, the way advertising destinations expect hashed emails, then replaced
+/// by the hex encoding of their hash. Other values are hashed as JSON, and
+/// `null` is left as it is. Strings which already look like a SHA-256 hash are
+/// assumed to have been hashed by the caller, and kept.
+///
+/// The traits are looked up in `identify` and `group` messages and in
+/// `context.traits`, the properties in `track`, `page` and `screen` events.
+/// By default only the `email` trait is hashed. It deserializes from:
+///
+/// ```json
+/// { "traits": ["email", "phone"], "properties": ["email"] }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FieldHashing {
+    /// The names of the traits hashed.
+    pub traits: BTreeSet<String>,
+    /// The names of the properties hashed.
+    pub properties: BTreeSet<String>,
+}
+
+impl Default for FieldHashing {
+    fn default() -> Self {
+        Self {
+            traits: BTreeSet::from(["email".to_owned()]),
+            properties: BTreeSet::new(),
+        }
+    }
+}
+
+impl FieldHashing {
+    /// Hash the `email` trait only.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hash the trait `name` too, returning `self` for chaining.
+    pub fn with_trait(mut self, name: impl Into<String>) -> Self {
+        self.traits.insert(name.into());
+        self
+    }
+
+    /// Hash the property `name` too, returning `self` for chaining.
+    pub fn with_property(mut self, name: impl Into<String>) -> Self {
+        self.properties.insert(name.into());
+        self
+    }
+
+    /// Replace the configured fields of `msg` with their hash.
+    pub(crate) fn apply(&self, msg: &mut BatchMessage) {
+        let (fields, names): (&mut Map<String, Value>, _) = match msg {
+            BatchMessage::Track(track) => (&mut track.properties, &self.properties),
+            BatchMessage::Page(page) => (&mut page.properties, &self.properties),
+            BatchMessage::Screen(screen) => (&mut screen.properties, &self.properties),
+            BatchMessage::Identify(identify) => (&mut identify.traits, &self.traits),
+            BatchMessage::Group(group) => (&mut group.traits, &self.traits),
+            BatchMessage::Alias(_) => return,
+        };
+        hash_fields(fields, names);
+        if let Some(Value::Object(traits)) = msg
+            .context_mut()
+            .as_mut()
+            .and_then(|context| context.get_mut("traits"))
+        {
+            hash_fields(traits, &self.traits);
+        }
+    }
+}
+
+fn hash_fields(fields: &mut Map<String, Value>, names: &BTreeSet<String>) {
+    for name in names {
+        let value = match fields.get_mut(name) {
+            Some(Value::Null) | None => continue,
+            Some(value) => value,
+        };
+        let normalized = match &*value {
+            Value::String(value) if is_hash(value) => continue,
+            Value::String(value) => value.trim().to_lowercase(),
+            value => value.to_string(),
+        };
+        let hash = Sha256::digest(normalized.as_bytes());
+        *value = Value::String(hash.iter().map(|byte| format!("{:02x}", byte)).collect());
+    }
+}
+
+/// Whether `value` is the hex encoding of a SHA-256 hash.
+fn is_hash(value: &str) -> bool {
+    value.len() == 64
+        && value
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn coarsen_ip(ip: &str) -> Option<String> {
+    let ip = match ip.trim().parse().ok()? {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0))
+        }
+    };
+    Some(ip.to_string())
+}
+
+fn coarsen_user_agent(user_agent: &str) -> Option<String> {
+    let mut products = Vec::new();
+    let mut token = String::new();
+    let mut depth = 0usize;
+    for c in user_agent.chars() {
+        match c {
+            '(' => {
+                push_product(&mut products, &mut token);
+                depth += 1;
+            }
+            ')' => depth = depth.saturating_sub(1),
+            _ if depth > 0 => (),
+            c if c.is_whitespace() => push_product(&mut products, &mut token),
+            c => token.push(c),
+        }
+    }
+    push_product(&mut products, &mut token);
+    (!products.is_empty()).then(|| products.join(" "))
+}
+
+/// Add the product of `token`, e.g. `Firefox/121.0`, to `products` with only
+/// its major version, and clear it.
+fn push_product(products: &mut Vec<String>, token: &mut String) {
+    if token.is_empty() {
+        return;
+    }
+    products.push(match token.split_once('/') {
+        Some((name, version)) => {
+            format!("{}/{}", name, version.split('.').next().unwrap_or_default())
+        }
+        None => token.clone(),
+    });
+    token.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Identify, Properties, Track, Traits, User};
+    use serde_json::json;
+
+    #[test]
+    fn test_context_privacy() {
+        let context = || {
+            Some(json!({
+                "ip": "203.0.113.7",
+                "userAgent": "Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0",
+                "locale": "en-US",
+            }))
+        };
+
+        let mut coarsened = context();
+        ContextPrivacy {
+            ip: PrivacyMode::Coarsen,
+            user_agent: PrivacyMode::Coarsen,
+        }
+        .apply(&mut coarsened);
+        assert_eq!(
+            coarsened,
+            Some(json!({
+                "ip": "203.0.113.0",
+                "userAgent": "Mozilla/5 Gecko/20100101 Firefox/121",
+                "locale": "en-US",
+            }))
+        );
+
+        let mut stripped = context();
+        ContextPrivacy {
+            ip: PrivacyMode::Strip,
+            ..Default::default()
+        }
+        .apply(&mut stripped);
+        assert!(stripped.as_ref().unwrap().get("ip").is_none());
+        assert_eq!(
+            stripped.unwrap()["userAgent"],
+            context().unwrap()["userAgent"]
+        );
+
+        assert_eq!(
+            coarsen_ip("2001:db8:85a3::8a2e:370:7334").unwrap(),
+            "2001:db8:85a3::"
+        );
+        assert_eq!(coarsen_ip("not an ip"), None);
+    }
+
+    #[test]
+    fn test_field_hashing() {
+        let hashing = FieldHashing::new().with_property("email");
+        let hash = "973dfe463ec85785f5f95af5ba3906eedb2d931c24e69824a89ea65dba4e813b";
+
+        let mut msg = BatchMessage::from(Identify {
+            user: User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            traits: Traits::new()
+                .with("email", "  Test@Example.com ")
+                .with("name", "Foo"),
+            ..Default::default()
+        });
+        hashing.apply(&mut msg);
+        match &msg {
+            BatchMessage::Identify(identify) => {
+                assert_eq!(identify.traits["email"], hash);
+                assert_eq!(identify.traits["name"], "Foo");
+            }
+            _ => unreachable!(),
+        }
+
+        let mut msg = BatchMessage::from(Track {
+            user: User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            event: "Signed Up".to_owned(),
+            properties: Properties::new().with("email", hash),
+            context: Some(json!({ "traits": { "email": "test@example.com" } })),
+            ..Default::default()
+        });
+        hashing.apply(&mut msg);
+        match &msg {
+            BatchMessage::Track(track) => {
+                assert_eq!(track.properties["email"], hash);
+                assert_eq!(track.context.as_ref().unwrap()["traits"]["email"], hash);
+            }
+            _ => unreachable!(),
+        }
+    }
+}
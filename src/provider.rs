@@ -0,0 +1,190 @@
+//! Properties looked up when messages are sent, rather than when they are
+//! queued.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::future::{self, Either};
+
+use crate::message::{Batch, BatchMessage, Properties};
+use crate::{runtime, Error, Result};
+
+/// A source of properties added to the messages of every batch when it is
+/// sent, such as the SHA of the running build or the feature flags currently
+/// enabled. Providers are added to an [`AutoBatcher`](crate::AutoBatcher)
+/// with
+/// [`add_property_provider`](crate::AutoBatcher::add_property_provider).
+///
+/// Unlike an [`Enricher`](crate::Enricher), a provider is queried once per
+/// batch rather than once per message, so queued messages stay small and the
+/// values are those current when the batch is flushed.
+///
+/// Closures returning [`Properties`] are providers, for values at hand:
+///
+/// ```
+/// use segment::PropertyProvider;
+/// use segment::message::Properties;
+///
+/// fn build() -> impl PropertyProvider {
+///     || Properties::new().with("build", env!("CARGO_PKG_VERSION"))
+/// }
+/// ```
+///
+/// Values which must be fetched implement the trait:
+///
+/// ```
+/// use segment::{PropertyProvider, Result};
+/// use segment::message::Properties;
+///
+/// struct Flags;
+///
+/// #[async_trait::async_trait]
+/// impl PropertyProvider for Flags {
+///     async fn properties(&self) -> Result<Properties> {
+///         // Usually fetched from the feature flag service.
+///         Ok(Properties::new().with("flags", vec!["new-checkout"]))
+///     }
+/// }
+/// ```
+#[async_trait::async_trait]
+pub trait PropertyProvider: Send + Sync {
+    /// The properties to add to the messages of the batch being sent.
+    ///
+    /// If this fails or takes longer than the timeout of the provider, the
+    /// batch is sent without them.
+    async fn properties(&self) -> Result<Properties>;
+}
+
+#[async_trait::async_trait]
+impl<F> PropertyProvider for F
+where
+    F: Fn() -> Properties + Send + Sync,
+{
+    async fn properties(&self) -> Result<Properties> {
+        Ok(self())
+    }
+}
+
+/// A property provider and how long it may take per batch.
+#[derive(Clone)]
+pub(crate) struct ProvidedProperties {
+    pub(crate) provider: Arc<dyn PropertyProvider>,
+    pub(crate) timeout: Duration,
+}
+
+impl fmt::Debug for ProvidedProperties {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProvidedProperties")
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ProvidedProperties {
+    /// Query the provider, logging its failure or timeout.
+    pub(crate) async fn fetch(&self) -> Option<Properties> {
+        let result = {
+            let properties = self.provider.properties();
+            let timeout = runtime::sleep(self.timeout);
+            futures_util::pin_mut!(timeout);
+            match future::select(properties, timeout).await {
+                Either::Left((result, _)) => result,
+                Either::Right(((), _)) => Err(Error::Timeout),
+            }
+        };
+        result
+            .map_err(|e| log::warn!("could not get the properties to inject: {}", e))
+            .ok()
+    }
+}
+
+/// Add `properties` to the `track`, `page` and `screen` messages of `batch`.
+/// The properties already set on a message take precedence.
+pub(crate) fn inject(batch: &mut Batch, properties: &Properties) {
+    for msg in &mut batch.batch {
+        let target = match msg {
+            BatchMessage::Track(track) => &mut track.properties,
+            BatchMessage::Page(page) => &mut page.properties,
+            BatchMessage::Screen(screen) => &mut screen.properties,
+            _ => continue,
+        };
+        for (key, value) in properties.iter() {
+            target.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Identify, Track, User};
+    use crate::test_utils::MockClient;
+    use crate::{AutoBatcher, Batcher, WriteKey};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Flags(Duration);
+
+    #[async_trait::async_trait]
+    impl PropertyProvider for Flags {
+        async fn properties(&self) -> Result<Properties> {
+            runtime::sleep(self.0).await;
+            Ok(Properties::new().with("flags", "slow"))
+        }
+    }
+
+    fn user() -> User {
+        User::UserId {
+            user_id: "foo".to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inject_properties() {
+        let client = MockClient::new();
+        let mut batcher = AutoBatcher::new(
+            client.clone(),
+            Batcher::new(None),
+            WriteKey::new("key").unwrap(),
+        );
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        batcher.add_property_provider(
+            move || {
+                let build = counted.fetch_add(1, Ordering::SeqCst);
+                Properties::new().with("build", build).with("region", "eu")
+            },
+            Duration::from_millis(100),
+        );
+        batcher.add_property_provider(Flags(Duration::from_secs(3600)), Duration::from_millis(10));
+
+        batcher
+            .push(Track {
+                user: user(),
+                event: "Bought".to_owned(),
+                properties: Properties::new().with("region", "us"),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        batcher
+            .push(Identify {
+                user: user(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        batcher.flush().await.unwrap();
+        // Nothing to send, so the provider isn't queried.
+        batcher.flush().await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let tracks = client.tracks();
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(
+            tracks[0].properties,
+            Properties::new().with("region", "us").with("build", 0)
+        );
+        assert_eq!(client.batch_messages().len(), 2);
+    }
+}
@@ -0,0 +1,132 @@
+//! Removal of the fields only used by destinations a message is not sent to.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::message::BatchMessage;
+
+/// The properties and traits which only matter to some destinations. See
+/// [`Batcher::set_pruning_rules`](crate::Batcher::set_pruning_rules).
+///
+/// Rules deserialize from a map of destination names to field names:
+///
+/// ```json
+/// { "Mixpanel": ["mp_lib", "mp_country_code"], "Amplitude": ["amplitude_session"] }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct PruningRules {
+    fields: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl PruningRules {
+    /// Construct empty rules, which don't remove anything.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declare that the given properties and traits are only used by
+    /// `destination`, returning `self` for chaining.
+    pub fn with<I, S>(mut self, destination: impl Into<String>, fields: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.fields
+            .entry(destination.into())
+            .or_default()
+            .extend(fields.into_iter().map(Into::into));
+        self
+    }
+
+    /// Remove from the properties or traits of `msg` the fields used only by
+    /// destinations its `integrations` disable.
+    ///
+    /// Nothing is removed unless `integrations.All` is `false`, since the
+    /// enabled destinations are not known otherwise.
+    pub(crate) fn apply(&self, msg: &mut BatchMessage) {
+        let integrations = match msg.integrations_mut() {
+            Some(Value::Object(integrations)) => integrations,
+            _ => return,
+        };
+        if integrations.get("All") != Some(&Value::Bool(false)) {
+            return;
+        }
+        let is_enabled = |destination: &str| {
+            integrations
+                .get(destination)
+                .is_some_and(|enabled| !matches!(enabled, Value::Bool(false) | Value::Null))
+        };
+        let (enabled, disabled): (Vec<_>, Vec<_>) = self
+            .fields
+            .iter()
+            .partition(|(destination, _)| is_enabled(destination));
+        let pruned: BTreeSet<&String> = disabled
+            .into_iter()
+            .flat_map(|(_, fields)| fields)
+            .filter(|field| !enabled.iter().any(|(_, fields)| fields.contains(*field)))
+            .collect();
+        if pruned.is_empty() {
+            return;
+        }
+
+        let fields: &mut Map<String, Value> = match msg {
+            BatchMessage::Track(track) => &mut track.properties,
+            BatchMessage::Page(page) => &mut page.properties,
+            BatchMessage::Screen(screen) => &mut screen.properties,
+            BatchMessage::Identify(identify) => &mut identify.traits,
+            BatchMessage::Group(group) => &mut group.traits,
+            BatchMessage::Alias(_) => return,
+        };
+        for field in pruned {
+            fields.remove(field);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Properties, Track, User};
+    use serde_json::json;
+
+    #[test]
+    fn test_prune() {
+        let rules = PruningRules::new()
+            .with("Mixpanel", ["mp_lib", "shared"])
+            .with("Amplitude", ["amp_session", "shared"]);
+        let track = |integrations| {
+            BatchMessage::from(Track {
+                user: User::UserId {
+                    user_id: "foo".to_owned(),
+                },
+                event: "Bought".to_owned(),
+                properties: Properties::new()
+                    .with("mp_lib", "rust")
+                    .with("amp_session", 1)
+                    .with("shared", true)
+                    .with("price", 10),
+                integrations: Some(integrations),
+                ..Default::default()
+            })
+        };
+        let properties = |msg: BatchMessage| match msg {
+            BatchMessage::Track(track) => track.properties.keys().cloned().collect::<Vec<_>>(),
+            _ => unreachable!(),
+        };
+
+        let mut msg = track(json!({ "All": false, "Amplitude": true }));
+        rules.apply(&mut msg);
+        assert_eq!(properties(msg), ["amp_session", "price", "shared"]);
+
+        let mut msg = track(json!({ "All": false, "Segment.io": true }));
+        rules.apply(&mut msg);
+        assert_eq!(properties(msg), ["price"]);
+
+        let mut msg = track(json!({ "Mixpanel": false }));
+        rules.apply(&mut msg);
+        assert_eq!(properties(msg).len(), 4);
+    }
+}
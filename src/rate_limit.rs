@@ -0,0 +1,103 @@
+//! Limits on how often each user sends a given event.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde::Deserialize;
+use time::OffsetDateTime;
+
+use crate::config::duration;
+use crate::message::BatchMessage;
+use crate::user_map::UserMap;
+
+/// At most `max` events `per` duration for each user. See
+/// [`Batcher::rate_limit`](crate::Batcher::rate_limit).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    /// The number of events a user may send in a window.
+    pub max: u32,
+    /// The length of a window.
+    #[serde(with = "duration")]
+    pub per: Duration,
+}
+
+impl RateLimit {
+    /// At most `max` events `per` duration for each user.
+    pub fn new(max: u32, per: Duration) -> Self {
+        Self { max, per }
+    }
+
+    /// At most `max` events per minute for each user.
+    pub fn per_minute(max: u32) -> Self {
+        Self::new(max, Duration::from_secs(60))
+    }
+}
+
+/// The rate limit of each event name, and the current window of each user for
+/// each of them.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RateLimiter {
+    pub(crate) limits: HashMap<String, RateLimit>,
+    windows: UserMap<(String, String), Window>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Window {
+    start: OffsetDateTime,
+    count: u32,
+}
+
+impl RateLimiter {
+    /// Whether `msg` is a `track` event its user already sent the maximum
+    /// number of times in the current window, returning its name if so.
+    pub(crate) fn is_limited<'a>(
+        &self,
+        msg: &'a BatchMessage,
+        now: OffsetDateTime,
+    ) -> Option<&'a str> {
+        let (event, limit) = self.limit(msg)?;
+        let window = self.windows.get(&key(event, msg))?;
+        let limited = now - window.start < limit.per && window.count >= limit.max;
+        limited.then_some(event)
+    }
+
+    /// Count `msg` in the current window of its user.
+    pub(crate) fn record(&mut self, msg: &BatchMessage, now: OffsetDateTime) {
+        let (event, limit) = match self.limit(msg) {
+            Some(limit) => limit,
+            None => return,
+        };
+        let limits = &self.limits;
+        self.windows.prune(
+            |(event, _), window| {
+                limits
+                    .get(event)
+                    .is_none_or(|limit| now - window.start >= limit.per)
+            },
+            |window| window.start,
+        );
+        let window = self.windows.entry(key(event, msg)).or_insert(Window {
+            start: now,
+            count: 0,
+        });
+        if now - window.start >= limit.per {
+            *window = Window {
+                start: now,
+                count: 0,
+            };
+        }
+        window.count += 1;
+    }
+
+    fn limit<'a>(&self, msg: &'a BatchMessage) -> Option<(&'a str, RateLimit)> {
+        match msg {
+            BatchMessage::Track(track) => Some((&track.event, *self.limits.get(&track.event)?)),
+            _ => None,
+        }
+    }
+}
+
+fn key(event: &str, msg: &BatchMessage) -> (String, String) {
+    (event.to_owned(), msg.user().key().to_owned())
+}
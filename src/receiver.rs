@@ -0,0 +1,234 @@
+//! Parsing the payloads Segment's client libraries send, to collect them.
+
+use serde_json::{Map, Value};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::message::{merge_context, BatchMessage};
+use crate::{Error, Result, WriteKey};
+
+/// The fields client libraries add to their payloads for Segment's API, which
+/// are not part of the messages themselves.
+const TRANSPORT_FIELDS: [&str; 4] = ["writeKey", "sentAt", "receivedAt", "_metadata"];
+
+/// A payload posted to Segment's tracking API by a client library such as
+/// analytics.js, normalized into messages.
+///
+/// This lets a collector receive the traffic of browsers or other clients
+/// in place of Segment's API, then forward it with an
+/// [`Analytics`](crate::Analytics) pipeline:
+///
+/// ```
+/// # fn main() -> segment::Result<()> {
+/// use segment::ClientPayload;
+///
+/// let body = br#"{
+///     "event": "Clicked",
+///     "anonymousId": "anon",
+///     "writeKey": "abcdEFGH1234",
+///     "sentAt": "2024-01-01T00:00:01Z",
+///     "_metadata": { "bundled": ["Segment.io"] }
+/// }"#;
+/// let payload = ClientPayload::parse(body, Some("t"))?;
+/// assert_eq!(payload.messages.len(), 1);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientPayload {
+    /// The write key sent in the body, if any. Clients may send it in an
+    /// `Authorization` header instead.
+    pub write_key: Option<WriteKey>,
+    /// When the client sent the payload, according to its clock.
+    pub sent_at: Option<OffsetDateTime>,
+    /// When the payload was received, if it was forwarded by a collector
+    /// which recorded it, or once [`correct_timestamps`](Self::correct_timestamps)
+    /// was called.
+    pub received_at: Option<OffsetDateTime>,
+    /// The messages of the payload, without the fields only meant for
+    /// Segment's API.
+    pub messages: Vec<BatchMessage>,
+}
+
+impl ClientPayload {
+    /// Parse the body of a request made to Segment's tracking API.
+    ///
+    /// `endpoint` is the last segment of the path the request was made to,
+    /// such as `track` or `batch`, or the short form analytics.js uses, such
+    /// as `t` or `b`. It gives the type of the message when the body does not
+    /// have one.
+    ///
+    /// The `writeKey`, `sentAt`, `receivedAt` and `_metadata` fields are
+    /// removed from the messages, and a `batch` payload's `context` and `integrations` are
+    /// merged into the ones of its messages with
+    /// [`merge_context`](crate::message::merge_context).
+    pub fn parse(body: &[u8], endpoint: Option<&str>) -> Result<Self> {
+        let mut body = match serde_json::from_slice(body)? {
+            Value::Object(body) => body,
+            _ => return Err(invalid("the payload is not a JSON object")),
+        };
+        let write_key = match body.get("writeKey") {
+            Some(Value::String(key)) => Some(WriteKey::new(key.clone())?),
+            Some(_) => return Err(invalid("writeKey is not a string")),
+            None => None,
+        };
+        let sent_at = timestamp(&body, "sentAt")?;
+        let received_at = timestamp(&body, "receivedAt")?;
+
+        let kind = body
+            .get("type")
+            .and_then(Value::as_str)
+            .or_else(|| endpoint.map(message_type))
+            .map(str::to_owned);
+        let messages = match (kind.as_deref(), body.remove("batch")) {
+            (Some("batch") | None, Some(Value::Array(batch))) => {
+                let (context, integrations) = (body.remove("context"), body.remove("integrations"));
+                batch
+                    .into_iter()
+                    .map(|msg| {
+                        let mut msg = match msg {
+                            Value::Object(msg) => msg,
+                            _ => return Err(invalid("a message of the batch is not an object")),
+                        };
+                        for (field, value) in
+                            [("context", &context), ("integrations", &integrations)]
+                        {
+                            if let Some(value) = value {
+                                match msg.get_mut(field) {
+                                    Some(own) => merge_context(own, value),
+                                    None => {
+                                        msg.insert(field.to_owned(), value.clone());
+                                    }
+                                }
+                            }
+                        }
+                        parse_message(msg, None)
+                    })
+                    .collect::<Result<_>>()?
+            }
+            (Some("batch") | None, _) => return Err(invalid("the payload has no type")),
+            (Some(kind), _) => vec![parse_message(body, Some(kind))?],
+        };
+
+        Ok(Self {
+            write_key,
+            sent_at,
+            received_at,
+            messages,
+        })
+    }
+
+    /// Correct the timestamps of the messages for the skew of the client's
+    /// clock, given when the payload was received, the way Segment's API
+    /// does.
+    ///
+    /// A message timestamped `timestamp` by a client which sent it at
+    /// `sent_at` is given the timestamp `received_at - (sent_at -
+    /// timestamp)`. Nothing changes if the payload has no `sentAt`, but
+    /// `received_at` is recorded either way.
+    pub fn correct_timestamps(&mut self, received_at: OffsetDateTime) {
+        self.received_at = Some(received_at);
+        let skew = match self.sent_at {
+            Some(sent_at) => received_at - sent_at,
+            None => return,
+        };
+        for msg in &mut self.messages {
+            if let Some(timestamp) = msg.timestamp_mut() {
+                *timestamp += skew;
+            }
+        }
+    }
+}
+
+/// The type of message sent to an endpoint.
+fn message_type(endpoint: &str) -> &str {
+    match endpoint {
+        "i" => "identify",
+        "t" => "track",
+        "p" => "page",
+        "s" => "screen",
+        "g" => "group",
+        "a" => "alias",
+        "b" => "batch",
+        endpoint => endpoint,
+    }
+}
+
+fn parse_message(mut msg: Map<String, Value>, kind: Option<&str>) -> Result<BatchMessage> {
+    for field in TRANSPORT_FIELDS {
+        msg.remove(field);
+    }
+    if let Some(kind) = kind {
+        msg.insert("type".to_owned(), Value::String(kind.to_owned()));
+    }
+    Ok(serde_json::from_value(Value::Object(msg))?)
+}
+
+/// The RFC 3339 timestamp in the field `name` of `body`, if any.
+fn timestamp(body: &Map<String, Value>, name: &str) -> Result<Option<OffsetDateTime>> {
+    match body.get(name) {
+        Some(Value::String(timestamp)) => OffsetDateTime::parse(timestamp, &Rfc3339)
+            .map(Some)
+            .map_err(|_| Error::invalid_field(name, "is not an RFC 3339 timestamp")),
+        Some(_) => Err(Error::invalid_field(name, "is not a string")),
+        None => Ok(None),
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::invalid_message(reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse() {
+        let body = json!({
+            "batch": [
+                {
+                    "type": "track",
+                    "event": "Clicked",
+                    "anonymousId": "anon",
+                    "timestamp": "2024-01-01T00:00:00Z",
+                    "_metadata": { "bundled": ["Segment.io"] },
+                },
+                {
+                    "type": "identify",
+                    "userId": "foo",
+                    "context": { "library": { "name": "analytics.js" } },
+                },
+            ],
+            "context": { "ip": "1.2.3.4" },
+            "writeKey": "abcdEFGH1234",
+            "sentAt": "2024-01-01T00:00:10Z",
+        });
+        let mut payload = ClientPayload::parse(body.to_string().as_bytes(), Some("b")).unwrap();
+        assert_eq!(
+            payload.write_key,
+            Some(WriteKey::new("abcdEFGH1234").unwrap())
+        );
+        let received_at = OffsetDateTime::parse("2024-01-01T00:01:10Z", &Rfc3339).unwrap();
+        payload.correct_timestamps(received_at);
+        assert_eq!(payload.received_at, Some(received_at));
+
+        let messages: Vec<Value> = payload
+            .messages
+            .iter()
+            .map(|msg| serde_json::to_value(msg).unwrap())
+            .collect();
+        assert_eq!(messages[0]["event"], "Clicked");
+        assert_eq!(messages[0]["timestamp"], "2024-01-01T00:01:00Z");
+        assert_eq!(messages[0]["context"]["ip"], "1.2.3.4");
+        assert!(messages[0].get("_metadata").is_none());
+        assert_eq!(messages[1]["context"]["library"]["name"], "analytics.js");
+        assert!(messages[1].get("sentAt").is_none());
+
+        let page = br#"{"anonymousId": "anon", "name": "Home", "writeKey": "abcdEFGH1234"}"#;
+        let payload = ClientPayload::parse(page, Some("p")).unwrap();
+        assert!(matches!(payload.messages[..], [BatchMessage::Page(_)]));
+        assert!(ClientPayload::parse(page, None).is_err());
+    }
+}
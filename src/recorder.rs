@@ -0,0 +1,145 @@
+//! The last messages sent, kept in memory for debugging live processes.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use time::OffsetDateTime;
+
+use crate::message::BatchMessage;
+use crate::{AuditOutcome, Error, Result};
+
+/// A message sent to Segment, or which failed to be, as kept by a
+/// [`Recorder`].
+#[derive(Clone, Debug)]
+pub struct RecentMessage {
+    /// The message, as it was sent.
+    pub message: BatchMessage,
+    /// When the batch the message was in was sent, or gave up on.
+    pub flushed_at: OffsetDateTime,
+    /// Whether Segment accepted the message.
+    pub outcome: AuditOutcome,
+    /// Why sending the message failed.
+    pub error: Option<String>,
+}
+
+/// A ring buffer of the last messages sent, with their outcomes, e.g. for an
+/// admin endpoint showing the last analytics events a running instance
+/// emitted.
+///
+/// Recorders are cheap to clone, and the clones share the same buffer, so one
+/// can be set on an [`AutoBatcher`](crate::AutoBatcher) with
+/// [`set_recorder`](crate::AutoBatcher::set_recorder) and read from elsewhere.
+///
+/// ```
+/// use segment::{AutoBatcher, Batcher, HttpClient, Recorder, WriteKey};
+///
+/// let recorder = Recorder::new(100);
+/// let mut batcher = AutoBatcher::new(
+///     HttpClient::default(),
+///     Batcher::new(None),
+///     WriteKey::new("your_write_key").unwrap(),
+/// );
+/// batcher.set_recorder(recorder.clone());
+///
+/// // Later, in a debug endpoint.
+/// for recent in recorder.recent() {
+///     println!("{:?} at {}: {:?}", recent.outcome, recent.flushed_at, recent.message);
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Recorder {
+    capacity: usize,
+    messages: Arc<Mutex<VecDeque<RecentMessage>>>,
+}
+
+impl Recorder {
+    /// Keep the last `capacity` messages.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            messages: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    /// The number of messages kept.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The last messages sent, oldest first.
+    pub fn recent(&self) -> Vec<RecentMessage> {
+        self.messages.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Forget the messages recorded so far.
+    pub fn clear(&self) {
+        self.messages.lock().unwrap().clear();
+    }
+
+    /// Keep `msg`, sent at `flushed_at` with `result`, forgetting the oldest
+    /// message if the buffer is full.
+    pub(crate) fn record(
+        &self,
+        msg: &BatchMessage,
+        flushed_at: OffsetDateTime,
+        result: &Result<()>,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() == self.capacity {
+            messages.pop_front();
+        }
+        messages.push_back(RecentMessage {
+            message: msg.clone(),
+            flushed_at,
+            outcome: match result {
+                Ok(()) => AuditOutcome::Sent,
+                Err(_) => AuditOutcome::Failed,
+            },
+            error: result.as_ref().err().map(Error::to_string),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Track, User};
+
+    #[test]
+    fn test_recorder() {
+        let recorder = Recorder::new(2);
+        let shared = recorder.clone();
+        for (user_id, result) in [
+            ("foo", Ok(())),
+            ("bar", Err(Error::from_status(503, None, String::new()))),
+            ("baz", Ok(())),
+        ] {
+            let msg = BatchMessage::from(Track {
+                user: User::UserId {
+                    user_id: user_id.to_owned(),
+                },
+                ..Default::default()
+            });
+            recorder.record(&msg, OffsetDateTime::UNIX_EPOCH, &result);
+        }
+
+        let recent = shared.recent();
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].outcome, AuditOutcome::Failed);
+        assert_eq!(
+            recent[0].error.as_deref(),
+            Some("server responded with status 503")
+        );
+        assert_eq!(recent[1].outcome, AuditOutcome::Sent);
+        match &recent[1].message {
+            BatchMessage::Track(track) => assert_eq!(track.user.key(), "baz"),
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        shared.clear();
+        assert!(recorder.recent().is_empty());
+    }
+}
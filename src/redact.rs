@@ -0,0 +1,112 @@
+//! Masking of personally identifiable information in logged payloads.
+
+use serde_json::Value;
+
+const MASK: &str = "[REDACTED]";
+
+/// A list of field names whose values should never appear in logs.
+///
+/// A `Redactor` walks a JSON payload and replaces the value of every object
+/// member whose key matches one of the configured fields (case-insensitively),
+/// at any depth. The default redactor masks the most common PII fields found in
+/// Segment's [reserved traits](https://segment.com/docs/spec/identify/#traits).
+///
+/// ```
+/// use segment::Redactor;
+/// use serde_json::json;
+///
+/// let mut payload = json!({ "traits": { "email": "jane@example.com", "plan": "pro" } });
+/// Redactor::default().redact(&mut payload);
+/// assert_eq!(payload, json!({ "traits": { "email": "[REDACTED]", "plan": "pro" } }));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Redactor {
+    fields: Vec<String>,
+}
+
+impl Default for Redactor {
+    fn default() -> Self {
+        Redactor::new([
+            "email",
+            "phone",
+            "firstName",
+            "lastName",
+            "username",
+            "address",
+            "birthday",
+            "ip",
+        ])
+    }
+}
+
+impl Redactor {
+    /// Construct a redactor masking the given field names.
+    pub fn new<I, S>(fields: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        Self {
+            fields: fields
+                .into_iter()
+                .map(|field| field.as_ref().to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Add a field name to mask.
+    pub fn add_field(&mut self, field: impl AsRef<str>) {
+        self.fields.push(field.as_ref().to_ascii_lowercase());
+    }
+
+    /// Mask every configured field in `value`, recursively.
+    pub fn redact(&self, value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    if self.fields.contains(&key.to_ascii_lowercase()) {
+                        *value = Value::String(MASK.to_owned());
+                    } else {
+                        self.redact(value);
+                    }
+                }
+            }
+            Value::Array(values) => values.iter_mut().for_each(|value| self.redact(value)),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn redact_nested() {
+        let mut payload = json!({
+            "userId": "foo",
+            "traits": {
+                "Email": "foo@example.com",
+                "friends": [{ "phone": "555-0100", "age": 12 }],
+                "address": { "city": "Paris" },
+            },
+        });
+
+        let mut redactor = Redactor::default();
+        redactor.add_field("age");
+        redactor.redact(&mut payload);
+
+        assert_eq!(
+            payload,
+            json!({
+                "userId": "foo",
+                "traits": {
+                    "Email": "[REDACTED]",
+                    "friends": [{ "phone": "[REDACTED]", "age": "[REDACTED]" }],
+                    "address": "[REDACTED]",
+                },
+            })
+        );
+    }
+}
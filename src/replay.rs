@@ -0,0 +1,254 @@
+//! Sending stored messages again, such as dead-lettered ones.
+
+use std::fmt;
+use std::io::BufRead;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::message::StoredMessage;
+use crate::{runtime, Analytics, Error, Result};
+
+/// How [`Analytics::replay`] sends messages.
+///
+/// ```
+/// use segment::ReplayOptions;
+///
+/// let options = ReplayOptions::new()
+///     .rate(500.)
+///     .on_progress(|progress| println!("{} messages replayed", progress.enqueued))
+///     .on_error(|line, error| eprintln!("skipping line {}: {}", line, error));
+/// ```
+#[derive(Clone, Debug)]
+pub struct ReplayOptions {
+    rate: Option<f64>,
+    chunk_size: usize,
+    on_progress: Option<ProgressHook>,
+    on_error: Option<LineErrorHook>,
+}
+
+/// How far a replay went.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplayProgress {
+    /// The number of lines read.
+    pub lines: usize,
+    /// The number of messages queued.
+    pub enqueued: usize,
+    /// The number of lines which could not be parsed.
+    pub failed: usize,
+}
+
+type ProgressFn = dyn Fn(&ReplayProgress) + Send + Sync;
+type LineErrorFn = dyn Fn(usize, &Error) + Send + Sync;
+
+#[derive(Clone)]
+struct ProgressHook(Arc<ProgressFn>);
+
+#[derive(Clone)]
+struct LineErrorHook(Arc<LineErrorFn>);
+
+impl fmt::Debug for ProgressHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressHook")
+    }
+}
+
+impl fmt::Debug for LineErrorHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LineErrorHook")
+    }
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        Self {
+            rate: None,
+            chunk_size: 1000,
+            on_progress: None,
+            on_error: None,
+        }
+    }
+}
+
+impl ReplayOptions {
+    /// Replay as fast as possible, reporting progress every 1000 messages.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue at most `messages_per_second` messages per second on average.
+    ///
+    /// The replay fails with [`Error::InvalidConfig`] unless it is a positive
+    /// number.
+    pub fn rate(mut self, messages_per_second: f64) -> Self {
+        self.rate = Some(messages_per_second);
+        self
+    }
+
+    /// Report progress, and throttle, every `chunk_size` messages.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Call `hook` with the progress of the replay after every chunk, and
+    /// once it completed.
+    pub fn on_progress(mut self, hook: impl Fn(&ReplayProgress) + Send + Sync + 'static) -> Self {
+        self.on_progress = Some(ProgressHook(Arc::new(hook)));
+        self
+    }
+
+    /// Call `hook` with the number of every line which could not be parsed,
+    /// starting at 1, and the error, instead of logging them.
+    pub fn on_error(mut self, hook: impl Fn(usize, &Error) + Send + Sync + 'static) -> Self {
+        self.on_error = Some(LineErrorHook(Arc::new(hook)));
+        self
+    }
+
+    fn report(&self, progress: &ReplayProgress) {
+        if let Some(ProgressHook(hook)) = &self.on_progress {
+            hook(progress);
+        }
+    }
+}
+
+impl Analytics {
+    /// Queue the messages read from `reader`, one JSON [`StoredMessage`] per
+    /// line as written by a [`DeadLetterFile`](crate::DeadLetterFile), and
+    /// wait until they are sent.
+    ///
+    /// Messages are batched by the pipeline like any other. Lines which can't
+    /// be parsed are skipped and reported to the error hook of `options`.
+    /// Batches of messages are split into their messages.
+    ///
+    /// Returns the final progress of the replay, or an error if the
+    /// [rate](ReplayOptions::rate) is invalid, if reading from `reader` failed
+    /// or if this handle was closed.
+    ///
+    /// ```no_run
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() -> segment::Result<()> {
+    /// use std::fs::File;
+    /// use std::io::BufReader;
+    /// use segment::{ClientBuilder, ReplayOptions};
+    ///
+    /// let analytics = ClientBuilder::from_env()?.build()?;
+    /// let file = BufReader::new(File::open("dead-letter.jsonl")?);
+    /// analytics.replay(file, ReplayOptions::new().rate(100.)).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn replay(
+        &self,
+        mut reader: impl BufRead,
+        options: ReplayOptions,
+    ) -> Result<ReplayProgress> {
+        let invalid_rate =
+            |rate| Error::InvalidConfig(format!("replay rate must be positive, not {}", rate));
+        if let Some(rate) = options
+            .rate
+            .filter(|rate| !(*rate > 0. && rate.is_finite()))
+        {
+            return Err(invalid_rate(rate));
+        }
+        let started = Instant::now();
+        let mut progress = ReplayProgress::default();
+        let mut chunk = 0;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                break;
+            }
+            progress.lines += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let msgs = match serde_json::from_str::<StoredMessage>(&line) {
+                Ok(msg) => msg.into_batch_messages(),
+                Err(e) => {
+                    progress.failed += 1;
+                    let e = Error::from(e);
+                    match &options.on_error {
+                        Some(LineErrorHook(hook)) => hook(progress.lines, &e),
+                        None => log::warn!("skipping line {}: {}", progress.lines, e),
+                    }
+                    continue;
+                }
+            };
+            for msg in msgs {
+                self.enqueue(msg)?;
+                progress.enqueued += 1;
+                chunk += 1;
+                if chunk == options.chunk_size {
+                    chunk = 0;
+                    options.report(&progress);
+                    if let Some(rate) = options.rate {
+                        let due = Duration::try_from_secs_f64(progress.enqueued as f64 / rate)
+                            .map_err(|_| invalid_rate(rate))?;
+                        runtime::sleep(due.saturating_sub(started.elapsed())).await;
+                    }
+                }
+            }
+        }
+        self.flush().await?;
+        options.report(&progress);
+        Ok(progress)
+    }
+}
+
+#[cfg(all(test, any(feature = "tokio", feature = "smol")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::MockClient;
+    use crate::{AutoBatcher, Batcher, WriteKey};
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn test_replay() {
+        let client = MockClient::new();
+        let batcher = AutoBatcher::new(
+            client.clone(),
+            Batcher::new(None),
+            WriteKey::new("key").unwrap(),
+        );
+        let analytics = Analytics::new(batcher, Duration::from_secs(3600));
+        let input = r#"{"type":"track","userId":"foo","event":"A"}
+not json
+{"type":"batch","batch":[{"type":"track","userId":"bar","event":"B"},{"type":"identify","userId":"bar"}]}
+"#;
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let failed_lines = Arc::new(Mutex::new(Vec::new()));
+        let options = ReplayOptions::new()
+            .chunk_size(2)
+            .on_progress({
+                let reported = reported.clone();
+                move |progress| reported.lock().unwrap().push(*progress)
+            })
+            .on_error({
+                let failed_lines = failed_lines.clone();
+                move |line, _| failed_lines.lock().unwrap().push(line)
+            });
+
+        let progress = analytics.replay(input.as_bytes(), options).await.unwrap();
+        assert_eq!(
+            progress,
+            ReplayProgress {
+                lines: 3,
+                enqueued: 3,
+                failed: 1
+            }
+        );
+        assert_eq!(reported.lock().unwrap().len(), 2);
+        assert_eq!(*failed_lines.lock().unwrap(), [2]);
+        assert_eq!(client.tracks().len(), 2);
+
+        for rate in [0., -1., f64::NAN, f64::INFINITY] {
+            let options = ReplayOptions::new().rate(rate);
+            assert!(matches!(
+                analytics.replay(input.as_bytes(), options).await,
+                Err(Error::InvalidConfig(_))
+            ));
+        }
+        assert_eq!(client.tracks().len(), 2);
+    }
+}
@@ -0,0 +1,70 @@
+//! Retrying failed requests to Segment's API.
+
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::config::duration;
+
+/// How to retry sending a batch when Segment's API fails transiently.
+///
+/// Network errors, timeouts, `429 Too Many Requests` and `5xx` responses are
+/// retried, with an exponentially growing delay between attempts. Other
+/// errors, e.g. a `400 Bad Request`, are never retried.
+///
+/// Each attempt is bounded by the [timeout](crate::ClientBuilder::timeout) of
+/// the client, while the [`deadline`](RetryPolicy::deadline) bounds the time
+/// spent delivering a batch across all its attempts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// How many times to retry a request after its first attempt failed.
+    pub max_retries: u32,
+    /// The delay before the first retry. It doubles with every retry.
+    #[serde(with = "duration")]
+    pub initial_backoff: Duration,
+    /// The longest delay between two attempts.
+    #[serde(with = "duration")]
+    pub max_backoff: Duration,
+    /// Give up on a batch once retrying it would end more than this long
+    /// after its first attempt, even if retries remain, and [dead-letter](crate::DeadLetter)
+    /// it. Spooled batches are otherwise retried forever.
+    #[serde(with = "duration::option")]
+    pub deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    /// Retry 3 times, waiting 100ms, 200ms and then 400ms.
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            deadline: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Never retry.
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Self::default()
+        }
+    }
+
+    /// Whether a batch whose first attempt failed `elapsed` ago may be retried
+    /// after `backoff`.
+    pub(crate) fn within_deadline(&self, elapsed: Duration, backoff: Duration) -> bool {
+        self.deadline
+            .is_none_or(|deadline| elapsed + backoff < deadline)
+    }
+
+    /// The delay before the given retry, starting at 0.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .checked_mul(2u32.saturating_pow(retry))
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
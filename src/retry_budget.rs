@@ -0,0 +1,141 @@
+//! Alerting when batches are retried more than usual.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Deserialize;
+use time::OffsetDateTime;
+
+use crate::config::duration;
+
+/// How many retries, and how much time spent waiting between them, are
+/// expected within a window of time. See
+/// [`AutoBatcher::set_retry_budget`](crate::AutoBatcher::set_retry_budget).
+///
+/// Retries absorb the transient failures of Segment's API, but hide them:
+/// a degraded delivery only shows once the queue overflows. Exceeding a
+/// budget raises an alert before it does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryBudget {
+    /// The length of the windows the retries are counted in.
+    #[serde(with = "duration")]
+    pub window: Duration,
+    /// Alert past this many retries within a window.
+    pub max_retries: Option<u64>,
+    /// Alert past this much time spent waiting to retry within a window.
+    #[serde(with = "duration::option")]
+    pub max_backoff: Option<Duration>,
+}
+
+impl Default for RetryBudget {
+    /// No limit, counted in windows of a minute.
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(60),
+            max_retries: None,
+            max_backoff: None,
+        }
+    }
+}
+
+/// The retries of a window which exceeded its [`RetryBudget`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryBudgetAlert {
+    /// When the window started.
+    pub window_start: OffsetDateTime,
+    /// The number of retries within the window so far.
+    pub retries: u64,
+    /// The time spent waiting to retry within the window so far.
+    pub backoff: Duration,
+}
+
+type AlertFn = dyn Fn(&RetryBudgetAlert) + Send + Sync;
+
+/// A callback notified when a retry budget is exceeded.
+#[derive(Clone)]
+pub(crate) struct AlertHook(pub(crate) Arc<AlertFn>);
+
+impl fmt::Debug for AlertHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AlertHook")
+    }
+}
+
+/// The retries of the current window, shared between the clones of a
+/// batcher.
+#[derive(Clone, Debug)]
+pub(crate) struct RetryBudgetMonitor {
+    pub(crate) budget: RetryBudget,
+    window: Arc<Mutex<Option<Window>>>,
+}
+
+#[derive(Debug)]
+struct Window {
+    start: OffsetDateTime,
+    retries: u64,
+    backoff: Duration,
+    alerted: bool,
+}
+
+impl RetryBudgetMonitor {
+    pub(crate) fn new(budget: RetryBudget) -> Self {
+        Self {
+            budget,
+            window: Arc::default(),
+        }
+    }
+
+    /// Count a retry after `backoff`, calling `hook` the first time the
+    /// budget of the window is exceeded, or logging if there is none.
+    pub(crate) fn record(&self, now: OffsetDateTime, backoff: Duration, hook: Option<&AlertHook>) {
+        let alert = match self.count(now, backoff) {
+            Some(alert) => alert,
+            None => return,
+        };
+        match hook {
+            Some(AlertHook(hook)) => hook(&alert),
+            None => log::error!(
+                "retried {} times, waiting {:?}, since {}: Segment's API may be degraded",
+                alert.retries,
+                alert.backoff,
+                alert.window_start
+            ),
+        }
+    }
+
+    /// Count a retry, returning the alert to raise if it exceeded the budget
+    /// of the window for the first time.
+    fn count(&self, now: OffsetDateTime, backoff: Duration) -> Option<RetryBudgetAlert> {
+        let mut window = self.window.lock().unwrap();
+        let window = match &mut *window {
+            Some(window) if now - window.start < self.budget.window => window,
+            window => window.insert(Window {
+                start: now,
+                retries: 0,
+                backoff: Duration::ZERO,
+                alerted: false,
+            }),
+        };
+        window.retries += 1;
+        window.backoff += backoff;
+        let exceeded = self
+            .budget
+            .max_retries
+            .is_some_and(|max| window.retries > max)
+            || self
+                .budget
+                .max_backoff
+                .is_some_and(|max| window.backoff > max);
+        if !exceeded || window.alerted {
+            return None;
+        }
+        window.alerted = true;
+        Some(RetryBudgetAlert {
+            window_start: window.start,
+            retries: window.retries,
+            backoff: window.backoff,
+        })
+    }
+}
@@ -0,0 +1,267 @@
+//! The few primitives the crate needs from an async runtime.
+//!
+//! The runtime is chosen with the `tokio` (the default) and `smol` features.
+//! If both are enabled, Tokio is used. If neither is, the background pipeline
+//! is not available, and the timers and blocking calls of the batchers and
+//! the HTTP client run on short-lived threads instead.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake};
+use std::thread::{self, Thread};
+use std::time::Duration;
+
+/// Run `future` in the background.
+#[cfg(feature = "tokio")]
+pub(crate) fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(future);
+}
+
+/// Run `future` in the background.
+#[cfg(all(feature = "smol", not(feature = "tokio")))]
+pub(crate) fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    smol::spawn(future).detach();
+}
+
+/// Wait until `duration` has elapsed.
+#[cfg(feature = "tokio")]
+pub(crate) async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await;
+}
+
+/// Wait until `duration` has elapsed.
+#[cfg(all(feature = "smol", not(feature = "tokio")))]
+pub(crate) async fn sleep(duration: Duration) {
+    smol::Timer::after(duration).await;
+}
+
+/// Run the blocking function `f` on a thread where blocking is acceptable.
+#[cfg(feature = "tokio")]
+pub(crate) async fn unblock<T, F>(f: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .expect("blocking task panicked")
+}
+
+/// Run the blocking function `f` on a thread where blocking is acceptable.
+#[cfg(all(feature = "smol", not(feature = "tokio")))]
+pub(crate) async fn unblock<T, F>(f: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    smol::unblock(f).await
+}
+
+/// Wait until `duration` has elapsed, on the timer thread since there is no
+/// runtime to drive timers.
+#[cfg(not(any(feature = "tokio", feature = "smol")))]
+pub(crate) async fn sleep(duration: Duration) {
+    timer::Sleep::new(duration).await;
+}
+
+/// Run the blocking function `f` on a new thread, since there is no runtime
+/// with a pool of threads where blocking is acceptable.
+#[cfg(not(any(feature = "tokio", feature = "smol")))]
+pub(crate) async fn unblock<T, F>(f: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (sender, receiver) = futures_channel::oneshot::channel();
+    thread::spawn(move || {
+        // The receiver is gone if the caller stopped waiting.
+        let _ = sender.send(f());
+    });
+    receiver.await.expect("blocking task panicked")
+}
+
+/// Run `future` to completion on the current thread, along with the timers
+/// and I/O it waits for. The thread must not be running an async runtime
+/// already.
+#[cfg(feature = "tokio")]
+pub(crate) fn block_on<F: Future>(future: F) -> io::Result<F::Output> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    Ok(runtime.block_on(future))
+}
+
+/// Run `future` to completion on the current thread, along with the timers
+/// and I/O it waits for. The thread must not be running an async runtime
+/// already.
+#[cfg(all(feature = "smol", not(feature = "tokio")))]
+pub(crate) fn block_on<F: Future>(future: F) -> io::Result<F::Output> {
+    Ok(smol::block_on(future))
+}
+
+/// Run `future` to completion on the current thread. Without a runtime, the
+/// timers are fired by the timer thread and the blocking calls run on their
+/// own threads, so [`wait`] drives it.
+#[cfg(not(any(feature = "tokio", feature = "smol")))]
+pub(crate) fn block_on<F: Future>(future: F) -> io::Result<F::Output> {
+    Ok(wait(future))
+}
+
+/// Wait for `future` by parking the current thread until it is woken.
+///
+/// Unlike [`block_on`], no timers or I/O are driven, so this is only fit for
+/// futures completed by other threads, such as the receivers of channels.
+pub(crate) fn wait<F: Future>(future: F) -> F::Output {
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Arc::new(Unpark(thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        match Pin::as_mut(&mut future).poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+/// The timers of the sleeps in progress, fired by a single thread.
+#[cfg(not(any(feature = "tokio", feature = "smol")))]
+mod timer {
+    use std::collections::BTreeMap;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Condvar, Mutex, OnceLock};
+    use std::task::{Context, Poll, Waker};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// The wakers of the pending sleeps, by deadline and ID.
+    #[derive(Default)]
+    struct Timers {
+        pending: Mutex<BTreeMap<(Instant, u64), Waker>>,
+        changed: Condvar,
+    }
+
+    static TIMERS: OnceLock<Timers> = OnceLock::new();
+
+    /// The timers, starting the thread firing them on first use.
+    fn timers() -> &'static Timers {
+        TIMERS.get_or_init(|| {
+            thread::Builder::new()
+                .name("segment-timer".to_owned())
+                .spawn(fire)
+                .expect("could not start the timer thread");
+            Timers::default()
+        })
+    }
+
+    /// Wake the sleeps as their deadlines pass, forever.
+    fn fire() {
+        let timers = timers();
+        let mut pending = timers.pending.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let mut due = Vec::new();
+            while let Some(entry) = pending.first_entry() {
+                if entry.key().0 > now {
+                    break;
+                }
+                due.push(entry.remove());
+            }
+            if !due.is_empty() {
+                // Woken without the lock, which they may take when polled.
+                drop(pending);
+                due.into_iter().for_each(Waker::wake);
+                pending = timers.pending.lock().unwrap();
+                continue;
+            }
+            pending = match pending.keys().next() {
+                Some(&(deadline, _)) => {
+                    timers
+                        .changed
+                        .wait_timeout(pending, deadline - now)
+                        .unwrap()
+                        .0
+                }
+                None => timers.changed.wait(pending).unwrap(),
+            };
+        }
+    }
+
+    /// A future completing once its deadline has passed.
+    pub(super) struct Sleep {
+        key: (Instant, u64),
+    }
+
+    impl Sleep {
+        pub(super) fn new(duration: Duration) -> Self {
+            static IDS: AtomicU64 = AtomicU64::new(0);
+            Self {
+                key: (
+                    Instant::now() + duration,
+                    IDS.fetch_add(1, Ordering::Relaxed),
+                ),
+            }
+        }
+    }
+
+    impl Future for Sleep {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if Instant::now() >= self.key.0 {
+                return Poll::Ready(());
+            }
+            let timers = timers();
+            let mut pending = timers.pending.lock().unwrap();
+            let first = pending.keys().next().is_none_or(|first| self.key < *first);
+            pending.insert(self.key, cx.waker().clone());
+            if first {
+                timers.changed.notify_one();
+            }
+            Poll::Pending
+        }
+    }
+
+    impl Drop for Sleep {
+        fn drop(&mut self) {
+            if let Some(timers) = TIMERS.get() {
+                timers.pending.lock().unwrap().remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_block_on() {
+        let started = Instant::now();
+        let output = block_on(async {
+            sleep(Duration::from_millis(50)).await;
+            unblock(|| 42).await
+        })
+        .unwrap();
+        assert_eq!(output, 42);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,181 @@
+//! Formatting messages with their personal data masked, so they can be
+//! logged.
+//!
+//! With the `safe-debug` feature enabled, the `Debug` implementations of the
+//! message types print their JSON form with:
+//!
+//! - the user, anonymous, previous and group IDs partially masked, keeping
+//!   their first and last two characters, e.g. `us******42`;
+//! - emails keeping the first character of their local part and their
+//!   domain, e.g. `j***@example.com`;
+//! - first names, last names and usernames, and names within traits, keeping
+//!   their first character, e.g. `J***`;
+//! - phone numbers, addresses, birthdays and IP addresses fully masked.
+//!
+//! Messages also implement `Display` the same way, and a [`User`] displays
+//! as its masked ID.
+
+use std::fmt;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::message::{
+    Alias, Batch, BatchMessage, Context, Group, Identify, Message, Page, Properties, Screen,
+    StoredMessage, Track, Traits, User,
+};
+
+const MASK: &str = "[REDACTED]";
+
+/// The fields holding IDs, at any depth.
+const ID_FIELDS: [&str; 4] = ["userId", "anonymousId", "previousId", "groupId"];
+
+/// The fields holding names, at any depth.
+const NAME_FIELDS: [&str; 3] = ["firstName", "lastName", "username"];
+
+/// The fields masked entirely, compared case-insensitively.
+const MASKED_FIELDS: [&str; 4] = ["phone", "address", "birthday", "ip"];
+
+/// `value` serialized, with its personal data masked.
+fn masked(value: &impl Serialize) -> Value {
+    let mut value = serde_json::to_value(value).unwrap_or(Value::Null);
+    mask(&mut value, false);
+    value
+}
+
+fn mask(value: &mut Value, in_traits: bool) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.as_str();
+                let masked = match &*value {
+                    Value::String(id) if ID_FIELDS.contains(&key) => mask_id(id),
+                    Value::String(email) if key.eq_ignore_ascii_case("email") => mask_email(email),
+                    Value::String(name)
+                        if NAME_FIELDS.contains(&key) || (in_traits && key == "name") =>
+                    {
+                        mask_name(name)
+                    }
+                    _ if MASKED_FIELDS.iter().any(|f| f.eq_ignore_ascii_case(key)) => {
+                        MASK.to_owned()
+                    }
+                    _ => {
+                        mask(value, key == "traits");
+                        continue;
+                    }
+                };
+                *value = Value::String(masked);
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|v| mask(v, in_traits)),
+        _ => (),
+    }
+}
+
+/// Keep the first and last two characters of `id`, if it is long enough for
+/// the rest to stay hidden.
+fn mask_id(id: &str) -> String {
+    let chars: Vec<char> = id.chars().collect();
+    if chars.len() <= 6 {
+        return "*".repeat(chars.len());
+    }
+    let (start, end) = (&chars[..2], &chars[chars.len() - 2..]);
+    format!(
+        "{}{}{}",
+        start.iter().collect::<String>(),
+        "*".repeat(chars.len() - 4),
+        end.iter().collect::<String>()
+    )
+}
+
+fn mask_email(email: &str) -> String {
+    match email.split_once('@') {
+        Some((local, domain)) => format!("{}@{}", mask_name(local), domain),
+        None => mask_id(email),
+    }
+}
+
+fn mask_name(name: &str) -> String {
+    match name.chars().next() {
+        Some(first) => format!("{}***", first),
+        None => String::new(),
+    }
+}
+
+macro_rules! masked_fmt {
+    ($($name:ident),+ $(,)?) => {
+        $(
+            impl fmt::Debug for $name {
+                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    write!(f, "{}({})", stringify!($name), masked(self))
+                }
+            }
+
+            impl fmt::Display for $name {
+                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    write!(f, "{}", masked(self))
+                }
+            }
+        )+
+    };
+}
+
+masked_fmt!(
+    Message,
+    StoredMessage,
+    BatchMessage,
+    Batch,
+    Identify,
+    Track,
+    Page,
+    Screen,
+    Group,
+    Alias,
+    Properties,
+    Traits,
+    Context,
+);
+
+impl fmt::Debug for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "User({})", masked(self))
+    }
+}
+
+impl fmt::Display for User {
+    /// Display the masked user ID, or anonymous ID if there is none.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&mask_id(self.key()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_masked_debug() {
+        let identify = Identify {
+            user: User::UserId {
+                user_id: "user-1234".to_owned(),
+            },
+            traits: Traits::new()
+                .with("email", "jane@example.com")
+                .with("name", "Jane Doe")
+                .with("phone", "555-0100")
+                .with("plan", "pro"),
+            ..Default::default()
+        };
+        let debug = format!("{:?}", identify);
+        assert!(debug.starts_with("Identify("));
+        for hidden in ["user-1234", "jane@", "Jane Doe", "555-0100"].iter() {
+            assert!(!debug.contains(hidden), "{} in {}", hidden, debug);
+        }
+        assert!(debug.contains("us*****34"));
+        assert!(debug.contains("j***@example.com"));
+        assert!(debug.contains("J***"));
+        assert!(debug.contains("pro"));
+        assert_eq!(identify.user.to_string(), "us*****34");
+        assert_eq!(mask_id("abc"), "***");
+    }
+}
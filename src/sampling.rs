@@ -0,0 +1,184 @@
+//! Keeping the events of a stable fraction of the users.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::message::BatchMessage;
+
+/// The number of buckets the users are spread over by [`Sampling`], so rates
+/// have a precision of 0.01%.
+pub const SAMPLING_BUCKETS: u32 = 10_000;
+
+/// The bucket of `user_id` among `n`, from `0` to `n - 1`.
+///
+/// A user always lands in the same bucket for a given `salt`, across runs,
+/// processes and versions of this crate, and users are spread evenly across
+/// buckets. The bucket is the first 8 bytes of the SHA-256 hash of
+/// `"<salt>:<user_id>"`, read as a big-endian integer, modulo `n`, so other
+/// services can compute it too.
+///
+/// [`Sampling`] keeps the users whose bucket among [`SAMPLING_BUCKETS`] is
+/// below the rate of an event, so code deciding who sees a feature can agree
+/// with it on which users are in:
+///
+/// ```
+/// use segment::{bucket, Sampling, SAMPLING_BUCKETS};
+///
+/// let sampling = Sampling::new("2024-rollout").with_rate("Search", 0.1);
+/// // The 10% of users in the rollout are the ones whose searches are kept.
+/// let in_rollout = bucket("user-42", "2024-rollout", SAMPLING_BUCKETS) < 1_000;
+/// assert_eq!(in_rollout, sampling.includes("user-42", "Search"));
+/// ```
+///
+/// # Panics
+///
+/// Panics if `n` is zero.
+pub fn bucket(user_id: &str, salt: &str, n: u32) -> u32 {
+    assert!(n > 0, "there must be at least one bucket");
+    let hash = Sha256::new()
+        .chain_update(salt)
+        .chain_update(":")
+        .chain_update(user_id)
+        .finalize();
+    let hash = u64::from_be_bytes(hash[..8].try_into().unwrap());
+    (hash % u64::from(n)) as u32
+}
+
+/// The fraction of the users whose `track` events are kept, by event name.
+/// See [`Batcher::set_sampling`](crate::Batcher::set_sampling).
+///
+/// Users are sampled rather than events, so the events kept for a user are
+/// all of theirs, and funnels stay complete. With the same `salt`, a user
+/// kept for an event at a rate is kept for every event at that rate or more.
+///
+/// The events and users listed as exempt, e.g. revenue events or internal QA
+/// accounts, are always kept, and are not subject to the
+/// [rate limits](crate::Batcher::rate_limit) of the batcher either. A
+/// `Sampling` without rates only exempts them, keeping every event.
+///
+/// It deserializes from:
+///
+/// ```json
+/// {
+///     "salt": "2024",
+///     "rates": { "Search": 0.1 },
+///     "default_rate": 1.0,
+///     "exempt_events": ["Order Completed"],
+///     "exempt_users": ["qa-1"]
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sampling {
+    /// The salt of the [bucket](bucket) of users.
+    pub salt: String,
+    /// The rate of each event name, from `0.0` to `1.0`.
+    pub rates: BTreeMap<String, f64>,
+    /// The rate of the events not listed in `rates`.
+    pub default_rate: f64,
+    /// The names of the events always kept.
+    pub exempt_events: BTreeSet<String>,
+    /// The IDs of the users whose events are always kept.
+    pub exempt_users: BTreeSet<String>,
+}
+
+impl Default for Sampling {
+    /// Keep every event.
+    fn default() -> Self {
+        Self {
+            salt: String::new(),
+            rates: BTreeMap::new(),
+            default_rate: 1.0,
+            exempt_events: BTreeSet::new(),
+            exempt_users: BTreeSet::new(),
+        }
+    }
+}
+
+impl Sampling {
+    /// Keep every event but the ones given a rate with
+    /// [`with_rate`](Self::with_rate), bucketing users with `salt`.
+    pub fn new(salt: impl Into<String>) -> Self {
+        Self {
+            salt: salt.into(),
+            ..Self::default()
+        }
+    }
+
+    /// Keep the `track` events named `event` of a fraction `rate` of the
+    /// users, returning `self` for chaining.
+    pub fn with_rate(mut self, event: impl Into<String>, rate: f64) -> Self {
+        self.rates.insert(event.into(), rate);
+        self
+    }
+
+    /// Always keep the events named `event`, returning `self` for chaining.
+    pub fn exempt_event(mut self, event: impl Into<String>) -> Self {
+        self.exempt_events.insert(event.into());
+        self
+    }
+
+    /// Always keep the events of `user_id`, returning `self` for chaining.
+    pub fn exempt_user(mut self, user_id: impl Into<String>) -> Self {
+        self.exempt_users.insert(user_id.into());
+        self
+    }
+
+    /// Whether the events named `event` of `user_id` are kept.
+    pub fn includes(&self, user_id: &str, event: &str) -> bool {
+        if self.exempt_users.contains(user_id) || self.exempt_events.contains(event) {
+            return true;
+        }
+        let rate = self.rates.get(event).copied().unwrap_or(self.default_rate);
+        let threshold = (rate.clamp(0.0, 1.0) * f64::from(SAMPLING_BUCKETS)).round() as u32;
+        bucket(user_id, &self.salt, SAMPLING_BUCKETS) < threshold
+    }
+
+    /// Whether `msg` is exempt from sampling and rate limits.
+    pub(crate) fn exempts(&self, msg: &BatchMessage) -> bool {
+        self.exempt_users.contains(msg.user().key())
+            || msg
+                .event_name()
+                .is_some_and(|event| self.exempt_events.contains(event))
+    }
+
+    /// Whether `msg` is kept. Only `track` events are sampled.
+    pub(crate) fn keeps(&self, msg: &BatchMessage) -> bool {
+        match msg {
+            BatchMessage::Track(track) => self.includes(track.user.key(), &track.event),
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sampling() {
+        assert_eq!(bucket("user-1", "salt", 100), bucket("user-1", "salt", 100));
+        let buckets: Vec<u32> = (0..1000)
+            .map(|i| bucket(&i.to_string(), "salt", 10))
+            .collect();
+        assert!(buckets.iter().all(|&b| b < 10));
+        assert!((0..10).all(|b| buckets.iter().filter(|&&x| x == b).count() > 50));
+
+        let sampling = Sampling::new("salt")
+            .with_rate("Search", 0.25)
+            .with_rate("Debug", 0.0);
+        let kept = (0..1000)
+            .filter(|i| sampling.includes(&i.to_string(), "Search"))
+            .count();
+        assert!((200..300).contains(&kept));
+        assert!((0..1000).all(|i| !sampling.includes(&i.to_string(), "Debug")));
+        assert!((0..1000).all(|i| sampling.includes(&i.to_string(), "Bought")));
+
+        let sampling = sampling.exempt_event("Debug").exempt_user("qa");
+        assert!(sampling.includes("1", "Debug"));
+        assert!(sampling.includes("qa", "Search"));
+    }
+}
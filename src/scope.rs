@@ -0,0 +1,173 @@
+//! Context attached to every message produced within a scope.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use serde_json::Value;
+
+use crate::message::{merge_context, merge_optional_context, BatchMessage};
+use crate::trace;
+
+thread_local! {
+    static CONTEXT: RefCell<Option<Value>> = const { RefCell::new(None) };
+}
+
+/// Attach `context` to every message queued while `future` runs.
+///
+/// Messages pushed to a [`Batcher`](crate::Batcher) or queued on an
+/// [`Analytics`](crate::Analytics) handle from within `future`, however
+/// deeply nested, get the fields of `context` merged into their own context.
+/// Fields already set on the message take precedence, and so do the fields of
+/// inner scopes over the ones of outer scopes.
+///
+/// The context follows the future itself rather than the task running it, so
+/// it works on any executor.
+///
+/// ```no_run
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> segment::Result<()> {
+/// # let analytics: segment::Analytics = unimplemented!();
+/// use segment::message::{Track, User};
+/// use serde_json::json;
+///
+/// segment::with_context(json!({ "ip": "203.0.113.7" }), async {
+///     // Sent with `"context": { "ip": "203.0.113.7" }`.
+///     analytics.enqueue(Track {
+///         user: User::UserId { user_id: "user".to_owned() },
+///         event: "Example".to_owned(),
+///         ..Default::default()
+///     })
+/// })
+/// .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn with_context<F: Future>(context: Value, future: F) -> WithContext<F> {
+    WithContext {
+        context,
+        future: Box::pin(future),
+    }
+}
+
+/// Attach `context` to every message queued on this thread until the returned
+/// guard is dropped. This is the synchronous counterpart of [`with_context`].
+pub fn enter_context(context: Value) -> ContextGuard {
+    ContextGuard {
+        previous: enter(&context),
+    }
+}
+
+/// The context of the current scope, if any.
+pub fn current_context() -> Option<Value> {
+    CONTEXT.with(|current| current.borrow().clone())
+}
+
+/// A future returned by [`with_context`].
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct WithContext<F> {
+    context: Value,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for WithContext<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let _guard = ContextGuard {
+            previous: enter(&self.context),
+        };
+        self.future.as_mut().poll(cx)
+    }
+}
+
+/// A guard returned by [`enter_context`], restoring the previous context when
+/// dropped.
+#[derive(Debug)]
+#[must_use = "the context is left as soon as the guard is dropped"]
+pub struct ContextGuard {
+    previous: Option<Value>,
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CONTEXT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// Make `context`, merged over the current context, the current context, and
+/// return the previous one.
+fn enter(context: &Value) -> Option<Value> {
+    CONTEXT.with(|current| {
+        let mut merged = context.clone();
+        if let Some(outer) = &*current.borrow() {
+            merge_context(&mut merged, outer);
+        }
+        current.replace(Some(merged))
+    })
+}
+
+/// Merge the context of the current scope, then the current [trace
+/// context](crate::propagate_trace_context), into the context of `msg`.
+pub(crate) fn apply(msg: &mut BatchMessage) {
+    CONTEXT.with(|current| {
+        if let Some(scope) = &*current.borrow() {
+            merge_optional_context(msg.context_mut(), scope);
+        }
+    });
+    trace::apply(msg);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Track;
+    use serde_json::json;
+
+    fn context_of(mut msg: BatchMessage) -> Option<Value> {
+        apply(&mut msg);
+        msg.context_mut().take()
+    }
+
+    #[tokio::test]
+    async fn test_with_context() {
+        let outer = json!({ "ip": "203.0.113.7", "app": { "name": "foo" } });
+        let inner = json!({ "app": { "version": "1.0" } });
+        let context = with_context(outer, async {
+            let context = with_context(inner, async {
+                tokio::task::yield_now().await;
+                context_of(
+                    Track {
+                        context: Some(json!({ "ip": "198.51.100.1" })),
+                        ..Default::default()
+                    }
+                    .into(),
+                )
+            })
+            .await;
+            assert_eq!(current_context().unwrap()["app"], json!({ "name": "foo" }));
+            context
+        })
+        .await;
+
+        assert_eq!(
+            context,
+            Some(json!({
+                "ip": "198.51.100.1",
+                "app": { "name": "foo", "version": "1.0" },
+            }))
+        );
+        assert_eq!(current_context(), None);
+
+        let guard = enter_context(json!({ "ip": "203.0.113.7" }));
+        assert_eq!(
+            context_of(Track::default().into()),
+            Some(json!({ "ip": "203.0.113.7" }))
+        );
+        drop(guard);
+        assert_eq!(context_of(Track::default().into()), None);
+    }
+}
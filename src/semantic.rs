@@ -0,0 +1,257 @@
+//! Typed builders for [semantic events](https://segment.com/docs/spec/semantic/),
+//! whose names and properties are standardized so every service logs them the
+//! same way.
+//!
+//! Each event converts into a [`Track`], and can be given directly to
+//! [`Analytics::enqueue`](crate::Analytics::enqueue) or
+//! [`AutoBatcher::push`](crate::AutoBatcher::push):
+//!
+//! ```no_run
+//! # fn main() -> segment::Result<()> {
+//! # let analytics: segment::Analytics = unimplemented!();
+//! use segment::message::User;
+//! use segment::semantic::ExperimentViewed;
+//!
+//! let user = User::UserId { user_id: "user".to_owned() };
+//! analytics.enqueue(ExperimentViewed::new(user, "checkout-redesign", "variant-b"))?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Every event of the catalog implements [`Event`], and is sent with the
+//! [version](Track::with_version) of its schema.
+
+#[cfg(feature = "revenue")]
+mod revenue;
+
+#[cfg(feature = "revenue")]
+pub use revenue::{Currency, Revenue};
+
+use serde_json::Value;
+
+use crate::message::{BatchMessage, Message, Properties, Track, User};
+
+/// An event of this catalog, with the name and version of its schema.
+///
+/// Its version is stamped on the [`Track`] it converts into, so that events
+/// sent before and after its properties changed can be told apart in the
+/// warehouse. Implement it for your own typed events to do the same:
+///
+/// ```
+/// use segment::message::{Properties, Track, User};
+/// use segment::semantic::Event;
+///
+/// struct OrderCompleted {
+///     user: User,
+///     order_id: String,
+/// }
+///
+/// impl Event for OrderCompleted {
+///     const NAME: &'static str = "Order Completed";
+///     const VERSION: u32 = 2;
+/// }
+///
+/// impl From<OrderCompleted> for Track {
+///     fn from(event: OrderCompleted) -> Self {
+///         Track {
+///             user: event.user,
+///             event: OrderCompleted::NAME.to_owned(),
+///             properties: Properties::new().with("order_id", event.order_id),
+///             ..Default::default()
+///         }
+///         .with_version(OrderCompleted::VERSION)
+///     }
+/// }
+/// ```
+pub trait Event: Into<Track> {
+    /// The name of the event.
+    const NAME: &'static str;
+    /// The version of the schema of the event, increased whenever its
+    /// properties change.
+    const VERSION: u32;
+}
+
+/// The name of [`ExperimentViewed`] events.
+pub const EXPERIMENT_VIEWED: &str = "Experiment Viewed";
+
+/// The name of [`FeatureFlagExposure`] events.
+pub const FEATURE_FLAG_EXPOSURE: &str = "Feature Flag Exposure";
+
+/// A user was exposed to a variation of an A/B test.
+///
+/// Sent as an `Experiment Viewed` event with the `experiment_id`,
+/// `experiment_name`, `variation_id` and `variation_name` properties.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExperimentViewed {
+    /// The user who viewed the experiment.
+    pub user: User,
+    /// The ID of the experiment.
+    pub experiment_id: String,
+    /// The human readable name of the experiment.
+    pub experiment_name: Option<String>,
+    /// The ID of the variation the user was assigned to.
+    pub variation_id: String,
+    /// The human readable name of the variation.
+    pub variation_name: Option<String>,
+    /// Other properties to send with the event.
+    pub properties: Properties,
+}
+
+impl ExperimentViewed {
+    /// An exposure of `user` to the variation `variation_id` of the experiment
+    /// `experiment_id`.
+    pub fn new(
+        user: User,
+        experiment_id: impl Into<String>,
+        variation_id: impl Into<String>,
+    ) -> Self {
+        Self {
+            user,
+            experiment_id: experiment_id.into(),
+            experiment_name: None,
+            variation_id: variation_id.into(),
+            variation_name: None,
+            properties: Properties::new(),
+        }
+    }
+
+    /// Set the human readable names of the experiment and of the variation.
+    pub fn with_names(
+        mut self,
+        experiment_name: impl Into<String>,
+        variation_name: impl Into<String>,
+    ) -> Self {
+        self.experiment_name = Some(experiment_name.into());
+        self.variation_name = Some(variation_name.into());
+        self
+    }
+}
+
+impl Event for ExperimentViewed {
+    const NAME: &'static str = EXPERIMENT_VIEWED;
+    const VERSION: u32 = 1;
+}
+
+impl From<ExperimentViewed> for Track {
+    fn from(event: ExperimentViewed) -> Self {
+        let mut properties = event
+            .properties
+            .with("experiment_id", event.experiment_id)
+            .with("variation_id", event.variation_id);
+        if let Some(name) = event.experiment_name {
+            properties = properties.with("experiment_name", name);
+        }
+        if let Some(name) = event.variation_name {
+            properties = properties.with("variation_name", name);
+        }
+        Track {
+            user: event.user,
+            event: ExperimentViewed::NAME.to_owned(),
+            properties,
+            ..Default::default()
+        }
+        .with_version(ExperimentViewed::VERSION)
+    }
+}
+
+/// A user was exposed to the value of a feature flag.
+///
+/// Sent as a `Feature Flag Exposure` event with the `flag_key` and `variant`
+/// properties.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FeatureFlagExposure {
+    /// The user who was exposed to the flag.
+    pub user: User,
+    /// The key of the flag.
+    pub flag_key: String,
+    /// The value of the flag the user got, e.g. `true` or `"variant-b"`.
+    pub variant: Value,
+    /// Other properties to send with the event.
+    pub properties: Properties,
+}
+
+impl FeatureFlagExposure {
+    /// An exposure of `user` to the value `variant` of the flag `flag_key`.
+    pub fn new(user: User, flag_key: impl Into<String>, variant: impl Into<Value>) -> Self {
+        Self {
+            user,
+            flag_key: flag_key.into(),
+            variant: variant.into(),
+            properties: Properties::new(),
+        }
+    }
+}
+
+impl Event for FeatureFlagExposure {
+    const NAME: &'static str = FEATURE_FLAG_EXPOSURE;
+    const VERSION: u32 = 1;
+}
+
+impl From<FeatureFlagExposure> for Track {
+    fn from(event: FeatureFlagExposure) -> Self {
+        Track {
+            user: event.user,
+            event: FeatureFlagExposure::NAME.to_owned(),
+            properties: event
+                .properties
+                .with("flag_key", event.flag_key)
+                .with("variant", event.variant),
+            ..Default::default()
+        }
+        .with_version(FeatureFlagExposure::VERSION)
+    }
+}
+
+macro_rules! into_track {
+    ($($event:ident),+ $(,)?) => {
+        $(
+            impl From<$event> for BatchMessage {
+                fn from(event: $event) -> Self {
+                    BatchMessage::Track(event.into())
+                }
+            }
+
+            impl From<$event> for Message {
+                fn from(event: $event) -> Self {
+                    Message::Track(event.into())
+                }
+            }
+        )+
+    };
+}
+
+into_track!(ExperimentViewed, FeatureFlagExposure);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_into_track() {
+        let user = User::UserId {
+            user_id: "foo".to_owned(),
+        };
+        let track = Track::from(
+            ExperimentViewed::new(user.clone(), "exp-1", "var-2").with_names("Checkout", "Blue"),
+        );
+        assert_eq!(track.event, "Experiment Viewed");
+        assert_eq!(track.version(), Some(1));
+        assert_eq!(
+            Value::from(track.properties),
+            json!({
+                "experiment_id": "exp-1",
+                "experiment_name": "Checkout",
+                "variation_id": "var-2",
+                "variation_name": "Blue",
+            })
+        );
+
+        let track = Track::from(FeatureFlagExposure::new(user, "new-nav", true));
+        assert_eq!(track.event, "Feature Flag Exposure");
+        assert_eq!(
+            Value::from(track.properties),
+            json!({ "flag_key": "new-nav", "variant": true })
+        );
+    }
+}
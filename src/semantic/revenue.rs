@@ -0,0 +1,197 @@
+//! Exact amounts of money, for the `revenue` of events.
+
+use std::fmt;
+use std::str::FromStr;
+
+use rust_decimal::Decimal;
+use serde_json::{Number, Value};
+
+use crate::message::Properties;
+use crate::{Error, Result};
+
+macro_rules! currencies {
+    ($($minor_units:literal => [$($code:ident),+ $(,)?]),+ $(,)?) => {
+        /// An active [ISO 4217](https://www.iso.org/iso-4217-currency-codes.html)
+        /// currency.
+        ///
+        /// Converts from and displays as its three-letter code.
+        #[allow(clippy::upper_case_acronyms)]
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        #[non_exhaustive]
+        pub enum Currency {
+            $($(
+                $code,
+            )+)+
+        }
+
+        impl Currency {
+            /// The three-letter code of the currency, e.g. `USD`.
+            pub fn code(self) -> &'static str {
+                match self {
+                    $($(Currency::$code => stringify!($code),)+)+
+                }
+            }
+
+            /// The number of digits after the decimal separator of the
+            /// currency, e.g. 2 for cents of `USD`, or 0 for `JPY`.
+            pub fn minor_units(self) -> u32 {
+                match self {
+                    $($(Currency::$code)|+ => $minor_units,)+
+                }
+            }
+        }
+
+        impl FromStr for Currency {
+            type Err = Error;
+
+            /// Parse a three-letter code, in upper case.
+            fn from_str(code: &str) -> Result<Self> {
+                match code {
+                    $($(stringify!($code) => Ok(Currency::$code),)+)+
+                    _ => Err(Error::invalid_message(format!("unknown currency: {:?}", code))),
+                }
+            }
+        }
+    };
+}
+
+currencies! {
+    0 => [
+        BIF, CLP, DJF, GNF, ISK, JPY, KMF, KRW, PYG, RWF, UGX, UYI, VND, VUV, XAF, XOF, XPF,
+    ],
+    2 => [
+        AED, AFN, ALL, AMD, ANG, AOA, ARS, AUD, AWG, AZN, BAM, BBD, BDT, BGN, BMD, BND, BOB, BOV,
+        BRL, BSD, BTN, BWP, BYN, BZD, CAD, CDF, CHE, CHF, CHW, CNY, COP, COU, CRC, CUC, CUP, CVE,
+        CZK, DKK, DOP, DZD, EGP, ERN, ETB, EUR, FJD, FKP, GBP, GEL, GHS, GIP, GMD, GTQ, GYD, HKD,
+        HNL, HTG, HUF, IDR, ILS, INR, IRR, JMD, KES, KGS, KHR, KPW, KYD, KZT, LAK, LBP, LKR, LRD,
+        LSL, MAD, MDL, MGA, MKD, MMK, MNT, MOP, MRU, MUR, MVR, MWK, MXN, MXV, MYR, MZN, NAD, NGN,
+        NIO, NOK, NPR, NZD, PAB, PEN, PGK, PHP, PKR, PLN, QAR, RON, RSD, RUB, SAR, SBD, SCR, SDG,
+        SEK, SGD, SHP, SLE, SLL, SOS, SRD, SSP, STN, SVC, SYP, SZL, THB, TJS, TMT, TOP, TRY, TTD,
+        TWD, TZS, UAH, USD, USN, UYU, UZS, VED, VES, WST, XCD, XCG, YER, ZAR, ZMW, ZWG, ZWL,
+    ],
+    3 => [BHD, IQD, JOD, KWD, LYD, OMR, TND],
+    4 => [CLF, UYW],
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+/// An exact amount of money, set as the `revenue` and `currency` properties
+/// of an event as the [semantic spec](https://segment.com/docs/spec/track/#properties)
+/// describes.
+///
+/// Summing revenues kept as floating-point numbers drifts from the amounts
+/// actually charged, so the amount is a [`Decimal`], checked to be a valid
+/// amount of the currency when the `Revenue` is built:
+///
+/// ```
+/// # fn main() -> segment::Result<()> {
+/// use rust_decimal::Decimal;
+/// use segment::message::Properties;
+/// use segment::semantic::{Currency, Revenue};
+///
+/// let revenue = Revenue::new(Decimal::new(1999, 2), Currency::EUR)?;
+/// let properties = Properties::new().with("order_id", "o-1").with_revenue(revenue);
+/// assert_eq!(properties["revenue"], 19.99);
+/// assert_eq!(properties["currency"], "EUR");
+///
+/// assert!(Revenue::new(Decimal::new(1999, 2), Currency::JPY).is_err());
+/// # Ok(())
+/// # }
+/// ```
+///
+/// This type is only available with the `revenue` feature enabled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Revenue {
+    amount: Decimal,
+    currency: Currency,
+}
+
+impl Revenue {
+    /// An `amount` of `currency`.
+    ///
+    /// Fails if the amount has more decimals than the currency has minor
+    /// units, e.g. fractions of a cent, or if it can't be sent as a JSON
+    /// number without being rounded.
+    pub fn new(amount: Decimal, currency: Currency) -> Result<Self> {
+        let amount = amount.normalize();
+        if amount.scale() > currency.minor_units() {
+            return Err(Error::invalid_message(format!(
+                "{} has more than {} decimals, which {} allows",
+                amount,
+                currency.minor_units(),
+                currency
+            )));
+        }
+        let revenue = Self { amount, currency };
+        if revenue.to_number().is_none() {
+            return Err(Error::invalid_message(format!(
+                "{} can't be sent as a JSON number without being rounded",
+                amount
+            )));
+        }
+        Ok(revenue)
+    }
+
+    /// The amount of money.
+    pub fn amount(&self) -> Decimal {
+        self.amount
+    }
+
+    /// The currency of the amount.
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    /// The amount as a JSON number, if it is exact.
+    fn to_number(self) -> Option<Number> {
+        let amount = self.amount.to_string();
+        let number: Number = if self.amount.scale() == 0 {
+            amount.parse::<i64>().ok()?.into()
+        } else {
+            Number::from_f64(amount.parse().ok()?)?
+        };
+        (number.to_string() == amount).then_some(number)
+    }
+}
+
+impl Properties {
+    /// Set the `revenue` and `currency` properties to `revenue`, returning
+    /// `self` for chaining.
+    ///
+    /// This method is only available with the `revenue` feature enabled.
+    pub fn with_revenue(self, revenue: Revenue) -> Self {
+        // Revenue::new checked the amount is exact as a number.
+        let amount = revenue.to_number().map_or(Value::Null, Value::Number);
+        self.with("revenue", amount)
+            .with("currency", revenue.currency.code())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_revenue() {
+        let properties = Properties::new()
+            .with_revenue(Revenue::new(Decimal::new(1000, 2), Currency::USD).unwrap());
+        assert_eq!(
+            Value::from(properties),
+            json!({ "revenue": 10, "currency": "USD" })
+        );
+        let revenue = Revenue::new(Decimal::new(-12345, 3), Currency::KWD).unwrap();
+        assert_eq!(Properties::new().with_revenue(revenue)["revenue"], -12.345);
+
+        assert!(Revenue::new(Decimal::new(1001, 3), Currency::USD).is_err());
+        assert!(Revenue::new(Decimal::new(123_456_789_012_345_678, 2), Currency::USD).is_err());
+
+        assert_eq!("CHF".parse::<Currency>().unwrap(), Currency::CHF);
+        assert_eq!(Currency::JPY.to_string(), "JPY");
+        assert!("usd".parse::<Currency>().is_err());
+    }
+}
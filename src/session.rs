@@ -0,0 +1,144 @@
+//! Automatic sessionization of the messages of each user.
+
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use time::OffsetDateTime;
+
+use crate::config::duration;
+use crate::message::BatchMessage;
+use crate::user_map::UserMap;
+
+/// Where the session ID is set on messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionField {
+    /// In `context.session_id`.
+    #[default]
+    Context,
+    /// In `properties.session_id` for `track`, `page` and `screen` events, and
+    /// in `context.session_id` for the other messages.
+    Properties,
+}
+
+/// How messages are grouped into sessions. See
+/// [`Batcher::track_sessions`](crate::Batcher::track_sessions).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// Start a new session after this long without a message from the user.
+    #[serde(with = "duration")]
+    pub timeout: Duration,
+    /// Where to set the session ID.
+    pub field: SessionField,
+    /// Set the `groupId` of the last `group` message of a user in the
+    /// current session as `context.groupId` of their later messages, as most
+    /// B2B destinations expect to attribute events to accounts.
+    pub stamp_group_id: bool,
+}
+
+impl Default for SessionConfig {
+    /// Sessions expiring after 30 minutes of inactivity, set in the context,
+    /// without group IDs.
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(30 * 60),
+            field: SessionField::default(),
+            stamp_group_id: false,
+        }
+    }
+}
+
+/// The current session of each user.
+#[derive(Clone, Debug)]
+pub(crate) struct Sessions {
+    pub(crate) config: SessionConfig,
+    sessions: UserMap<String, Session>,
+}
+
+#[derive(Clone, Debug)]
+struct Session {
+    id: i64,
+    last_seen: OffsetDateTime,
+    /// The group the user was last added to in this session.
+    group_id: Option<String>,
+}
+
+impl Sessions {
+    pub(crate) fn new(config: SessionConfig) -> Self {
+        Self {
+            config,
+            sessions: UserMap::new(),
+        }
+    }
+
+    /// Set the ID of the current session of the user of `msg` on it, unless
+    /// it already has one, starting a new session if the last one expired.
+    /// Set their current group too, if enabled.
+    pub(crate) fn apply(&mut self, msg: &mut BatchMessage, now: OffsetDateTime) {
+        let timeout = self.config.timeout;
+        self.sessions.prune(
+            |_, session| now - session.last_seen > timeout,
+            |session| session.last_seen,
+        );
+        let session = self
+            .sessions
+            .entry(msg.user().key().to_owned())
+            .and_modify(|session| {
+                if now - session.last_seen > timeout {
+                    session.id = session_id(now);
+                    session.group_id = None;
+                }
+                session.last_seen = now;
+            })
+            .or_insert_with(|| Session {
+                id: session_id(now),
+                last_seen: now,
+                group_id: None,
+            });
+        let id = Value::from(session.id);
+        let group_id = match msg {
+            _ if !self.config.stamp_group_id => None,
+            BatchMessage::Group(group) => {
+                session.group_id = Some(group.group_id.clone());
+                None
+            }
+            _ => session.group_id.clone(),
+        };
+        if let Some(group_id) = group_id {
+            let context = msg
+                .context_mut()
+                .get_or_insert_with(|| Value::Object(Map::new()));
+            if let Value::Object(context) = context {
+                context.entry("groupId").or_insert(Value::String(group_id));
+            }
+        }
+
+        let properties = match msg {
+            BatchMessage::Track(track) => Some(&mut track.properties.0),
+            BatchMessage::Page(page) => Some(&mut page.properties.0),
+            BatchMessage::Screen(screen) => Some(&mut screen.properties.0),
+            _ => None,
+        };
+        match (self.config.field, properties) {
+            (SessionField::Properties, Some(properties)) => {
+                properties.entry("session_id").or_insert(id);
+            }
+            _ => {
+                let context = msg
+                    .context_mut()
+                    .get_or_insert_with(|| Value::Object(Map::new()));
+                if let Value::Object(context) = context {
+                    context.entry("session_id").or_insert(id);
+                }
+            }
+        }
+    }
+}
+
+/// Sessions are identified by the time they started at, in milliseconds since
+/// the Unix epoch, like most destinations expect.
+fn session_id(start: OffsetDateTime) -> i64 {
+    (start.unix_timestamp_nanos() / 1_000_000) as i64
+}
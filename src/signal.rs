@@ -0,0 +1,57 @@
+//! Flushing the queued messages when the process is asked to stop.
+
+use std::io;
+use std::time::Duration;
+
+use crate::{Analytics, Result};
+
+/// Wait until the process receives `SIGTERM` or `ctrl-c`, then
+/// [close](Analytics::close_within) `analytics`, giving up after `timeout`.
+///
+/// Orchestrators such as Kubernetes send `SIGTERM` and kill the process after
+/// a grace period, so `timeout` should leave room for the rest of the
+/// shutdown within it. This returns once the queued messages were sent, or
+/// with [`Error::Timeout`](crate::Error::Timeout) if they could not be in
+/// time, so the process can exit:
+///
+/// ```no_run
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> segment::Result<()> {
+/// use std::time::Duration;
+/// use segment::{ClientBuilder, WriteKey};
+///
+/// let analytics = ClientBuilder::new(WriteKey::new("your_write_key")?).build()?;
+/// tokio::spawn(serve(analytics.clone()));
+/// segment::shutdown_on_signal(analytics, Duration::from_secs(10)).await
+/// # }
+/// # async fn serve(analytics: segment::Analytics) {}
+/// ```
+///
+/// Requires the `signal` feature and the Tokio runtime. Only `ctrl-c` is
+/// listened to on platforms other than Unix.
+pub async fn shutdown_on_signal(analytics: Analytics, timeout: Duration) -> Result<()> {
+    wait_for_signal().await?;
+    log::info!("sending the queued analytics before shutting down");
+    analytics.close_within(timeout).await
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> io::Result<()> {
+    use futures_util::future::{self, Either};
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let terminate = terminate.recv();
+    futures_util::pin_mut!(terminate);
+    let ctrl_c = tokio::signal::ctrl_c();
+    futures_util::pin_mut!(ctrl_c);
+    match future::select(terminate, ctrl_c).await {
+        Either::Left(_) => Ok(()),
+        Either::Right((result, _)) => result,
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
@@ -0,0 +1,194 @@
+//! A [`Sink`] adapter over the [`AutoBatcher`].
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_sink::Sink;
+
+use crate::message::BatchMessage;
+use crate::{AutoBatcher, Client, Error, HttpClient, Result};
+
+type Sending<C> = Pin<Box<dyn Future<Output = (AutoBatcher<C>, Result<()>)> + Send>>;
+
+/// A [`Sink`] batching messages and sending them to Segment, built with
+/// [`AutoBatcher::into_sink`].
+///
+/// The sink is ready to accept a message unless a batch is being sent, so a
+/// producer feeding it with [`SinkExt::send_all`] slows down to the pace at
+/// which Segment accepts batches. Flushing or closing the sink sends the
+/// messages it holds.
+///
+/// [`SinkExt::send_all`]: https://docs.rs/futures/0.3/futures/sink/trait.SinkExt.html#method.send_all
+///
+/// ```no_run
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> segment::Result<()> {
+/// use futures::{stream, SinkExt, StreamExt};
+/// use segment::{AutoBatcher, Batcher, HttpClient, WriteKey};
+/// use segment::message::{Track, User};
+///
+/// let key = WriteKey::new("key")?;
+/// let batcher = AutoBatcher::new(HttpClient::default(), Batcher::new(None), key);
+/// let mut sink = batcher.into_sink();
+///
+/// let mut events = stream::iter(0..100).map(|i| {
+///     Ok(Track {
+///         user: User::UserId { user_id: format!("user-{}", i) },
+///         event: "Example".to_owned(),
+///         ..Default::default()
+///     }
+///     .into())
+/// });
+/// sink.send_all(&mut events).await?;
+/// sink.close().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct BatchSink<C = HttpClient> {
+    state: State<C>,
+}
+
+enum State<C> {
+    Idle(Box<AutoBatcher<C>>),
+    Sending(Sending<C>),
+    // Only held while the batcher is moved into a future.
+    Poisoned,
+}
+
+// The batcher is never pinned: it is moved in and out of the futures sending
+// its batches.
+impl<C> Unpin for BatchSink<C> {}
+
+impl<C> fmt::Debug for BatchSink<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self.state {
+            State::Idle(_) => "Idle",
+            State::Sending(_) => "Sending",
+            State::Poisoned => "Poisoned",
+        };
+        f.debug_struct("BatchSink").field("state", &state).finish()
+    }
+}
+
+impl<C: Client + Send + Sync + 'static> AutoBatcher<C> {
+    /// Turn this batcher into a [`Sink`] of messages.
+    pub fn into_sink(self) -> BatchSink<C> {
+        BatchSink {
+            state: State::Idle(Box::new(self)),
+        }
+    }
+}
+
+impl<C: Client + Send + Sync + 'static> BatchSink<C> {
+    /// Wait until the batch being sent, if any, was sent.
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<Result<&mut AutoBatcher<C>>> {
+        if let State::Sending(sending) = &mut self.state {
+            let (batcher, result) = match sending.as_mut().poll(cx) {
+                Poll::Ready(output) => output,
+                Poll::Pending => return Poll::Pending,
+            };
+            self.state = State::Idle(Box::new(batcher));
+            result?;
+        }
+        match &mut self.state {
+            State::Idle(batcher) => Poll::Ready(Ok(batcher)),
+            _ => Poll::Ready(Err(Error::Closed)),
+        }
+    }
+
+    /// Start sending a batch with `send`, which gets ownership of the batcher
+    /// until it completes.
+    fn start<F>(&mut self, send: impl FnOnce(AutoBatcher<C>) -> F)
+    where
+        F: Future<Output = (AutoBatcher<C>, Result<()>)> + Send + 'static,
+    {
+        if let State::Idle(batcher) = std::mem::replace(&mut self.state, State::Poisoned) {
+            self.state = State::Sending(Box::pin(send(*batcher)));
+        }
+    }
+}
+
+impl<C> Sink<BatchMessage> for BatchSink<C>
+where
+    C: Client + Send + Sync + 'static,
+{
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_idle(cx).map_ok(|_| ())
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: BatchMessage) -> Result<()> {
+        let this = self.get_mut();
+        let batcher = match &mut this.state {
+            State::Idle(batcher) => batcher,
+            _ => panic!("`start_send` called without `poll_ready`"),
+        };
+        // Most messages fit in the current batch, and don't need a future.
+        if let Some(msg) = batcher.batcher.push(msg)? {
+            this.start(|mut batcher| async move {
+                let result = batcher.push(msg).await;
+                (batcher, result)
+            });
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        let batcher = match this.poll_idle(cx) {
+            Poll::Ready(Ok(batcher)) => batcher,
+            other => return other.map_ok(|_| ()),
+        };
+        if batcher.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        this.start(|mut batcher| async move {
+            let result = batcher.flush().await;
+            (batcher, result)
+        });
+        this.poll_idle(cx).map_ok(|_| ())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Track, User};
+    use crate::test_utils::MockClient;
+    use crate::{Batcher, WriteKey};
+    use futures::{stream, SinkExt, StreamExt};
+
+    #[tokio::test]
+    async fn test_send_all() {
+        let client = MockClient::new();
+        let mut batcher = Batcher::new(None);
+        batcher.set_max_batch_messages(10);
+        let mut sink =
+            AutoBatcher::new(client.clone(), batcher, WriteKey::new("key").unwrap()).into_sink();
+
+        let mut tracks = stream::iter(0..25).map(|i| {
+            Ok(Track {
+                user: User::UserId {
+                    user_id: format!("user-{}", i),
+                },
+                event: "Example".to_owned(),
+                ..Default::default()
+            }
+            .into())
+        });
+        // Sends the two full batches, then flushes the last one.
+        sink.send_all(&mut tracks).await.unwrap();
+        assert_eq!(client.messages().len(), 3);
+        assert_eq!(client.tracks().len(), 25);
+
+        sink.close().await.unwrap();
+        assert_eq!(client.messages().len(), 3);
+    }
+}
@@ -0,0 +1,700 @@
+//! A durable queue of batches waiting to be sent.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use hmac::{Hmac, Mac};
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::hkdf;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use time::OffsetDateTime;
+
+use crate::message::Batch;
+use crate::Result;
+
+const BACKOFF_FILE: &str = "backoff.json";
+const CHECKPOINT_FILE: &str = "checkpoint.json";
+
+/// A directory holding the batches which were not sent yet, so they survive
+/// a restart of the process.
+///
+/// When an [`AutoBatcher`](crate::AutoBatcher) has a spool (see
+/// [`set_spool`](crate::AutoBatcher::set_spool)), every batch is written to the
+/// spool before being sent, and only removed from it once Segment accepted it.
+/// Batches are sent in the order they were spooled, including the ones left
+/// over by a previous process.
+///
+/// When the oldest batch fails transiently, the time at which it may be
+/// retried is persisted too, growing exponentially with each failure, so a
+/// process restarting in a loop during an outage doesn't retry it any sooner.
+///
+/// Before a batch Segment accepted is removed, a [`Checkpoint`] of it is
+/// persisted. A process crashing between the two does not send the batch
+/// again when it restarts, and a crash while sending it makes it send at
+/// most this batch twice.
+///
+/// Batch files which can't be read as a batch, such as those truncated by a
+/// full disk, are renamed to `*.batch.rejected` and logged as errors, rather
+/// than blocking the ones spooled after them.
+///
+/// A spool opened with [`open_signed`](Spool::open_signed) appends an
+/// HMAC-SHA256 of each batch to its file, and refuses to send the batches
+/// whose HMAC does not match, so events fabricated by someone with access to
+/// the directory but not to the key are never sent to Segment. Those batches
+/// are renamed to `*.batch.rejected` and logged as errors instead.
+///
+/// A spool opened with [`open_encrypted`](Spool::open_encrypted) encrypts
+/// each batch with ChaCha20-Poly1305 instead, under a key derived from the
+/// given one, so the messages can't be read from the directory either, and
+/// rejects the batches which don't decrypt the same way. To rotate the key,
+/// open the spool with
+/// [`open_encrypted_with_previous`](Spool::open_encrypted_with_previous): the
+/// batches encrypted with a previous key are re-encrypted with the new one as
+/// the spool is opened, after which the previous keys can be dropped. The
+/// backoff and checkpoint files, which hold no message data but the
+/// `messageId` of the last batch sent, are not encrypted.
+///
+/// Clones of a `Spool` share the same directory and index.
+#[derive(Clone, Debug)]
+pub struct Spool {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    dir: PathBuf,
+    /// The number of messages in each spooled batch, by sequence number.
+    batches: BTreeMap<u64, usize>,
+    next_seq: u64,
+    protection: Protection,
+}
+
+/// How the batch files are protected.
+#[derive(Debug)]
+enum Protection {
+    /// Plain JSON.
+    None,
+    /// Followed by their HMAC-SHA256 with the key.
+    Signed(SpoolKey),
+    /// Encrypted with the first key, the others being the previous keys
+    /// batches may have been encrypted with.
+    Encrypted(Vec<SpoolKey>),
+}
+
+/// A secret key signing or encrypting the batches of a [`Spool`].
+///
+/// Like a [`WriteKey`](crate::WriteKey), its `Debug` and `Display`
+/// implementations mask it, entirely since any part of it would help forging
+/// batches, so it never leaks into logs, e.g. through the `Debug` output of a
+/// [`Config`](crate::Config):
+///
+/// ```
+/// use segment::SpoolKey;
+///
+/// let key = SpoolKey::from("hunter2");
+/// assert_eq!(format!("{:?}", key), "SpoolKey(\"********\")");
+/// assert_eq!(key.expose(), b"hunter2");
+/// ```
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "String")]
+pub struct SpoolKey(Vec<u8>);
+
+impl SpoolKey {
+    /// Wrap a key.
+    pub fn new(key: impl Into<Vec<u8>>) -> Self {
+        Self(key.into())
+    }
+
+    /// The unmasked key.
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for SpoolKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // A fixed width, so the length of the key isn't given away either.
+        f.write_str("********")
+    }
+}
+
+impl fmt::Debug for SpoolKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SpoolKey({:?})", self.to_string())
+    }
+}
+
+impl From<String> for SpoolKey {
+    fn from(key: String) -> Self {
+        Self::new(key)
+    }
+}
+
+impl From<&str> for SpoolKey {
+    fn from(key: &str) -> Self {
+        Self::new(key)
+    }
+}
+
+impl From<Vec<u8>> for SpoolKey {
+    fn from(key: Vec<u8>) -> Self {
+        Self(key)
+    }
+}
+
+impl From<&[u8]> for SpoolKey {
+    fn from(key: &[u8]) -> Self {
+        Self::new(key)
+    }
+}
+
+/// When the batch at the head of the spool may be retried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Backoff {
+    /// The sequence number of the batch.
+    pub(crate) seq: u64,
+    /// How many times sending the batch failed.
+    pub(crate) failures: u32,
+    /// The batch must not be sent again before this time.
+    #[serde(with = "time::serde::rfc3339")]
+    pub(crate) retry_at: OffsetDateTime,
+    /// When sending the batch failed for the first time.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub(crate) since: Option<OffsetDateTime>,
+}
+
+/// The last batch of a [`Spool`] Segment accepted.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Checkpoint {
+    /// The sequence number of the batch, increasing with every batch
+    /// spooled.
+    pub seq: u64,
+    /// The `messageId` of the last message of the batch, if it had one.
+    pub message_id: Option<String>,
+    /// When Segment accepted the batch.
+    #[serde(with = "time::serde::rfc3339")]
+    pub acked_at: OffsetDateTime,
+}
+
+impl Spool {
+    /// Open the spool in `dir`, creating the directory if needed, and load the
+    /// batches it already contains.
+    ///
+    /// Batches up to the [checkpoint](Spool::checkpoint) are removed without
+    /// being loaded, since Segment already accepted them.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with(dir.into(), Protection::None)
+    }
+
+    /// Open the spool in `dir` like [`open`](Spool::open), signing the
+    /// batches written to it and verifying the ones read from it with `key`.
+    pub fn open_signed(dir: impl Into<PathBuf>, key: impl Into<SpoolKey>) -> Result<Self> {
+        Self::open_with(dir.into(), Protection::Signed(key.into()))
+    }
+
+    /// Open the spool in `dir` like [`open`](Spool::open), encrypting the
+    /// batches written to it and decrypting the ones read from it with `key`.
+    pub fn open_encrypted(dir: impl Into<PathBuf>, key: impl Into<SpoolKey>) -> Result<Self> {
+        Self::open_with(dir.into(), Protection::Encrypted(vec![key.into()]))
+    }
+
+    /// Open the spool in `dir` like [`open_encrypted`](Spool::open_encrypted),
+    /// and re-encrypt the batches it contains which were encrypted with any
+    /// of `previous_keys` with `key`.
+    ///
+    /// This lets the key be rotated without losing the batches spooled
+    /// before: once the spool was opened, the previous keys can be dropped.
+    pub fn open_encrypted_with_previous<I, K>(
+        dir: impl Into<PathBuf>,
+        key: impl Into<SpoolKey>,
+        previous_keys: I,
+    ) -> Result<Self>
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<SpoolKey>,
+    {
+        let keys = std::iter::once(key.into())
+            .chain(previous_keys.into_iter().map(Into::into))
+            .collect();
+        Self::open_with(dir.into(), Protection::Encrypted(keys))
+    }
+
+    fn open_with(dir: PathBuf, protection: Protection) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let mut inner = Inner {
+            dir,
+            batches: BTreeMap::new(),
+            next_seq: 0,
+            protection,
+        };
+        let checkpoint = read_json::<Checkpoint>(&inner.dir.join(CHECKPOINT_FILE))?;
+        if let Some(checkpoint) = &checkpoint {
+            // Batches spooled later must never be taken for acked ones.
+            inner.next_seq = checkpoint.seq + 1;
+        }
+        let mut seqs = Vec::new();
+        for entry in fs::read_dir(&inner.dir)? {
+            if let Some(seq) = batch_seq(&entry?.path()) {
+                seqs.push(seq);
+            }
+        }
+        for seq in seqs {
+            inner.next_seq = inner.next_seq.max(seq + 1);
+            if checkpoint
+                .as_ref()
+                .is_some_and(|checkpoint| seq <= checkpoint.seq)
+            {
+                log::debug!("removing spooled batch {} which was already sent", seq);
+                fs::remove_file(inner.dir.join(batch_file(seq)))?;
+                continue;
+            }
+            if let Some((batch, key)) = inner.load(seq)? {
+                if key > 0 {
+                    log::info!("re-encrypting spooled batch {} with the current key", seq);
+                    write_atomic(
+                        &inner.dir.join(batch_file(seq)),
+                        &inner.encode(seq, &batch)?,
+                    )?;
+                }
+                inner.batches.insert(seq, batch.batch.len());
+            }
+        }
+        Ok(Self {
+            inner: Arc::new(Mutex::new(inner)),
+        })
+    }
+
+    /// The number of spooled batches.
+    pub fn batches(&self) -> usize {
+        self.lock().batches.len()
+    }
+
+    /// The number of messages in the spooled batches.
+    pub fn len(&self) -> usize {
+        self.lock().batches.values().sum()
+    }
+
+    /// Whether no batch is spooled.
+    pub fn is_empty(&self) -> bool {
+        self.lock().batches.is_empty()
+    }
+
+    /// Durably append `batch` to the spool.
+    pub(crate) fn push(&self, batch: &Batch) -> Result<()> {
+        let mut inner = self.lock();
+        let seq = inner.next_seq;
+        let content = inner.encode(seq, batch)?;
+        write_atomic(&inner.dir.join(batch_file(seq)), &content)?;
+        inner.batches.insert(seq, batch.batch.len());
+        inner.next_seq += 1;
+        Ok(())
+    }
+
+    /// The oldest spooled batch, and its sequence number.
+    ///
+    /// Batches which fail verification are rejected and skipped.
+    pub(crate) fn head(&self) -> Result<Option<(u64, Batch)>> {
+        let mut inner = self.lock();
+        while let Some(&seq) = inner.batches.keys().next() {
+            match inner.read(seq)? {
+                Some(batch) => return Ok(Some((seq, batch))),
+                None => {
+                    inner.batches.remove(&seq);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// The last batch Segment accepted, if any.
+    pub fn checkpoint(&self) -> Result<Option<Checkpoint>> {
+        read_json(&self.lock().dir.join(CHECKPOINT_FILE))
+    }
+
+    /// Durably record that Segment accepted the batch with the given sequence
+    /// number, then remove it.
+    pub(crate) fn ack(&self, seq: u64, batch: &Batch, acked_at: OffsetDateTime) -> Result<()> {
+        let checkpoint = Checkpoint {
+            seq,
+            message_id: batch
+                .batch
+                .last()
+                .and_then(|msg| msg.extra().get("messageId"))
+                .and_then(|id| id.as_str())
+                .map(str::to_owned),
+            acked_at,
+        };
+        write_atomic(
+            &self.lock().dir.join(CHECKPOINT_FILE),
+            &serde_json::to_vec(&checkpoint)?,
+        )?;
+        self.remove(seq)
+    }
+
+    /// Remove the batch with the given sequence number, and its backoff.
+    pub(crate) fn remove(&self, seq: u64) -> Result<()> {
+        let mut inner = self.lock();
+        if let Some(backoff) = read_backoff(&inner.dir)? {
+            if backoff.seq == seq {
+                fs::remove_file(inner.dir.join(BACKOFF_FILE))?;
+            }
+        }
+        fs::remove_file(inner.dir.join(batch_file(seq)))?;
+        inner.batches.remove(&seq);
+        Ok(())
+    }
+
+    /// Replace the batch with the given sequence number with `batch`, e.g.
+    /// the part of it which is still to be sent.
+    pub(crate) fn replace(&self, seq: u64, batch: &Batch) -> Result<()> {
+        let mut inner = self.lock();
+        let content = inner.encode(seq, batch)?;
+        write_atomic(&inner.dir.join(batch_file(seq)), &content)?;
+        inner.batches.insert(seq, batch.batch.len());
+        Ok(())
+    }
+
+    /// The backoff of the batch at the head of the spool, if it failed.
+    pub(crate) fn backoff(&self) -> Result<Option<Backoff>> {
+        let inner = self.lock();
+        let head = inner.batches.keys().next().copied();
+        Ok(read_backoff(&inner.dir)?.filter(|backoff| Some(backoff.seq) == head))
+    }
+
+    /// Durably record the backoff of the batch at the head of the spool.
+    pub(crate) fn set_backoff(&self, backoff: &Backoff) -> Result<()> {
+        let inner = self.lock();
+        write_atomic(&inner.dir.join(BACKOFF_FILE), &serde_json::to_vec(backoff)?)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap()
+    }
+}
+
+impl Inner {
+    /// The content of the file of the batch with the given sequence number.
+    fn encode(&self, seq: u64, batch: &Batch) -> Result<Vec<u8>> {
+        let mut content = serde_json::to_vec(batch)?;
+        match &self.protection {
+            Protection::None => (),
+            Protection::Signed(key) => {
+                let signature = sign(key.expose(), &content).finalize().into_bytes();
+                content.push(b'\n');
+                content.extend(
+                    signature
+                        .iter()
+                        .flat_map(|byte| format!("{:02x}", byte).into_bytes()),
+                );
+            }
+            Protection::Encrypted(keys) => content = encrypt(&keys[0], seq, content)?,
+        }
+        Ok(content)
+    }
+
+    /// The JSON of the batch in `content`, and the index of the key it was
+    /// verified or decrypted with, or `None` if it fails verification.
+    fn decode(&self, seq: u64, content: Vec<u8>) -> Option<(Vec<u8>, usize)> {
+        match &self.protection {
+            Protection::None => Some((content, 0)),
+            Protection::Signed(key) => {
+                let split = content.iter().rposition(|&byte| byte == b'\n')?;
+                let signature = decode_hex(&content[split + 1..])?;
+                sign(key.expose(), &content[..split])
+                    .verify_slice(&signature)
+                    .ok()?;
+                Some((content[..split].to_vec(), 0))
+            }
+            Protection::Encrypted(keys) => keys
+                .iter()
+                .enumerate()
+                .find_map(|(index, key)| Some((decrypt(key, seq, &content)?, index))),
+        }
+    }
+
+    /// Read the batch with the given sequence number.
+    fn read(&self, seq: u64) -> Result<Option<Batch>> {
+        Ok(self.load(seq)?.map(|(batch, _)| batch))
+    }
+
+    /// Read the batch with the given sequence number, verifying or
+    /// decrypting it with any of the keys, and return it with the index of
+    /// the key. Batches which fail verification or are corrupt are renamed
+    /// so they are not read again, and `None` is returned.
+    fn load(&self, seq: u64) -> Result<Option<(Batch, usize)>> {
+        let path = self.dir.join(batch_file(seq));
+        let reason = match self.decode(seq, fs::read(&path)?) {
+            Some((json, key)) => match serde_json::from_slice(&json) {
+                Ok(batch) => return Ok(Some((batch, key))),
+                Err(e) => format!("is corrupt: {}", e),
+            },
+            None => "fails verification".to_owned(),
+        };
+        log::error!(
+            "rejecting spooled batch {} which {}",
+            path.display(),
+            reason
+        );
+        fs::rename(&path, path.with_extension("rejected"))?;
+        Ok(None)
+    }
+}
+
+/// The ChaCha20-Poly1305 key derived from `key`.
+fn cipher(key: &SpoolKey) -> LessSafeKey {
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, b"segment spool").extract(key.expose());
+    let okm = prk
+        .expand(&[b"batch encryption"], &aead::CHACHA20_POLY1305)
+        .expect("HKDF-SHA256 can derive a ChaCha20-Poly1305 key");
+    LessSafeKey::new(UnboundKey::from(okm))
+}
+
+/// Encrypt `content`, prefixed with its random nonce. The sequence number of
+/// the batch is authenticated too, so batch files can't be swapped.
+fn encrypt(key: &SpoolKey, seq: u64, mut content: Vec<u8>) -> Result<Vec<u8>> {
+    let mut nonce = [0; aead::NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| io::Error::other("could not generate a nonce"))?;
+    cipher(key)
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(seq.to_be_bytes()),
+            &mut content,
+        )
+        .map_err(|_| io::Error::other("could not encrypt the batch"))?;
+    Ok([&nonce[..], &content].concat())
+}
+
+/// Decrypt what [`encrypt`] produced, or `None` if it was encrypted with
+/// another key or tampered with.
+fn decrypt(key: &SpoolKey, seq: u64, content: &[u8]) -> Option<Vec<u8>> {
+    if content.len() < aead::NONCE_LEN {
+        return None;
+    }
+    let (nonce, sealed) = content.split_at(aead::NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+    let mut content = sealed.to_vec();
+    let len = cipher(key)
+        .open_in_place(nonce, Aad::from(seq.to_be_bytes()), &mut content)
+        .ok()?
+        .len();
+    content.truncate(len);
+    Some(content)
+}
+
+fn sign(key: &[u8], content: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(content);
+    mac
+}
+
+fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+fn batch_file(seq: u64) -> String {
+    format!("{:020}.batch.json", seq)
+}
+
+fn batch_seq(path: &Path) -> Option<u64> {
+    path.file_name()?
+        .to_str()?
+        .strip_suffix(".batch.json")?
+        .parse()
+        .ok()
+}
+
+fn read_backoff(dir: &Path) -> Result<Option<Backoff>> {
+    read_json(&dir.join(BACKOFF_FILE))
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    match fs::read(path) {
+        Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Write `content` to `path` so that a crash leaves either the previous
+/// content or the new one.
+fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{BatchMessage, Track};
+
+    #[test]
+    fn test_signed() {
+        let dir = std::env::temp_dir().join(format!("segment-spool-{}", uuid::Uuid::new_v4()));
+        let batch = |event: &str| Batch {
+            batch: vec![BatchMessage::Track(Track {
+                event: event.to_owned(),
+                ..Default::default()
+            })],
+            ..Default::default()
+        };
+
+        let spool = Spool::open_signed(&dir, "secret").unwrap();
+        spool.push(&batch("Genuine")).unwrap();
+        spool.push(&batch("Genuine")).unwrap();
+        spool.push(&batch("Genuine")).unwrap();
+
+        let path = dir.join(batch_file(1));
+        let content = fs::read_to_string(&path).unwrap();
+        fs::write(&path, content.replace("Genuine", "Tampered")).unwrap();
+        let forged = serde_json::to_string(&batch("Forged")).unwrap();
+        fs::write(dir.join(batch_file(3)), forged + "\n" + &"00".repeat(32)).unwrap();
+
+        let spool = Spool::open_signed(&dir, "secret").unwrap();
+        assert_eq!(spool.batches(), 2);
+        assert!(dir.join("00000000000000000001.batch.rejected").exists());
+        assert!(dir.join("00000000000000000003.batch.rejected").exists());
+        while let Some((seq, head)) = spool.head().unwrap() {
+            assert_eq!(head, batch("Genuine"));
+            spool.remove(seq).unwrap();
+        }
+
+        // Batches are numbered after the rejected ones.
+        spool.push(&batch("Genuine")).unwrap();
+        assert!(dir.join(batch_file(4)).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_encrypted() {
+        let dir = std::env::temp_dir().join(format!("segment-spool-{}", uuid::Uuid::new_v4()));
+        let batch = |event: &str| Batch {
+            batch: vec![BatchMessage::Track(Track {
+                event: event.to_owned(),
+                ..Default::default()
+            })],
+            ..Default::default()
+        };
+
+        let spool = Spool::open_encrypted(&dir, "old").unwrap();
+        spool.push(&batch("Secret")).unwrap();
+        spool.push(&batch("Secret")).unwrap();
+        spool.push(&batch("Secret")).unwrap();
+        let content = fs::read(dir.join(batch_file(0))).unwrap();
+        assert!(!String::from_utf8_lossy(&content).contains("Secret"));
+        assert_eq!(spool.head().unwrap().unwrap().1, batch("Secret"));
+
+        // Tampered with, or swapped with another batch.
+        let mut tampered = content.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        fs::write(dir.join(batch_file(1)), tampered).unwrap();
+        fs::write(dir.join(batch_file(2)), &content).unwrap();
+
+        // The remaining batch is re-encrypted with the new key.
+        let spool = Spool::open_encrypted_with_previous(&dir, "new", ["old"]).unwrap();
+        assert_eq!(spool.batches(), 1);
+        assert!(dir.join("00000000000000000001.batch.rejected").exists());
+        assert!(dir.join("00000000000000000002.batch.rejected").exists());
+        assert!(format!("{:?}", spool)
+            .contains(r#"Encrypted([SpoolKey("********"), SpoolKey("********")])"#));
+        assert_ne!(fs::read(dir.join(batch_file(0))).unwrap(), content);
+
+        let spool = Spool::open_encrypted(&dir, "new").unwrap();
+        assert_eq!(spool.head().unwrap().unwrap(), (0, batch("Secret")));
+
+        // Without the key, the batch is rejected.
+        let spool = Spool::open_encrypted(&dir, "old").unwrap();
+        assert_eq!(spool.batches(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let dir = std::env::temp_dir().join(format!("segment-spool-{}", uuid::Uuid::new_v4()));
+        let spool = Spool::open(&dir).unwrap();
+        for _ in 0..3 {
+            spool.push(&Batch::default()).unwrap();
+        }
+        fs::write(dir.join(batch_file(0)), "{\"batch\": [").unwrap();
+
+        // Corrupt batches are skipped when the spool is opened,
+        let spool = Spool::open(&dir).unwrap();
+        assert_eq!(spool.batches(), 2);
+        assert!(dir.join("00000000000000000000.batch.rejected").exists());
+
+        // or when they are read.
+        fs::write(dir.join(batch_file(1)), "").unwrap();
+        assert_eq!(spool.head().unwrap().unwrap().0, 2);
+        assert!(dir.join("00000000000000000001.batch.rejected").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let dir = std::env::temp_dir().join(format!("segment-spool-{}", uuid::Uuid::new_v4()));
+        let batch = |id: &str| Batch {
+            batch: vec![BatchMessage::Track(Track {
+                extra: std::iter::once(("messageId".to_owned(), id.into())).collect(),
+                ..Default::default()
+            })],
+            ..Default::default()
+        };
+        let spool = Spool::open(&dir).unwrap();
+        for id in ["a", "b", "c"] {
+            spool.push(&batch(id)).unwrap();
+        }
+        spool
+            .ack(0, &batch("a"), OffsetDateTime::UNIX_EPOCH)
+            .unwrap();
+        // A crash after the checkpoint of the second batch, but before it was
+        // removed.
+        let checkpoint = Checkpoint {
+            seq: 1,
+            message_id: Some("b".to_owned()),
+            acked_at: OffsetDateTime::UNIX_EPOCH,
+        };
+        write_atomic(
+            &dir.join(CHECKPOINT_FILE),
+            &serde_json::to_vec(&checkpoint).unwrap(),
+        )
+        .unwrap();
+
+        let spool = Spool::open(&dir).unwrap();
+        assert_eq!(spool.checkpoint().unwrap(), Some(checkpoint));
+        assert_eq!(spool.head().unwrap(), Some((2, batch("c"))));
+        spool
+            .ack(2, &batch("c"), OffsetDateTime::UNIX_EPOCH)
+            .unwrap();
+
+        // Sequence numbers keep increasing once the spool is empty.
+        let spool = Spool::open(&dir).unwrap();
+        spool.push(&batch("d")).unwrap();
+        assert_eq!(spool.head().unwrap(), Some((3, batch("d"))));
+        assert_eq!(
+            spool.checkpoint().unwrap().unwrap().message_id.as_deref(),
+            Some("c")
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
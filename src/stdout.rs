@@ -0,0 +1,92 @@
+//! A client printing messages instead of sending them.
+
+use std::io::{self, Write};
+
+use crate::message::{Message, StoredMessage};
+use crate::{Client, Result};
+
+/// A [`Client`] writing every message to the standard output or error as one
+/// JSON line, instead of sending it to Segment.
+///
+/// This lets a log shipper deliver the messages of a lambda or a container,
+/// and makes the messages of an application visible during local
+/// development. Batches are split into their messages, and every line is a
+/// [`StoredMessage`], so the output can be [replayed](crate::Analytics::replay).
+///
+/// ```no_run
+/// # fn main() -> segment::Result<()> {
+/// use segment::{ClientBuilder, StdoutClient, WriteKey};
+///
+/// let write_key = WriteKey::new("local")?;
+/// let analytics = ClientBuilder::new(write_key).build_with_client(StdoutClient::stdout())?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StdoutClient {
+    stderr: bool,
+}
+
+impl StdoutClient {
+    /// Write messages to the standard output.
+    pub fn stdout() -> Self {
+        Self { stderr: false }
+    }
+
+    /// Write messages to the standard error.
+    pub fn stderr() -> Self {
+        Self { stderr: true }
+    }
+}
+
+#[async_trait::async_trait]
+impl Client for StdoutClient {
+    async fn send(&self, _write_key: String, msg: Message) -> Result<()> {
+        let lines = json_lines(msg)?;
+        if self.stderr {
+            io::stderr().lock().write_all(&lines)?;
+        } else {
+            io::stdout().lock().write_all(&lines)?;
+        }
+        Ok(())
+    }
+}
+
+/// The messages of `msg`, one JSON [`StoredMessage`] per line.
+fn json_lines(msg: Message) -> Result<Vec<u8>> {
+    let mut lines = Vec::new();
+    for msg in StoredMessage::from(msg).into_batch_messages() {
+        serde_json::to_writer(&mut lines, &StoredMessage::from(msg))?;
+        lines.push(b'\n');
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Batch, BatchMessage, Identify, Track};
+
+    #[test]
+    fn test_json_lines() {
+        let batch = Batch {
+            batch: vec![
+                BatchMessage::Track(Track::default()),
+                BatchMessage::Identify(Identify::default()),
+            ],
+            ..Default::default()
+        };
+        let lines = String::from_utf8(json_lines(batch.into()).unwrap()).unwrap();
+        let lines: Vec<StoredMessage> = lines
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(matches!(
+            lines.as_slice(),
+            [StoredMessage::Track(_), StoredMessage::Identify(_)]
+        ));
+
+        let line = json_lines(Track::default().into()).unwrap();
+        assert!(line.starts_with(br#"{"type":"track""#));
+    }
+}
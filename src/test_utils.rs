@@ -0,0 +1,264 @@
+//! Utilities for testing code which sends messages to Segment.
+//!
+//! This module is only available with the `test-utils` feature enabled.
+
+mod assertions;
+mod canonical;
+mod deterministic;
+mod fake_server;
+mod faulty_client;
+mod harness;
+
+pub use assertions::json_contains;
+pub use canonical::Canonicalizer;
+pub use deterministic::{MockClock, SequentialIdGenerator};
+pub use fake_server::{FakeServer, RecordedRequest};
+pub use faulty_client::{Fault, FaultyClient};
+pub use harness::{SegmentTestHarness, TEST_WRITE_KEY};
+
+use std::sync::{Arc, Mutex, OnceLock};
+
+use serde_json::Value;
+
+use crate::message::{Alias, BatchMessage, Group, Identify, Page, Screen, Track, User};
+use crate::{Client, Message, Result};
+
+#[doc(hidden)]
+pub mod __private {
+    pub use serde_json::{json, Value};
+}
+
+/// A [`Client`] which records every message it is asked to send instead of
+/// sending it over the network.
+///
+/// Clones of a `MockClient` share the same recording, so you can hand one
+/// clone to the code under test and keep another to make assertions with:
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use segment::{AutoBatcher, Batcher, WriteKey};
+/// use segment::message::{Track, User};
+/// use segment::test_utils::MockClient;
+///
+/// let client = MockClient::new();
+/// let key = WriteKey::new("key").unwrap();
+/// let mut batcher = AutoBatcher::new(client.clone(), Batcher::new(None), key);
+///
+/// batcher.push(Track {
+///     user: User::UserId { user_id: "user".to_owned() },
+///     event: "Signed Up".to_owned(),
+///     ..Default::default()
+/// }).await.unwrap();
+/// batcher.flush().await.unwrap();
+///
+/// assert_eq!(client.events("Signed Up").len(), 1);
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MockClient {
+    sent: Arc<Mutex<Vec<Message>>>,
+}
+
+impl MockClient {
+    /// Construct a new `MockClient` with an empty recording.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// All the messages sent through this client, in the order they were sent.
+    pub fn messages(&self) -> Vec<Message> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    /// All the messages sent through this client, with batches flattened into
+    /// the messages they contain.
+    pub fn batch_messages(&self) -> Vec<BatchMessage> {
+        self.messages()
+            .into_iter()
+            .flat_map(|message| match message {
+                Message::Identify(identify) => vec![identify.into()],
+                Message::Track(track) => vec![track.into()],
+                Message::Page(page) => vec![page.into()],
+                Message::Screen(screen) => vec![screen.into()],
+                Message::Group(group) => vec![group.into()],
+                Message::Alias(alias) => vec![alias.into()],
+                Message::Batch(batch) => batch.batch,
+            })
+            .collect()
+    }
+
+    /// All the `identify` messages sent.
+    pub fn identifies(&self) -> Vec<Identify> {
+        self.filter_map(|message| match message {
+            BatchMessage::Identify(identify) => Some(identify),
+            _ => None,
+        })
+    }
+
+    /// All the `track` messages sent.
+    pub fn tracks(&self) -> Vec<Track> {
+        self.filter_map(|message| match message {
+            BatchMessage::Track(track) => Some(track),
+            _ => None,
+        })
+    }
+
+    /// All the `page` messages sent.
+    pub fn pages(&self) -> Vec<Page> {
+        self.filter_map(|message| match message {
+            BatchMessage::Page(page) => Some(page),
+            _ => None,
+        })
+    }
+
+    /// All the `screen` messages sent.
+    pub fn screens(&self) -> Vec<Screen> {
+        self.filter_map(|message| match message {
+            BatchMessage::Screen(screen) => Some(screen),
+            _ => None,
+        })
+    }
+
+    /// All the `group` messages sent.
+    pub fn groups(&self) -> Vec<Group> {
+        self.filter_map(|message| match message {
+            BatchMessage::Group(group) => Some(group),
+            _ => None,
+        })
+    }
+
+    /// All the `alias` messages sent.
+    pub fn aliases(&self) -> Vec<Alias> {
+        self.filter_map(|message| match message {
+            BatchMessage::Alias(alias) => Some(alias),
+            _ => None,
+        })
+    }
+
+    /// All the `track` messages sent with the given event name.
+    pub fn events(&self, name: &str) -> Vec<Track> {
+        self.tracks()
+            .into_iter()
+            .filter(|track| track.event == name)
+            .collect()
+    }
+
+    /// All the messages sent about the user with the given user ID or
+    /// anonymous ID.
+    pub fn for_user(&self, id: &str) -> Vec<BatchMessage> {
+        self.filter_map(|message| {
+            let user = match &message {
+                BatchMessage::Identify(identify) => &identify.user,
+                BatchMessage::Track(track) => &track.user,
+                BatchMessage::Page(page) => &page.user,
+                BatchMessage::Screen(screen) => &screen.user,
+                BatchMessage::Group(group) => &group.user,
+                BatchMessage::Alias(alias) => &alias.user,
+            };
+            let matches = is_user(user, id);
+            Some(message).filter(|_| matches)
+        })
+    }
+
+    /// The `track` messages sent with the given event name, optionally for the
+    /// given user ID or anonymous ID and with properties containing the given
+    /// JSON (see [`json_contains`]).
+    ///
+    /// This is what [`assert_tracked!`](crate::assert_tracked) is built on.
+    pub fn tracked(
+        &self,
+        event: &str,
+        user: Option<&str>,
+        properties: Option<&Value>,
+    ) -> Vec<Track> {
+        self.events(event)
+            .into_iter()
+            .filter(|track| user.is_none_or(|user| is_user(&track.user, user)))
+            .filter(|track| {
+                properties.is_none_or(|props| {
+                    json_contains(&Value::from(track.properties.clone()), props)
+                })
+            })
+            .collect()
+    }
+
+    /// The `identify` messages sent for the given user ID or anonymous ID,
+    /// optionally with traits containing the given JSON (see
+    /// [`json_contains`]).
+    ///
+    /// This is what [`assert_identified!`](crate::assert_identified) is built
+    /// on.
+    pub fn identified(&self, user: &str, traits: Option<&Value>) -> Vec<Identify> {
+        self.identifies()
+            .into_iter()
+            .filter(|identify| is_user(&identify.user, user))
+            .filter(|identify| {
+                traits.is_none_or(|traits| {
+                    json_contains(&Value::from(identify.traits.clone()), traits)
+                })
+            })
+            .collect()
+    }
+
+    /// The messages sent through this client in a canonical form, as a
+    /// pretty-printed JSON array, for snapshot tests. See [`Canonicalizer`].
+    pub fn snapshot(&self) -> String {
+        Canonicalizer::new().snapshot(
+            self.messages()
+                .iter()
+                .map(|msg| serde_json::to_value(msg).unwrap()),
+        )
+    }
+
+    /// Forget every message recorded so far.
+    pub fn clear(&self) {
+        self.sent.lock().unwrap().clear();
+    }
+
+    fn filter_map<T>(&self, f: impl FnMut(BatchMessage) -> Option<T>) -> Vec<T> {
+        self.batch_messages().into_iter().filter_map(f).collect()
+    }
+}
+
+/// The recording of the messages sent by every pipeline built while
+/// [disabled](crate::ClientBuilder::disabled).
+///
+/// It is shared by the whole process, so tests running concurrently should
+/// look for their own messages, e.g. with [`MockClient::for_user`].
+pub fn recorder() -> MockClient {
+    static RECORDER: OnceLock<MockClient> = OnceLock::new();
+    RECORDER.get_or_init(MockClient::new).clone()
+}
+
+/// A `track` event named `event` of the user `user_id`, for the tests of this
+/// crate.
+#[cfg(test)]
+pub(crate) fn track(user_id: &str, event: &str) -> Track {
+    Track {
+        user: User::UserId {
+            user_id: user_id.to_owned(),
+        },
+        event: event.to_owned(),
+        ..Default::default()
+    }
+}
+
+fn is_user(user: &User, id: &str) -> bool {
+    match user {
+        User::UserId { user_id } => user_id == id,
+        User::AnonymousId { anonymous_id } => anonymous_id == id,
+        User::Both {
+            user_id,
+            anonymous_id,
+        } => user_id == id || anonymous_id == id,
+    }
+}
+
+#[async_trait::async_trait]
+impl Client for MockClient {
+    async fn send(&self, _write_key: String, msg: Message) -> Result<()> {
+        self.sent.lock().unwrap().push(msg);
+        Ok(())
+    }
+}
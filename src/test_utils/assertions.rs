@@ -0,0 +1,123 @@
+//! Assertion macros for analytics tests.
+
+use serde_json::Value;
+
+/// Whether `value` contains `expected`.
+///
+/// Objects contain another object when they contain each of its members;
+/// arrays contain another array when each of its elements is contained in one
+/// of theirs. Any other values must be equal.
+pub fn json_contains(value: &Value, expected: &Value) -> bool {
+    match (value, expected) {
+        (Value::Object(value), Value::Object(expected)) => {
+            expected.iter().all(|(key, expected)| {
+                value
+                    .get(key)
+                    .is_some_and(|value| json_contains(value, expected))
+            })
+        }
+        (Value::Array(value), Value::Array(expected)) => expected
+            .iter()
+            .all(|expected| value.iter().any(|value| json_contains(value, expected))),
+        (value, expected) => value == expected,
+    }
+}
+
+/// Assert that a [`MockClient`](crate::test_utils::MockClient) recorded a
+/// `track` event with the given name, optionally sent for a given user ID or
+/// anonymous ID, and with properties containing the given JSON (see
+/// [`json_contains`]).
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use segment::{assert_not_tracked, assert_tracked, Client};
+/// use segment::message::{Properties, Track, User};
+/// use segment::test_utils::MockClient;
+///
+/// let mock = MockClient::new();
+/// mock.send("key".to_owned(), Track {
+///     user: User::UserId { user_id: "u1".to_owned() },
+///     event: "Order Completed".to_owned(),
+///     properties: Properties::new().with("revenue", 10).with("currency", "USD"),
+///     ..Default::default()
+/// }.into()).await.unwrap();
+///
+/// assert_tracked!(mock, "Order Completed");
+/// assert_tracked!(mock, "Order Completed", user = "u1", props contains { "revenue": 10 });
+/// assert_not_tracked!(mock, "Order Completed", user = "u2");
+/// # }
+/// ```
+#[macro_export]
+macro_rules! assert_tracked {
+    ($mock:expr, $event:expr $(, user = $user:expr)? $(, props contains $props:tt)? $(,)?) => {{
+        let user = ::std::option::Option::<&str>::None
+            $(.or(::std::option::Option::Some($user)))?;
+        let props = ::std::option::Option::<$crate::test_utils::__private::Value>::None
+            $(.or(::std::option::Option::Some($crate::test_utils::__private::json!($props))))?;
+        let mock = &$mock;
+        if mock.tracked($event, user, props.as_ref()).is_empty() {
+            panic!(
+                "expected a `{}` event{}{}, got {:?}",
+                $event,
+                user.map(|user| format!(" for user `{}`", user)).unwrap_or_default(),
+                props.map(|props| format!(" with properties containing {}", props)).unwrap_or_default(),
+                mock.tracks(),
+            );
+        }
+    }};
+}
+
+/// The opposite of [`assert_tracked!`]: assert that no matching `track` event
+/// was recorded.
+#[macro_export]
+macro_rules! assert_not_tracked {
+    ($mock:expr, $event:expr $(, user = $user:expr)? $(, props contains $props:tt)? $(,)?) => {{
+        let user = ::std::option::Option::<&str>::None
+            $(.or(::std::option::Option::Some($user)))?;
+        let props = ::std::option::Option::<$crate::test_utils::__private::Value>::None
+            $(.or(::std::option::Option::Some($crate::test_utils::__private::json!($props))))?;
+        let matching = $mock.tracked($event, user, props.as_ref());
+        if !matching.is_empty() {
+            panic!("expected no `{}` event, got {:?}", $event, matching);
+        }
+    }};
+}
+
+/// Assert that a [`MockClient`](crate::test_utils::MockClient) recorded an
+/// `identify` message for the given user ID or anonymous ID, optionally with
+/// traits containing the given JSON (see [`json_contains`]).
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use segment::{assert_identified, Client};
+/// use segment::message::{Identify, Traits, User};
+/// use segment::test_utils::MockClient;
+///
+/// let mock = MockClient::new();
+/// mock.send("key".to_owned(), Identify {
+///     user: User::UserId { user_id: "u1".to_owned() },
+///     traits: Traits::new().with("plan", "pro"),
+///     ..Default::default()
+/// }.into()).await.unwrap();
+///
+/// assert_identified!(mock, "u1", traits contains { "plan": "pro" });
+/// # }
+/// ```
+#[macro_export]
+macro_rules! assert_identified {
+    ($mock:expr, $user:expr $(, traits contains $traits:tt)? $(,)?) => {{
+        let traits = ::std::option::Option::<$crate::test_utils::__private::Value>::None
+            $(.or(::std::option::Option::Some($crate::test_utils::__private::json!($traits))))?;
+        let mock = &$mock;
+        if mock.identified($user, traits.as_ref()).is_empty() {
+            panic!(
+                "expected an identify for user `{}`{}, got {:?}",
+                $user,
+                traits.map(|traits| format!(" with traits containing {}", traits)).unwrap_or_default(),
+                mock.identifies(),
+            );
+        }
+    }};
+}
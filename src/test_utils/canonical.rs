@@ -0,0 +1,155 @@
+//! A canonical form of payloads, for snapshot tests.
+
+use std::collections::HashMap;
+
+use serde_json::{Map, Value};
+use time::format_description::well_known::Rfc3339;
+use time::{OffsetDateTime, UtcOffset};
+
+/// The fields holding timestamps, at any depth.
+const TIMESTAMP_FIELDS: [&str; 4] = ["timestamp", "originalTimestamp", "receivedAt", "sentAt"];
+
+/// Rewrites payloads into a canonical form, so that snapshots of them only
+/// change when their content does:
+///
+/// - the keys of every object are sorted;
+/// - timestamps are written in UTC with millisecond precision, e.g.
+///   `2024-01-01T00:00:00.000Z`;
+/// - every message is given a `messageId` of the form `message-1`,
+///   `message-2`, and so on, in the order they are first seen. Messages which
+///   had the same ID before still share one.
+///
+/// The IDs are numbered across all the payloads given to the same
+/// `Canonicalizer`. [`MockClient::snapshot`](crate::test_utils::MockClient::snapshot)
+/// and [`FakeServer::snapshot`](crate::test_utils::FakeServer::snapshot) use
+/// one for all the messages they recorded.
+#[derive(Clone, Debug, Default)]
+pub struct Canonicalizer {
+    ids: HashMap<String, String>,
+    count: usize,
+}
+
+impl Canonicalizer {
+    /// Construct a canonicalizer which did not see any message yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rewrite a message, or a batch of messages, into its canonical form.
+    pub fn canonicalize(&mut self, payload: &mut Value) {
+        match payload.get_mut("batch") {
+            Some(Value::Array(batch)) => batch.iter_mut().for_each(|msg| self.set_id(msg)),
+            _ => self.set_id(payload),
+        }
+        canonicalize_value(payload);
+    }
+
+    /// The canonical form of `payloads`, as a pretty-printed JSON array.
+    pub fn snapshot(&mut self, payloads: impl IntoIterator<Item = Value>) -> String {
+        let payloads: Vec<Value> = payloads
+            .into_iter()
+            .map(|mut payload| {
+                self.canonicalize(&mut payload);
+                payload
+            })
+            .collect();
+        serde_json::to_string_pretty(&payloads).unwrap()
+    }
+
+    fn set_id(&mut self, msg: &mut Value) {
+        let msg = match msg {
+            Value::Object(msg) => msg,
+            _ => return,
+        };
+        let id = match msg.get("messageId").and_then(Value::as_str) {
+            Some(id) => match self.ids.get(id) {
+                Some(canonical) => canonical.clone(),
+                None => {
+                    let canonical = self.next_id();
+                    self.ids.insert(id.to_owned(), canonical.clone());
+                    canonical
+                }
+            },
+            None => self.next_id(),
+        };
+        msg.insert("messageId".to_owned(), Value::String(id));
+    }
+
+    fn next_id(&mut self) -> String {
+        self.count += 1;
+        format!("message-{}", self.count)
+    }
+}
+
+fn canonicalize_value(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            let mut fields: Vec<(String, Value)> = std::mem::take(object).into_iter().collect();
+            fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (key, value) in &mut fields {
+                canonicalize_value(value);
+                if TIMESTAMP_FIELDS.contains(&key.as_str()) {
+                    canonicalize_timestamp(value);
+                }
+            }
+            *object = fields.into_iter().collect::<Map<_, _>>();
+        }
+        Value::Array(array) => array.iter_mut().for_each(canonicalize_value),
+        _ => (),
+    }
+}
+
+fn canonicalize_timestamp(value: &mut Value) {
+    let timestamp = match value.as_str().map(|s| OffsetDateTime::parse(s, &Rfc3339)) {
+        Some(Ok(timestamp)) => timestamp.to_offset(UtcOffset::UTC),
+        _ => return,
+    };
+    *value = Value::String(format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        timestamp.year(),
+        u8::from(timestamp.month()),
+        timestamp.day(),
+        timestamp.hour(),
+        timestamp.minute(),
+        timestamp.second(),
+        timestamp.millisecond()
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_canonicalize() {
+        let mut canonicalizer = Canonicalizer::new();
+        let mut batch = json!({
+            "batch": [
+                { "type": "track", "messageId": "a", "timestamp": "2024-01-01T01:00:00.123456+01:00" },
+                { "type": "track", "messageId": "b" },
+                { "type": "track", "messageId": "a" },
+                { "type": "track", "properties": { "z": 1, "a": 2 } },
+            ],
+            "sentAt": "2024-01-01T00:00:01Z",
+        });
+        canonicalizer.canonicalize(&mut batch);
+        assert_eq!(
+            batch,
+            json!({
+                "batch": [
+                    { "messageId": "message-1", "timestamp": "2024-01-01T00:00:00.123Z", "type": "track" },
+                    { "messageId": "message-2", "type": "track" },
+                    { "messageId": "message-1", "type": "track" },
+                    { "messageId": "message-3", "properties": { "a": 2, "z": 1 }, "type": "track" },
+                ],
+                "sentAt": "2024-01-01T00:00:01.000Z",
+            })
+        );
+        let properties = batch["batch"][3]["properties"].as_object().unwrap();
+        assert_eq!(properties.keys().collect::<Vec<_>>(), ["a", "z"]);
+
+        let snapshot = canonicalizer.snapshot(vec![json!({ "type": "identify" })]);
+        assert!(snapshot.contains(r#""messageId": "message-4""#));
+    }
+}
@@ -0,0 +1,85 @@
+//! Deterministic implementations of the clock and ID generator.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use time::OffsetDateTime;
+
+use crate::{Clock, IdGenerator};
+
+/// A [`Clock`] which only moves when told to.
+///
+/// Clones of a `MockClock` share the same time.
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    now: Arc<Mutex<OffsetDateTime>>,
+}
+
+impl Default for MockClock {
+    /// A clock stopped at the Unix epoch.
+    fn default() -> Self {
+        Self::new(OffsetDateTime::UNIX_EPOCH)
+    }
+}
+
+impl MockClock {
+    /// Construct a clock stopped at the given time.
+    pub fn new(now: OffsetDateTime) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    /// Set the current time.
+    pub fn set(&self, now: OffsetDateTime) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    /// Move the clock forward.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> OffsetDateTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// An [`IdGenerator`] producing `{prefix}-1`, `{prefix}-2`, and so on.
+///
+/// Clones of a `SequentialIdGenerator` share the same counter.
+#[derive(Clone, Debug)]
+pub struct SequentialIdGenerator {
+    prefix: String,
+    next: Arc<AtomicUsize>,
+}
+
+impl Default for SequentialIdGenerator {
+    /// A generator using the `message` prefix.
+    fn default() -> Self {
+        Self::new("message")
+    }
+}
+
+impl SequentialIdGenerator {
+    /// Construct a generator using the given prefix.
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            next: Arc::new(AtomicUsize::new(1)),
+        }
+    }
+}
+
+impl IdGenerator for SequentialIdGenerator {
+    fn generate(&self) -> String {
+        format!(
+            "{}-{}",
+            self.prefix,
+            self.next.fetch_add(1, Ordering::SeqCst)
+        )
+    }
+}
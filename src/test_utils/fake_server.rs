@@ -0,0 +1,318 @@
+//! A fake implementation of Segment's tracking API.
+
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use time::format_description::well_known::Rfc2822;
+use time::OffsetDateTime;
+
+use crate::message::{Alias, Batch, Group, Identify, Page, Screen, Track};
+use crate::test_utils::Canonicalizer;
+
+/// A request received by a [`FakeServer`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedRequest {
+    /// The path the request was sent to, e.g. `/v1/batch`.
+    pub path: String,
+    /// The write key sent as the basic auth username, if any.
+    pub write_key: Option<String>,
+    /// The status code the server answered with.
+    pub status: u16,
+    /// The JSON body of the request, or `Value::Null` if it wasn't valid JSON.
+    pub body: Value,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    requests: Vec<RecordedRequest>,
+    /// The status codes to answer with, and their `Retry-After` header.
+    faults: VecDeque<(u16, Option<String>)>,
+    /// How far ahead of the local clock the `Date` header of the responses is.
+    clock_offset: time::Duration,
+}
+
+/// A local HTTP server implementing the `/v1/*` endpoints of Segment's
+/// tracking API, for hermetic integration tests.
+///
+/// Every request is validated (the path must be a known endpoint, a write key
+/// must be given, and the body must deserialize into the matching message
+/// type) and recorded. Invalid requests are answered with a `400`.
+///
+/// Failures can be injected with [`fail_next`](FakeServer::fail_next) to
+/// exercise error handling:
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use segment::{Client, HttpClient};
+/// use segment::message::{Track, User};
+/// use segment::test_utils::FakeServer;
+///
+/// let server = FakeServer::start();
+/// let client = HttpClient::new(reqwest::Client::new(), server.url());
+/// let msg = Track {
+///     user: User::UserId { user_id: "user".to_owned() },
+///     event: "Example".to_owned(),
+///     ..Default::default()
+/// };
+///
+/// server.fail_next(500, 1);
+/// assert!(client.send("key".to_owned(), msg.clone().into()).await.is_err());
+/// assert!(client.send("key".to_owned(), msg.into()).await.is_ok());
+/// assert_eq!(server.requests().len(), 2);
+/// # }
+/// ```
+///
+/// The server stops when it is dropped.
+#[derive(Debug)]
+pub struct FakeServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl FakeServer {
+    /// Start a server listening on a random local port.
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("could not bind fake server");
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State::default()));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let (accept_state, accept_shutdown) = (state.clone(), shutdown.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if accept_shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let state = accept_state.clone();
+                    thread::spawn(move || handle_connection(stream, state));
+                }
+            }
+        });
+
+        Self {
+            addr,
+            state,
+            shutdown,
+        }
+    }
+
+    /// The base URL of this server, suitable for [`HttpClient::new`](crate::HttpClient::new).
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Answer the next `count` requests with the given status code instead of
+    /// processing them.
+    pub fn fail_next(&self, status: u16, count: usize) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .faults
+            .extend(std::iter::repeat_n((status, None), count));
+    }
+
+    /// Answer the next `count` requests with `429 Too Many Requests` and the
+    /// given `Retry-After` header, a number of seconds or an HTTP date,
+    /// instead of processing them.
+    pub fn rate_limit_next(&self, retry_after: &str, count: usize) {
+        let mut state = self.state.lock().unwrap();
+        let fault = (429, Some(retry_after.to_owned()));
+        state.faults.extend(std::iter::repeat_n(fault, count));
+    }
+
+    /// Answer with a `Date` header `offset` ahead of the local clock, to
+    /// simulate a local clock which drifted.
+    pub fn set_clock_offset(&self, offset: time::Duration) {
+        self.state.lock().unwrap().clock_offset = offset;
+    }
+
+    /// All the requests received so far, including failed ones.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// The bodies of the requests which were accepted.
+    pub fn accepted(&self) -> Vec<Value> {
+        self.requests()
+            .into_iter()
+            .filter(|request| request.status == 200)
+            .map(|request| request.body)
+            .collect()
+    }
+
+    /// The bodies of the requests which were accepted in a canonical form, as
+    /// a pretty-printed JSON array, for snapshot tests. See [`Canonicalizer`].
+    pub fn snapshot(&self) -> String {
+        Canonicalizer::new().snapshot(self.accepted())
+    }
+}
+
+impl Drop for FakeServer {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // Wake up the accept loop so it notices the shutdown.
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+fn handle_connection(stream: TcpStream, state: Arc<Mutex<State>>) {
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    let mut reader = BufReader::new(stream);
+
+    while let Some((path, headers, body)) = read_request(&mut reader) {
+        let (status, response, retry_after) = process(&path, &headers, &body, &state);
+        let response = response.to_string();
+        let offset = state.lock().unwrap().clock_offset;
+        let date = (OffsetDateTime::now_utc() + offset)
+            .format(&Rfc2822)
+            .unwrap()
+            .replace("+0000", "GMT");
+        let retry_after = retry_after
+            .map(|retry_after| format!("Retry-After: {}\r\n", retry_after))
+            .unwrap_or_default();
+        let written = write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nDate: {}\r\n{}Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            reason(status),
+            date,
+            retry_after,
+            response.len(),
+            response
+        );
+        if written.is_err() {
+            return;
+        }
+    }
+}
+
+type Headers = Vec<(String, String)>;
+
+fn read_request(reader: &mut impl BufRead) -> Option<(String, Headers, Vec<u8>)> {
+    let mut line = String::new();
+    reader.read_line(&mut line).ok().filter(|&n| n > 0)?;
+    let path = line.split_whitespace().nth(1)?.to_owned();
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line).ok().filter(|&n| n > 0)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header.split_at(header.find(':')?);
+        headers.push((name.to_ascii_lowercase(), value[1..].trim().to_owned()));
+    }
+
+    let length = header(&headers, "content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+
+    Some((path, headers, body))
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
+fn process(
+    path: &str,
+    headers: &Headers,
+    body: &[u8],
+    state: &Mutex<State>,
+) -> (u16, Value, Option<String>) {
+    let write_key = header(headers, "authorization").and_then(parse_basic_auth);
+    let body: Value = match header(headers, "content-encoding") {
+        Some("gzip") => {
+            let mut decoded = Vec::new();
+            match flate2::read::GzDecoder::new(body).read_to_end(&mut decoded) {
+                Ok(_) => serde_json::from_slice(&decoded).unwrap_or(Value::Null),
+                Err(_) => Value::Null,
+            }
+        }
+        _ => serde_json::from_slice(body).unwrap_or(Value::Null),
+    };
+
+    let mut state = state.lock().unwrap();
+    let (status, response, retry_after) = match state.faults.pop_front() {
+        Some((status, retry_after)) => (
+            status,
+            json!({ "success": false, "message": reason(status) }),
+            retry_after,
+        ),
+        None => match validate(path, write_key.as_deref(), &body) {
+            Ok(()) => (200, json!({ "success": true }), None),
+            Err(message) => (400, json!({ "success": false, "message": message }), None),
+        },
+    };
+    state.requests.push(RecordedRequest {
+        path: path.to_owned(),
+        write_key,
+        status,
+        body,
+    });
+
+    (status, response, retry_after)
+}
+
+fn validate(path: &str, write_key: Option<&str>, body: &Value) -> Result<(), String> {
+    if write_key.is_none_or(str::is_empty) {
+        return Err("missing write key".to_owned());
+    }
+
+    let body = body.clone();
+    let result = match path {
+        "/v1/identify" => serde_json::from_value::<Identify>(body).map(drop),
+        "/v1/track" => serde_json::from_value::<Track>(body).map(drop),
+        "/v1/page" => serde_json::from_value::<Page>(body).map(drop),
+        "/v1/screen" => serde_json::from_value::<Screen>(body).map(drop),
+        "/v1/group" => serde_json::from_value::<Group>(body).map(drop),
+        "/v1/alias" => serde_json::from_value::<Alias>(body).map(drop),
+        "/v1/batch" => serde_json::from_value::<Batch>(body).map(drop),
+        _ => return Err(format!("unknown endpoint {}", path)),
+    };
+    result.map_err(|e| e.to_string())
+}
+
+fn parse_basic_auth(header: &str) -> Option<String> {
+    use base64::Engine;
+
+    let encoded = header.strip_prefix("Basic ")?;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .ok()?;
+    let credentials = String::from_utf8(decoded).ok()?;
+    Some(credentials.split(':').next()?.to_owned())
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
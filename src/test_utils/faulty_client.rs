@@ -0,0 +1,171 @@
+//! A client wrapper injecting faults into calls to another client.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::{Client, Config, Error, Message, RejectedMessage, Result};
+
+/// A fault to inject into a single call to [`Client::send`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Let the call through unchanged.
+    None,
+    /// Delay the call by the given duration, then let it through.
+    Latency(Duration),
+    /// Wait for the given duration, then fail with [`Error::Timeout`] without
+    /// sending the message.
+    Timeout(Duration),
+    /// Fail immediately with the error of a response with the given status
+    /// code, e.g. [`Error::Server`] for `503`, without sending the message.
+    Status(u16),
+    /// Let the call through, then fail with [`Error::PartiallyRejected`],
+    /// rejecting the first message of the batch with the given status code.
+    RejectFirst(u16),
+}
+
+#[derive(Debug)]
+struct Schedule {
+    faults: VecDeque<Fault>,
+    cycle: bool,
+    calls: usize,
+}
+
+/// A [`Client`] wrapping another client and injecting faults into its calls
+/// according to a schedule, for testing how an application behaves when
+/// Segment is slow or unavailable.
+///
+/// Each call to `send` consumes the next [`Fault`] of the schedule. Once the
+/// schedule is exhausted, calls go through unchanged, unless the schedule was
+/// created with [`cycling`](FaultyClient::cycling).
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use segment::Client;
+/// use segment::message::{Track, User};
+/// use segment::test_utils::{Fault, FaultyClient, MockClient};
+///
+/// let mock = MockClient::new();
+/// let client = FaultyClient::new(mock.clone(), vec![Fault::Status(503), Fault::None]);
+/// let msg = Track {
+///     user: User::UserId { user_id: "user".to_owned() },
+///     event: "Example".to_owned(),
+///     ..Default::default()
+/// };
+///
+/// assert!(client.send("key".to_owned(), msg.clone().into()).await.is_err());
+/// assert!(client.send("key".to_owned(), msg.into()).await.is_ok());
+/// assert_eq!(mock.messages().len(), 1);
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct FaultyClient<C> {
+    inner: C,
+    schedule: Arc<Mutex<Schedule>>,
+}
+
+impl<C: Client> FaultyClient<C> {
+    /// Wrap `inner`, injecting the given faults into the next calls.
+    pub fn new(inner: C, faults: impl IntoIterator<Item = Fault>) -> Self {
+        Self::with_schedule(inner, faults, false)
+    }
+
+    /// Wrap `inner`, injecting the given faults forever, starting over once
+    /// the end of the schedule is reached.
+    pub fn cycling(inner: C, faults: impl IntoIterator<Item = Fault>) -> Self {
+        Self::with_schedule(inner, faults, true)
+    }
+
+    fn with_schedule(inner: C, faults: impl IntoIterator<Item = Fault>, cycle: bool) -> Self {
+        Self {
+            inner,
+            schedule: Arc::new(Mutex::new(Schedule {
+                faults: faults.into_iter().collect(),
+                cycle,
+                calls: 0,
+            })),
+        }
+    }
+
+    /// Append faults to the schedule.
+    pub fn push(&self, faults: impl IntoIterator<Item = Fault>) {
+        self.schedule.lock().unwrap().faults.extend(faults);
+    }
+
+    /// The number of calls made to this client so far.
+    pub fn calls(&self) -> usize {
+        self.schedule.lock().unwrap().calls
+    }
+
+    /// The wrapped client.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    fn next_fault(&self) -> Fault {
+        let mut schedule = self.schedule.lock().unwrap();
+        schedule.calls += 1;
+        let fault = schedule.faults.pop_front().unwrap_or(Fault::None);
+        if schedule.cycle {
+            schedule.faults.push_back(fault);
+        }
+        fault
+    }
+}
+
+#[async_trait::async_trait]
+impl<C: Client + Send + Sync> Client for FaultyClient<C> {
+    async fn send(&self, write_key: String, msg: Message) -> Result<()> {
+        match self.next_fault() {
+            Fault::None => (),
+            Fault::RejectFirst(status) => {
+                self.inner.send(write_key, msg).await?;
+                return Err(Error::PartiallyRejected(vec![RejectedMessage {
+                    index: 0,
+                    status,
+                    reason: "rejected by FaultyClient".to_owned(),
+                }]));
+            }
+            Fault::Latency(delay) => crate::runtime::sleep(delay).await,
+            Fault::Timeout(delay) => {
+                crate::runtime::sleep(delay).await;
+                return Err(Error::Timeout);
+            }
+            Fault::Status(status) => return Err(Error::from_status(status, None, String::new())),
+        }
+
+        self.inner.send(write_key, msg).await
+    }
+
+    fn reconfigure(&mut self, config: &Config) -> Result<()> {
+        self.inner.reconfigure(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Track;
+    use crate::test_utils::MockClient;
+
+    #[tokio::test]
+    async fn cycling_schedule() {
+        let mock = MockClient::new();
+        let client = FaultyClient::cycling(
+            mock.clone(),
+            vec![Fault::Timeout(Duration::from_millis(1)), Fault::None],
+        );
+
+        for _ in 0..4 {
+            let _ = client.send("key".to_owned(), Track::default().into()).await;
+        }
+
+        assert_eq!(client.calls(), 4);
+        assert_eq!(mock.messages().len(), 2);
+        assert!(matches!(
+            client.send("key".to_owned(), Track::default().into()).await,
+            Err(Error::Timeout)
+        ));
+    }
+}
@@ -0,0 +1,174 @@
+//! A ready-made setup for end-to-end tests of code sending analytics.
+
+use std::time::Duration;
+
+use serde_json::Value;
+use time::OffsetDateTime;
+
+use crate::message::{Alias, Batch, BatchMessage, Group, Identify, Page, Screen, Track};
+use crate::test_utils::{FakeServer, MockClock, SequentialIdGenerator};
+#[cfg(any(feature = "tokio", feature = "smol"))]
+use crate::{Analytics, Result};
+use crate::{ClientBuilder, WriteKey};
+
+/// The write key the pipelines of a [`SegmentTestHarness`] send messages
+/// with.
+pub const TEST_WRITE_KEY: &str = "test-write-key";
+
+/// A [`FakeServer`], a [`MockClock`] and a [`SequentialIdGenerator`] wired
+/// together, for crates wrapping this one to test what they send end to end,
+/// through the real HTTP client and pipeline.
+///
+/// Pipelines built from [`builder`](SegmentTestHarness::builder) send their
+/// messages to the fake server, timestamped by the mock clock and with
+/// sequential message IDs, so the captured messages are reproducible:
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> segment::Result<()> {
+/// use segment::message::{Track, User};
+/// use segment::test_utils::SegmentTestHarness;
+///
+/// let harness = SegmentTestHarness::start();
+/// let analytics = harness.build()?;
+///
+/// analytics.enqueue(Track {
+///     user: User::UserId { user_id: "user".to_owned() },
+///     event: "Signed Up".to_owned(),
+///     ..Default::default()
+/// })?;
+/// analytics.close().await?;
+///
+/// let signups = harness.events("Signed Up");
+/// assert_eq!(signups.len(), 1);
+/// assert_eq!(signups[0].timestamp, Some(harness.clock_start()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct SegmentTestHarness {
+    server: FakeServer,
+    clock: MockClock,
+    ids: SequentialIdGenerator,
+    start: OffsetDateTime,
+}
+
+impl SegmentTestHarness {
+    /// Start a fake server, with a clock stopped at 2024-01-01T00:00:00Z.
+    pub fn start() -> Self {
+        Self::start_at(OffsetDateTime::from_unix_timestamp(1_704_067_200).unwrap())
+    }
+
+    /// Start a fake server, with a clock stopped at `now`.
+    pub fn start_at(now: OffsetDateTime) -> Self {
+        Self {
+            server: FakeServer::start(),
+            clock: MockClock::new(now),
+            ids: SequentialIdGenerator::default(),
+            start: now,
+        }
+    }
+
+    /// A builder sending messages to the fake server with
+    /// [`TEST_WRITE_KEY`], using the mock clock and sequential message IDs.
+    ///
+    /// Batches are only sent when full or flushed, so tests decide when
+    /// messages are captured.
+    pub fn builder(&self) -> ClientBuilder {
+        ClientBuilder::new(WriteKey::new(TEST_WRITE_KEY).unwrap())
+            .host(self.server.url())
+            .flush_interval(Duration::from_secs(3600))
+            .clock(self.clock.clone())
+            .id_generator(self.ids.clone())
+    }
+
+    /// Build a pipeline with the default settings of
+    /// [`builder`](SegmentTestHarness::builder). See
+    /// [`Analytics::new`](crate::Analytics::new) for the runtime
+    /// requirements.
+    #[cfg(any(feature = "tokio", feature = "smol"))]
+    pub fn build(&self) -> Result<Analytics> {
+        self.builder().build()
+    }
+
+    /// The fake server, e.g. to [inject failures](FakeServer::fail_next).
+    pub fn server(&self) -> &FakeServer {
+        &self.server
+    }
+
+    /// The clock timestamping messages, which only moves when told to.
+    pub fn clock(&self) -> &MockClock {
+        &self.clock
+    }
+
+    /// The time the clock was stopped at when the harness started.
+    pub fn clock_start(&self) -> OffsetDateTime {
+        self.start
+    }
+
+    /// All the messages the fake server accepted, in the order they were
+    /// received, with batches flattened into the messages they contain.
+    pub fn messages(&self) -> Vec<BatchMessage> {
+        self.server
+            .requests()
+            .into_iter()
+            .filter(|request| request.status == 200)
+            .flat_map(|request| parse(&request.path, request.body))
+            .collect()
+    }
+
+    /// All the `track` messages accepted.
+    pub fn tracks(&self) -> Vec<Track> {
+        self.messages()
+            .into_iter()
+            .filter_map(|msg| match msg {
+                BatchMessage::Track(track) => Some(track),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// All the `identify` messages accepted.
+    pub fn identifies(&self) -> Vec<Identify> {
+        self.messages()
+            .into_iter()
+            .filter_map(|msg| match msg {
+                BatchMessage::Identify(identify) => Some(identify),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The `track` messages accepted with the given event name.
+    pub fn events(&self, name: &str) -> Vec<Track> {
+        self.tracks()
+            .into_iter()
+            .filter(|track| track.event == name)
+            .collect()
+    }
+
+    /// The requests accepted in a canonical form, as a pretty-printed JSON
+    /// array, for snapshot tests. See [`FakeServer::snapshot`].
+    pub fn snapshot(&self) -> String {
+        self.server.snapshot()
+    }
+}
+
+/// The messages of an accepted request body sent to `path`.
+fn parse(path: &str, body: Value) -> Vec<BatchMessage> {
+    let msg = match path {
+        "/v1/batch" => {
+            return serde_json::from_value::<Batch>(body)
+                .map(|batch| batch.batch)
+                .unwrap_or_default()
+        }
+        "/v1/identify" => serde_json::from_value::<Identify>(body).map(BatchMessage::from),
+        "/v1/track" => serde_json::from_value::<Track>(body).map(BatchMessage::from),
+        "/v1/page" => serde_json::from_value::<Page>(body).map(BatchMessage::from),
+        "/v1/screen" => serde_json::from_value::<Screen>(body).map(BatchMessage::from),
+        "/v1/group" => serde_json::from_value::<Group>(body).map(BatchMessage::from),
+        "/v1/alias" => serde_json::from_value::<Alias>(body).map(BatchMessage::from),
+        _ => return Vec::new(),
+    };
+    msg.into_iter().collect()
+}
@@ -0,0 +1,230 @@
+//! Copying the trace context of the caller into the context of messages.
+
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+use serde_json::{Map, Value};
+
+use crate::message::{merge_optional_context, BatchMessage};
+
+static PROPAGATION: RwLock<Option<Propagation>> = RwLock::new(None);
+
+/// The trace, span and baggage of the operation a message is produced in, as
+/// read from OpenTelemetry or from W3C Trace Context headers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraceContext {
+    /// The ID of the trace, as 32 hex digits.
+    pub trace_id: String,
+    /// The ID of the current span, as 16 hex digits.
+    pub span_id: Option<String>,
+    /// The baggage entries, by key.
+    pub baggage: Map<String, Value>,
+}
+
+impl TraceContext {
+    /// A context with the given trace ID, and no span or baggage.
+    pub fn new(trace_id: impl Into<String>) -> Self {
+        Self {
+            trace_id: trace_id.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Set the span ID, returning `self` for chaining.
+    pub fn with_span_id(mut self, span_id: impl Into<String>) -> Self {
+        self.span_id = Some(span_id.into());
+        self
+    }
+
+    /// Add a baggage entry, returning `self` for chaining.
+    pub fn with_baggage(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.baggage.insert(key.into(), Value::String(value.into()));
+        self
+    }
+
+    /// Parse the values of the `traceparent` and `baggage` headers of the W3C
+    /// Trace Context and Baggage specifications, e.g. those of an incoming
+    /// request.
+    ///
+    /// Returns `None` if `traceparent` is not valid. Baggage entries which
+    /// can't be parsed are skipped, and their properties are left out.
+    pub fn from_headers(traceparent: &str, baggage: Option<&str>) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let (_version, trace_id, span_id) = (parts.next()?, parts.next()?, parts.next()?);
+        let is_id = |id: &str, len| {
+            id.len() == len
+                && id.bytes().all(|b| b.is_ascii_hexdigit())
+                && id.bytes().any(|b| b != b'0')
+        };
+        if !is_id(trace_id, 32) || !is_id(span_id, 16) || parts.next().is_none() {
+            return None;
+        }
+        let mut context =
+            Self::new(trace_id.to_ascii_lowercase()).with_span_id(span_id.to_ascii_lowercase());
+        for entry in baggage.unwrap_or_default().split(',') {
+            let entry = entry.split(';').next().unwrap_or_default();
+            if let Some((key, value)) = entry.split_once('=') {
+                let key = key.trim();
+                if !key.is_empty() {
+                    context = context.with_baggage(key, value.trim());
+                }
+            }
+        }
+        Some(context)
+    }
+}
+
+type ProviderFn = dyn Fn() -> Option<TraceContext> + Send + Sync;
+
+#[derive(Clone)]
+struct Propagation {
+    provider: Arc<ProviderFn>,
+    baggage_keys: Vec<String>,
+}
+
+impl fmt::Debug for Propagation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Propagation")
+            .field("baggage_keys", &self.baggage_keys)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Copy the trace context returned by `provider` into the context of every
+/// message queued from now on in this process, so analytics events can be
+/// joined with traces, e.g. while investigating an incident.
+///
+/// `provider` is called on the thread queueing the message, where the current
+/// OpenTelemetry context is available: it can build a [`TraceContext`] from
+/// the span context and baggage of `opentelemetry::Context::current()`, or
+/// from the headers of the request being handled. The trace ID is set as
+/// `context.traceId`, the span ID as `context.spanId`, and the baggage
+/// entries named in `baggage_keys`, and only those, in `context.baggage`.
+/// Fields already set on the message take precedence.
+///
+/// ```
+/// use segment::TraceContext;
+///
+/// segment::propagate_trace_context(
+///     || {
+///         // Usually read from the current OpenTelemetry context.
+///         TraceContext::from_headers(
+///             "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+///             Some("tenant=acme,session=1234"),
+///         )
+///     },
+///     ["tenant"],
+/// );
+/// # segment::stop_trace_propagation();
+/// ```
+pub fn propagate_trace_context<I, S>(
+    provider: impl Fn() -> Option<TraceContext> + Send + Sync + 'static,
+    baggage_keys: I,
+) where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    *PROPAGATION.write().unwrap() = Some(Propagation {
+        provider: Arc::new(provider),
+        baggage_keys: baggage_keys.into_iter().map(Into::into).collect(),
+    });
+}
+
+/// Stop copying the trace context into messages. See
+/// [`propagate_trace_context`].
+pub fn stop_trace_propagation() {
+    *PROPAGATION.write().unwrap() = None;
+}
+
+/// Merge the current trace context into the context of `msg`.
+pub(crate) fn apply(msg: &mut BatchMessage) {
+    // Cloned so that the provider doesn't run with the lock held.
+    let propagation = PROPAGATION.read().unwrap().clone();
+    if let Some(propagation) = propagation {
+        propagation.apply(msg);
+    }
+}
+
+impl Propagation {
+    fn apply(&self, msg: &mut BatchMessage) {
+        let trace = match (self.provider)() {
+            Some(trace) => trace,
+            None => return,
+        };
+        let mut context = Map::new();
+        context.insert("traceId".to_owned(), Value::String(trace.trace_id));
+        if let Some(span_id) = trace.span_id {
+            context.insert("spanId".to_owned(), Value::String(span_id));
+        }
+        let baggage: Map<String, Value> = trace
+            .baggage
+            .into_iter()
+            .filter(|(key, _)| self.baggage_keys.contains(key))
+            .collect();
+        if !baggage.is_empty() {
+            context.insert("baggage".to_owned(), Value::Object(baggage));
+        }
+        merge_optional_context(msg.context_mut(), &Value::Object(context));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Track;
+    use serde_json::json;
+
+    #[test]
+    fn test_from_headers() {
+        let context = TraceContext::from_headers(
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            Some("tenant=acme;prop=1, session = 1234,invalid"),
+        )
+        .unwrap();
+        assert_eq!(
+            context,
+            TraceContext::new("4bf92f3577b34da6a3ce929d0e0e4736")
+                .with_span_id("00f067aa0ba902b7")
+                .with_baggage("tenant", "acme")
+                .with_baggage("session", "1234")
+        );
+
+        assert_eq!(TraceContext::from_headers("00-abc-def-01", None), None);
+        assert_eq!(
+            TraceContext::from_headers(
+                "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+                None
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_propagation() {
+        // Not registered globally, which would affect the other tests.
+        let propagation = Propagation {
+            provider: Arc::new(|| {
+                Some(
+                    TraceContext::new("4bf92f3577b34da6a3ce929d0e0e4736")
+                        .with_span_id("00f067aa0ba902b7")
+                        .with_baggage("tenant", "acme")
+                        .with_baggage("secret", "hunter2"),
+                )
+            }),
+            baggage_keys: vec!["tenant".to_owned()],
+        };
+        let mut msg = BatchMessage::from(Track {
+            context: Some(json!({ "traceId": "mine" })),
+            ..Default::default()
+        });
+        propagation.apply(&mut msg);
+        assert_eq!(
+            msg.context(),
+            Some(&json!({
+                "traceId": "mine",
+                "spanId": "00f067aa0ba902b7",
+                "baggage": { "tenant": "acme" },
+            }))
+        );
+    }
+}
@@ -0,0 +1,118 @@
+//! Caching the traits of users to attach them to their later events.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde_json::{Map, Value};
+
+use crate::message::BatchMessage;
+
+/// The number of users whose traits are cached. Past it, the least recently
+/// identified user is forgotten.
+const CAPACITY: usize = 10_000;
+
+/// The traits of the users identified so far.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TraitCache {
+    users: HashMap<String, (Map<String, Value>, u64)>,
+    /// The users by when they were last identified, least recently first.
+    used: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl TraitCache {
+    /// Cache the traits of `msg` if it is an `identify`, or set the cached
+    /// traits of its user in `context.traits` if it is a `track`, `page` or
+    /// `screen` event.
+    ///
+    /// Traits already in `context.traits` are kept.
+    pub(crate) fn apply(&mut self, msg: &mut BatchMessage) {
+        match msg {
+            BatchMessage::Identify(identify) => {
+                if identify.traits.is_empty() {
+                    return;
+                }
+                self.clock += 1;
+                let user = identify.user.key();
+                if self.users.len() >= CAPACITY && !self.users.contains_key(user) {
+                    if let Some((_, oldest)) = self.used.pop_first() {
+                        self.users.remove(&oldest);
+                    }
+                }
+                let (traits, used) = self.users.entry(user.to_owned()).or_default();
+                traits.extend(identify.traits.iter().map(|(k, v)| (k.clone(), v.clone())));
+                self.used.remove(used);
+                *used = self.clock;
+                self.used.insert(self.clock, user.to_owned());
+            }
+            BatchMessage::Track(_) | BatchMessage::Page(_) | BatchMessage::Screen(_) => {
+                let cached = match self.users.get(msg.user().key()) {
+                    Some((traits, _)) => traits,
+                    None => return,
+                };
+                let context = msg
+                    .context_mut()
+                    .get_or_insert_with(|| Value::Object(Map::new()));
+                if let Value::Object(context) = context {
+                    let traits = context
+                        .entry("traits")
+                        .or_insert_with(|| Value::Object(Map::new()));
+                    if let Value::Object(traits) = traits {
+                        for (key, value) in cached {
+                            traits.entry(key.clone()).or_insert_with(|| value.clone());
+                        }
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Identify, Track, Traits, User};
+
+    fn user(id: usize) -> User {
+        User::UserId {
+            user_id: id.to_string(),
+        }
+    }
+
+    fn traits(msg: BatchMessage) -> Option<Value> {
+        msg.context()?.get("traits").cloned()
+    }
+
+    #[test]
+    fn test_evict_least_recently_identified() {
+        let mut cache = TraitCache::default();
+        let mut identify = |id| {
+            cache.apply(&mut BatchMessage::from(Identify {
+                user: user(id),
+                traits: Traits::new().with("plan", "pro"),
+                ..Default::default()
+            }))
+        };
+        for id in 0..CAPACITY {
+            identify(id);
+        }
+        // Identifying the first user again keeps them cached.
+        identify(0);
+        identify(CAPACITY);
+        assert_eq!(cache.users.len(), CAPACITY);
+        assert_eq!(cache.used.len(), CAPACITY);
+
+        let mut track = |id| {
+            let mut msg = BatchMessage::from(Track {
+                user: user(id),
+                ..Default::default()
+            });
+            cache.apply(&mut msg);
+            traits(msg)
+        };
+        assert!(track(0).is_some());
+        assert!(track(1).is_none());
+        assert!(track(2).is_some());
+        assert!(track(CAPACITY).is_some());
+    }
+}
@@ -0,0 +1,294 @@
+//! Fixes to the data of messages, declared in the configuration.
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::message::BatchMessage;
+use crate::Filter;
+
+/// A change made to the fields of messages by a [`Transform`].
+///
+/// Fields are given by their path in the message as sent to Segment, such as
+/// `properties.total` or `context.app.version`, like in [`Filter`]s.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum TransformStep {
+    /// Move the value of the field `from` to the field `to`, replacing its
+    /// value if any.
+    Rename {
+        /// The path of the field to move.
+        from: String,
+        /// The path of the field to move it to.
+        to: String,
+    },
+    /// Copy the value of the field `from` to the field `to`, replacing its
+    /// value if any.
+    Copy {
+        /// The path of the field to copy.
+        from: String,
+        /// The path of the field to copy it to.
+        to: String,
+    },
+    /// Remove the field.
+    Drop(String),
+    /// Set the field to a fixed value.
+    Set {
+        /// The path of the field to set.
+        field: String,
+        /// Its new value.
+        value: Value,
+    },
+}
+
+/// A list of [steps](TransformStep) applied to the messages matching a
+/// filter, so that bad data can be fixed by a configuration change rather
+/// than a deploy. See [`Batcher::add_transform`](crate::Batcher::add_transform).
+///
+/// The steps are applied in order. The fields they need to move, copy or
+/// create objects in are created as needed, while the steps whose source
+/// field is missing do nothing. A transform producing a message which isn't
+/// valid any more, e.g. without a `userId` nor an `anonymousId`, is not
+/// applied, and a warning is logged.
+///
+/// It deserializes from:
+///
+/// ```json
+/// {
+///     "when": "event = \"Order Completed\"",
+///     "steps": [
+///         { "rename": { "from": "properties.total", "to": "properties.revenue" } },
+///         { "copy": { "from": "userId", "to": "properties.customer" } },
+///         { "drop": "properties.card_number" },
+///         { "set": { "field": "properties.currency", "value": "USD" } }
+///     ]
+/// }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Transform {
+    /// The messages transformed, or all of them if `None`.
+    pub when: Option<Filter>,
+    /// The changes made to them.
+    pub steps: Vec<TransformStep>,
+}
+
+impl Transform {
+    /// A transform of every message, without steps yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only transform the messages matching `filter`, returning `self` for
+    /// chaining.
+    pub fn when(mut self, filter: Filter) -> Self {
+        self.when = Some(filter);
+        self
+    }
+
+    /// Move the field `from` to `to`, returning `self` for chaining.
+    pub fn rename(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+        self.steps.push(TransformStep::Rename {
+            from: from.into(),
+            to: to.into(),
+        });
+        self
+    }
+
+    /// Copy the field `from` to `to`, returning `self` for chaining.
+    pub fn copy(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+        self.steps.push(TransformStep::Copy {
+            from: from.into(),
+            to: to.into(),
+        });
+        self
+    }
+
+    /// Remove `field`, returning `self` for chaining.
+    pub fn drop(mut self, field: impl Into<String>) -> Self {
+        self.steps.push(TransformStep::Drop(field.into()));
+        self
+    }
+
+    /// Set `field` to `value`, returning `self` for chaining.
+    pub fn set(mut self, field: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.steps.push(TransformStep::Set {
+            field: field.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    /// The paths of the fields the steps touch.
+    pub(crate) fn fields(&self) -> impl Iterator<Item = &str> {
+        self.steps.iter().flat_map(|step| match step {
+            TransformStep::Rename { from, to } | TransformStep::Copy { from, to } => {
+                vec![from.as_str(), to.as_str()]
+            }
+            TransformStep::Drop(field) | TransformStep::Set { field, .. } => vec![field.as_str()],
+        })
+    }
+
+    /// Apply the steps to `msg` if it matches.
+    pub(crate) fn apply(&self, msg: &mut BatchMessage) {
+        let mut fields = match serde_json::to_value(&*msg) {
+            Ok(Value::Object(fields)) => fields,
+            _ => return,
+        };
+        if let Some(filter) = &self.when {
+            if !filter.matches_value(&Value::Object(fields.clone())) {
+                return;
+            }
+        }
+        for step in &self.steps {
+            match step {
+                TransformStep::Rename { from, to } => {
+                    if let Some(value) = remove(&mut fields, from) {
+                        insert(&mut fields, to, value);
+                    }
+                }
+                TransformStep::Copy { from, to } => {
+                    if let Some(value) = get(&fields, from).cloned() {
+                        insert(&mut fields, to, value);
+                    }
+                }
+                TransformStep::Drop(field) => {
+                    remove(&mut fields, field);
+                }
+                TransformStep::Set { field, value } => insert(&mut fields, field, value.clone()),
+            }
+        }
+        match serde_json::from_value(Value::Object(fields)) {
+            Ok(transformed) => *msg = transformed,
+            Err(e) => log::warn!(
+                "not transforming a message of {}, which would be invalid: {}",
+                msg.user(),
+                e
+            ),
+        }
+    }
+}
+
+/// Whether `path` can name a field: it has no empty segment, and isn't the
+/// type of the message.
+pub(crate) fn is_valid_path(path: &str) -> bool {
+    path != "type" && path.split('.').all(|segment| !segment.is_empty())
+}
+
+fn get<'a>(fields: &'a Map<String, Value>, path: &str) -> Option<&'a Value> {
+    let (parent, name) = match path.rsplit_once('.') {
+        Some((parent, name)) => (get(fields, parent)?.as_object()?, name),
+        None => (fields, path),
+    };
+    parent.get(name)
+}
+
+fn remove(fields: &mut Map<String, Value>, path: &str) -> Option<Value> {
+    let (parent, name) = match path.rsplit_once('.') {
+        Some((parent, name)) => {
+            let parent = parent
+                .split('.')
+                .try_fold(&mut *fields, |fields, segment| {
+                    fields.get_mut(segment)?.as_object_mut()
+                })?;
+            (parent, name)
+        }
+        None => (fields, path),
+    };
+    parent.remove(name)
+}
+
+/// Set the field at `path`, creating the objects leading to it, unless a
+/// value which isn't an object is in the way.
+fn insert(fields: &mut Map<String, Value>, path: &str, value: Value) {
+    let mut segments = path.split('.');
+    let name = match segments.next_back() {
+        Some(name) => name,
+        None => return,
+    };
+    let parent = segments.try_fold(fields, |fields, segment| {
+        let child = fields
+            .entry(segment)
+            .or_insert_with(|| Value::Object(Map::new()));
+        if child.is_null() {
+            *child = Value::Object(Map::new());
+        }
+        child.as_object_mut()
+    });
+    match parent {
+        Some(parent) => {
+            parent.insert(name.to_owned(), value);
+        }
+        None => log::warn!(
+            "not setting {}, which is inside a value which isn't an object",
+            path
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Properties, Track, User};
+    use serde_json::json;
+
+    fn order(total: u64) -> BatchMessage {
+        BatchMessage::from(Track {
+            user: User::UserId {
+                user_id: "foo".to_owned(),
+            },
+            event: "Order Completed".to_owned(),
+            properties: Properties::new()
+                .with("total", total)
+                .with("card_number", "4242"),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_transform() {
+        let transform: Transform = serde_json::from_value(json!({
+            "when": "event = \"Order Completed\"",
+            "steps": [
+                { "rename": { "from": "properties.total", "to": "properties.revenue" } },
+                { "copy": { "from": "userId", "to": "context.customer.id" } },
+                { "drop": "properties.card_number" },
+                { "set": { "field": "properties.currency", "value": "USD" } },
+                { "rename": { "from": "properties.missing", "to": "properties.other" } }
+            ]
+        }))
+        .unwrap();
+
+        let mut msg = order(10);
+        transform.apply(&mut msg);
+        let track = match msg {
+            BatchMessage::Track(track) => track,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            Value::Object(track.properties.0),
+            json!({ "revenue": 10, "currency": "USD" })
+        );
+        assert_eq!(track.context, Some(json!({ "customer": { "id": "foo" } })));
+
+        let mut msg = BatchMessage::from(Track {
+            event: "Signed Up".to_owned(),
+            ..Default::default()
+        });
+        let before = msg.clone();
+        transform.apply(&mut msg);
+        assert_eq!(msg, before);
+
+        // Transforms which would make the message invalid are not applied.
+        let mut msg = order(10);
+        Transform::new().drop("event").apply(&mut msg);
+        assert_eq!(msg, order(10));
+    }
+
+    #[test]
+    fn test_is_valid_path() {
+        assert!(is_valid_path("properties.total"));
+        assert!(!is_valid_path("properties..total"));
+        assert!(!is_valid_path(""));
+        assert!(!is_valid_path("type"));
+    }
+}
@@ -0,0 +1,157 @@
+//! Shrinking the properties of `track` events too large to be sent.
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::message::BatchMessage;
+use crate::{Error, Result};
+
+/// The properties of `track` events which can be cut short or removed when an
+/// event exceeds the size limit of a message, so the event itself is still
+/// sent. See [`Batcher::set_truncation`](crate::Batcher::set_truncation).
+///
+/// The fields are shrunk in order, until the event fits: strings lose their
+/// end, and other values are removed. The names of the shrunk fields are
+/// listed in the `marker` property of the event.
+///
+/// It deserializes from:
+///
+/// ```json
+/// { "fields": ["stack_trace", "html"], "marker": "truncated_properties" }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Truncation {
+    /// The top-level properties which may be shrunk, least valuable first.
+    pub fields: Vec<String>,
+    /// The property listing the fields which were shrunk.
+    pub marker: String,
+}
+
+impl Default for Truncation {
+    /// Shrink no fields, marking them in `truncated_properties`.
+    fn default() -> Self {
+        Self {
+            fields: Vec::new(),
+            marker: "truncated_properties".to_owned(),
+        }
+    }
+}
+
+impl Truncation {
+    /// Shrink the given properties, least valuable first.
+    pub fn new<I, S>(fields: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            fields: fields.into_iter().map(Into::into).collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Shrink the properties of `msg`, whose serialized size is `size`, until
+    /// it is at most `max` bytes, returning its new size.
+    ///
+    /// Fails with [`Error::MessageTooLarge`] if `msg` is not a `track` event,
+    /// or if shrinking every field is not enough.
+    pub(crate) fn apply(
+        &self,
+        msg: &mut BatchMessage,
+        mut size: usize,
+        max: usize,
+    ) -> Result<usize> {
+        let mut truncated = Vec::new();
+        for field in &self.fields {
+            if size <= max {
+                break;
+            }
+            match msg {
+                BatchMessage::Track(track) if track.properties.contains_key(field) => {
+                    truncated.push(Value::String(field.clone()));
+                    track
+                        .properties
+                        .insert(self.marker.clone(), Value::Array(truncated.clone()));
+                }
+                BatchMessage::Track(_) => continue,
+                _ => break,
+            }
+            // The marker itself takes some of the room freed by the field.
+            let excess = serde_json::to_vec(&*msg)?.len().saturating_sub(max);
+            if let BatchMessage::Track(track) = msg {
+                shrink(&mut track.properties, field, excess);
+            }
+            size = serde_json::to_vec(&*msg)?.len();
+        }
+        if size > max {
+            return Err(Error::MessageTooLarge);
+        }
+        Ok(size)
+    }
+}
+
+/// Remove at least `excess` bytes from the serialized `field` of `properties`.
+fn shrink(properties: &mut Map<String, Value>, field: &str, excess: usize) {
+    match properties.get_mut(field) {
+        // Escaped characters only make the serialized string longer, so
+        // removing `excess` bytes of it frees at least as many.
+        Some(Value::String(value)) if value.len() > excess => {
+            let mut end = value.len() - excess;
+            while !value.is_char_boundary(end) {
+                end -= 1;
+            }
+            value.truncate(end);
+        }
+        _ => {
+            properties.remove(field);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Properties, Track};
+
+    #[test]
+    fn test_truncation() {
+        let track = || {
+            BatchMessage::from(Track {
+                event: "Crashed".to_owned(),
+                properties: Properties::new()
+                    .with("stack_trace", "é".repeat(500))
+                    .with("dump", vec![1; 200])
+                    .with("version", "1.2.3"),
+                ..Default::default()
+            })
+        };
+        let size = |msg: &BatchMessage| serde_json::to_vec(msg).unwrap().len();
+        let truncation = Truncation::new(["dump", "missing", "stack_trace"]);
+
+        let mut msg = track();
+        let (old_size, max) = (size(&msg), size(&msg) - 1000);
+        let new_size = truncation.apply(&mut msg, old_size, max).unwrap();
+        assert_eq!(new_size, size(&msg));
+        assert!(new_size <= max);
+        let properties = match msg {
+            BatchMessage::Track(track) => track.properties,
+            _ => unreachable!(),
+        };
+        assert!(!properties.contains_key("dump"));
+        let stack_trace = properties["stack_trace"].as_str().unwrap();
+        assert!(!stack_trace.is_empty() && stack_trace.len() < 1000);
+        assert_eq!(properties["version"], "1.2.3");
+        assert_eq!(
+            properties["truncated_properties"],
+            serde_json::json!(["dump", "stack_trace"])
+        );
+
+        let mut msg = track();
+        let old_size = size(&msg);
+        assert!(matches!(
+            truncation.apply(&mut msg, old_size, 50),
+            Err(Error::MessageTooLarge)
+        ));
+    }
+}
@@ -0,0 +1,123 @@
+//! State kept for each user, forgotten once expired and capped in size.
+
+use std::borrow::Borrow;
+use std::collections::hash_map::{Entry, HashMap};
+use std::hash::Hash;
+
+use time::OffsetDateTime;
+
+/// Maps smaller than this are never pruned.
+const MIN_PRUNE: usize = 1024;
+
+/// The number of entries past which the least recently seen ones are
+/// forgotten, even if they haven't expired yet.
+const MAX_ENTRIES: usize = 100_000;
+
+/// A map of the state of each user, pruned as it grows.
+///
+/// Pruning happens each time the map has doubled since it was last pruned,
+/// so it costs a constant time per insertion on average: the expired entries
+/// are removed, and if more than half of [`MAX_ENTRIES`] remain, the least
+/// recently seen ones are too.
+#[derive(Clone, Debug)]
+pub(crate) struct UserMap<K, V> {
+    entries: HashMap<K, V>,
+    /// The length at which the map is pruned next.
+    prune_at: usize,
+}
+
+impl<K: Eq + Hash, V> Default for UserMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Eq + Hash, V> UserMap<K, V> {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            prune_at: MIN_PRUNE,
+        }
+    }
+
+    pub(crate) fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.entries.get(key)
+    }
+
+    pub(crate) fn insert(&mut self, key: K, value: V) {
+        self.entries.insert(key, value);
+    }
+
+    pub(crate) fn entry(&mut self, key: K) -> Entry<'_, K, V> {
+        self.entries.entry(key)
+    }
+
+    /// Prune the map if it grew enough since it was last, removing the
+    /// entries which `is_expired`, then if there are still too many, those
+    /// `seen` least recently. Call this before inserting.
+    pub(crate) fn prune(
+        &mut self,
+        mut is_expired: impl FnMut(&K, &V) -> bool,
+        seen: impl Fn(&V) -> OffsetDateTime,
+    ) {
+        if self.entries.len() < self.prune_at {
+            return;
+        }
+        self.entries.retain(|key, value| !is_expired(key, value));
+        if self.entries.len() > MAX_ENTRIES / 2 {
+            let mut times: Vec<_> = self.entries.values().map(&seen).collect();
+            let evicted = times.len() - MAX_ENTRIES / 2;
+            let (_, &mut oldest_kept, _) = times.select_nth_unstable(evicted);
+            self.entries.retain(|_, value| seen(value) >= oldest_kept);
+        }
+        self.prune_at = (self.entries.len() * 2).clamp(MIN_PRUNE, MAX_ENTRIES);
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[cfg(test)]
+    fn contains_key(&self, key: &K) -> bool {
+        self.entries.contains_key(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_prune() {
+        let start = OffsetDateTime::now_utc();
+        let ttl = Duration::from_secs(60);
+        let mut map = UserMap::new();
+        for i in 0..MIN_PRUNE {
+            map.prune(|_, seen| start - *seen >= ttl, |seen| *seen);
+            map.entry(i).or_insert(start);
+        }
+        assert_eq!(map.len(), MIN_PRUNE);
+
+        // The expired entries are forgotten once the map is due for pruning.
+        let now = start + Duration::from_secs(120);
+        map.prune(|_, seen| now - *seen >= ttl, |seen| *seen);
+        map.entry(MIN_PRUNE).or_insert(now);
+        assert_eq!(map.len(), 1);
+
+        // Entries which did not expire are capped, keeping the latest.
+        for i in 0..2 * MAX_ENTRIES {
+            map.prune(|_, _| false, |seen| *seen);
+            map.entry(i)
+                .or_insert(now + Duration::from_millis(i as u64));
+        }
+        assert!(map.len() <= MAX_ENTRIES);
+        assert!(map.contains_key(&(2 * MAX_ENTRIES - 1)));
+        assert!(!map.contains_key(&0));
+    }
+}
@@ -0,0 +1,131 @@
+//! Validation of messages before they are sent.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use time::OffsetDateTime;
+
+use crate::message::{BatchMessage, User};
+use crate::{Error, Message, Result};
+
+/// How far from the current time message timestamps may be.
+///
+/// Most destinations silently drop or misfile events with implausible
+/// timestamps, typically caused by a wrong clock or a unit mix-up (seconds
+/// instead of milliseconds).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimestampBounds {
+    /// How far in the past a timestamp may be.
+    pub max_past: Duration,
+    /// How far in the future a timestamp may be.
+    pub max_future: Duration,
+}
+
+impl Default for TimestampBounds {
+    /// Accept timestamps up to a year in the past and a day in the future.
+    fn default() -> Self {
+        Self {
+            max_past: Duration::from_secs(365 * 24 * 60 * 60),
+            max_future: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+impl TimestampBounds {
+    /// Check that `timestamp` is within bounds of `now`.
+    pub(crate) fn check(&self, timestamp: OffsetDateTime, now: OffsetDateTime) -> Result<()> {
+        if now - timestamp > self.max_past {
+            return Err(Error::invalid_field(
+                "timestamp",
+                format!("{} is more than {:?} in the past", timestamp, self.max_past),
+            ));
+        }
+        if timestamp - now > self.max_future {
+            return Err(Error::invalid_field(
+                "timestamp",
+                format!(
+                    "{} is more than {:?} in the future",
+                    timestamp, self.max_future
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// When messages are validated in [strict mode](crate::Batcher::enable_strict_mode).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidationMode {
+    /// Validate messages when they are pushed or queued, failing fast: the
+    /// caller gets the error, and can fix the call site.
+    #[default]
+    Enqueue,
+    /// Validate messages when their batch is flushed, so queueing never
+    /// fails because of them. Invalid messages are not sent, but handed to
+    /// the [`DeadLetter`](crate::DeadLetter) of the
+    /// [`AutoBatcher`](crate::AutoBatcher), if any, or logged.
+    Flush,
+}
+
+type WarningFn = dyn Fn(&BatchMessage, &Error) + Send + Sync;
+
+/// A callback notified of messages which fail validation but are sent
+/// anyway.
+#[derive(Clone)]
+pub(crate) struct WarningHook(pub(crate) Arc<WarningFn>);
+
+impl fmt::Debug for WarningHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WarningHook")
+    }
+}
+
+/// Check that `msg` can be attributed to a user and, for `track` events, that
+/// it has an event name.
+pub(crate) fn check_complete(msg: &BatchMessage) -> Result<()> {
+    check_user(msg.user())?;
+    match msg {
+        BatchMessage::Track(track) => check_event(&track.event),
+        _ => Ok(()),
+    }
+}
+
+/// Same as [`check_complete`], for every message contained in `msg`.
+pub(crate) fn check_message_complete(msg: &Message) -> Result<()> {
+    match msg {
+        Message::Identify(identify) => check_user(&identify.user),
+        Message::Track(track) => check_user(&track.user).and_then(|()| check_event(&track.event)),
+        Message::Page(page) => check_user(&page.user),
+        Message::Screen(screen) => check_user(&screen.user),
+        Message::Group(group) => check_user(&group.user),
+        Message::Alias(alias) => check_user(&alias.user),
+        Message::Batch(batch) => batch.batch.iter().try_for_each(check_complete),
+    }
+}
+
+fn check_user(user: &User) -> Result<()> {
+    let anonymous = match user {
+        User::UserId { user_id } => user_id.is_empty(),
+        User::AnonymousId { anonymous_id } => anonymous_id.is_empty(),
+        User::Both {
+            user_id,
+            anonymous_id,
+        } => user_id.is_empty() && anonymous_id.is_empty(),
+    };
+    if anonymous {
+        return Err(Error::invalid_message(
+            "message has neither a user ID nor an anonymous ID",
+        ));
+    }
+    Ok(())
+}
+
+fn check_event(event: &str) -> Result<()> {
+    if event.is_empty() {
+        return Err(Error::invalid_field("event", "must not be empty"));
+    }
+    Ok(())
+}
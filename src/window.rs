@@ -0,0 +1,111 @@
+//! Restricting when batches are sent.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer};
+use time::{OffsetDateTime, Time};
+
+use crate::{Error, Result};
+
+/// A daily time window, in UTC, during which batches may be sent. See
+/// [`AutoBatcher::set_flush_windows`](crate::AutoBatcher::set_flush_windows).
+///
+/// Windows are written `HH:MM-HH:MM`, and wrap around midnight if they end
+/// before they start:
+///
+/// ```
+/// use segment::FlushWindow;
+///
+/// let night: FlushWindow = "22:30-04:00".parse().unwrap();
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct FlushWindow {
+    start: Time,
+    end: Time,
+}
+
+impl FlushWindow {
+    /// The window from `start` to `end`, in UTC.
+    pub fn new(start: Time, end: Time) -> Self {
+        Self { start, end }
+    }
+
+    /// Whether `now` is within this window.
+    pub fn contains(&self, now: OffsetDateTime) -> bool {
+        let now = now.to_offset(time::UtcOffset::UTC).time();
+        if self.start <= self.end {
+            self.start <= now && now < self.end
+        } else {
+            self.start <= now || now < self.end
+        }
+    }
+}
+
+impl FromStr for FlushWindow {
+    type Err = Error;
+
+    fn from_str(window: &str) -> Result<Self> {
+        let invalid = || Error::InvalidConfig(format!("invalid flush window: {:?}", window));
+        let parse_time = |time: &str| {
+            let (hours, minutes) = time.trim().split_once(':').ok_or_else(invalid)?;
+            let hours = hours.parse().map_err(|_| invalid())?;
+            let minutes = minutes.parse().map_err(|_| invalid())?;
+            Time::from_hms(hours, minutes, 0).map_err(|_| invalid())
+        };
+        let (start, end) = window.split_once('-').ok_or_else(invalid)?;
+        Ok(Self::new(parse_time(start)?, parse_time(end)?))
+    }
+}
+
+impl fmt::Debug for FlushWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start.hour(),
+            self.start.minute(),
+            self.end.hour(),
+            self.end.minute()
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for FlushWindow {
+    /// Deserialize from a `HH:MM-HH:MM` string.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::UtcOffset;
+
+    fn at(hour: u8, minute: u8, offset: i8) -> OffsetDateTime {
+        OffsetDateTime::UNIX_EPOCH
+            .replace_time(Time::from_hms(hour, minute, 0).unwrap())
+            .replace_offset(UtcOffset::from_hms(offset, 0, 0).unwrap())
+    }
+
+    #[test]
+    fn test_contains() {
+        let day: FlushWindow = "09:00-17:30".parse().unwrap();
+        assert!(day.contains(at(9, 0, 0)));
+        assert!(!day.contains(at(17, 30, 0)));
+        assert!(day.contains(at(18, 0, 2)));
+        assert!(!day.contains(at(20, 0, 2)));
+
+        let night: FlushWindow = "22:00-02:00".parse().unwrap();
+        assert!(night.contains(at(23, 0, 0)));
+        assert!(night.contains(at(1, 0, 0)));
+        assert!(!night.contains(at(12, 0, 0)));
+
+        for invalid in ["", "9-17", "09:00", "25:00-26:00", "09:00-17:60"] {
+            assert!(invalid.parse::<FlushWindow>().is_err(), "{:?}", invalid);
+        }
+    }
+}
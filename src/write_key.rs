@@ -0,0 +1,138 @@
+//! Segment write keys.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::{Error, Result};
+
+/// A validated Segment write key.
+///
+/// A write key is an API key for Segment's tracking API. See [Segment's
+/// documentation](https://segment.com/docs/guides/setup/how-do-i-find-my-write-key/)
+/// for how to find this value.
+///
+/// A `WriteKey` can only be constructed from a non-empty string made of
+/// base64 characters, which catches most copy-paste mistakes (quotes,
+/// whitespace, or an empty environment variable) early. Its `Debug` and
+/// `Display` implementations mask all but the first few characters, so the
+/// key never leaks into logs:
+///
+/// ```
+/// use segment::WriteKey;
+///
+/// let key = WriteKey::new("abcdEFGH1234").unwrap();
+/// assert_eq!(key.to_string(), "abcd********");
+/// assert_eq!(key.expose(), "abcdEFGH1234");
+///
+/// assert!(WriteKey::new("").is_err());
+/// assert!(WriteKey::new(" abcdEFGH1234\n").is_err());
+/// ```
+#[derive(Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct WriteKey(String);
+
+impl WriteKey {
+    /// Validate and wrap a write key.
+    pub fn new(key: impl Into<String>) -> Result<Self> {
+        let key = key.into();
+        if key.is_empty() {
+            return Err(Error::InvalidWriteKey("the write key is empty"));
+        }
+        let base64_safe = key
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"+/=-_".contains(&c));
+        if !base64_safe {
+            return Err(Error::InvalidWriteKey(
+                "the write key contains characters which are not base64-safe",
+            ));
+        }
+        Ok(Self(key))
+    }
+
+    /// The unmasked write key.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for WriteKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Short keys are masked entirely, since a prefix would give away too
+        // much of them.
+        let visible = if self.0.len() >= 12 { 4 } else { 0 };
+        write!(
+            f,
+            "{}{}",
+            &self.0[..visible],
+            "*".repeat(self.0.len() - visible)
+        )
+    }
+}
+
+impl fmt::Debug for WriteKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WriteKey({:?})", self.to_string())
+    }
+}
+
+impl FromStr for WriteKey {
+    type Err = Error;
+
+    fn from_str(key: &str) -> Result<Self> {
+        Self::new(key)
+    }
+}
+
+impl TryFrom<String> for WriteKey {
+    type Error = Error;
+
+    fn try_from(key: String) -> Result<Self> {
+        Self::new(key)
+    }
+}
+
+impl TryFrom<&str> for WriteKey {
+    type Error = Error;
+
+    fn try_from(key: &str) -> Result<Self> {
+        Self::new(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(WriteKey::new("abcdEFGH1234").is_ok());
+        assert!(WriteKey::new("a+b/c=d-e_f").is_ok());
+        assert!(matches!(WriteKey::new(""), Err(Error::InvalidWriteKey(_))));
+        for key in ["\"abcd\"", "abcd efgh", "abcd\n", "abcdé"] {
+            assert!(
+                matches!(WriteKey::new(key), Err(Error::InvalidWriteKey(_))),
+                "{:?} was accepted",
+                key
+            );
+        }
+
+        let key: WriteKey = "abcd".parse().unwrap();
+        assert_eq!(key.expose(), "abcd");
+        assert!(serde_json::from_str::<WriteKey>("\"\"").is_err());
+    }
+
+    #[test]
+    fn test_redact() {
+        let key = WriteKey::new("abcdEFGH1234").unwrap();
+        assert_eq!(key.to_string(), "abcd********");
+        assert_eq!(format!("{:?}", key), "WriteKey(\"abcd********\")");
+
+        // Short keys are masked entirely.
+        let key = WriteKey::new("abcdEFGH").unwrap();
+        assert_eq!(key.to_string(), "********");
+        assert_eq!(format!("{:?}", key), "WriteKey(\"********\")");
+    }
+}